@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+"""Regenerate the Unicode tables at the end of src/normalization.rs.
+
+The tables are taken from the Unicode Character Database that comes with Python's unicodedata
+module. Run it from the root of the repository and update the version in the module documentation
+if it changed:
+
+    python3 scripts/gen_normalization.py
+"""
+import re
+import sys
+import unicodedata
+
+PATH = "src/normalization.rs"
+HANGUL = range(0xAC00, 0xAC00 + 11172)
+
+
+def decompositions():
+    """The canonical decompositions, other than the Hangul syllables."""
+    table = []
+    for code in range(0x110000):
+        if code in HANGUL:
+            continue
+        decomposition = unicodedata.decomposition(chr(code))
+        if not decomposition or decomposition.startswith("<"):
+            continue
+        parts = [int(part, 16) for part in decomposition.split()]
+        table.append((code, parts[0], parts[1] if len(parts) > 1 else 0))
+    return table
+
+
+def composition_exclusions(table):
+    """The characters decomposing into two that NFC doesn't compose again."""
+    return [
+        code
+        for code, _, second in table
+        if second and unicodedata.normalize("NFC", chr(code)) != chr(code)
+    ]
+
+
+def combining_classes():
+    """The ranges of characters with the same combining class other than 0."""
+    ranges = []
+    for code in range(0x110000):
+        combining = unicodedata.combining(chr(code))
+        if not combining:
+            continue
+        if ranges and ranges[-1][1] == code - 1 and ranges[-1][2] == combining:
+            ranges[-1][1] = code
+        else:
+            ranges.append([code, code, combining])
+    return ranges
+
+
+def rows(items, per_line):
+    """Format table items, a number of them on every line."""
+    return "".join(
+        "    " + " ".join(items[start:start + per_line]) + "\n"
+        for start in range(0, len(items), per_line)
+    )
+
+
+def replace(source, name, element, items, per_line):
+    """Replace the contents and length of a table in the source."""
+    pattern = re.compile(
+        r"((?:static|const) %s: \[%s; )\d+(\] = \[\n).*?(^\];)" % (name, re.escape(element)),
+        re.DOTALL | re.MULTILINE,
+    )
+    source, count = pattern.subn(
+        lambda match: match.group(1) + str(len(items)) + match.group(2) + rows(items, per_line)
+        + match.group(3),
+        source,
+    )
+    if count != 1:
+        sys.exit("table %s not found in %s" % (name, PATH))
+    return source
+
+
+def main():
+    table = decompositions()
+    with open(PATH, encoding="utf-8") as file:
+        source = file.read()
+    source = replace(
+        source,
+        "DECOMPOSITIONS",
+        "(u32, u32, u32)",
+        ["(0x%04X, 0x%04X, 0x%04X)," % entry for entry in table],
+        4,
+    )
+    source = replace(
+        source,
+        "COMPOSITION_EXCLUSIONS",
+        "u32",
+        ["0x%04X," % code for code in composition_exclusions(table)],
+        10,
+    )
+    source = replace(
+        source,
+        "COMBINING_CLASSES",
+        "(u32, u32, u8)",
+        ["(0x%04X, 0x%04X, %d)," % tuple(entry) for entry in combining_classes()],
+        4,
+    )
+    with open(PATH, "w", encoding="utf-8") as file:
+        file.write(source)
+    print("Generated the tables from version %s of the Unicode Character Database."
+          % unicodedata.unidata_version)
+
+
+if __name__ == "__main__":
+    main()
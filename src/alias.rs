@@ -0,0 +1,216 @@
+//! This module makes up the 8.3 short names of entries with long names, as set by `-o alias=`.
+//! `fatfs` generates them the way Windows does, e.g. `LONGFI~1.TXT`, and falls back to a checksum
+//! of the long name, e.g. `LO3F2A~1.TXT`, once the first four numeric tails are taken. The short
+//! names made up here replace the ones `fatfs` generated.
+use crate::options::AliasStyle;
+
+/// The characters allowed in short names besides letters and digits.
+const SPECIAL_CHARACTERS: &str = "!#$%&'()-@^_`{}~";
+
+/// Make up the short names to try for a long name, in order.
+///
+/// # Parameters
+///
+/// * `long_name: &str` - The long name of the entry.
+/// * `style: AliasStyle` - How short names are made up.
+///
+/// # Returns
+///
+/// * `Vec<[u8; 11]>` - The short names as stored, padded with spaces, or none at all to keep the
+///   short name generated by `fatfs`.
+pub fn candidates(long_name: &str, style: AliasStyle) -> Vec<[u8; 11]> {
+    // Like `fatfs`, the extension is whatever follows the last `.`.
+    let (base, ext) = match long_name.rfind('.') {
+        Some(index) => (basis(&long_name[..index]), basis(&long_name[index + 1..])),
+        None => (basis(long_name), Vec::new()),
+    };
+    if base.is_empty() {
+        return Vec::new();
+    }
+    let short_name = |base: &[u8]| {
+        let mut raw_name = [b' '; 11];
+        raw_name[..base.len().min(8)].copy_from_slice(&base[..base.len().min(8)]);
+        raw_name[8..8 + ext.len().min(3)].copy_from_slice(&ext[..ext.len().min(3)]);
+        raw_name
+    };
+    match style {
+        AliasStyle::NumericTail => Vec::new(),
+        AliasStyle::NoTail => vec![short_name(&base)],
+        AliasStyle::Hash => {
+            let prefix = &base[..base.len().min(2)];
+            let hash = format!("{:04X}", checksum(long_name));
+            (1..10)
+                .map(|n| {
+                    short_name(&[prefix, hash.as_bytes(), format!("~{}", n).as_bytes()].concat())
+                })
+                .collect()
+        }
+    }
+}
+
+/// Find what the short name `fatfs` generates for a long name is made of, if it needs a numeric
+/// tail. Long names making up the same prefix and extension in one directory get their tails in
+/// the order they are created, e.g. `CONFIG~1.TXT` and `CONFIG~2.TXT`.
+///
+/// # Parameters
+///
+/// * `long_name: &str` - The long name of the entry.
+///
+/// # Returns
+///
+/// * `Option<(Vec<u8>, Vec<u8>)>` - The up to 6 characters the tail follows and the extension,
+///   or `None` if the long name fits into a short name as it is.
+pub fn tail_basis(long_name: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (base, ext) = match long_name.rfind('.') {
+        Some(index) => (&long_name[..index], &long_name[index + 1..]),
+        None => (long_name, ""),
+    };
+    let (short_base, short_ext) = (basis(base), basis(ext));
+    // Only letters changing case are not a loss, as the long name keeps them.
+    let kept = |part: &str, short_part: &[u8], length: usize| {
+        part.len() <= length && part.to_ascii_uppercase().as_bytes() == short_part
+    };
+    if !base.is_empty() && kept(base, &short_base, 8) && kept(ext, &short_ext, 3) {
+        return None;
+    }
+    Some((
+        short_base[..short_base.len().min(6)].to_vec(),
+        short_ext[..short_ext.len().min(3)].to_vec(),
+    ))
+}
+
+/// Turn a part of a long name into the characters allowed in short names, the way `fatfs` does:
+/// spaces and dots are dropped, letters made uppercase and other characters replaced by `_`.
+///
+/// # Parameters
+///
+/// * `part: &str` - The name or the extension of the long name.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The name or extension of the short name, not yet cut to length.
+fn basis(part: &str) -> Vec<u8> {
+    part.chars()
+        .filter(|c| !matches!(c, ' ' | '.'))
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || SPECIAL_CHARACTERS.contains(c) {
+                c.to_ascii_uppercase() as u8
+            } else {
+                b'_'
+            }
+        })
+        .collect()
+}
+
+/// Compute the checksum `fatfs` puts into the short names it falls back to, so both kinds of
+/// short names look alike.
+///
+/// # Parameters
+///
+/// * `long_name: &str` - The long name of the entry.
+///
+/// # Returns
+///
+/// * `u16` - The checksum.
+fn checksum(long_name: &str) -> u16 {
+    long_name.chars().fold(0u16, |sum, c| {
+        (sum >> 1).wrapping_add(sum << 15).wrapping_add(c as u16)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pad a short name the way it is stored.
+    fn raw(name: &str) -> [u8; 11] {
+        let mut raw_name = [b' '; 11];
+        raw_name[..name.len()].copy_from_slice(name.as_bytes());
+        raw_name
+    }
+
+    #[test]
+    fn numeric_tail_keeps_the_name_fatfs_made_up() {
+        assert!(candidates("Long File Name.txt", AliasStyle::NumericTail).is_empty());
+    }
+
+    #[test]
+    fn no_tail_cuts_the_name() {
+        assert_eq!(
+            candidates("Long File Name.txt", AliasStyle::NoTail),
+            vec![raw("LONGFILETXT")]
+        );
+        assert_eq!(
+            candidates("readme", AliasStyle::NoTail),
+            vec![raw("README")]
+        );
+    }
+
+    #[test]
+    fn hash_puts_the_checksum_after_two_characters() {
+        let names = candidates("Long File Name.txt", AliasStyle::Hash);
+        assert_eq!(names.len(), 9);
+        assert_eq!(names[0], raw("LO1BEC~1TXT"));
+        assert_eq!(names[8], raw("LO1BEC~9TXT"));
+        assert_eq!(
+            candidates("a.b", AliasStyle::Hash)[0],
+            raw(&format!("A{:04X}~1 B", checksum("a.b")))
+        );
+    }
+
+    #[test]
+    fn names_without_a_base_name_keep_the_name_fatfs_made_up() {
+        assert!(candidates(".bashrc", AliasStyle::NoTail).is_empty());
+        assert!(candidates("...", AliasStyle::Hash).is_empty());
+        assert!(candidates(" .txt", AliasStyle::Hash).is_empty());
+    }
+
+    #[test]
+    fn the_extension_follows_the_last_dot() {
+        assert_eq!(
+            candidates("archive.tar.gz", AliasStyle::NoTail),
+            vec![raw("ARCHIVETGZ")]
+        );
+        assert_eq!(
+            candidates("notes.markdown", AliasStyle::NoTail),
+            vec![raw("NOTES   MAR")]
+        );
+    }
+
+    #[test]
+    fn tails_are_only_needed_by_names_short_names_cannot_hold() {
+        assert_eq!(tail_basis("README.TXT"), None);
+        assert_eq!(tail_basis("readme.txt"), None);
+        assert_eq!(tail_basis("Makefile"), None);
+        assert_eq!(
+            tail_basis("configuration_one.txt"),
+            Some((b"CONFIG".to_vec(), b"TXT".to_vec()))
+        );
+        assert_eq!(
+            tail_basis("notes.markdown"),
+            Some((b"NOTES".to_vec(), b"MAR".to_vec()))
+        );
+        assert_eq!(tail_basis("a+b.c"), Some((b"A_B".to_vec(), b"C".to_vec())));
+        assert_eq!(
+            tail_basis("archive.tar.gz"),
+            Some((b"ARCHIV".to_vec(), b"GZ".to_vec()))
+        );
+    }
+
+    #[test]
+    fn basis_replaces_characters_short_names_cannot_hold() {
+        assert_eq!(basis("a b.c"), b"ABC");
+        assert_eq!(basis("a+b,c"), b"A_B_C");
+        assert_eq!(basis("~x!{}"), b"~X!{}");
+        assert_eq!(basis("été"), b"_T_");
+        assert_eq!(basis(""), b"");
+    }
+
+    #[test]
+    fn checksum_rotates_and_adds() {
+        assert_eq!(checksum(""), 0);
+        assert_eq!(checksum("a"), 0x0061);
+        assert_eq!(checksum("ab"), 0x8092);
+        assert_eq!(checksum("Long File Name.txt"), 0x1BEC);
+    }
+}
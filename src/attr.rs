@@ -0,0 +1,424 @@
+//! This module implements the `attr` subcommand, which shows and changes the Read-only, Hidden,
+//! System and Archive attributes of files and directories on a disk image that is not mounted,
+//! e.g. to hide a file from a provisioning script. The attributes are written to the directory
+//! entries directly, the way Windows and the Linux vfat driver read them. With `--dry-run`, the
+//! disk image is only opened for reading, and the changes are printed by the `dry_run` module
+//! instead.
+use crate::direntry;
+use crate::dry_run::{print_action, Arg};
+use crate::filesystem::{check_supported, find_entry, open_dir, short_names};
+use crate::floppy;
+use crate::storage::Storage;
+use fatfs::{FileAttributes, FileSystem as FatfsFileSystem, FsOptions};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The attributes that can be changed, with the letters standing for them, in the order they are
+/// printed.
+const ATTRIBUTE_LETTERS: [(FileAttributes, char); 4] = [
+    (FileAttributes::READ_ONLY, 'r'),
+    (FileAttributes::HIDDEN, 'h'),
+    (FileAttributes::SYSTEM, 's'),
+    (FileAttributes::ARCHIVE, 'a'),
+];
+
+/// The usage of the subcommand.
+const USAGE: &str =
+    "Usage: nuFAT attr <disk.img> <path> [--recursive] [+r|-r] [+h|-h] [+s|-s] [+a|-a] [--dry-run]";
+
+/// Represent the changes to make to the attributes of the entries.
+///
+/// # Members
+///
+/// * `set: FileAttributes` - The attributes to set.
+/// * `clear: FileAttributes` - The attributes to clear.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Changes {
+    set: FileAttributes,
+    clear: FileAttributes,
+}
+
+/// Represent a file or directory whose attributes are shown or changed.
+///
+/// # Members
+///
+/// * `path: PathBuf` - The path of the entry, with its long name.
+/// * `short_names: Vec<Vec<u8>>` - The short names leading to the entry, as `direntry` finds it.
+/// * `attributes: FileAttributes` - The attributes of the entry.
+struct Target {
+    path: PathBuf,
+    short_names: Vec<Vec<u8>>,
+    attributes: FileAttributes,
+}
+
+/// Run the `attr` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand:
+///   `<disk.img> <path> [--recursive] [+r|-r] [+h|-h] [+s|-s] [+a|-a] [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image or the entry can't be read or
+///   written, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut recursive = false;
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+    let mut changes = Changes::default();
+    for arg in args {
+        if arg == "--recursive" {
+            recursive = true;
+        } else if arg == "--dry-run" {
+            dry_run = true;
+        } else if paths.len() < 2 && !arg.starts_with("--") {
+            paths.push(arg.as_str());
+        } else {
+            match parse_changes(arg, changes) {
+                Ok(parsed) => changes = parsed,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            }
+        }
+    }
+    let [disk_image_path, path] = paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let path = Path::new("/").join(path.trim_start_matches('/'));
+    if path == Path::new("/") {
+        eprintln!("The root directory has no attributes.");
+        return 2;
+    }
+
+    let modify = changes != Changes::default();
+    let result = OpenOptions::new()
+        .read(true)
+        .write(modify && !dry_run)
+        .open(disk_image_path)
+        .and_then(|image| {
+            let targets = find_targets(&image, &path, recursive)?;
+            if !modify {
+                for target in &targets {
+                    println!(
+                        "{} {}",
+                        format_letters(target.attributes),
+                        target.path.display()
+                    );
+                }
+                return Ok(());
+            }
+            for target in &targets {
+                let attributes = (target.attributes - changes.clear) | changes.set;
+                if attributes == target.attributes {
+                    continue;
+                }
+                if dry_run {
+                    print_action(
+                        "attr",
+                        &[
+                            Arg::Text(&format!("img:{}", target.path.display())),
+                            Arg::Text(&format_letters(target.attributes)),
+                            Arg::Text(&format_letters(attributes)),
+                        ],
+                    );
+                } else {
+                    direntry::set_attributes(&image, &target.short_names, attributes.bits())?;
+                }
+            }
+            if dry_run {
+                return Ok(());
+            }
+            image.sync_all()
+        });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Parse a change of attributes like `+h`, `-a` or `+rs`.
+///
+/// # Parameters
+///
+/// * `arg: &str` - The argument.
+/// * `changes: Changes` - The changes parsed so far.
+///
+/// # Returns
+///
+/// * `Result<Changes, String>` - The changes including this one, or why the argument is invalid,
+///   e.g. if it sets an attribute another clears.
+fn parse_changes(arg: &str, mut changes: Changes) -> Result<Changes, String> {
+    let (set, letters) = match arg.split_at_checked(1) {
+        Some(("+", letters)) if !letters.is_empty() => (true, letters),
+        Some(("-", letters)) if !letters.is_empty() => (false, letters),
+        _ => return Err(format!("Invalid argument '{}'.", arg)),
+    };
+    for letter in letters.chars() {
+        let Some((attribute, _)) = ATTRIBUTE_LETTERS.iter().find(|(_, known)| *known == letter)
+        else {
+            return Err(format!(
+                "Unknown attribute '{}', expected one of r, h, s and a.",
+                letter
+            ));
+        };
+        if set {
+            changes.set |= *attribute;
+        } else {
+            changes.clear |= *attribute;
+        }
+    }
+    if changes.set.intersects(changes.clear) {
+        return Err(format!(
+            "Can't both set and clear {}.",
+            format_letters(changes.set & changes.clear).replace('-', "")
+        ));
+    }
+    Ok(changes)
+}
+
+/// Find the file or directory at a path, and with `--recursive`, everything below a directory.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `path: &Path` - The absolute path of the file or directory.
+/// * `recursive: bool` - Whether to include the entries below a directory.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Target>>` - The entries, the one at `path` first, a `NotFound` error if
+///   there is none, or an `InvalidInput` error if `recursive` is given for a file.
+fn find_targets(image: &File, path: &Path, recursive: bool) -> io::Result<Vec<Target>> {
+    check_supported(image)?;
+    // `fatfs` only reads the volume, on a handle of its own.
+    let storage = Storage::new(image.try_clone()?, 0, floppy::patched_boot_sector(image)?);
+    let fs = FatfsFileSystem::new(storage, FsOptions::new())?;
+    let entry = find_entry(&fs, path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => io::Error::new(e.kind(), format!("{} not found", path.display())),
+        _ => e,
+    })?;
+    if recursive && !entry.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} is not a directory, --recursive needs one",
+                path.display()
+            ),
+        ));
+    }
+    // The name as stored, in case it was given in another case.
+    let path = path.with_file_name(entry.file_name());
+    let mut targets = vec![Target {
+        short_names: short_names(&fs, &path)?,
+        path,
+        attributes: entry.attributes(),
+    }];
+    if recursive {
+        let mut index = 0;
+        while index < targets.len() {
+            if targets[index]
+                .attributes
+                .contains(FileAttributes::DIRECTORY)
+            {
+                let (dir, short_names) = (
+                    targets[index].path.clone(),
+                    targets[index].short_names.clone(),
+                );
+                for entry in open_dir(&fs, &dir)?.iter() {
+                    let entry = entry?;
+                    if entry.attributes().contains(FileAttributes::VOLUME_ID)
+                        || matches!(entry.file_name().as_str(), "." | "..")
+                    {
+                        continue;
+                    }
+                    let mut entry_short_names = short_names.clone();
+                    entry_short_names.push(entry.short_file_name_as_bytes().to_vec());
+                    targets.push(Target {
+                        path: dir.join(entry.file_name()),
+                        short_names: entry_short_names,
+                        attributes: entry.attributes(),
+                    });
+                }
+            }
+            index += 1;
+        }
+    }
+    Ok(targets)
+}
+
+/// Format the changeable attributes of an entry as letters, e.g. `-h-a` for a hidden file that
+/// was changed since its last backup.
+///
+/// # Parameters
+///
+/// * `attributes: FileAttributes` - The attributes.
+///
+/// # Returns
+///
+/// * `String` - The letters `r`, `h`, `s` and `a` for the attributes that are set, `-` for the
+///   others.
+pub fn format_letters(attributes: FileAttributes) -> String {
+    ATTRIBUTE_LETTERS
+        .iter()
+        .map(|(attribute, letter)| {
+            if attributes.contains(*attribute) {
+                *letter
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Parse the letters `format_letters` formats attributes as.
+///
+/// # Parameters
+///
+/// * `letters: &str` - The letters, e.g. `-h-a`.
+///
+/// # Returns
+///
+/// * `Option<FileAttributes>` - The attributes, or `None` if the letters are malformed.
+pub fn parse_letters(letters: &str) -> Option<FileAttributes> {
+    let letters: Vec<char> = letters.chars().collect();
+    if letters.len() != ATTRIBUTE_LETTERS.len() {
+        return None;
+    }
+    let mut attributes = FileAttributes::empty();
+    for ((attribute, known), letter) in ATTRIBUTE_LETTERS.iter().zip(letters) {
+        match letter {
+            '-' => {}
+            _ if letter == *known => attributes |= *attribute,
+            _ => return None,
+        }
+    }
+    Some(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+
+    /// Run the subcommand on a disk image.
+    fn attr(image: &TestImage, args: &[&str]) -> i32 {
+        let image_path = image.options().disk_image_path;
+        let mut all_args = vec![image_path.to_str().unwrap().to_string()];
+        all_args.extend(args.iter().map(|arg| arg.to_string()));
+        run(&all_args)
+    }
+
+    /// Get the attributes of an entry, as read by `fatfs`.
+    fn attributes(image: &TestImage, path: &str) -> FileAttributes {
+        let fs = image.volume();
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = match parent {
+            "" => fs.root_dir(),
+            parent => fs.root_dir().open_dir(parent).unwrap(),
+        };
+        let entry = dir
+            .iter()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name() == name);
+        entry.unwrap().attributes()
+    }
+
+    #[test]
+    fn changes_are_parsed_and_conflicts_refused() {
+        let changes = parse_changes("+hs", Changes::default()).unwrap();
+        let changes = parse_changes("-a", changes).unwrap();
+        assert_eq!(changes.set, FileAttributes::HIDDEN | FileAttributes::SYSTEM);
+        assert_eq!(changes.clear, FileAttributes::ARCHIVE);
+        assert!(parse_changes("-h", changes)
+            .unwrap_err()
+            .contains("set and clear h"));
+        assert!(parse_changes("+x", changes).is_err());
+        assert!(parse_changes("+", changes).is_err());
+        assert!(parse_changes("h", changes).is_err());
+    }
+
+    #[test]
+    fn attributes_are_formatted_as_letters() {
+        assert_eq!(format_letters(FileAttributes::empty()), "----");
+        assert_eq!(
+            format_letters(FileAttributes::HIDDEN | FileAttributes::ARCHIVE),
+            "-h-a"
+        );
+        assert_eq!(format_letters(FileAttributes::all()), "rhsa");
+    }
+
+    #[test]
+    fn attributes_are_set_and_cleared() {
+        for fat_type in [FatType::Fat12, FatType::Fat32] {
+            let image = TestImage::with_files(fat_type, &[("Long Name.txt", b"data")]);
+            let before = attributes(&image, "Long Name.txt");
+            assert_eq!(attr(&image, &["/long name.TXT", "+h", "+rs"]), 0);
+            assert_eq!(
+                attributes(&image, "Long Name.txt"),
+                before
+                    | FileAttributes::READ_ONLY
+                    | FileAttributes::HIDDEN
+                    | FileAttributes::SYSTEM
+            );
+            assert_eq!(attr(&image, &["Long Name.txt", "-r", "-s"]), 0);
+            assert_eq!(
+                attributes(&image, "Long Name.txt"),
+                before | FileAttributes::HIDDEN
+            );
+            assert_eq!(attr(&image, &["Long Name.txt"]), 0);
+        }
+    }
+
+    #[test]
+    fn directories_are_changed_recursively() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "dir", "entries": [
+                    {"name": "sub", "entries": [{"name": "deep.txt"}]},
+                    {"name": "file.txt"}
+                ]},
+                {"name": "outside.txt"}
+            ]}"#,
+        );
+        let checksum = image.checksum();
+        assert_eq!(attr(&image, &["dir", "+h", "--recursive", "--dry-run"]), 0);
+        assert_eq!(image.checksum(), checksum);
+        assert_eq!(attr(&image, &["dir", "+h", "--recursive"]), 0);
+        for path in ["dir", "dir/sub", "dir/sub/deep.txt", "dir/file.txt"] {
+            assert!(
+                attributes(&image, path).contains(FileAttributes::HIDDEN),
+                "{}",
+                path
+            );
+        }
+        assert!(attributes(&image, "dir").contains(FileAttributes::DIRECTORY));
+        assert!(!attributes(&image, "outside.txt").contains(FileAttributes::HIDDEN));
+        // Without `--recursive`, only the directory itself is changed.
+        assert_eq!(attr(&image, &["dir", "-h"]), 0);
+        assert!(!attributes(&image, "dir").contains(FileAttributes::HIDDEN));
+        assert!(attributes(&image, "dir/sub").contains(FileAttributes::HIDDEN));
+    }
+
+    #[test]
+    fn invalid_arguments_and_missing_entries_fail() {
+        let image = TestImage::with_files(FatType::Fat12, &[("file.txt", b"")]);
+        let before = attributes(&image, "file.txt");
+        assert_eq!(attr(&image, &["file.txt", "+h", "-h"]), 2);
+        assert_eq!(attr(&image, &["file.txt", "+q"]), 2);
+        assert_eq!(attr(&image, &["/", "+h"]), 2);
+        assert_eq!(attr(&image, &[]), 2);
+        assert_eq!(attr(&image, &["missing.txt", "+h"]), 1);
+        assert_eq!(attr(&image, &["file.txt", "--recursive", "+h"]), 1);
+        assert_eq!(attributes(&image, "file.txt"), before);
+    }
+}
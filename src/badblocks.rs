@@ -0,0 +1,361 @@
+//! This module implements the `badblocks` subcommand, which reads every cluster of a disk image or
+//! device that is not mounted to find the ones that can't be read anymore, e.g. on an aging SD
+//! card. Bad clusters that are free can be marked as such in the FAT, so they are never used, and
+//! the files stored in bad clusters are listed, to copy what can still be read elsewhere first.
+use crate::direntry::{self, ClusterState};
+use crate::filesystem::{check_supported, lock_image};
+use crate::fsck::format_ranges;
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT badblocks <disk.img|device> [--write-test] [--mark]";
+
+/// The most bytes read in one go. If reading them fails, every cluster is read on its own.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// The patterns written to free clusters by `--write-test`, and read back.
+const PATTERNS: [u8; 2] = [0xAA, 0x55];
+
+/// Run the `badblocks` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand:
+///   `<disk.img|device> [--write-test] [--mark]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 if no bad clusters were found, 1 if there are some or the disk
+///   image can't be read or written, and 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut disk_image_path = None;
+    let mut write_test = false;
+    let mut mark = false;
+    for arg in args {
+        match arg.as_str() {
+            "--write-test" => write_test = true,
+            "--mark" => mark = true,
+            _ if disk_image_path.is_none() && !arg.starts_with("--") => {
+                disk_image_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                disk_image_path = None;
+                break;
+            }
+        }
+    }
+    let Some(disk_image_path) = disk_image_path else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let result = OpenOptions::new()
+        .read(true)
+        .write(write_test || mark)
+        .open(&disk_image_path)
+        .and_then(|image| {
+            lock_image(&image, write_test || mark)?;
+            check_supported(&image)?;
+            check_bad_clusters(&image, write_test, mark)
+        });
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Scan the volume for bad clusters, report them and the files stored in them, and mark the free
+/// ones as bad if asked to.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, opened for writing for `write_test` and `mark`.
+/// * `write_test: bool` - Whether to write to the free clusters and read them back as well.
+/// * `mark: bool` - Whether to mark the free clusters found to be bad as such in the FAT.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - Whether no bad clusters were found.
+fn check_bad_clusters(image: &File, write_test: bool, mark: bool) -> io::Result<bool> {
+    let map = direntry::cluster_map(image)?;
+    let known = map
+        .states
+        .iter()
+        .filter(|state| **state == ClusterState::Bad)
+        .count();
+    if known > 0 {
+        println!("{} clusters are already marked as bad.", known);
+    }
+    let (scanned, bad) = scan(image, write_test)?;
+    if bad.is_empty() {
+        println!("Scanned {} clusters, none of them are bad.", scanned);
+        return Ok(true);
+    }
+    println!(
+        "Scanned {} clusters, {} of them are bad: {}.",
+        scanned,
+        bad.len(),
+        format_ranges(&cluster_ranges(&bad))
+    );
+
+    let bad_set: HashSet<u32> = bad.iter().copied().collect();
+    let files = overlapping_files(image, &bad_set)?;
+    if !files.is_empty() {
+        println!("Files and directories stored in bad clusters:");
+        for (path, clusters) in &files {
+            println!("  {}: {} bad clusters", path, clusters);
+        }
+    }
+
+    let free: Vec<u32> = bad
+        .iter()
+        .copied()
+        .filter(|cluster| map.states[*cluster as usize - 2] == ClusterState::Free)
+        .collect();
+    if mark {
+        direntry::mark_bad_clusters(image, &free)?;
+        image.sync_all()?;
+        println!("Marked {} free clusters as bad.", free.len());
+        if free.len() < bad.len() {
+            println!("Copy the files above elsewhere and delete them, then run badblocks --mark again to mark the rest.");
+        }
+    } else if !free.is_empty() {
+        println!(
+            "Run with --mark to mark the {} free ones as bad.",
+            free.len()
+        );
+    }
+    Ok(false)
+}
+
+/// Read every cluster that is not marked as bad yet, and with `write_test`, write patterns to the
+/// free ones and read them back, leaving them zeroed.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, opened for writing for `write_test`.
+/// * `write_test: bool` - Whether to write to the free clusters as well.
+///
+/// # Returns
+///
+/// * `io::Result<(u64, Vec<u32>)>` - How many clusters were scanned, and the clusters that
+///   couldn't be read, or written and read back, in order.
+fn scan(image: &File, write_test: bool) -> io::Result<(u64, Vec<u32>)> {
+    let map = direntry::cluster_map(image)?;
+    // Whether the clusters are written to as well, or skipped if they are marked as bad.
+    let mode = |state: ClusterState| match state {
+        ClusterState::Bad => None,
+        ClusterState::Free => Some(write_test),
+        _ => Some(false),
+    };
+    let per_chunk = cmp::max(1, CHUNK_SIZE / map.cluster_size) as usize;
+    let mut scanned = 0;
+    let mut bad = Vec::new();
+    let mut index = 0;
+    while index < map.states.len() {
+        let run_mode = mode(map.states[index]);
+        let run = map.states[index..]
+            .iter()
+            .take(per_chunk)
+            .take_while(|state| mode(**state) == run_mode)
+            .count();
+        if let Some(write) = run_mode {
+            let offset = map.data_start + index as u64 * map.cluster_size;
+            let size = (run as u64 * map.cluster_size) as usize;
+            if !check_region(image, offset, size, write) {
+                // Find out which of the clusters are bad.
+                for cluster in 0..run {
+                    let offset = offset + cluster as u64 * map.cluster_size;
+                    if !check_region(image, offset, map.cluster_size as usize, write) {
+                        bad.push((index + cluster) as u32 + 2);
+                    }
+                }
+            }
+            scanned += run as u64;
+        }
+        index += run;
+    }
+    Ok((scanned, bad))
+}
+
+/// Check whether a region of the disk image can be read, and written and read back.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, opened for writing for `write`.
+/// * `offset: u64` - The offset of the region.
+/// * `size: usize` - The size of the region.
+/// * `write: bool` - Whether to write the patterns to the region and read them back.
+///
+/// # Returns
+///
+/// * `bool` - Whether the region is fine.
+fn check_region(image: &File, offset: u64, size: usize, write: bool) -> bool {
+    let mut buf = vec![0; size];
+    if image.read_exact_at(&mut buf, offset).is_err() {
+        return false;
+    }
+    if !write {
+        return true;
+    }
+    for pattern in PATTERNS {
+        buf.fill(pattern);
+        let written = image
+            .write_all_at(&buf, offset)
+            .and_then(|()| image.sync_data())
+            .is_ok();
+        // Read from the device, not from the page cache.
+        unsafe {
+            libc::posix_fadvise(
+                image.as_raw_fd(),
+                offset as i64,
+                size as i64,
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+        if !written
+            || image.read_exact_at(&mut buf, offset).is_err()
+            || buf.iter().any(|b| *b != pattern)
+        {
+            return false;
+        }
+    }
+    buf.fill(0);
+    image.write_all_at(&buf, offset).is_ok()
+}
+
+/// Find the files and directories stored in bad clusters.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `bad: &HashSet<u32>` - The bad clusters.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<(String, usize)>>` - The path of every file and directory stored in a bad
+///   cluster, with how many of its clusters are bad.
+fn overlapping_files(image: &File, bad: &HashSet<u32>) -> io::Result<Vec<(String, usize)>> {
+    Ok(direntry::walk_tree(image)?
+        .into_iter()
+        .map(|entry| {
+            let clusters = entry.clusters.iter().filter(|c| bad.contains(c)).count();
+            (entry.path, clusters)
+        })
+        .filter(|(_, clusters)| *clusters > 0)
+        .collect())
+}
+
+/// Merge clusters into ranges of adjacent ones.
+///
+/// # Parameters
+///
+/// * `clusters: &[u32]` - The clusters, in order.
+///
+/// # Returns
+///
+/// * `Vec<Range<u32>>` - The ranges.
+fn cluster_ranges(clusters: &[u32]) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    for cluster in clusters {
+        match ranges.last_mut() {
+            Some(range) if range.end == *cluster => range.end += 1,
+            _ => ranges.push(*cluster..cluster + 1),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+    use std::io::Write;
+
+    #[test]
+    fn scan_finds_clusters_that_cant_be_read() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        let map = direntry::cluster_map(&file).unwrap();
+        assert_eq!(
+            scan(&file, false).unwrap(),
+            (map.states.len() as u64, vec![])
+        );
+        assert_eq!(scan(&file, true).unwrap().1, []);
+
+        // The clusters past the end of a disk image can't be read.
+        let size = file.metadata().unwrap().len();
+        file.set_len(size - 3 * map.cluster_size).unwrap();
+        let last = map.states.len() as u32 + 1;
+        let (_, bad) = scan(&file, false).unwrap();
+        assert_eq!(bad[bad.len() - 3..], [last - 2, last - 1, last]);
+        assert!(!check_bad_clusters(&file, false, false).unwrap());
+    }
+
+    #[test]
+    fn marked_clusters_are_never_allocated() {
+        let image = TestImage::new(FatType::Fat12);
+        let file = image.open();
+        let free_clusters = image.volume().stats().unwrap().free_clusters();
+        direntry::mark_bad_clusters(&file, &[3, 4, 10]).unwrap();
+        let map = direntry::cluster_map(&file).unwrap();
+        let states = map.states;
+        assert_eq!(states[1..3], [ClusterState::Bad, ClusterState::Bad]);
+        assert_eq!(states[8], ClusterState::Bad);
+        assert!(direntry::compare_fats(&file).unwrap()[0].1.is_empty());
+
+        {
+            let fs = image.volume();
+            assert_eq!(fs.stats().unwrap().free_clusters(), free_clusters - 3);
+            let mut data = fs.root_dir().create_file("data.bin").unwrap();
+            data.write_all(&vec![1; 10 * map.cluster_size as usize])
+                .unwrap();
+        }
+        let first = direntry::first_cluster_of(&file, &[b"DATA.BIN".to_vec()])
+            .unwrap()
+            .unwrap();
+        let chain = direntry::cluster_chain(&file, first).unwrap();
+        assert_eq!(chain.len(), 10);
+        assert!(!chain.iter().any(|cluster| [3, 4, 10].contains(cluster)));
+        // Already marked clusters are not scanned again.
+        let (scanned, _) = scan(&file, false).unwrap();
+        assert_eq!(scanned, states.len() as u64 - 3);
+    }
+
+    #[test]
+    fn overlapping_files_lists_files_in_bad_clusters() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "Damaged file.bin", "size": 20000, "fill": 7},
+                {"name": "fine.txt", "size": 4, "fill": "fine"}
+            ]}"#,
+        );
+        let file = image.open();
+        let first = direntry::first_cluster_of(&file, &[b"DAMAGE~1.BIN".to_vec()])
+            .unwrap()
+            .unwrap();
+        let bad = HashSet::from([first + 1, first + 2, 60000]);
+        assert_eq!(
+            overlapping_files(&file, &bad).unwrap(),
+            [("/Damaged file.bin".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn cluster_ranges_merges_adjacent_clusters() {
+        assert_eq!(cluster_ranges(&[2, 3, 4, 7, 9, 10]), [2..5, 7..8, 9..11]);
+        assert_eq!(cluster_ranges(&[]), []);
+    }
+}
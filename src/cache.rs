@@ -0,0 +1,329 @@
+//! This module keeps the memory nuFAT caches under one budget, set with `--cache-limit`. It holds
+//! the listings of the open directory handles and the cluster chains of FAT+ files larger than
+//! 4 GiB, counts the bytes every one of them takes up, and once they take up more than the budget
+//! evicts the least recently used cluster chains first, then the least recently used listings.
+//! An evicted cluster chain is followed again when it is needed, an evicted listing is taken
+//! again when its directory is read on. What is being added is never evicted, so a single
+//! listing larger than the budget is still kept while its directory is read.
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The smallest budget `--cache-limit` defaults to.
+const MIN_DEFAULT_LIMIT: u64 = 4 << 20;
+
+/// Tell the bytes a cached value takes up on the heap, besides its own size.
+pub trait Footprint {
+    /// Get the bytes the value takes up on the heap.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The bytes, roughly.
+    fn heap_size(&self) -> usize;
+}
+
+/// Represent the bytes taken up by every cache, shared with the metrics.
+///
+/// # Members
+///
+/// * `listings: AtomicU64` - The bytes taken up by the listings of open directories.
+/// * `cluster_chains: AtomicU64` - The bytes taken up by the cluster chains of large files.
+#[derive(Default)]
+pub struct CacheUsage {
+    pub listings: AtomicU64,
+    pub cluster_chains: AtomicU64,
+}
+
+impl CacheUsage {
+    /// Get the bytes taken up by all caches together.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The bytes.
+    pub fn total(&self) -> u64 {
+        self.listings.load(Ordering::Relaxed) + self.cluster_chains.load(Ordering::Relaxed)
+    }
+}
+
+/// Represent a cached value.
+///
+/// # Members
+///
+/// * `value: T` - The value.
+/// * `bytes: u64` - The bytes it takes up.
+/// * `used: u64` - When it was last used, as a tick of `Caches::clock`.
+struct Cached<T> {
+    value: T,
+    bytes: u64,
+    used: u64,
+}
+
+/// Represent the caches of a mounted filesystem.
+///
+/// # Members
+///
+/// * `limit: u64` - The bytes all caches may take up together.
+/// * `listings: HashMap<u64, Option<Cached<Vec<L>>>>` - The listings of the open directory
+///   handles, `None` for handles that have no listing, as their directory wasn't read yet or the
+///   listing was evicted.
+/// * `cluster_chains: HashMap<u32, Cached<Vec<u32>>>` - The cluster chains of the FAT+ files
+///   larger than 4 GiB read so far, by their first cluster.
+/// * `usage: Arc<CacheUsage>` - The bytes taken up by every cache.
+/// * `clock: u64` - Counts every use of a cached value, to tell which was used least recently.
+pub struct Caches<L> {
+    limit: u64,
+    listings: HashMap<u64, Option<Cached<Vec<L>>>>,
+    cluster_chains: HashMap<u32, Cached<Vec<u32>>>,
+    usage: Arc<CacheUsage>,
+    clock: u64,
+}
+
+impl<L: Footprint> Caches<L> {
+    /// Create empty caches.
+    ///
+    /// # Parameters
+    ///
+    /// * `limit: u64` - The bytes all caches may take up together.
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The caches.
+    pub fn new(limit: u64) -> Self {
+        Caches {
+            limit,
+            listings: HashMap::new(),
+            cluster_chains: HashMap::new(),
+            usage: Arc::new(CacheUsage::default()),
+            clock: 0,
+        }
+    }
+
+    /// Get the bytes taken up by every cache.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<CacheUsage>` - The bytes, which keep being updated.
+    #[cfg(any(feature = "metrics", test))]
+    pub fn usage(&self) -> Arc<CacheUsage> {
+        Arc::clone(&self.usage)
+    }
+
+    /// Start tracking the listing of a new directory handle.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The directory handle.
+    pub fn open_listing(&mut self, fh: u64) {
+        self.listings.insert(fh, None);
+    }
+
+    /// Drop the listing of a released directory handle.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The directory handle.
+    pub fn close_listing(&mut self, fh: u64) {
+        if let Some(Some(listing)) = self.listings.remove(&fh) {
+            self.usage
+                .listings
+                .fetch_sub(listing.bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Check whether a directory handle is open, but has no listing to read on from.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The directory handle.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the listing has to be taken, e.g. because it was evicted.
+    pub fn lacks_listing(&self, fh: u64) -> bool {
+        matches!(self.listings.get(&fh), Some(None))
+    }
+
+    /// Replace the listing of an open directory handle, evicting others if that takes up more
+    /// than the budget.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The directory handle. Nothing is kept if it is not open.
+    /// * `entries: Vec<L>` - The entries of the directory.
+    pub fn set_listing(&mut self, fh: u64, entries: Vec<L>) {
+        let bytes = entries
+            .iter()
+            .map(|entry| (mem::size_of::<L>() + entry.heap_size()) as u64)
+            .sum::<u64>()
+            + mem::size_of::<Vec<L>>() as u64;
+        self.clock += 1;
+        let Some(listing) = self.listings.get_mut(&fh) else {
+            return;
+        };
+        let old = listing.replace(Cached {
+            value: entries,
+            bytes,
+            used: self.clock,
+        });
+        self.usage.listings.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(old) = old {
+            self.usage.listings.fetch_sub(old.bytes, Ordering::Relaxed);
+        }
+        self.evict(Some(fh), None);
+    }
+
+    /// Get the listing of an open directory handle.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The directory handle.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&[L]>` - The entries, or `None` if the handle is not open or has no listing.
+    pub fn listing(&mut self, fh: u64) -> Option<&[L]> {
+        self.clock += 1;
+        let listing = self.listings.get_mut(&fh)?.as_mut()?;
+        listing.used = self.clock;
+        Some(&listing.value)
+    }
+
+    /// Get the cluster chain of a file, following it only if it isn't cached, and evicting other
+    /// values if keeping it takes up more than the budget.
+    ///
+    /// # Parameters
+    ///
+    /// * `first_cluster: u32` - The first cluster of the file.
+    /// * `follow: impl FnOnce() -> io::Result<Vec<u32>>` - Follows the cluster chain.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<&[u32]>` - The cluster chain, or the error following it failed with.
+    pub fn cluster_chain(
+        &mut self,
+        first_cluster: u32,
+        follow: impl FnOnce() -> io::Result<Vec<u32>>,
+    ) -> io::Result<&[u32]> {
+        self.clock += 1;
+        if !self.cluster_chains.contains_key(&first_cluster) {
+            let chain = follow()?;
+            let bytes = (mem::size_of::<Vec<u32>>() + chain.len() * mem::size_of::<u32>()) as u64;
+            self.usage
+                .cluster_chains
+                .fetch_add(bytes, Ordering::Relaxed);
+            self.cluster_chains.insert(
+                first_cluster,
+                Cached {
+                    value: chain,
+                    bytes,
+                    used: self.clock,
+                },
+            );
+            self.evict(None, Some(first_cluster));
+        }
+        let chain = self.cluster_chains.get_mut(&first_cluster).unwrap();
+        chain.used = self.clock;
+        Ok(&chain.value)
+    }
+
+    /// Drop all cluster chains, e.g. once clusters may have been reused.
+    pub fn clear_cluster_chains(&mut self) {
+        self.cluster_chains.clear();
+        self.usage.cluster_chains.store(0, Ordering::Relaxed);
+    }
+
+    /// Helper function to evict the least recently used values until all caches take up no more
+    /// than the budget, the cluster chains before the listings.
+    ///
+    /// # Parameters
+    ///
+    /// * `listing: Option<u64>` - The directory handle whose listing must be kept.
+    /// * `cluster_chain: Option<u32>` - The first cluster of the cluster chain that must be kept.
+    fn evict(&mut self, listing: Option<u64>, cluster_chain: Option<u32>) {
+        while self.usage.total() > self.limit {
+            let oldest_chain = self
+                .cluster_chains
+                .iter()
+                .filter(|(first_cluster, _)| Some(**first_cluster) != cluster_chain)
+                .min_by_key(|(_, chain)| chain.used)
+                .map(|(first_cluster, _)| *first_cluster);
+            if let Some(first_cluster) = oldest_chain {
+                let chain = self.cluster_chains.remove(&first_cluster).unwrap();
+                self.usage
+                    .cluster_chains
+                    .fetch_sub(chain.bytes, Ordering::Relaxed);
+                continue;
+            }
+            let oldest_listing = self
+                .listings
+                .iter()
+                .filter(|(fh, _)| Some(**fh) != listing)
+                .filter_map(|(fh, cached)| Some((*fh, cached.as_ref()?.used)))
+                .min_by_key(|(_, used)| *used)
+                .map(|(fh, _)| fh);
+            let Some(fh) = oldest_listing else {
+                break;
+            };
+            let evicted = self.listings.get_mut(&fh).unwrap().take().unwrap();
+            self.usage
+                .listings
+                .fetch_sub(evicted.bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Get the budget `--cache-limit` defaults to, a 64th of the memory of the machine, but at least
+/// 4 MiB.
+///
+/// # Returns
+///
+/// * `u64` - The budget in bytes.
+pub fn default_limit() -> u64 {
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if pages <= 0 || page_size <= 0 {
+        return MIN_DEFAULT_LIMIT;
+    }
+    (pages as u64 * page_size as u64 / 64).max(MIN_DEFAULT_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Footprint for String {
+        fn heap_size(&self) -> usize {
+            self.capacity()
+        }
+    }
+
+    #[test]
+    fn cluster_chains_are_evicted_before_listings() {
+        let mut caches = Caches::<String>::new(1024);
+        caches.open_listing(1);
+        caches.open_listing(2);
+        caches.set_listing(1, vec!["a".repeat(400)]);
+        caches.cluster_chain(7, || Ok(vec![0; 50])).unwrap();
+        assert!(caches.usage().total() <= 1024);
+
+        // The chain goes first, then the listing that was used least recently.
+        caches.set_listing(2, vec!["b".repeat(400)]);
+        assert!(caches.cluster_chains.is_empty());
+        assert!(caches.listing(1).is_some());
+        caches.set_listing(2, vec!["b".repeat(600)]);
+        assert!(caches.lacks_listing(1));
+        assert_eq!(caches.listing(2).unwrap().len(), 1);
+        assert!(caches.usage().total() <= 1024);
+
+        caches.close_listing(2);
+        assert_eq!(caches.usage().total(), 0);
+        assert!(caches.listing(2).is_none());
+        assert!(!caches.lacks_listing(2));
+    }
+}
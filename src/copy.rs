@@ -0,0 +1,1196 @@
+//! This module copies files and directories between a disk image that is not mounted and the
+//! host, for the `cp`, `extract`, `pack` and `unpack` subcommands. Entries keep the times they
+//! were last modified in either direction, and entries whose relative paths match an exclude
+//! pattern are left out, with everything below them. Every copy is checked before anything is
+//! written: files at the destination are only replaced if asked to, with `--force`, or if they
+//! are older with `--update`, two entries that would end up at the same path are refused, and so
+//! are copies that don't fit. With `--dry-run`, that is all that is done, and the actions that
+//! would be taken are printed by the `dry_run` module. With `--owner`, what is written to the
+//! host is given to a user and group, directories once everything in them is written.
+//!
+//! Copying to the volume is done by `fatfs`, which stamps what it writes with the current time,
+//! so the times of the copied entries are written once it is done, through the `direntry`
+//! module.
+use crate::direntry;
+use crate::dry_run::{print_action, Arg};
+use crate::exclude::{is_excluded, Exclude};
+use crate::filesystem::{fat_to_system_time, open_dir, short_names, system_to_fat_time};
+use crate::json;
+use crate::storage::Storage;
+use crate::tree::{self, Found};
+use fatfs::{Date, DateTime, FileAttributes, FileSystem as FatfsFileSystem};
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::chown;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How much newer a file must be to replace another with `--update`. FAT keeps modification
+/// times in steps of two seconds, so a file copied to or from it may seem up to that much older.
+const UPDATE_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Represent what to do with files that exist at the destination.
+///
+/// # Variants
+///
+/// * `Refuse` - Copy nothing, and fail.
+/// * `Overwrite` - Replace them, as with `--force`.
+/// * `Update` - Replace them if the file copied to them is newer, as with `--update`, and leave
+///   them otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflicts {
+    #[default]
+    Refuse,
+    Overwrite,
+    Update,
+}
+
+/// Represent how entries are copied.
+///
+/// # Members
+///
+/// * `dotify_hidden: bool` - Whether to put a `.` in front of the names of entries with the
+///   Hidden attribute copied to the host, so they are hidden there as well, as with
+///   `--dotify-hidden`.
+/// * `conflicts: Conflicts` - What to do with files that exist at the destination.
+/// * `excludes: Vec<Exclude>` - The patterns of entries to leave out, as given with `--exclude`
+///   and `--exclude-from`, matched against their paths below the entry copied.
+/// * `dry_run: bool` - Whether to only check the copy and print what it would do, as with
+///   `--dry-run`, writing nothing.
+/// * `owner: Option<Owner>` - Who to give the entries copied to the host, as with `--owner`, or
+///   `None` to leave them to whoever runs nuFAT.
+#[derive(Clone, Debug, Default)]
+pub struct CopyOptions {
+    pub dotify_hidden: bool,
+    pub conflicts: Conflicts,
+    pub excludes: Vec<Exclude>,
+    pub dry_run: bool,
+    pub owner: Option<Owner>,
+}
+
+/// Represent the owner to give entries copied to the host, as set by `--owner UID[:GID]`. The
+/// IDs are taken as they are, like with `tar --numeric-owner`, and never looked up by name.
+///
+/// # Members
+///
+/// * `uid: u32` - The ID of the user.
+/// * `gid: Option<u32>` - The ID of the group, or `None` to leave the group alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// Parse the argument of `--owner`.
+    ///
+    /// # Parameters
+    ///
+    /// * `arg: &str` - The argument, `UID` or `UID:GID`, e.g. `1000:100`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Owner, String>` - The owner, or why the argument is invalid.
+    pub fn parse(arg: &str) -> Result<Owner, String> {
+        let (uid, gid) = match arg.split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (arg, None),
+        };
+        let id = |id: &str| {
+            id.parse::<u32>()
+                .map_err(|_| format!("--owner: expected UID[:GID] as numbers, found '{}'", arg))
+        };
+        Ok(Owner {
+            uid: id(uid)?,
+            gid: gid.map(id).transpose()?,
+        })
+    }
+
+    /// Format the owner for the JSON report of `--json`.
+    ///
+    /// # Parameters
+    ///
+    /// * `owner: Option<Owner>` - The owner, if `--owner` was given.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The owner as a JSON object with `uid` and `gid`, `gid` being `null` if the
+    ///   group is left alone, or `null` if there is no owner.
+    pub fn format_json(owner: Option<Owner>) -> String {
+        match owner {
+            Some(Owner { uid, gid }) => format!(
+                "{{\"uid\": {}, \"gid\": {}}}",
+                uid,
+                gid.map_or("null".to_string(), |gid| gid.to_string())
+            ),
+            None => "null".to_string(),
+        }
+    }
+}
+
+/// Represent what was copied.
+///
+/// # Members
+///
+/// * `files: u64` - The number of files copied.
+/// * `bytes: u64` - The number of bytes in them.
+/// * `skipped: u64` - The number of files left alone with `--update`, as the file at the
+///   destination was not older.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+}
+
+impl Summary {
+    /// Describe what was copied, for printing.
+    ///
+    /// # Parameters
+    ///
+    /// * `dry_run: bool` - Whether it was only checked what would be copied, with `--dry-run`.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The description, e.g. `Copied 3 files, 1024 bytes.`
+    pub fn describe(&self, dry_run: bool) -> String {
+        let (copied, skipped) = if dry_run {
+            ("Would copy", "Would skip")
+        } else {
+            ("Copied", "Skipped")
+        };
+        let mut description = format!("{} {} files, {} bytes.", copied, self.files, self.bytes);
+        if self.skipped > 0 {
+            description.push_str(&format!(
+                " {} {} files that are not newer.",
+                skipped, self.skipped
+            ));
+        }
+        description
+    }
+
+    /// Format what was copied to the host as the JSON report of `--json`.
+    ///
+    /// # Parameters
+    ///
+    /// * `output: &Path` - The directory the entries were copied to.
+    /// * `owner: Option<Owner>` - Who the entries were given to, if `--owner` was given.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The report, e.g. `{"output": "esp", "files": 3, "bytes": 1024, "skipped": 0,
+    ///   "owner": {"uid": 1000, "gid": 100}}`.
+    pub fn format_json(&self, output: &Path, owner: Option<Owner>) -> String {
+        format!(
+            "{{\"output\": {}, \"files\": {}, \"bytes\": {}, \"skipped\": {}, \"owner\": {}}}",
+            json::string(&output.to_string_lossy()),
+            self.files,
+            self.bytes,
+            self.skipped,
+            Owner::format_json(owner)
+        )
+    }
+}
+
+/// Represent the times to give an entry copied to the volume once `fatfs` is done with it.
+///
+/// # Members
+///
+/// * `short_names: Vec<Vec<u8>>` - The short names leading to the entry, as `direntry` finds it.
+/// * `accessed: Date` - When the entry was last accessed.
+/// * `modified: DateTime` - When the entry was last modified.
+/// * `created: Option<DateTime>` - When the entry was created, or `None` if the host doesn't
+///   know.
+pub struct Stamp {
+    short_names: Vec<Vec<u8>>,
+    accessed: Date,
+    modified: DateTime,
+    created: Option<DateTime>,
+}
+
+/// Represent a copy from the host to the volume, checked by `plan_in`.
+///
+/// # Members
+///
+/// * `entries: Vec<HostEntry>` - The entries to copy, every directory before the entries in it.
+/// * `missing: Vec<PathBuf>` - The directories leading to the target to create, the outermost
+///   first.
+/// * `existing: Vec<Option<u64>>` - For every entry, the size of the one in its place on the
+///   volume, or `None` if there is none.
+/// * `skip: Vec<bool>` - For every entry, whether it is left alone with `--update`.
+pub struct InPlan {
+    entries: Vec<HostEntry>,
+    missing: Vec<PathBuf>,
+    existing: Vec<Option<u64>>,
+    skip: Vec<bool>,
+}
+
+/// Represent a file or directory on the host to copy to the volume.
+///
+/// # Members
+///
+/// * `source: PathBuf` - The path on the host.
+/// * `target: PathBuf` - The absolute path on the volume to copy it to.
+/// * `metadata: Metadata` - The metadata of the entry on the host.
+struct HostEntry {
+    source: PathBuf,
+    target: PathBuf,
+    metadata: Metadata,
+}
+
+/// Leave the entries out that match an exclude pattern, with everything below them.
+///
+/// # Parameters
+///
+/// * `entries: Vec<Found>` - The entry copied and those below it, every directory before the
+///   entries in it.
+/// * `top: &Path` - The path of the entry copied, which the patterns are matched below.
+/// * `excludes: &[Exclude]` - The patterns.
+///
+/// # Returns
+///
+/// * `Vec<Found>` - The entries not left out.
+pub fn exclude(entries: Vec<Found>, top: &Path, excludes: &[Exclude]) -> Vec<Found> {
+    let mut excluded: Vec<PathBuf> = Vec::new();
+    entries
+        .into_iter()
+        .filter(|entry| {
+            if excluded.iter().any(|dir| entry.path.starts_with(dir)) {
+                return false;
+            }
+            let Ok(relative) = entry.path.strip_prefix(top) else {
+                return true;
+            };
+            if is_excluded(excludes, relative, entry.is_dir) {
+                excluded.push(entry.path.clone());
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+/// Check whether a file replaces another with `--update`.
+///
+/// # Parameters
+///
+/// * `source: SystemTime` - When the file copied was last modified.
+/// * `target: SystemTime` - When the file at the destination was last modified.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the file copied is newer by more than FAT can tell apart.
+fn is_newer(source: SystemTime, target: SystemTime) -> bool {
+    source > target + UPDATE_TOLERANCE
+}
+
+/// Copy files and directories from the volume to the host.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `entries: &[Found]` - The entries, every directory before the entries in it. They are
+///   copied to their paths below their bases, an entry that is its own base to `output` itself.
+/// * `output: &Path` - The directory on the host to copy them to, created if it is missing.
+/// * `options: &CopyOptions` - How to copy them. The exclude patterns are not applied, see
+///   `exclude`.
+///
+/// # Returns
+///
+/// * `io::Result<Summary>` - What was copied, an error if an entry can't be read or written, an
+///   `AlreadyExists` error if a file exists on the host and is not to be replaced, an
+///   `InvalidInput` error if two entries would be copied to the same path, or a `StorageFull`
+///   error if the files don't fit on the host.
+pub fn copy_out(
+    fs: &FatfsFileSystem<Storage>,
+    entries: &[Found],
+    output: &Path,
+    options: &CopyOptions,
+) -> io::Result<Summary> {
+    let mut host_paths: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut targets = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let target = host_path(fs, entry, output, options, &mut host_paths)?;
+        targets.push(target);
+    }
+    let mut sources: HashMap<&Path, &Path> = HashMap::new();
+    let mut existing = vec![None; entries.len()];
+    let mut skip = vec![false; entries.len()];
+    let mut needed: u64 = 0;
+    for (index, (entry, target)) in entries.iter().zip(&targets).enumerate() {
+        match sources.insert(target, &entry.path) {
+            Some(other) if other != entry.path => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} and {} would both be copied to {}",
+                        other.display(),
+                        entry.path.display(),
+                        target.display()
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        if let Ok(metadata) = target.metadata() {
+            let modified = fat_to_system_time(entry.modified.date, entry.modified.time);
+            skip[index] = check_conflict(
+                target,
+                entry.is_dir,
+                metadata.is_dir(),
+                modified,
+                metadata.modified()?,
+                options.conflicts,
+            )?;
+            existing[index] = Some(metadata.len());
+        }
+        if !entry.is_dir && !skip[index] {
+            needed = needed.saturating_add(entry.size.saturating_sub(existing[index].unwrap_or(0)));
+        }
+    }
+    let free = host_free_space(output)?;
+    if needed > free {
+        return Err(not_enough_space(needed, free));
+    }
+
+    let mut summary = Summary::default();
+    let mut dirs = Vec::new();
+    let mut chown_failed = false;
+    for (index, (entry, target)) in entries.iter().zip(&targets).enumerate() {
+        let source = format!("img:{}", entry.path.display());
+        let host = format!("host:{}", target.display());
+        let modified = fat_to_system_time(entry.modified.date, entry.modified.time);
+        if entry.is_dir {
+            if options.dry_run {
+                if existing[index].is_none() {
+                    print_action("mkdir", &[Arg::Text(&host)]);
+                }
+                continue;
+            }
+            fs::create_dir_all(target)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", target.display(), e)))?;
+            // Copying the entries in a directory changes its modification time, so it is set
+            // once they are all copied, and the directory given away after that.
+            dirs.push((target, entry.path.parent().map(|_| modified)));
+            continue;
+        }
+        if skip[index] {
+            summary.skipped += 1;
+            if options.dry_run {
+                print_action("skip", &[Arg::Text(&source), Arg::Text(&host)]);
+            }
+            continue;
+        }
+        if options.dry_run {
+            print_action(
+                if existing[index].is_some() {
+                    "replace"
+                } else {
+                    "copy"
+                },
+                &[
+                    Arg::Text(&source),
+                    Arg::Text(&host),
+                    Arg::Number(entry.size),
+                ],
+            );
+            summary.files += 1;
+            summary.bytes += entry.size;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut target_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(target)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", target.display(), e)))?;
+        let mut file = fs.root_dir().open_file(entry.path.to_str().unwrap())?;
+        summary.bytes += io::copy(&mut file, &mut target_file)?;
+        summary.files += 1;
+        target_file.set_modified(modified)?;
+        set_owner(target, options.owner, &mut chown_failed)?;
+    }
+    for (dir, modified) in dirs.iter().rev() {
+        if let Some(modified) = modified {
+            File::open(dir)?.set_modified(*modified)?;
+        }
+        set_owner(dir, options.owner, &mut chown_failed)?;
+    }
+    Ok(summary)
+}
+
+/// Give an entry copied to the host to the owner asked for with `--owner`. Only root may give
+/// entries away, so if that is refused, a warning says so once, and the entries copied keep
+/// their owner.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path of the entry on the host.
+/// * `owner: Option<Owner>` - The owner, if any.
+/// * `failed: &mut bool` - Whether giving an entry away was refused before, so no more are tried.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the owner can't be changed for another reason.
+fn set_owner(path: &Path, owner: Option<Owner>, failed: &mut bool) -> io::Result<()> {
+    let Some(owner) = owner.filter(|_| !*failed) else {
+        return Ok(());
+    };
+    match chown(path, Some(owner.uid), owner.gid) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            eprintln!(
+                "{}: can't change the owner ({}), everything copied keeps the one it has",
+                path.display(),
+                e
+            );
+            *failed = true;
+            Ok(())
+        }
+        result => {
+            result.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        }
+    }
+}
+
+/// Get how much space there is on the filesystem of the host a path is on.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path, which may not exist yet.
+///
+/// # Returns
+///
+/// * `io::Result<u64>` - The number of bytes free for unprivileged users, or an error if the
+///   filesystem can't be asked.
+fn host_free_space(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .map(|dir| {
+            if dir == Path::new("") {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stats: libc::statvfs = unsafe { mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stats` is big enough for what `statvfs` writes.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Make the error for a copy that doesn't fit.
+///
+/// # Parameters
+///
+/// * `needed: u64` - The number of bytes the copy needs.
+/// * `free: u64` - The number of bytes free.
+///
+/// # Returns
+///
+/// * `io::Error` - A `StorageFull` error saying so.
+fn not_enough_space(needed: u64, free: u64) -> io::Error {
+    io::Error::new(
+        ErrorKind::StorageFull,
+        format!(
+            "not enough space, {} bytes are needed and {} are free",
+            needed, free
+        ),
+    )
+}
+
+/// Check what to do with an entry that exists at the destination.
+///
+/// # Parameters
+///
+/// * `target: &Path` - The path at the destination, for messages.
+/// * `is_dir: bool` - Whether the entry copied is a directory.
+/// * `target_is_dir: bool` - Whether the entry at the destination is a directory.
+/// * `modified: SystemTime` - When the entry copied was last modified.
+/// * `target_modified: SystemTime` - When the entry at the destination was last modified.
+/// * `conflicts: Conflicts` - What to do with files that exist at the destination.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - Whether to leave the entry at the destination alone, an
+///   `AlreadyExists` error if it is a file not to be replaced, or an `InvalidInput` error if one
+///   of them is a directory and the other is not. Directories are merged.
+fn check_conflict(
+    target: &Path,
+    is_dir: bool,
+    target_is_dir: bool,
+    modified: SystemTime,
+    target_modified: SystemTime,
+    conflicts: Conflicts,
+) -> io::Result<bool> {
+    match (is_dir, target_is_dir) {
+        (true, true) => Ok(false),
+        (true, false) | (false, true) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} exists and is {} a directory",
+                target.display(),
+                if target_is_dir { "" } else { "not" }
+            )
+            .replace("  ", " "),
+        )),
+        (false, false) => match conflicts {
+            Conflicts::Refuse => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "{} exists, --force replaces it and --update replaces it if it is older",
+                    target.display()
+                ),
+            )),
+            Conflicts::Overwrite => Ok(false),
+            Conflicts::Update => Ok(!is_newer(modified, target_modified)),
+        },
+    }
+}
+
+/// Get the path on the host an entry is copied to.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `entry: &Found` - The entry.
+/// * `output: &Path` - The directory on the host entries are copied to.
+/// * `options: &CopyOptions` - How entries are copied.
+/// * `host_paths: &mut HashMap<PathBuf, PathBuf>` - The paths on the host of the directories on
+///   the volume found so far, which those found now are added to.
+///
+/// # Returns
+///
+/// * `io::Result<PathBuf>` - The path on the host, or an error if a directory leading to the
+///   entry can't be read.
+fn host_path(
+    fs: &FatfsFileSystem<Storage>,
+    entry: &Found,
+    output: &Path,
+    options: &CopyOptions,
+    host_paths: &mut HashMap<PathBuf, PathBuf>,
+) -> io::Result<PathBuf> {
+    let Some(parent) = entry.path.parent().filter(|_| entry.path != entry.base) else {
+        return Ok(output.to_path_buf());
+    };
+    let host_parent = if parent.components().count() <= entry.base.components().count() {
+        output.to_path_buf()
+    } else if let Some(host_parent) = host_paths.get(parent) {
+        host_parent.clone()
+    } else {
+        // A file found by a pattern without the directories leading to it.
+        let dir = tree::find(fs, parent, &entry.base)?;
+        host_path(fs, &dir, output, options, host_paths)?
+    };
+    let mut name = OsString::new();
+    if options.dotify_hidden
+        && entry.attributes.contains(FileAttributes::HIDDEN)
+        && !entry
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with('.')
+    {
+        name.push(".");
+    }
+    name.push(entry.path.file_name().unwrap());
+    let host = host_parent.join(name);
+    if entry.is_dir {
+        host_paths.insert(entry.path.clone(), host.clone());
+    }
+    Ok(host)
+}
+
+/// Measure a file or directory on the host to copy, leaving out what is excluded.
+///
+/// # Parameters
+///
+/// * `source: &Path` - The path on the host.
+/// * `options: &CopyOptions` - How it would be copied.
+///
+/// # Returns
+///
+/// * `io::Result<(u64, u64)>` - The number of bytes in the files and the number of directories,
+///   or an error if an entry can't be read or can't be copied.
+pub fn measure_host(source: &Path, options: &CopyOptions) -> io::Result<(u64, u64)> {
+    let mut entries = Vec::new();
+    walk_host(source, Path::new("/"), Path::new(""), options, &mut entries)?;
+    let bytes = entries.iter().map(|entry| entry.metadata.len()).sum();
+    let dirs = entries
+        .iter()
+        .filter(|entry| entry.metadata.is_dir())
+        .count();
+    Ok((bytes, dirs as u64))
+}
+
+/// Find the paths a file or directory on the host would be copied to, leaving out what is
+/// excluded.
+///
+/// # Parameters
+///
+/// * `source: &Path` - The path on the host.
+/// * `target: &Path` - The absolute path on the volume to copy it to.
+/// * `options: &CopyOptions` - How it would be copied.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<PathBuf>>` - The paths on the volume, every directory before the entries in
+///   it, or an error if an entry can't be read or can't be copied.
+pub fn host_targets(
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+) -> io::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    walk_host(source, target, Path::new(""), options, &mut entries)?;
+    Ok(entries.into_iter().map(|entry| entry.target).collect())
+}
+
+/// Check a copy of a file or directory from the host to the volume, with everything below it, as
+/// `copy_in` does before writing anything.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `source: &Path` - The path on the host.
+/// * `target: &Path` - The absolute path on the volume to copy it to.
+/// * `options: &CopyOptions` - How to copy it.
+///
+/// # Returns
+///
+/// * `io::Result<InPlan>` - What to copy, or an error as `copy_in` returns one.
+pub fn plan_in(
+    fs: &FatfsFileSystem<Storage>,
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+) -> io::Result<InPlan> {
+    let mut entries = Vec::new();
+    walk_host(source, target, Path::new(""), options, &mut entries)?;
+    let missing = match target.parent() {
+        Some(parent) => missing_dirs(fs, parent)?,
+        None => Vec::new(),
+    };
+    let cluster_size = u64::from(fs.cluster_size());
+    let clusters = |size: u64| size.div_ceil(cluster_size);
+    // Every directory created takes a cluster, and every file as many as its size. Directories
+    // that grow take more, so this is the least the copy needs.
+    let mut needed = missing.len() as u64;
+    let mut existing = vec![None; entries.len()];
+    let mut skip = vec![false; entries.len()];
+    for (index, entry) in entries.iter().enumerate() {
+        match tree::find(fs, &entry.target, Path::new("/")) {
+            Ok(found) => {
+                skip[index] = check_conflict(
+                    &entry.target,
+                    entry.metadata.is_dir(),
+                    found.is_dir,
+                    entry.metadata.modified()?,
+                    fat_to_system_time(found.modified.date, found.modified.time),
+                    options.conflicts,
+                )?;
+                existing[index] = Some(found.size);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if entry.metadata.is_dir() {
+                    needed += 1;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        if entry.metadata.is_file() && !skip[index] {
+            needed += clusters(entry.metadata.len())
+                .saturating_sub(clusters(existing[index].unwrap_or(0)));
+        }
+    }
+    let free = fs.stats()?.free_clusters() as u64;
+    if needed > free {
+        return Err(not_enough_space(needed * cluster_size, free * cluster_size));
+    }
+    Ok(InPlan {
+        entries,
+        missing,
+        existing,
+        skip,
+    })
+}
+
+/// Copy a file or directory from the host to the volume, with everything below it. The
+/// directories leading to the target are created if they are missing.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume, which is only read with `--dry-run`.
+/// * `source: &Path` - The path on the host.
+/// * `target: &Path` - The absolute path on the volume to copy it to. A directory copied to the
+///   root directory has its entries copied there.
+/// * `options: &CopyOptions` - How to copy it.
+///
+/// # Returns
+///
+/// * `io::Result<(Summary, Vec<Stamp>)>` - What was copied and the times to give it with
+///   `apply_stamps` once the volume is unmounted, an error if an entry can't be read or written,
+///   an `AlreadyExists` error if a file exists on the volume and is not to be replaced, an
+///   `InvalidInput` error if an entry has a name that isn't valid UTF-8 or is neither a file nor
+///   a directory, or a `StorageFull` error if the files don't fit on the volume.
+pub fn copy_in(
+    fs: &FatfsFileSystem<Storage>,
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+) -> io::Result<(Summary, Vec<Stamp>)> {
+    let InPlan {
+        entries,
+        missing,
+        existing,
+        skip,
+    } = plan_in(fs, source, target, options)?;
+    let mut summary = Summary::default();
+    let mut stamps = Vec::new();
+    if options.dry_run {
+        for dir in &missing {
+            print_action("mkdir", &[Arg::Text(&format!("img:{}", dir.display()))]);
+        }
+    } else if let Some(parent) = target.parent() {
+        create_dirs(fs, parent)?;
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        let host = format!("host:{}", entry.source.display());
+        let image = format!("img:{}", entry.target.display());
+        if skip[index] {
+            summary.skipped += 1;
+            if options.dry_run {
+                print_action("skip", &[Arg::Text(&host), Arg::Text(&image)]);
+            }
+            continue;
+        }
+        if options.dry_run {
+            if entry.metadata.is_file() {
+                print_action(
+                    if existing[index].is_some() {
+                        "replace"
+                    } else {
+                        "copy"
+                    },
+                    &[
+                        Arg::Text(&host),
+                        Arg::Text(&image),
+                        Arg::Number(entry.metadata.len()),
+                    ],
+                );
+                summary.files += 1;
+                summary.bytes += entry.metadata.len();
+            } else if existing[index].is_none() {
+                print_action("mkdir", &[Arg::Text(&image)]);
+            }
+            continue;
+        }
+        let Some(parent) = entry.target.parent() else {
+            continue;
+        };
+        let dir = open_dir(fs, parent)?;
+        let name = entry.target.file_name().unwrap().to_str().unwrap();
+        if entry.metadata.is_dir() {
+            dir.create_dir(name)?;
+        } else {
+            let mut file = dir.create_file(name)?;
+            file.truncate()?;
+            summary.bytes += io::copy(&mut File::open(&entry.source)?, &mut file)?;
+            summary.files += 1;
+        }
+        stamps.push(Stamp {
+            short_names: short_names(fs, &entry.target)?,
+            accessed: system_to_fat_time(entry.metadata.accessed()?).date,
+            modified: system_to_fat_time(entry.metadata.modified()?),
+            created: entry.metadata.created().ok().map(system_to_fat_time),
+        });
+    }
+    Ok((summary, stamps))
+}
+
+/// Find the directories leading to a path on the volume that are missing.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The absolute path of the last directory.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<PathBuf>>` - The missing directories, the outermost first, or an
+///   `InvalidInput` error if a file is in the place of one.
+fn missing_dirs(fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut missing = Vec::new();
+    for dir in path.ancestors() {
+        match tree::find(fs, dir, Path::new("/")) {
+            Ok(found) if found.is_dir => break,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} exists and is not a directory", dir.display()),
+                ))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => missing.push(dir.to_path_buf()),
+            Err(e) => return Err(e),
+        }
+    }
+    missing.reverse();
+    Ok(missing)
+}
+
+/// Find a file or directory on the host to copy, and everything below it that is not excluded.
+///
+/// # Parameters
+///
+/// * `source: &Path` - The path on the host.
+/// * `target: &Path` - The absolute path on the volume to copy it to.
+/// * `relative: &Path` - The path below the entry copied, which exclude patterns are matched
+///   against.
+/// * `options: &CopyOptions` - How entries are copied.
+/// * `entries: &mut Vec<HostEntry>` - The entries found so far, which these are added to, every
+///   directory before the entries in it.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if an entry can't be read, or an `InvalidInput` error if it has
+///   a name that isn't valid UTF-8 or is neither a file nor a directory.
+fn walk_host(
+    source: &Path,
+    target: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    entries: &mut Vec<HostEntry>,
+) -> io::Result<()> {
+    let metadata = fs::metadata(source)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", source.display(), e)))?;
+    if is_excluded(&options.excludes, relative, metadata.is_dir()) {
+        return Ok(());
+    }
+    if !metadata.is_dir() && !metadata.is_file() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is neither a file nor a directory", source.display()),
+        ));
+    }
+    let is_dir = metadata.is_dir();
+    entries.push(HostEntry {
+        source: source.to_path_buf(),
+        target: target.to_path_buf(),
+        metadata,
+    });
+    if !is_dir {
+        return Ok(());
+    }
+    let mut names = fs::read_dir(source)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    // Sorted, so the same tree is always copied in the same order.
+    names.sort();
+    for name in names {
+        let Some(utf8_name) = name.to_str() else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} is not valid UTF-8, which FAT names are",
+                    source.join(&name).display()
+                ),
+            ));
+        };
+        walk_host(
+            &source.join(&name),
+            &target.join(utf8_name),
+            &relative.join(utf8_name),
+            options,
+            entries,
+        )?;
+    }
+    Ok(())
+}
+
+/// Create the directories leading to a path on the volume that are missing.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The absolute path of the last directory to create.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if a directory can't be created, e.g. as a file is in its place.
+pub fn create_dirs(fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<()> {
+    let mut dir = fs.root_dir();
+    for component in path.components().skip(1) {
+        dir = dir.create_dir(component.as_os_str().to_str().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Give the entries copied to the volume their times.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, with the volume unmounted by `fatfs`.
+/// * `stamps: &[Stamp]` - The times of the entries, as returned by `copy_in`.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if an entry can't be found or written.
+pub fn apply_stamps(image: &File, stamps: &[Stamp]) -> io::Result<()> {
+    for stamp in stamps {
+        direntry::set_times(
+            image,
+            &stamp.short_names,
+            Some(stamp.accessed),
+            Some(stamp.modified),
+            stamp.created,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use std::env;
+
+    /// Get the time FAT would keep for a date and time.
+    fn fat_time(date: (u16, u16, u16), time: (u16, u16, u16)) -> SystemTime {
+        fat_to_system_time(
+            Date {
+                year: date.0,
+                month: date.1,
+                day: date.2,
+            },
+            fatfs::Time {
+                hour: time.0,
+                min: time.1,
+                sec: time.2,
+                millis: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn subtrees_are_copied_out_with_their_times() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "boot", "modified": "2020-02-03 04:05:06", "entries": [
+                    {"name": "grub", "modified": "2021-01-01 00:00:00", "entries": [
+                        {"name": "grub.cfg", "size": 5, "fill": "set\n", "modified": "2022-06-07 08:09:10"}
+                    ]},
+                    {"name": "desktop.ini", "attributes": "hs"}
+                ]}
+            ]}"#,
+        );
+        let file = image.open();
+        let fs = tree::open_volume(&file).unwrap();
+        let boot = tree::find(&fs, Path::new("/boot"), Path::new("/")).unwrap();
+        let mut entries = vec![boot.clone()];
+        entries.extend(tree::walk(&fs, &boot).unwrap());
+        let output = env::temp_dir().join(format!("nufat-copy-{}", std::process::id()));
+        let options = CopyOptions {
+            dotify_hidden: true,
+            ..CopyOptions::default()
+        };
+        let summary = copy_out(&fs, &entries, &output, &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                files: 2,
+                bytes: 5,
+                skipped: 0
+            }
+        );
+
+        assert_eq!(
+            fs::read(output.join("boot/grub/grub.cfg")).unwrap(),
+            b"set\ns"
+        );
+        assert!(output.join("boot/.desktop.ini").exists());
+        let modified = |path: &str| fs::metadata(output.join(path)).unwrap().modified().unwrap();
+        assert_eq!(
+            modified("boot/grub/grub.cfg"),
+            fat_time((2022, 6, 7), (8, 9, 10))
+        );
+        assert_eq!(modified("boot/grub"), fat_time((2021, 1, 1), (0, 0, 0)));
+        assert_eq!(modified("boot"), fat_time((2020, 2, 3), (4, 5, 6)));
+
+        // Files on the host are only replaced when asked to.
+        let e = copy_out(&fs, &entries, &output, &options).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        let update = CopyOptions {
+            conflicts: Conflicts::Update,
+            ..options.clone()
+        };
+        assert_eq!(
+            copy_out(&fs, &entries, &output, &update).unwrap().skipped,
+            2
+        );
+        let force = CopyOptions {
+            conflicts: Conflicts::Overwrite,
+            ..options
+        };
+        assert_eq!(copy_out(&fs, &entries, &output, &force).unwrap().files, 2);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn entries_copied_to_the_same_path_are_refused() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "notes", "attributes": "h"},
+                {"name": ".notes"}
+            ]}"#,
+        );
+        let file = image.open();
+        let fs = tree::open_volume(&file).unwrap();
+        let root = tree::find(&fs, Path::new("/"), Path::new("/")).unwrap();
+        let entries = tree::walk(&fs, &root).unwrap();
+        let output = env::temp_dir().join(format!("nufat-copy-same-{}", std::process::id()));
+        let options = CopyOptions {
+            dotify_hidden: true,
+            ..CopyOptions::default()
+        };
+        let e = copy_out(&fs, &entries, &output, &options).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(!output.exists());
+        copy_out(&fs, &entries, &output, &CopyOptions::default()).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn host_trees_are_copied_in_with_their_times() {
+        let source = env::temp_dir().join(format!("nufat-copy-in-{}", std::process::id()));
+        fs::create_dir_all(source.join("sub/.git")).unwrap();
+        fs::write(source.join("a.txt"), b"hello").unwrap();
+        fs::write(source.join("sub/b.o"), b"obj").unwrap();
+        fs::write(source.join("sub/.git/HEAD"), b"ref").unwrap();
+        let modified = fat_time((2019, 9, 9), (9, 9, 10));
+        File::options()
+            .write(true)
+            .open(source.join("a.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        File::open(source.join("sub"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let image = TestImage::new(fatfs::FatType::Fat16);
+        let file = image.open();
+        let fs = tree::open_volume(&file).unwrap();
+        let options = CopyOptions {
+            excludes: vec![Exclude::new(".git/").unwrap()],
+            ..CopyOptions::default()
+        };
+        let (summary, stamps) = copy_in(&fs, &source, Path::new("/deep/copy"), &options).unwrap();
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.bytes, 8);
+        // Exclusion is checked, and nothing is copied, before files are replaced.
+        let e = copy_in(&fs, &source, Path::new("/deep/copy"), &options)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        fs.unmount().unwrap();
+        apply_stamps(&file, &stamps).unwrap();
+
+        let fs = tree::open_volume(&file).unwrap();
+        let found = |path: &str| tree::find(&fs, Path::new(path), Path::new("/"));
+        let a = found("/deep/copy/a.txt").unwrap();
+        assert_eq!(
+            fat_to_system_time(a.modified.date, a.modified.time),
+            modified
+        );
+        let sub = found("/deep/copy/sub").unwrap();
+        assert_eq!(
+            fat_to_system_time(sub.modified.date, sub.modified.time),
+            modified
+        );
+        assert_eq!(found("/deep/copy/sub/b.o").unwrap().size, 3);
+        assert!(found("/deep/copy/sub/.git").is_err());
+
+        // With `--update`, only newer files replace older ones.
+        File::options()
+            .write(true)
+            .open(source.join("sub/b.o"))
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let update = CopyOptions {
+            conflicts: Conflicts::Update,
+            ..options
+        };
+        let (summary, _) = copy_in(&fs, &source, Path::new("/deep/copy"), &update).unwrap();
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.files, 1);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn newer_means_newer_than_fat_can_tell_apart() {
+        let time = SystemTime::now();
+        assert!(!is_newer(time + Duration::from_secs(2), time));
+        assert!(is_newer(time + Duration::from_secs(3), time));
+        assert!(!is_newer(time, time + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn owners_are_given_to_everything_copied_out() {
+        use std::os::unix::fs::MetadataExt;
+        let image = TestImage::from_spec(
+            r#"{"size": "4M", "entries": [
+                {"name": "etc", "entries": [{"name": "motd", "size": 3, "fill": "hi\n"}]}
+            ]}"#,
+        );
+        let file = image.open();
+        let fs = tree::open_volume(&file).unwrap();
+        let root = tree::find(&fs, Path::new("/"), Path::new("/")).unwrap();
+        let mut entries = vec![root.clone()];
+        entries.extend(tree::walk(&fs, &root).unwrap());
+        let output = env::temp_dir().join(format!("nufat-copy-owner-{}", std::process::id()));
+        let owner = Owner::parse("1234:5678").unwrap();
+        let options = CopyOptions {
+            owner: Some(owner),
+            ..CopyOptions::default()
+        };
+        let summary = copy_out(&fs, &entries, &output, &options).unwrap();
+        // Only root may give files away, others keep them with a warning.
+        if unsafe { libc::geteuid() } == 0 {
+            for path in [output.clone(), output.join("etc"), output.join("etc/motd")] {
+                let metadata = fs::metadata(&path).unwrap();
+                assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+            }
+        }
+        assert_eq!(
+            summary.format_json(Path::new("out"), Some(owner)),
+            r#"{"output": "out", "files": 1, "bytes": 3, "skipped": 0, "owner": {"uid": 1234, "gid": 5678}}"#
+        );
+        fs::remove_dir_all(&output).unwrap();
+
+        assert_eq!(
+            Owner::parse("1000"),
+            Ok(Owner {
+                uid: 1000,
+                gid: None
+            })
+        );
+        assert!(Owner::parse("root").is_err());
+        assert!(Owner::parse("1000:").is_err());
+        assert_eq!(Owner::format_json(None), "null");
+    }
+}
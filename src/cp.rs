@@ -0,0 +1,318 @@
+//! This module implements the `cp` subcommand, which copies files, and with `--recursive`
+//! directories with everything in them, between a disk image that is not mounted and the host,
+//! in either direction. Paths on the volume are prefixed with `img:` and those on the host with
+//! `host:`. Like `cp`, a copy to an existing directory ends up in it under the name of the
+//! source, and one to any other path is given that name. The copying is done by the `copy`
+//! module, which with `--dry-run` only prints what it would do, and the disk image is then only
+//! opened for reading.
+use crate::copy::{self, Conflicts, CopyOptions, Summary};
+use crate::exclude::{read_excludes, Exclude};
+use crate::filesystem::lock_image;
+use crate::tree;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT cp [-r|--recursive] <disk.img> <host:SRC|img:SRC> <img:DST|host:DST> [--force|--update] [--exclude GLOB]... [--exclude-from FILE]... [--dotify-hidden] [--dry-run]";
+
+/// Represent a path given to the subcommand.
+///
+/// # Variants
+///
+/// * `Host(PathBuf)` - A path on the host, given as `host:PATH`.
+/// * `Image(PathBuf)` - An absolute path on the volume, given as `img:PATH`.
+enum Location {
+    Host(PathBuf),
+    Image(PathBuf),
+}
+
+impl Location {
+    /// Parse a path given to the subcommand.
+    ///
+    /// # Parameters
+    ///
+    /// * `arg: &str` - The path, starting with `host:` or `img:`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Location>` - The path, or `None` if it has neither prefix or is empty.
+    fn parse(arg: &str) -> Option<Self> {
+        if let Some(path) = arg.strip_prefix("host:").filter(|path| !path.is_empty()) {
+            Some(Location::Host(PathBuf::from(path)))
+        } else {
+            let path = arg.strip_prefix("img:")?;
+            Some(Location::Image(
+                Path::new("/").join(path.trim_start_matches('/')),
+            ))
+        }
+    }
+}
+
+/// Run the `cp` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `[-r|--recursive] <disk.img>
+///   <host:SRC|img:SRC> <img:DST|host:DST> [--force|--update] [--exclude GLOB]...
+///   [--exclude-from FILE]... [--dotify-hidden] [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or written, the
+///   source doesn't exist, is a directory given without `--recursive` or an entry can't be
+///   copied, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut recursive = false;
+    let mut options = CopyOptions::default();
+    let mut paths = Vec::new();
+    let mut valid = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--recursive" => recursive = true,
+            "--force" => options.conflicts = Conflicts::Overwrite,
+            "--update" => options.conflicts = Conflicts::Update,
+            "--dotify-hidden" => options.dotify_hidden = true,
+            "--dry-run" => options.dry_run = true,
+            "--exclude" => match args.next().map(|glob| Exclude::new(glob)) {
+                Some(Ok(exclude)) => options.excludes.push(exclude),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            "--exclude-from" => match args.next().map(|path| read_excludes(Path::new(path))) {
+                Some(Ok(excludes)) => options.excludes.extend(excludes),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            _ if arg.starts_with('-') => valid = false,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let [disk_image_path, source, target] = &paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let (Some(source), Some(target), true) =
+        (Location::parse(source), Location::parse(target), valid)
+    else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let result = match (source, target) {
+        (Location::Image(source), Location::Host(target)) => {
+            copy_out(disk_image_path, &source, &target, recursive, &options)
+        }
+        (Location::Host(source), Location::Image(target)) => {
+            copy_in(disk_image_path, &source, &target, recursive, &options)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            eprintln!("One of the paths must be on the disk image and the other on the host.");
+            return 2;
+        }
+    };
+    match result {
+        Ok(summary) => {
+            println!("{}", summary.describe(options.dry_run));
+            0
+        }
+        Err(e) if options.dry_run => {
+            eprintln!("{:?}: would fail: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+/// Copy a file or directory from the volume to the host.
+///
+/// # Parameters
+///
+/// * `disk_image_path: &str` - The path of the disk image.
+/// * `source: &Path` - The absolute path on the volume.
+/// * `target: &Path` - The path on the host.
+/// * `recursive: bool` - Whether directories may be copied.
+/// * `options: &CopyOptions` - How to copy.
+///
+/// # Returns
+///
+/// * `io::Result<Summary>` - What was copied, or an error if it can't be.
+fn copy_out(
+    disk_image_path: &str,
+    source: &Path,
+    target: &Path,
+    recursive: bool,
+    options: &CopyOptions,
+) -> io::Result<Summary> {
+    let image = fs::File::open(disk_image_path)?;
+    lock_image(&image, false)?;
+    let fs = tree::open_volume(&image)?;
+    // The base is the directory the entry is copied below, the entry itself if it takes the
+    // name of the target.
+    let base = match source.parent() {
+        Some(parent) if target.is_dir() => parent,
+        _ => source,
+    };
+    let top = tree::find(&fs, source, base)?;
+    check_recursive(&top.path, top.is_dir, recursive)?;
+    let mut entries = vec![top.clone()];
+    if top.is_dir {
+        entries.extend(tree::walk(&fs, &top)?);
+    }
+    let entries = copy::exclude(entries, &top.path, &options.excludes);
+    copy::copy_out(&fs, &entries, target, options)
+}
+
+/// Copy a file or directory from the host to the volume.
+///
+/// # Parameters
+///
+/// * `disk_image_path: &str` - The path of the disk image.
+/// * `source: &Path` - The path on the host.
+/// * `target: &Path` - The absolute path on the volume.
+/// * `recursive: bool` - Whether directories may be copied.
+/// * `options: &CopyOptions` - How to copy.
+///
+/// # Returns
+///
+/// * `io::Result<Summary>` - What was copied, or an error if it can't be.
+fn copy_in(
+    disk_image_path: &str,
+    source: &Path,
+    target: &Path,
+    recursive: bool,
+    options: &CopyOptions,
+) -> io::Result<Summary> {
+    let metadata = fs::metadata(source)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", source.display(), e)))?;
+    check_recursive(source, metadata.is_dir(), recursive)?;
+    let image = OpenOptions::new()
+        .read(true)
+        .write(!options.dry_run)
+        .open(disk_image_path)?;
+    lock_image(&image, !options.dry_run)?;
+    let fs = tree::open_volume(&image)?;
+    let target = match tree::find(&fs, target, Path::new("/")) {
+        Ok(existing) if existing.is_dir => {
+            // `.` and `..` have no name of their own.
+            let source = source.canonicalize()?;
+            match source.file_name() {
+                Some(name) => target.join(name),
+                None => target.to_path_buf(),
+            }
+        }
+        Ok(_) => target.to_path_buf(),
+        Err(e) if e.kind() == ErrorKind::NotFound => target.to_path_buf(),
+        Err(e) => return Err(e),
+    };
+    let (summary, stamps) = copy::copy_in(&fs, source, &target, options)?;
+    if options.dry_run {
+        return Ok(summary);
+    }
+    fs.unmount()?;
+    copy::apply_stamps(&image, &stamps)?;
+    image.sync_all()?;
+    Ok(summary)
+}
+
+/// Check that a directory is only copied with `--recursive`.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path of the source, for messages.
+/// * `is_dir: bool` - Whether the source is a directory.
+/// * `recursive: bool` - Whether `--recursive` is given.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An `InvalidInput` error if a directory is given without `--recursive`.
+fn check_recursive(path: &Path, is_dir: bool, recursive: bool) -> io::Result<()> {
+    if is_dir && !recursive {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} is a directory, --recursive copies it with everything in it",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use std::env;
+
+    #[test]
+    fn trees_are_copied_both_ways() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "target", "entries": []},
+                {"name": "notes.txt", "size": 4, "fill": "note"}
+            ]}"#,
+        );
+        let host = env::temp_dir().join(format!("nufat-cp-{}", std::process::id()));
+        fs::create_dir_all(host.join("dir/sub")).unwrap();
+        fs::write(host.join("dir/sub/a.bin"), b"abc").unwrap();
+        fs::write(host.join("dir/skip.tmp"), b"tmp").unwrap();
+        let cp = |args: &[&str]| {
+            let mut all = vec![image.options().disk_image_path.display().to_string()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            run(&all)
+        };
+        let host_arg = |path: &str| format!("host:{}", host.join(path).display());
+
+        // A directory needs `--recursive`, and ends up in an existing one under its name.
+        assert_eq!(cp(&[&host_arg("dir"), "img:/target"]), 1);
+        assert_eq!(
+            cp(&["-r", &host_arg("dir"), "img:/target", "--exclude", "*.tmp"]),
+            0
+        );
+        let fs = image.volume();
+        let dir = fs.root_dir().open_dir("target/dir").unwrap();
+        assert!(dir.open_file("sub/a.bin").is_ok());
+        assert!(dir.open_file("skip.tmp").is_err());
+        drop(dir);
+        drop(fs);
+
+        // Back to the host, under a new name.
+        assert_eq!(cp(&["-r", "img:/target/dir", &host_arg("out")]), 0);
+        assert_eq!(fs::read(host.join("out/sub/a.bin")).unwrap(), b"abc");
+        assert_eq!(cp(&["img:/notes.txt", &host_arg("out")]), 0);
+        assert_eq!(fs::read(host.join("out/notes.txt")).unwrap(), b"note");
+        assert_eq!(cp(&["img:/notes.txt", &host_arg("out")]), 1);
+        assert_eq!(cp(&["img:/notes.txt", &host_arg("out"), "--update"]), 0);
+
+        // A dry run prints what it would do, and leaves the image alone, even if the copy
+        // wouldn't fit.
+        let checksum = image.checksum();
+        assert_eq!(
+            cp(&["-r", &host_arg("dir"), "img:/new/dir", "--dry-run"]),
+            0
+        );
+        fs::File::create(host.join("huge.bin"))
+            .unwrap()
+            .set_len(32 << 20)
+            .unwrap();
+        assert_eq!(cp(&[&host_arg("huge.bin"), "img:/", "--dry-run"]), 1);
+        assert_eq!(image.checksum(), checksum);
+        assert!(image.volume().root_dir().open_dir("new").is_err());
+
+        assert_eq!(cp(&["img:/notes.txt", "img:/copy.txt"]), 2);
+        assert_eq!(cp(&["img:/notes.txt"]), 2);
+        fs::remove_dir_all(&host).unwrap();
+    }
+}
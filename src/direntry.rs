@@ -0,0 +1,1782 @@
+//! This module changes directory entries on the disk image directly, for the few things `fatfs`
+//! offers no way to change, like the attributes of an entry, the timestamps of a directory or the
+//! volume label. The FAT is read here as well, to show which clusters are in use, and the
+//! directory tree is walked to find which files they belong to. The dirty flag, which `fatfs` only
+//! sets in the boot sector, is set here as well. Files of FAT+ volumes larger than 4 GiB, which
+//! `fatfs` can't get past the 32 bit size field of, are accessed here too.
+use crate::floppy;
+use fatfs::{Date, DateTime, FatType, Time};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+/// The size of a directory entry in bytes.
+const ENTRY_SIZE: usize = 32;
+
+/// The offset of the attributes in a directory entry.
+const ATTRIBUTES_OFFSET: u64 = 11;
+
+/// The offset of the creation time in units of 10 ms past the two seconds of the creation time,
+/// followed by the creation time and date, the access date, and after the high half of the first
+/// cluster, the modification time and date.
+const CREATED_OFFSET: u64 = 13;
+
+/// The offset of the modification time in a directory entry, followed by the modification date.
+const MODIFIED_OFFSET: u64 = 22;
+
+/// The attribute marking the volume label entry in the root directory.
+const VOLUME_ID: u8 = 0x08;
+
+/// The attributes marking the parts of a long name.
+const LONG_NAME: u8 = 0x0F;
+
+/// The offset of the checksum of the short name in the parts of a long name.
+const LFN_CHECKSUM_OFFSET: u64 = 13;
+
+/// The attribute marking directories.
+const DIRECTORY: u8 = 0x10;
+
+/// The offset of the byte whose bits 0-2 and 5-7 hold bits 32-34 and 35-37 of the file size on
+/// FAT+ volumes. Bits 3 and 4 mark short names stored in lowercase.
+const FAT_PLUS_OFFSET: usize = 0x0C;
+
+/// Represent a file of a FAT+ volume that is too large for the 32 bit size of its directory entry.
+///
+/// # Members
+///
+/// * `size: u64` - The size of the file, up to 256 GiB.
+/// * `first_cluster: u32` - The first cluster of the file.
+#[derive(Clone, Copy, Debug)]
+pub struct LargeFile {
+    pub size: u64,
+    pub first_cluster: u32,
+}
+
+/// Represent what a cluster of the data area is used for, as recorded in the FAT.
+///
+/// # Variants
+///
+/// * `Free` - The cluster is free.
+/// * `Used` - The cluster is part of a file or directory.
+/// * `Bad` - The cluster was marked as bad, and is never used.
+/// * `Reserved` - The FAT entry of the cluster holds one of the reserved values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterState {
+    Free,
+    Used,
+    Bad,
+    Reserved,
+}
+
+/// Represent the clusters of a FAT volume and what they are used for.
+///
+/// # Members
+///
+/// * `cluster_size: u64` - The size of a cluster in bytes.
+/// * `data_start: u64` - The offset of the first cluster, after the boot sector, the FATs and the
+///   root directory of FAT12 and FAT16 volumes.
+/// * `states: Vec<ClusterState>` - What every cluster is used for, starting with cluster 2.
+#[derive(Clone, Debug)]
+pub struct ClusterMap {
+    pub cluster_size: u64,
+    pub data_start: u64,
+    pub states: Vec<ClusterState>,
+}
+
+/// Represent a file or directory found by walking the directory tree of the volume.
+///
+/// # Members
+///
+/// * `path: String` - The path of the entry, with its long name, e.g. `/DOCS/Report.txt`.
+/// * `is_dir: bool` - Whether the entry is a directory.
+/// * `size: u64` - The size of a file as stored in its directory entry, 0 for directories.
+/// * `clusters: Vec<u32>` - The cluster chain of the entry, empty for empty files and the root
+///   directory of FAT12 and FAT16 volumes.
+#[derive(Clone, Debug)]
+pub struct TreeEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub clusters: Vec<u32>,
+}
+
+/// Represent the layout of a FAT volume, as described by its boot sector.
+///
+/// # Members
+///
+/// * `fat_type: FatType` - The FAT type, which decides the size of the entries in the FAT.
+/// * `fat_start: u64` - The offset of the first FAT.
+/// * `fat_size: u64` - The size of a FAT in bytes.
+/// * `fat_count: u64` - How many copies of the FAT there are.
+/// * `root_dir: Option<(u64, u64)>` - The offset and size of the root directory of FAT12 and
+///   FAT16 volumes, which is stored outside of the data area.
+/// * `root_cluster: u32` - The first cluster of the root directory of FAT32 volumes.
+/// * `data_start: u64` - The offset of the first cluster.
+/// * `cluster_size: u64` - The size of a cluster in bytes.
+/// * `cluster_count: u32` - How many clusters the data area holds.
+/// * `max_clusters: u64` - How many clusters fit on the disk image, used to stop at loops in a
+///   cluster chain.
+struct Layout {
+    fat_type: FatType,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u64,
+    root_dir: Option<(u64, u64)>,
+    root_cluster: u32,
+    data_start: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    max_clusters: u64,
+}
+
+impl Layout {
+    /// Read the layout of the volume from its boot sector.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Self>` - The layout of the volume.
+    fn read(image: &File) -> io::Result<Self> {
+        let sector = floppy::boot_sector(image)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]) as u64;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap()) as u64
+        };
+
+        let bytes_per_sector = u16_at(0x0B);
+        let cluster_size = bytes_per_sector * sector[0x0D] as u64;
+        if cluster_size == 0 {
+            return Err(invalid_data());
+        }
+        let fat_start = u16_at(0x0E) * bytes_per_sector;
+        let fat_size = match u16_at(0x16) {
+            0 => u32_at(0x24),
+            sectors => sectors,
+        } * bytes_per_sector;
+        let fat_count = sector[0x10] as u64;
+        let root_start = fat_start + fat_count * fat_size;
+        let root_size = u16_at(0x11) * ENTRY_SIZE as u64;
+        // The root directory is padded to whole sectors.
+        let root_sectors = root_size.div_ceil(bytes_per_sector.max(1));
+        let data_start = root_start + root_sectors * bytes_per_sector;
+
+        // The FAT type is decided by the number of clusters alone, the same way `fatfs` does.
+        let total_size = match u16_at(0x13) {
+            0 => u32_at(0x20),
+            sectors => sectors,
+        } * bytes_per_sector;
+        let cluster_count = total_size.saturating_sub(data_start) / cluster_size;
+        let fat_type = match cluster_count {
+            clusters if clusters < 4085 => FatType::Fat12,
+            clusters if clusters < 65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        Ok(Layout {
+            fat_type,
+            fat_start,
+            fat_size,
+            fat_count,
+            root_dir: (fat_type != FatType::Fat32).then_some((root_start, root_size)),
+            root_cluster: u32_at(0x2C) as u32,
+            data_start,
+            cluster_size,
+            cluster_count: cluster_count as u32,
+            max_clusters: image.metadata()?.len() / cluster_size,
+        })
+    }
+
+    /// Get the next cluster of a cluster chain from the FAT.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `cluster: u32` - The current cluster.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<u32>>` - The next cluster, or `None` if the chain ends here.
+    fn next_cluster(&self, image: &File, cluster: u32) -> io::Result<Option<u32>> {
+        let (offset, len) = self.fat_entry_position(cluster);
+        let mut buf = [0; 4];
+        image.read_exact_at(&mut buf[..len], self.fat_start + offset)?;
+        let next = self.decode_fat_entry(&buf, cluster);
+        match next {
+            _ if next >= self.end_of_chain() => Ok(None),
+            // Free and reserved clusters can't be part of a chain.
+            0 | 1 => Err(invalid_data()),
+            _ => Ok(Some(next)),
+        }
+    }
+
+    /// Get where the FAT entry of a cluster is stored. FAT12 packs two entries into three bytes, so
+    /// the entry shares its first or last byte with the one next to it.
+    ///
+    /// # Parameters
+    ///
+    /// * `cluster: u32` - The cluster.
+    ///
+    /// # Returns
+    ///
+    /// * `(u64, usize)` - The offset of the entry in the FAT and how many bytes to read for it.
+    fn fat_entry_position(&self, cluster: u32) -> (u64, usize) {
+        match self.fat_type {
+            FatType::Fat12 => ((cluster + cluster / 2) as u64, 2),
+            FatType::Fat16 => (cluster as u64 * 2, 2),
+            FatType::Fat32 => (cluster as u64 * 4, 4),
+        }
+    }
+
+    /// Decode the FAT entry of a cluster.
+    ///
+    /// # Parameters
+    ///
+    /// * `bytes: &[u8]` - The bytes at the position given by `fat_entry_position`.
+    /// * `cluster: u32` - The cluster.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The value of the entry: the next cluster, 0 for free clusters, or one of the
+    ///   values marking bad clusters and the end of the chain.
+    fn decode_fat_entry(&self, bytes: &[u8], cluster: u32) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => {
+                let entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                if cluster.is_multiple_of(2) {
+                    (entry & 0x0FFF) as u32
+                } else {
+                    (entry >> 4) as u32
+                }
+            }
+            FatType::Fat16 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            FatType::Fat32 => u32::from_le_bytes(bytes[..4].try_into().unwrap()) & 0x0FFF_FFFF,
+        }
+    }
+
+    /// Get the lowest FAT entry value marking the end of a cluster chain. The value below marks
+    /// bad clusters, and the seven below that are reserved.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The value.
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0x0FF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// Read what the clusters of the data area are used for from the first FAT.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<ClusterState>>` - What every cluster is used for, starting with cluster
+    ///   2, up to the last cluster the FAT has an entry for.
+    fn cluster_states(&self, image: &File) -> io::Result<Vec<ClusterState>> {
+        let end = self.end_of_chain();
+        Ok(self
+            .fat_values(image, 0)?
+            .into_iter()
+            .skip(2)
+            .map(|value| match value {
+                0 => ClusterState::Free,
+                value if value == end - 1 => ClusterState::Bad,
+                value if value == 1 || (end - 8..end - 1).contains(&value) => {
+                    ClusterState::Reserved
+                }
+                _ => ClusterState::Used,
+            })
+            .collect())
+    }
+
+    /// Read and decode all entries of a copy of the FAT at once.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `copy: u64` - Which copy of the FAT to read, 0 for the first one.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<u32>>` - The value of every entry, starting with the two reserved ones
+    ///   before cluster 2, up to the last cluster the FAT has an entry for.
+    fn fat_values(&self, image: &File, copy: u64) -> io::Result<Vec<u32>> {
+        let mut fat = vec![0; self.fat_size as usize];
+        image.read_exact_at(&mut fat, self.fat_start + copy * self.fat_size)?;
+        let mut values = Vec::with_capacity(self.cluster_count as usize + 2);
+        for cluster in 0..self.cluster_count.saturating_add(2) {
+            let (offset, len) = self.fat_entry_position(cluster);
+            let Some(bytes) = fat.get(offset as usize..offset as usize + len) else {
+                break;
+            };
+            values.push(self.decode_fat_entry(bytes, cluster));
+        }
+        Ok(values)
+    }
+
+    /// Follow a cluster chain through a FAT read with `fat_values`.
+    ///
+    /// # Parameters
+    ///
+    /// * `fat: &[u32]` - The entries of the FAT.
+    /// * `first_cluster: u32` - The first cluster of the chain.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<u32>>` - The clusters of the chain, in order, or an `InvalidData` error
+    ///   if it runs into a free cluster, past the end of the FAT or in a loop.
+    fn chain_in(&self, fat: &[u32], first_cluster: u32) -> io::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            if cluster < 2 || cluster as usize >= fat.len() || clusters.len() >= fat.len() {
+                return Err(invalid_data());
+            }
+            clusters.push(cluster);
+            match fat[cluster as usize] {
+                next if next >= self.end_of_chain() => return Ok(clusters),
+                next => cluster = next,
+            }
+        }
+    }
+
+    /// Walk the directory tree of the volume, following the cluster chains through a FAT read
+    /// with `fat_values`.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `fat: &[u32]` - The entries of the FAT.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<TreeEntry>>` - The root directory first, followed by every file and
+    ///   directory below it, each directory before its entries. Directories that are reached a
+    ///   second time, through a damaged entry, are not walked again.
+    fn walk_tree(&self, image: &File, fat: &[u32]) -> io::Result<Vec<TreeEntry>> {
+        let root_clusters = match self.root_dir {
+            Some(_) => Vec::new(),
+            None => self.chain_in(fat, self.root_cluster)?,
+        };
+        let mut tree = vec![TreeEntry {
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            clusters: root_clusters,
+        }];
+        let mut walked = HashSet::new();
+        let mut index = 0;
+        while index < tree.len() {
+            let dir = &tree[index];
+            index += 1;
+            if !dir.is_dir || !walked.insert(dir.clusters.first().copied()) {
+                continue;
+            }
+            let mut contents = Vec::new();
+            for (start, size) in self.tree_regions(dir) {
+                let mut region = vec![0; size as usize];
+                image.read_exact_at(&mut region, start)?;
+                contents.extend(region);
+            }
+            let parent = dir.path.trim_end_matches('/').to_string();
+            let mut long_name_parts = Vec::new();
+            for entry in contents.chunks_exact(ENTRY_SIZE) {
+                let entry: &[u8; ENTRY_SIZE] = entry.try_into().unwrap();
+                let attributes = entry[ATTRIBUTES_OFFSET as usize];
+                match entry[0] {
+                    // The end of the directory.
+                    0x00 => break,
+                    0xE5 => long_name_parts.clear(),
+                    _ if attributes == LONG_NAME => {
+                        if entry[0] & 0x40 != 0 {
+                            long_name_parts.clear();
+                        }
+                        long_name_parts.push(*entry);
+                    }
+                    // The volume label, and the entries for the directory and its parent.
+                    _ if attributes & VOLUME_ID != 0 || entry[0] == b'.' => long_name_parts.clear(),
+                    _ => {
+                        let name = long_name(&long_name_parts, entry)
+                            .unwrap_or_else(|| display_short_name(entry));
+                        long_name_parts.clear();
+                        let clusters = match first_cluster(entry) {
+                            0 => Vec::new(),
+                            cluster => self.chain_in(fat, cluster)?,
+                        };
+                        let is_dir = attributes & DIRECTORY != 0;
+                        tree.push(TreeEntry {
+                            path: format!("{}/{}", parent, name),
+                            is_dir,
+                            size: if is_dir {
+                                0
+                            } else {
+                                u32::from_le_bytes(entry[28..32].try_into().unwrap()) as u64
+                            },
+                            clusters,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Get the regions of the disk image an entry found by `walk_tree` is stored in.
+    ///
+    /// # Parameters
+    ///
+    /// * `entry: &TreeEntry` - The file or directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(u64, u64)>` - The offset and size of every region, in order.
+    fn tree_regions(&self, entry: &TreeEntry) -> Vec<(u64, u64)> {
+        match self.root_dir {
+            Some(root_dir) if entry.path == "/" => vec![root_dir],
+            _ => entry
+                .clusters
+                .iter()
+                .map(|cluster| (self.cluster_offset(*cluster), self.cluster_size))
+                .collect(),
+        }
+    }
+
+    /// Change the FAT entry of a cluster in every copy of the FAT.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The disk image, opened for writing.
+    /// * `cluster: u32` - The cluster.
+    /// * `value: u32` - The new value of the entry, as returned by `decode_fat_entry`.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if writing failed.
+    fn write_fat_entry(&self, image: &File, cluster: u32, value: u32) -> io::Result<()> {
+        let (offset, len) = self.fat_entry_position(cluster);
+        for copy in 0..self.fat_count {
+            let offset = self.fat_start + copy * self.fat_size + offset;
+            let mut buf = [0; 4];
+            image.read_exact_at(&mut buf[..len], offset)?;
+            let entry = u32::from_le_bytes(buf);
+            // FAT12 entries share a byte with the one next to them, and the upper four bits of
+            // FAT32 entries are reserved.
+            let entry = match self.fat_type {
+                FatType::Fat12 if cluster.is_multiple_of(2) => (entry & 0xF000) | value,
+                FatType::Fat12 => (entry & 0x000F) | (value << 4),
+                FatType::Fat16 => value,
+                FatType::Fat32 => (entry & 0xF000_0000) | value,
+            };
+            image.write_all_at(&entry.to_le_bytes()[..len], offset)?;
+        }
+        Ok(())
+    }
+
+    /// Change the number of free clusters recorded in the FS information sector of FAT32 volumes,
+    /// if it is known.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The disk image, opened for writing.
+    /// * `change: i64` - How many clusters were freed, or taken if negative.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if writing failed.
+    fn change_free_clusters(&self, image: &File, change: i64) -> io::Result<()> {
+        if self.fat_type != FatType::Fat32 {
+            return Ok(());
+        }
+        let sector = floppy::boot_sector(image)?;
+        let bytes_per_sector = u16::from_le_bytes([sector[0x0B], sector[0x0C]]) as u64;
+        let offset = u16::from_le_bytes([sector[0x30], sector[0x31]]) as u64 * bytes_per_sector;
+        let mut info = [0; 512];
+        image.read_exact_at(&mut info, offset)?;
+        let signatures = (&info[0..4], &info[484..488]);
+        let free_count = u32::from_le_bytes(info[488..492].try_into().unwrap());
+        if signatures != (b"RRaA".as_ref(), b"rrAa".as_ref()) || free_count == u32::MAX {
+            return Ok(());
+        }
+        let free_count = (free_count as i64 + change).clamp(0, self.cluster_count as i64) as u32;
+        image.write_all_at(&free_count.to_le_bytes(), offset + 488)
+    }
+
+    /// Get the offset of a cluster on the disk image.
+    ///
+    /// # Parameters
+    ///
+    /// * `cluster: u32` - The cluster, at least 2.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The offset of the cluster.
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// Follow a cluster chain through the FAT.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `first_cluster: u32` - The first cluster of the chain.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<u32>>` - The clusters of the chain, in order.
+    fn cluster_chain(&self, image: &File, first_cluster: u32) -> io::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            if cluster < 2 || clusters.len() as u64 > self.max_clusters {
+                return Err(invalid_data());
+            }
+            clusters.push(cluster);
+            match self.next_cluster(image, cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(clusters),
+            }
+        }
+    }
+
+    /// Get the regions of the disk image a part of a file is stored in. Adjacent clusters are
+    /// merged into one region.
+    ///
+    /// # Parameters
+    ///
+    /// * `clusters: &[u32]` - The cluster chain of the file.
+    /// * `offset: u64` - The offset in the file the part starts at.
+    /// * `len: usize` - The size of the part.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<(u64, Range<usize>)>>` - The offset on the disk image of every region,
+    ///   and which bytes of the part it holds, or an error if the chain ends too early.
+    fn data_regions(
+        &self,
+        clusters: &[u32],
+        offset: u64,
+        len: usize,
+    ) -> io::Result<Vec<(u64, Range<usize>)>> {
+        let mut regions: Vec<(u64, Range<usize>)> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / self.cluster_size) as usize)
+                .ok_or_else(invalid_data)?;
+            let within = position % self.cluster_size;
+            let size = cmp::min((self.cluster_size - within) as usize, len - done);
+            let start = self.cluster_offset(cluster) + within;
+            match regions.last_mut() {
+                Some((last, range)) if *last + range.len() as u64 == start => range.end += size,
+                _ => regions.push((start, done..done + size)),
+            }
+            done += size;
+        }
+        Ok(regions)
+    }
+
+    /// Get the regions of the disk image a directory is stored in.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `first_cluster: Option<u32>` - The first cluster of the directory, or `None` for the root
+    ///   directory.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<(u64, u64)>>` - The offset and size of every region, in order.
+    fn dir_regions(&self, image: &File, first_cluster: Option<u32>) -> io::Result<Vec<(u64, u64)>> {
+        let cluster = match (first_cluster, self.root_dir) {
+            (Some(cluster), _) => cluster,
+            (None, Some(root_dir)) => return Ok(vec![root_dir]),
+            (None, None) => self.root_cluster,
+        };
+        Ok(self
+            .cluster_chain(image, cluster)?
+            .into_iter()
+            .map(|cluster| (self.cluster_offset(cluster), self.cluster_size))
+            .collect())
+    }
+
+    /// Read the entries of a directory, up to and including the entry marking its end.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `dir: Option<u32>` - The first cluster of the directory, or `None` for the root directory.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Vec<(u64, [u8; ENTRY_SIZE])>>` - The offset and the contents of every entry,
+    ///   including deleted ones.
+    fn entries(&self, image: &File, dir: Option<u32>) -> io::Result<Vec<(u64, [u8; ENTRY_SIZE])>> {
+        let mut entries = Vec::new();
+        for (start, size) in self.dir_regions(image, dir)? {
+            let mut region = vec![0; size as usize];
+            image.read_exact_at(&mut region, start)?;
+            for (index, entry) in region.chunks_exact(ENTRY_SIZE).enumerate() {
+                let offset = start + (index * ENTRY_SIZE) as u64;
+                entries.push((offset, entry.try_into().unwrap()));
+                // The end of the directory.
+                if entry[0] == 0x00 {
+                    return Ok(entries);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Find an entry of a directory by its short name.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `dir: Option<u32>` - The first cluster of the directory, or `None` for the root directory.
+    /// * `short_name: &[u8]` - The short name of the entry, as returned by
+    ///   `DirEntry::short_file_name_as_bytes`.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<(u64, [u8; ENTRY_SIZE])>` - The offset and the contents of the entry, or a
+    ///   `NotFound` error if there is none.
+    fn find_entry(
+        &self,
+        image: &File,
+        dir: Option<u32>,
+        short_name: &[u8],
+    ) -> io::Result<(u64, [u8; ENTRY_SIZE])> {
+        self.entries(image, dir)?
+            .into_iter()
+            // Skip the end, deleted entries, long name parts and volume labels.
+            .filter(|(_, entry)| {
+                !matches!(entry[0], 0x00 | 0xE5)
+                    && entry[ATTRIBUTES_OFFSET as usize] & VOLUME_ID == 0
+            })
+            .find(|(_, entry)| format_short_name(entry[..11].try_into().unwrap()) == short_name)
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    /// Find a directory by the short names of the directories leading to it.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    /// * `short_names: &[Vec<u8>]` - The short names of the directories, starting below the root
+    ///   directory.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<u32>>` - The first cluster of the directory, or `None` for the root
+    ///   directory.
+    fn find_dir(&self, image: &File, short_names: &[Vec<u8>]) -> io::Result<Option<u32>> {
+        let mut dir = None;
+        for short_name in short_names {
+            let (_, entry) = self.find_entry(image, dir, short_name)?;
+            dir = Some(first_cluster(&entry));
+        }
+        Ok(dir)
+    }
+
+    /// Find the volume label entry of the root directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `image: &File` - The opened disk image.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<(u64, [u8; ENTRY_SIZE])>>` - The offset and the contents of the entry,
+    ///   or `None` if the volume has no label entry.
+    fn find_label_entry(&self, image: &File) -> io::Result<Option<(u64, [u8; ENTRY_SIZE])>> {
+        Ok(self.entries(image, None)?.into_iter().find(|(_, entry)| {
+            let attributes = entry[ATTRIBUTES_OFFSET as usize];
+            !matches!(entry[0], 0x00 | 0xE5)
+                && attributes & VOLUME_ID != 0
+                && attributes & LONG_NAME != LONG_NAME
+        }))
+    }
+}
+
+/// Change the attributes of a directory entry.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the entry and of
+///   the entry itself, starting below the root directory.
+/// * `attributes: u8` - The new attributes.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be found or written.
+pub fn set_attributes(image: &File, short_names: &[Vec<u8>], attributes: u8) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let (short_name, parents) = short_names.split_last().ok_or(ErrorKind::InvalidInput)?;
+    let dir = layout.find_dir(image, parents)?;
+    let (offset, _) = layout.find_entry(image, dir, short_name)?;
+    image.write_all_at(&[attributes], offset + ATTRIBUTES_OFFSET)
+}
+
+/// Change the timestamps of a directory entry. `fatfs` only sets them on files, as they are written.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the entry and of
+///   the entry itself, starting below the root directory.
+/// * `accessed: Option<Date>` - The new access date, or `None` to keep it.
+/// * `modified: Option<DateTime>` - The new modification time, or `None` to keep it. It is rounded
+///   down to two seconds.
+/// * `created: Option<DateTime>` - The new creation time, or `None` to keep it. It is rounded down
+///   to 10 ms.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be found or written.
+pub fn set_times(
+    image: &File,
+    short_names: &[Vec<u8>],
+    accessed: Option<Date>,
+    modified: Option<DateTime>,
+    created: Option<DateTime>,
+) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let (short_name, parents) = short_names.split_last().ok_or(ErrorKind::InvalidInput)?;
+    let dir = layout.find_dir(image, parents)?;
+    let (offset, _) = layout.find_entry(image, dir, short_name)?;
+    if let Some(created) = created {
+        let tenths = (created.time.sec % 2 * 100 + created.time.millis / 10) as u8;
+        let mut field = [tenths, 0, 0, 0, 0];
+        field[1..3].copy_from_slice(&encode_time(created.time).to_le_bytes());
+        field[3..5].copy_from_slice(&encode_date(created.date).to_le_bytes());
+        image.write_all_at(&field, offset + CREATED_OFFSET)?;
+    }
+    if let Some(accessed) = accessed {
+        image.write_all_at(
+            &encode_date(accessed).to_le_bytes(),
+            offset + CREATED_OFFSET + 5,
+        )?;
+    }
+    if let Some(modified) = modified {
+        let mut field = [0; 4];
+        field[..2].copy_from_slice(&encode_time(modified.time).to_le_bytes());
+        field[2..].copy_from_slice(&encode_date(modified.date).to_le_bytes());
+        image.write_all_at(&field, offset + MODIFIED_OFFSET)?;
+    }
+    Ok(())
+}
+
+/// Change the short name of a directory entry, and the checksum of it that the parts of its long
+/// name carry.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the entry and of
+///   the entry itself, starting below the root directory.
+/// * `raw_name: &[u8; 11]` - The new name and extension as stored, padded with spaces.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be found or written.
+pub fn set_short_name(
+    image: &File,
+    short_names: &[Vec<u8>],
+    raw_name: &[u8; 11],
+) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let (short_name, parents) = short_names.split_last().ok_or(ErrorKind::InvalidInput)?;
+    let dir = layout.find_dir(image, parents)?;
+    let (offset, _) = layout.find_entry(image, dir, short_name)?;
+    image.write_all_at(raw_name, offset)?;
+
+    let checksum = raw_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c));
+    let entries = layout.entries(image, dir)?;
+    let index = entries.iter().position(|(o, _)| *o == offset).unwrap();
+    // The parts of the long name come right before the entry, the last part first.
+    for (offset, entry) in entries[..index].iter().rev() {
+        if entry[0] == 0xE5 || entry[ATTRIBUTES_OFFSET as usize] != LONG_NAME {
+            break;
+        }
+        image.write_all_at(&[checksum], offset + LFN_CHECKSUM_OFFSET)?;
+        if entry[0] & 0x40 != 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the parts of the long name of a directory entry, leaving only its short name, as DOS
+/// writes entries.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the entry and of
+///   the entry itself, starting below the root directory.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be found or written.
+pub fn drop_long_name(image: &File, short_names: &[Vec<u8>]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let (short_name, parents) = short_names.split_last().ok_or(ErrorKind::InvalidInput)?;
+    let dir = layout.find_dir(image, parents)?;
+    let (offset, _) = layout.find_entry(image, dir, short_name)?;
+    let entries = layout.entries(image, dir)?;
+    let index = entries.iter().position(|(o, _)| *o == offset).unwrap();
+    for (offset, entry) in entries[..index].iter().rev() {
+        if entry[0] == 0xE5 || entry[ATTRIBUTES_OFFSET as usize] != LONG_NAME {
+            break;
+        }
+        image.write_all_at(&[0xE5], *offset)?;
+        if entry[0] & 0x40 != 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Find the files of a directory on a FAT+ volume that are larger than 4 GiB.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the directory,
+///   starting below the root directory.
+///
+/// # Returns
+///
+/// * `io::Result<HashMap<Vec<u8>, LargeFile>>` - The files larger than 4 GiB by their short
+///   name, as returned by `DirEntry::short_file_name_as_bytes`.
+pub fn large_files(
+    image: &File,
+    short_names: &[Vec<u8>],
+) -> io::Result<HashMap<Vec<u8>, LargeFile>> {
+    let layout = Layout::read(image)?;
+    let dir = layout.find_dir(image, short_names)?;
+    Ok(layout
+        .entries(image, dir)?
+        .into_iter()
+        // Skip the end, deleted entries, long name parts, volume labels and directories.
+        .filter(|(_, entry)| {
+            !matches!(entry[0], 0x00 | 0xE5)
+                && entry[ATTRIBUTES_OFFSET as usize] & (VOLUME_ID | DIRECTORY) == 0
+                && entry[FAT_PLUS_OFFSET] & 0xE7 != 0
+        })
+        .map(|(_, entry)| {
+            let high = entry[FAT_PLUS_OFFSET] as u64;
+            let low = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as u64;
+            let large_file = LargeFile {
+                size: low | (high & 0x07) << 32 | (high >> 5) << 35,
+                first_cluster: first_cluster(&entry),
+            };
+            (
+                format_short_name(entry[..11].try_into().unwrap()),
+                large_file,
+            )
+        })
+        .collect())
+}
+
+/// Follow the cluster chain of a file through the FAT.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `first_cluster: u32` - The first cluster of the file.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<u32>>` - The clusters of the file, in order.
+pub fn cluster_chain(image: &File, first_cluster: u32) -> io::Result<Vec<u32>> {
+    Layout::read(image)?.cluster_chain(image, first_cluster)
+}
+
+/// Find the first cluster of a file or directory.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the entry and of
+///   the entry itself, starting below the root directory. None for the root directory.
+///
+/// # Returns
+///
+/// * `io::Result<Option<u32>>` - The first cluster, or `None` for empty files and the root
+///   directory of FAT12 and FAT16 volumes, which are stored in no cluster.
+pub fn first_cluster_of(image: &File, short_names: &[Vec<u8>]) -> io::Result<Option<u32>> {
+    let layout = Layout::read(image)?;
+    let Some((short_name, parents)) = short_names.split_last() else {
+        return Ok(layout.root_dir.is_none().then_some(layout.root_cluster));
+    };
+    let dir = layout.find_dir(image, parents)?;
+    let (_, entry) = layout.find_entry(image, dir, short_name)?;
+    Ok(Some(first_cluster(&entry)).filter(|&cluster| cluster != 0))
+}
+
+/// Read which clusters of the volume are free, used, bad or reserved from the FAT.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<ClusterMap>` - The clusters of the volume.
+pub fn cluster_map(image: &File) -> io::Result<ClusterMap> {
+    let layout = Layout::read(image)?;
+    Ok(ClusterMap {
+        cluster_size: layout.cluster_size,
+        data_start: layout.data_start,
+        states: layout.cluster_states(image)?,
+    })
+}
+
+/// Walk the directory tree of the volume, to find all files and directories and their clusters.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<TreeEntry>>` - The root directory first, followed by every file and
+///   directory below it, each directory before its entries.
+pub fn walk_tree(image: &File) -> io::Result<Vec<TreeEntry>> {
+    let layout = Layout::read(image)?;
+    layout.walk_tree(image, &layout.fat_values(image, 0)?)
+}
+
+/// Compare the copies of the FAT with the first one. The first two entries, which hold the media
+/// type and the dirty flag rather than clusters, are left out.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<(u64, Vec<Range<u32>>)>>` - Every copy after the first one, counting from 0,
+///   with the ranges of clusters whose entries differ from those of the first one.
+pub fn compare_fats(image: &File) -> io::Result<Vec<(u64, Vec<Range<u32>>)>> {
+    let layout = Layout::read(image)?;
+    let first = layout.fat_values(image, 0)?;
+    let mut mismatches = Vec::new();
+    for copy in 1..layout.fat_count {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        let values = layout.fat_values(image, copy)?;
+        for (cluster, (a, b)) in first.iter().zip(&values).enumerate().skip(2) {
+            let cluster = cluster as u32;
+            if a == b {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == cluster => range.end += 1,
+                _ => ranges.push(cluster..cluster + 1),
+            }
+        }
+        mismatches.push((copy, ranges));
+    }
+    Ok(mismatches)
+}
+
+/// Check whether a copy of the FAT matches the directory tree: every cluster chain can be followed
+/// to its end, no cluster belongs to two chains, and every file has as many clusters as its size
+/// takes up.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `copy: u64` - Which copy of the FAT to check, 0 for the first one.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - Whether the copy matches the directory tree.
+pub fn fat_matches_tree(image: &File, copy: u64) -> io::Result<bool> {
+    let layout = Layout::read(image)?;
+    let tree = match layout.walk_tree(image, &layout.fat_values(image, copy)?) {
+        Ok(tree) => tree,
+        Err(e) if e.kind() == ErrorKind::InvalidData => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut seen = HashSet::new();
+    Ok(tree.iter().all(|entry| {
+        (entry.is_dir || entry.clusters.len() as u64 == entry.size.div_ceil(layout.cluster_size))
+            && entry.clusters.iter().all(|cluster| seen.insert(*cluster))
+    }))
+}
+
+/// Find the cluster chains that the FAT marks as used, but that no file or directory of the
+/// directory tree is stored in, e.g. because nuFAT or another driver was interrupted while
+/// deleting a file.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Vec<u32>>>` - The clusters of every lost chain, in order, or an
+///   `InvalidData` error if the directory tree can't be walked.
+pub fn lost_chains(image: &File) -> io::Result<Vec<Vec<u32>>> {
+    let layout = Layout::read(image)?;
+    let fat = layout.fat_values(image, 0)?;
+    let reachable: HashSet<u32> = layout
+        .walk_tree(image, &fat)?
+        .into_iter()
+        .flat_map(|entry| entry.clusters)
+        .collect();
+    let end = layout.end_of_chain();
+    let lost: Vec<u32> = (2..fat.len() as u32)
+        .filter(|cluster| {
+            let value = fat[*cluster as usize];
+            value != 0 && value != 1 && !(end - 8..end).contains(&value)
+        })
+        .filter(|cluster| !reachable.contains(cluster))
+        .collect();
+    let lost_set: HashSet<u32> = lost.iter().copied().collect();
+
+    // A chain starts at a cluster no other lost cluster points to. What is left after following
+    // them is made of loops, which are cut open at their lowest cluster.
+    let pointed_to: HashSet<u32> = lost
+        .iter()
+        .map(|cluster| fat[*cluster as usize])
+        .filter(|next| lost_set.contains(next))
+        .collect();
+    let (heads, rest): (Vec<u32>, Vec<u32>) = lost
+        .iter()
+        .partition(|cluster| !pointed_to.contains(cluster));
+    let mut visited = HashSet::new();
+    let mut chains = Vec::new();
+    for head in heads.into_iter().chain(rest) {
+        let mut chain = Vec::new();
+        let mut cluster = head;
+        while lost_set.contains(&cluster) && visited.insert(cluster) {
+            chain.push(cluster);
+            cluster = fat[cluster as usize];
+        }
+        if !chain.is_empty() {
+            chains.push(chain);
+        }
+    }
+    Ok(chains)
+}
+
+/// Free the clusters of cluster chains, in every copy of the FAT and in the number of free
+/// clusters recorded in the FS information sector of FAT32 volumes.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `chains: &[Vec<u32>]` - The cluster chains, as returned by `lost_chains`.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing failed.
+pub fn free_chains(image: &File, chains: &[Vec<u32>]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let mut freed = 0;
+    for cluster in chains.iter().flatten() {
+        layout.write_fat_entry(image, *cluster, 0)?;
+        freed += 1;
+    }
+    layout.change_free_clusters(image, freed)
+}
+
+/// Mark free clusters as bad, in every copy of the FAT, so they are never used. The number of
+/// free clusters recorded in the FS information sector of FAT32 volumes is updated as well.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `clusters: &[u32]` - The clusters, which have to be free.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing failed.
+pub fn mark_bad_clusters(image: &File, clusters: &[u32]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    for cluster in clusters {
+        layout.write_fat_entry(image, *cluster, layout.end_of_chain() - 1)?;
+    }
+    layout.change_free_clusters(image, -(clusters.len() as i64))
+}
+
+/// Make a cluster chain the contents of an empty file, which takes up all of its clusters. The end
+/// of the chain is marked as such, in case it pointed on into another chain.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `short_names: &[Vec<u8>]` - The short names of the directories leading to the file and of
+///   the file itself, starting below the root directory.
+/// * `chain: &[u32]` - The clusters of the chain, in order.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the file could not be found or written.
+pub fn attach_chain(image: &File, short_names: &[Vec<u8>], chain: &[u32]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    let (short_name, parents) = short_names.split_last().ok_or(ErrorKind::InvalidInput)?;
+    let (&first, &last) = chain
+        .first()
+        .zip(chain.last())
+        .ok_or(ErrorKind::InvalidInput)?;
+    let dir = layout.find_dir(image, parents)?;
+    let (offset, _) = layout.find_entry(image, dir, short_name)?;
+    let size = cmp::min(chain.len() as u64 * layout.cluster_size, u32::MAX as u64) as u32;
+    layout.write_fat_entry(image, last, layout.end_of_chain() | 0x07)?;
+    image.write_all_at(&((first >> 16) as u16).to_le_bytes(), offset + 20)?;
+    image.write_all_at(&(first as u16).to_le_bytes(), offset + 26)?;
+    image.write_all_at(&size.to_le_bytes(), offset + 28)
+}
+
+/// Read a copy of the FAT as it is stored.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `copy: u64` - Which copy of the FAT to read, 0 for the first one.
+///
+/// # Returns
+///
+/// * `io::Result<(u64, Vec<u8>)>` - The offset of the copy on the disk image, and its contents.
+pub fn read_fat_copy(image: &File, copy: u64) -> io::Result<(u64, Vec<u8>)> {
+    let layout = Layout::read(image)?;
+    if copy >= layout.fat_count {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+    let offset = layout.fat_start + copy * layout.fat_size;
+    let mut fat = vec![0; layout.fat_size as usize];
+    image.read_exact_at(&mut fat, offset)?;
+    Ok((offset, fat))
+}
+
+/// Overwrite a copy of the FAT.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `copy: u64` - Which copy of the FAT to overwrite, 0 for the first one.
+/// * `fat: &[u8]` - The new contents of the copy, as returned by `read_fat_copy`.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the contents don't fit or writing failed.
+pub fn write_fat_copy(image: &File, copy: u64, fat: &[u8]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    if copy >= layout.fat_count || fat.len() as u64 != layout.fat_size {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+    image.write_all_at(fat, layout.fat_start + copy * layout.fat_size)
+}
+
+/// Overwrite what is left of deleted directory entries, and the unused slots after the last entry
+/// of every directory, with zeros, so no names of deleted files can be read from them. Deleted
+/// entries keep the byte marking them as deleted, to keep the entries after them.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+///
+/// # Returns
+///
+/// * `io::Result<u64>` - How many bytes were overwritten, not counting those that were already
+///   zero.
+pub fn scrub_dir_slots(image: &File) -> io::Result<u64> {
+    let layout = Layout::read(image)?;
+    let tree = layout.walk_tree(image, &layout.fat_values(image, 0)?)?;
+    let mut scrubbed = 0;
+    for dir in tree.iter().filter(|entry| entry.is_dir) {
+        let mut ended = false;
+        for (start, size) in layout.tree_regions(dir) {
+            let mut region = vec![0; size as usize];
+            image.read_exact_at(&mut region, start)?;
+            let mut changed = false;
+            for entry in region.chunks_exact_mut(ENTRY_SIZE) {
+                ended = ended || entry[0] == 0x00;
+                let kept = match entry[0] {
+                    _ if ended => 0,
+                    0xE5 => 1,
+                    _ => continue,
+                };
+                let unused = &mut entry[kept..];
+                let nonzero = unused.iter().filter(|b| **b != 0).count();
+                if nonzero > 0 {
+                    scrubbed += nonzero as u64;
+                    unused.fill(0);
+                    changed = true;
+                }
+            }
+            if changed {
+                image.write_all_at(&region, start)?;
+            }
+        }
+    }
+    Ok(scrubbed)
+}
+
+/// Read a part of a file from the clusters it is stored in.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `clusters: &[u32]` - The cluster chain of the file, as returned by `cluster_chain`.
+/// * `offset: u64` - The offset in the file to start reading at.
+/// * `buf: &mut [u8]` - The buffer to read into, which has to end before the cluster chain does.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if reading failed.
+pub fn read_data(image: &File, clusters: &[u32], offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    for (start, range) in layout.data_regions(clusters, offset, buf.len())? {
+        image.read_exact_at(&mut buf[range], start)?;
+    }
+    Ok(())
+}
+
+/// Overwrite a part of a file in the clusters it is stored in, without changing its size.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `clusters: &[u32]` - The cluster chain of the file, as returned by `cluster_chain`.
+/// * `offset: u64` - The offset in the file to start writing at.
+/// * `data: &[u8]` - The data to write, which has to end before the cluster chain does.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing failed.
+pub fn write_data(image: &File, clusters: &[u32], offset: u64, data: &[u8]) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    for (start, range) in layout.data_regions(clusters, offset, data.len())? {
+        image.write_all_at(&data[range], start)?;
+    }
+    Ok(())
+}
+
+/// Read the volume label from its entry in the root directory.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Option<[u8; 11]>>` - The label as stored, padded with spaces, or `None` if the
+///   volume has no label entry.
+pub fn read_volume_label(image: &File) -> io::Result<Option<[u8; 11]>> {
+    let layout = Layout::read(image)?;
+    Ok(layout
+        .find_label_entry(image)?
+        .map(|(_, entry)| entry[..11].try_into().unwrap()))
+}
+
+/// Change the volume label entry in the root directory. A new entry is put into the first free
+/// slot if there is none yet.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `label: Option<&[u8; 11]>` - The new label, padded with spaces, or `None` to remove the
+///   label entry.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the root directory has no free slot or writing failed.
+pub fn write_volume_label(image: &File, label: Option<&[u8; 11]>) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+    match (layout.find_label_entry(image)?, label) {
+        (Some((offset, _)), Some(label)) => image.write_all_at(label, offset),
+        // Mark the entry as deleted.
+        (Some((offset, _)), None) => image.write_all_at(&[0xE5], offset),
+        (None, Some(label)) => {
+            // All slots after the end of the directory are free as well, so taking the end leaves
+            // the next slot as the new end.
+            let (offset, _) = layout
+                .entries(image, None)?
+                .into_iter()
+                .find(|(_, entry)| matches!(entry[0], 0x00 | 0xE5))
+                .ok_or_else(|| io::Error::other("No space left on device"))?;
+            let mut entry = [0; ENTRY_SIZE];
+            entry[..11].copy_from_slice(label);
+            entry[ATTRIBUTES_OFFSET as usize] = VOLUME_ID;
+            image.write_all_at(&entry, offset)
+        }
+        (None, None) => Ok(()),
+    }
+}
+
+/// Set or clear the dirty flag of the volume, which tells DOS, Windows and fsck tools that the
+/// volume was not cleanly unmounted. It is kept both in the boot sector and, on FAT16 and FAT32
+/// volumes, as the clean shutdown bit of the second entry of every FAT.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `dirty: bool` - Whether to set or clear the flag.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing the flag failed.
+pub fn set_dirty_flag(image: &File, dirty: bool) -> io::Result<()> {
+    let layout = Layout::read(image)?;
+
+    let flags_offset = if layout.fat_type == FatType::Fat32 {
+        0x41
+    } else {
+        0x25
+    };
+    // Without the extended boot signature, the flags may be overlapped by boot code.
+    let sector = floppy::boot_sector(image)?;
+    if sector[flags_offset as usize + 1] == 0x29 {
+        let mut flags = [sector[flags_offset as usize]];
+        if dirty {
+            flags[0] |= 0x01;
+        } else {
+            flags[0] &= !0x01;
+        }
+        image.write_all_at(&flags, flags_offset)?;
+    }
+
+    // FAT12 has no room for the bit.
+    let (entry_offset, entry_size, clean_bit) = match layout.fat_type {
+        FatType::Fat12 => return Ok(()),
+        FatType::Fat16 => (2, 2, 0x8000),
+        FatType::Fat32 => (4, 4, 0x0800_0000),
+    };
+    for copy in 0..layout.fat_count {
+        let offset = layout.fat_start + copy * layout.fat_size + entry_offset;
+        let mut buf = [0; 4];
+        image.read_exact_at(&mut buf[..entry_size], offset)?;
+        let mut entry = u32::from_le_bytes(buf);
+        if dirty {
+            entry &= !clean_bit;
+        } else {
+            entry |= clean_bit;
+        }
+        image.write_all_at(&entry.to_le_bytes()[..entry_size], offset)?;
+    }
+    Ok(())
+}
+
+/// Get the first cluster of a file or directory from its directory entry.
+///
+/// # Parameters
+///
+/// * `entry: &[u8; ENTRY_SIZE]` - The directory entry.
+///
+/// # Returns
+///
+/// * `u32` - The first cluster.
+fn first_cluster(entry: &[u8; ENTRY_SIZE]) -> u32 {
+    let high = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let low = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+    high << 16 | low
+}
+
+/// Put together the long name of a directory entry from its parts.
+///
+/// # Parameters
+///
+/// * `parts: &[[u8; ENTRY_SIZE]]` - The long name entries before the directory entry, in the order
+///   they are stored, the last part first.
+/// * `entry: &[u8; ENTRY_SIZE]` - The directory entry.
+///
+/// # Returns
+///
+/// * `Option<String>` - The long name, or `None` if there is none, or its parts don't belong to
+///   the entry.
+fn long_name(parts: &[[u8; ENTRY_SIZE]], entry: &[u8; ENTRY_SIZE]) -> Option<String> {
+    let checksum = entry[..11]
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c));
+    if parts.is_empty()
+        || parts
+            .iter()
+            .any(|part| part[LFN_CHECKSUM_OFFSET as usize] != checksum)
+    {
+        return None;
+    }
+    let units: Vec<u16> = parts
+        .iter()
+        .rev()
+        .flat_map(|part| [&part[1..11], &part[14..26], &part[28..32]].concat())
+        .collect::<Vec<u8>>()
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0x0000)
+        .collect();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Format the short name of a directory entry for display, lowercase where Windows NT and later
+/// marked the name or the extension as such.
+///
+/// # Parameters
+///
+/// * `entry: &[u8; ENTRY_SIZE]` - The directory entry.
+///
+/// # Returns
+///
+/// * `String` - The short name, e.g. `README.txt`.
+fn display_short_name(entry: &[u8; ENTRY_SIZE]) -> String {
+    let mut raw_name: [u8; 11] = entry[..11].try_into().unwrap();
+    for (range, flag) in [(0..8, 0x08), (8..11, 0x10)] {
+        if entry[12] & flag != 0 {
+            raw_name[range].make_ascii_lowercase();
+        }
+    }
+    String::from_utf8_lossy(&format_short_name(&raw_name)).into_owned()
+}
+
+/// Encode a date the way directory entries store it.
+///
+/// # Parameters
+///
+/// * `date: Date` - The date, from 1980 to 2107.
+///
+/// # Returns
+///
+/// * `u16` - The years since 1980 in bits 9-15, the month in bits 5-8 and the day in bits 0-4.
+fn encode_date(date: Date) -> u16 {
+    ((date.year - 1980) << 9) | (date.month << 5) | date.day
+}
+
+/// Encode a time the way directory entries store it.
+///
+/// # Parameters
+///
+/// * `time: Time` - The time.
+///
+/// # Returns
+///
+/// * `u16` - The hour in bits 11-15, the minute in bits 5-10 and half the seconds in bits 0-4.
+fn encode_time(time: Time) -> u16 {
+    (time.hour << 11) | (time.min << 5) | (time.sec / 2)
+}
+
+/// Format the short name stored in a directory entry the way `fatfs` returns it, e.g.
+/// `README.TXT`.
+///
+/// # Parameters
+///
+/// * `raw_name: &[u8; 11]` - The name and extension as stored, padded with spaces.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The short name.
+pub fn format_short_name(raw_name: &[u8; 11]) -> Vec<u8> {
+    let trimmed = |part: &[u8]| {
+        let len = part.iter().rposition(|c| *c != b' ').map_or(0, |p| p + 1);
+        part[..len].to_vec()
+    };
+    let mut name = trimmed(&raw_name[..8]);
+    let extension = trimmed(&raw_name[8..]);
+    if !extension.is_empty() {
+        name.push(b'.');
+        name.extend(extension);
+    }
+    // 0xE5 marks deleted entries, so a name starting with it is stored with 0x05.
+    if name.first() == Some(&0x05) {
+        name[0] = 0xE5;
+    }
+    name
+}
+
+/// Create an error for a volume whose structures are damaged.
+///
+/// # Returns
+///
+/// * `io::Error` - The error.
+fn invalid_data() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "The volume is damaged.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::Dir;
+    use std::io::Write;
+
+    /// Find an entry of a directory by its long name, as `fatfs` lists it.
+    fn entry<'a, T: fatfs::ReadWriteSeek>(
+        dir: &Dir<'a, T>,
+        name: &str,
+    ) -> Option<fatfs::DirEntry<'a, T>> {
+        dir.iter()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name() == name)
+    }
+
+    #[test]
+    fn set_times_changes_only_the_given_times() {
+        let image = TestImage::new(FatType::Fat32);
+        {
+            let fs = image.volume();
+            fs.root_dir()
+                .create_dir("Outer")
+                .unwrap()
+                .create_dir("Inner")
+                .unwrap();
+        }
+        let at = |year, sec, millis| DateTime {
+            date: Date {
+                year,
+                month: 7,
+                day: 14,
+            },
+            time: Time {
+                hour: 13,
+                min: 37,
+                sec,
+                millis,
+            },
+        };
+
+        let path = [b"OUTER".to_vec(), b"INNER".to_vec()];
+        set_times(
+            &image.open(),
+            &path,
+            None,
+            Some(at(2001, 43, 0)),
+            Some(at(1999, 59, 990)),
+        )
+        .unwrap();
+        let before = {
+            let fs = image.volume();
+            let outer = fs.root_dir().open_dir("Outer").unwrap();
+            let inner = entry(&outer, "Inner").unwrap();
+            assert_eq!(inner.modified().date, at(2001, 0, 0).date);
+            // Modification times are kept to two seconds.
+            assert_eq!(inner.modified().time, at(2001, 42, 0).time);
+            assert_eq!(inner.created().date, at(1999, 0, 0).date);
+            assert_eq!(inner.created().time, at(1999, 59, 990).time);
+            inner.accessed()
+        };
+
+        set_times(&image.open(), &path, Some(at(2020, 0, 0).date), None, None).unwrap();
+        let fs = image.volume();
+        let outer = fs.root_dir().open_dir("Outer").unwrap();
+        let inner = entry(&outer, "Inner").unwrap();
+        assert_ne!(before, inner.accessed());
+        assert_eq!(inner.accessed(), at(2020, 0, 0).date);
+        assert_eq!(inner.modified().time, at(2001, 42, 0).time);
+        assert_eq!(inner.created().time, at(1999, 59, 990).time);
+        assert!(set_times(&image.open(), &[b"MISSING".to_vec()], None, None, None).is_err());
+    }
+
+    #[test]
+    fn set_short_name_keeps_the_long_name() {
+        let image = TestImage::new(FatType::Fat16);
+        {
+            let fs = image.volume();
+            let dir = fs.root_dir().create_dir("Some Directory").unwrap();
+            dir.create_file("Another Long Name.txt").unwrap();
+            let mut file = dir.create_file("Long File Name.txt").unwrap();
+            file.write_all(b"data").unwrap();
+        }
+
+        let file = image.open();
+        set_short_name(
+            &file,
+            &[b"SOMEDI~1".to_vec(), b"LONGFI~1.TXT".to_vec()],
+            b"LONGFILETXT",
+        )
+        .unwrap();
+
+        let fs = image.volume();
+        let dir = fs.root_dir().open_dir("Some Directory").unwrap();
+        // With a wrong checksum, `fatfs` would drop the long name and list the short one.
+        let renamed = entry(&dir, "Long File Name.txt").unwrap();
+        assert_eq!(renamed.short_file_name(), "LONGFILE.TXT");
+        assert_eq!(renamed.len(), 4);
+        let other = entry(&dir, "Another Long Name.txt").unwrap();
+        assert_eq!(other.short_file_name(), "ANOTHE~1.TXT");
+    }
+
+    #[test]
+    fn drop_long_name_leaves_the_short_name() {
+        let image = TestImage::new(FatType::Fat12);
+        {
+            let fs = image.volume();
+            fs.root_dir().create_file("Long File Name.txt").unwrap();
+            fs.root_dir().create_file("Another Long Name.txt").unwrap();
+        }
+
+        drop_long_name(&image.open(), &[b"LONGFI~1.TXT".to_vec()]).unwrap();
+
+        let fs = image.volume();
+        let names: Vec<String> = fs
+            .root_dir()
+            .iter()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["LONGFI~1.TXT", "Another Long Name.txt"]);
+    }
+
+    #[test]
+    fn set_short_name_needs_an_existing_entry() {
+        let image = TestImage::new(FatType::Fat12);
+        let file = image.open();
+        assert!(set_short_name(&file, &[b"MISSING.TXT".to_vec()], b"OTHER   TXT").is_err());
+        assert!(set_short_name(&file, &[], b"OTHER   TXT").is_err());
+    }
+
+    #[test]
+    fn cluster_map_matches_the_cluster_chains() {
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let image =
+                TestImage::with_files(fat_type, &[("data.bin", &[0xAA; 5000]), ("empty.txt", b"")]);
+            let free_clusters = image.volume().stats().unwrap().free_clusters();
+            let file = image.open();
+            let map = cluster_map(&file).unwrap();
+            let first = first_cluster_of(&file, &[b"DATA.BIN".to_vec()])
+                .unwrap()
+                .unwrap();
+            let chain = cluster_chain(&file, first).unwrap();
+            assert_eq!(chain.len() as u64, 5000u64.div_ceil(map.cluster_size));
+            for cluster in &chain {
+                assert_eq!(map.states[*cluster as usize - 2], ClusterState::Used);
+            }
+            let count = |state| map.states.iter().filter(|&&s| s == state).count();
+            assert_eq!(count(ClusterState::Free) as u32, free_clusters);
+            // The root directory of FAT32 volumes takes up a cluster of its own.
+            let root = first_cluster_of(&file, &[]).unwrap();
+            assert_eq!(root.is_some(), fat_type == FatType::Fat32);
+            assert_eq!(count(ClusterState::Used), chain.len() + root.iter().count());
+            assert_eq!(
+                first_cluster_of(&file, &[b"EMPTY.TXT".to_vec()]).unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn cluster_map_decodes_packed_fat12_entries() {
+        let image = TestImage::new(FatType::Fat12);
+        let file = image.open();
+        let layout = Layout::read(&file).unwrap();
+        // Two FAT12 entries share the middle of three bytes.
+        let set_entry = |cluster: u32, value: u16| {
+            let offset = layout.fat_start + (cluster + cluster / 2) as u64;
+            let mut buf = [0; 2];
+            file.read_exact_at(&mut buf, offset).unwrap();
+            let entry = u16::from_le_bytes(buf);
+            let entry = if cluster.is_multiple_of(2) {
+                (entry & 0xF000) | value
+            } else {
+                (entry & 0x000F) | (value << 4)
+            };
+            file.write_all_at(&entry.to_le_bytes(), offset).unwrap();
+        };
+        set_entry(5, 0x0FF7);
+        set_entry(6, 0x0FF7);
+        set_entry(8, 0x0FF0);
+        set_entry(9, 0x0FFF);
+        let states = cluster_map(&file).unwrap().states;
+        assert_eq!(
+            states[2..9],
+            [
+                ClusterState::Free,
+                ClusterState::Bad,
+                ClusterState::Bad,
+                ClusterState::Free,
+                ClusterState::Reserved,
+                ClusterState::Used,
+                ClusterState::Free,
+            ]
+        );
+        assert_eq!(states.len() as u64, layout.cluster_count as u64);
+    }
+
+    /// Read the flags byte of the boot sector and the second entry of every FAT.
+    fn dirty_flags(file: &File) -> (u8, Vec<u32>) {
+        let layout = Layout::read(file).unwrap();
+        let (flags_offset, entry_size) = match layout.fat_type {
+            FatType::Fat12 => (0x25, 2),
+            FatType::Fat16 => (0x25, 2),
+            FatType::Fat32 => (0x41, 4),
+        };
+        let mut flags = [0];
+        file.read_exact_at(&mut flags, flags_offset).unwrap();
+        let entries = (0..layout.fat_count)
+            .map(|copy| {
+                let mut buf = [0; 4];
+                let offset = layout.fat_start + copy * layout.fat_size + entry_size as u64;
+                file.read_exact_at(&mut buf[..entry_size], offset).unwrap();
+                u32::from_le_bytes(buf)
+            })
+            .collect();
+        (flags[0], entries)
+    }
+
+    #[test]
+    fn set_dirty_flag_marks_the_boot_sector_and_the_fats() {
+        for (fat_type, clean_bit) in [(FatType::Fat16, 0x8000), (FatType::Fat32, 0x0800_0000)] {
+            let image = TestImage::new(fat_type);
+            let file = image.open();
+            let (flags, entries) = dirty_flags(&file);
+            assert_eq!(flags & 0x01, 0);
+            assert!(entries.iter().all(|entry| entry & clean_bit != 0));
+
+            set_dirty_flag(&file, true).unwrap();
+            let (dirty_flags_byte, dirty_entries) = dirty_flags(&file);
+            assert_eq!(dirty_flags_byte, flags | 0x01);
+            assert_eq!(dirty_entries.len(), 2);
+            for (dirty, clean) in dirty_entries.iter().zip(&entries) {
+                assert_eq!(*dirty, clean & !clean_bit);
+            }
+            assert!(image.volume().read_status_flags().unwrap().dirty());
+
+            set_dirty_flag(&file, false).unwrap();
+            assert_eq!(dirty_flags(&file), (flags, entries));
+            assert!(!image.volume().read_status_flags().unwrap().dirty());
+        }
+    }
+
+    #[test]
+    fn set_dirty_flag_leaves_the_fat12_fat_alone() {
+        let image = TestImage::new(FatType::Fat12);
+        let file = image.open();
+        let (flags, entries) = dirty_flags(&file);
+
+        set_dirty_flag(&file, true).unwrap();
+        assert_eq!(dirty_flags(&file), (flags | 0x01, entries.clone()));
+        set_dirty_flag(&file, false).unwrap();
+        assert_eq!(dirty_flags(&file), (flags, entries));
+    }
+
+    #[test]
+    fn set_dirty_flag_spares_boot_code_without_extended_boot_signature() {
+        let image = TestImage::new(FatType::Fat16);
+        let file = image.open();
+        // Old boot sectors may have boot code where the flags are.
+        file.write_all_at(&[0xAB, 0x00], 0x25).unwrap();
+
+        set_dirty_flag(&file, true).unwrap();
+        let (flags, entries) = dirty_flags(&file);
+        assert_eq!(flags, 0xAB);
+        assert!(entries.iter().all(|entry| entry & 0x8000 == 0));
+    }
+}
@@ -0,0 +1,69 @@
+//! This module prints what the subcommands given `--dry-run` would do. They open the disk image
+//! for reading only, check and plan everything as they would otherwise, and print every action
+//! on a line of its own: its name, then its arguments, strings quoted and escaped like in Rust
+//! and numbers as they are, e.g. `copy "host:notes.txt" "img:/notes.txt" 1234`. Scripts can rely
+//! on this format.
+use std::fmt::Write;
+
+/// Represent an argument of an action.
+///
+/// # Variants
+///
+/// * `Text(&str)` - A path, a label or anything else, printed quoted.
+/// * `Number(u64)` - A size or a count, printed as it is.
+pub enum Arg<'a> {
+    Text(&'a str),
+    Number(u64),
+}
+
+/// Format an action that would be taken.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name of the action, e.g. `copy` or `delete`.
+/// * `args: &[Arg]` - Its arguments.
+///
+/// # Returns
+///
+/// * `String` - The line to print.
+pub fn format_action(name: &str, args: &[Arg]) -> String {
+    let mut line = name.to_string();
+    for arg in args {
+        match arg {
+            Arg::Text(text) => write!(line, " {:?}", text),
+            Arg::Number(number) => write!(line, " {}", number),
+        }
+        .unwrap();
+    }
+    line
+}
+
+/// Print an action that would be taken.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name of the action, e.g. `copy` or `delete`.
+/// * `args: &[Arg]` - Its arguments.
+pub fn print_action(name: &str, args: &[Arg]) {
+    println!("{}", format_action(name, args));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_quote_their_text() {
+        assert_eq!(
+            format_action(
+                "copy",
+                &[
+                    Arg::Text("host:a \"b\".txt"),
+                    Arg::Text("img:/A.TXT"),
+                    Arg::Number(12)
+                ]
+            ),
+            r#"copy "host:a \"b\".txt" "img:/A.TXT" 12"#
+        );
+    }
+}
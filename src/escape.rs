@@ -0,0 +1,170 @@
+//! This module escapes the characters long file names can't hold, with `-o escape`. Names like
+//! `12:30.log` are common on Linux, but `:`, `*`, `?`, `"`, `<`, `>`, `|`, `\`, control
+//! characters, and characters outside of the Basic Multilingual Plane like emoji, are refused by
+//! `fatfs`. With `-o escape`, they are stored as `%` followed by the hex digits of every byte of
+//! their UTF-8 encoding, e.g. `12%3A30.log`, the way URLs escape them, and turned back when the
+//! directory is listed. A `%` that would be taken for the start of such an escape is escaped
+//! itself, as `%25`, so `12%3A30.log` is stored as `12%253A30.log`. Any other `%`, e.g. in
+//! `100%.txt`, is stored as it is, so names written by other systems are listed unchanged.
+
+/// Check whether a character can't be stored in a long file name.
+///
+/// # Parameters
+///
+/// * `c: char` - The character.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the character has to be escaped.
+fn needs_escape(c: char) -> bool {
+    c.is_ascii_control()
+        || matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+        || c > '\u{FFFF}'
+}
+
+/// Escape the characters of a name that can't be stored in a long file name.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name as passed by the kernel.
+///
+/// # Returns
+///
+/// * `String` - The name as stored.
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for (index, c) in name.char_indices() {
+        if needs_escape(c) || (c == '%' && unescape_char(&name[index..]).is_some()) {
+            for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Turn the escaped characters of a stored name back. A `%` that isn't followed by the escaped
+/// bytes of a character `escape` escapes is kept as it is, so names like `100%.txt` are listed as
+/// they are stored. Escaping the name again gives the stored name.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name as stored.
+///
+/// # Returns
+///
+/// * `String` - The name as listed.
+pub fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('%') {
+        unescaped.push_str(&rest[..index]);
+        rest = &rest[index..];
+        match unescape_char(rest) {
+            Some((c, len)) => {
+                unescaped.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                unescaped.push('%');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Helper function to decode the escaped character at the start of a name.
+///
+/// # Parameters
+///
+/// * `escaped: &str` - The part of the name starting with a `%`.
+///
+/// # Returns
+///
+/// * `Option<(char, usize)>` - The character and the length of its escaped form, or `None` if the
+///   name doesn't start with the escaped form of a character that has to be escaped, or of a `%`
+///   that had to be.
+fn unescape_char(escaped: &str) -> Option<(char, usize)> {
+    let byte_at = |index: usize| {
+        let group = escaped.get(index * 3..index * 3 + 3)?;
+        let hex = group.strip_prefix('%')?;
+        // `from_str_radix` would accept a sign as well.
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        u8::from_str_radix(hex, 16).ok()
+    };
+    let first = byte_at(0)?;
+    let len = match first.leading_ones() {
+        0 => 1,
+        len @ 2..=4 => len as usize,
+        _ => return None,
+    };
+    let bytes = (0..len).map(byte_at).collect::<Option<Vec<u8>>>()?;
+    let c = std::str::from_utf8(&bytes).ok()?.chars().next()?;
+    // A `%` is only escaped where it would start an escape itself.
+    if c == '%' && unescape_char(&format!("%{}", &escaped[3..])).is_none() {
+        return None;
+    }
+    (needs_escape(c) || c == '%').then_some((c, len * 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that a name is stored as expected and listed as it was written.
+    fn assert_round_trip(name: &str, stored: &str) {
+        assert_eq!(escape(name), stored);
+        assert_eq!(unescape(stored), name);
+    }
+
+    #[test]
+    fn characters_fat_cannot_hold_are_escaped() {
+        assert_round_trip("12:30.log", "12%3A30.log");
+        assert_round_trip("a*b?c", "a%2Ab%3Fc");
+        assert_round_trip("<\"|\\>", "%3C%22%7C%5C%3E");
+        assert_round_trip("plain.txt", "plain.txt");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_round_trip("tab\there", "tab%09here");
+        assert_round_trip("new\nline\u{7F}", "new%0Aline%7F");
+    }
+
+    #[test]
+    fn characters_beyond_the_basic_multilingual_plane_are_escaped() {
+        assert_round_trip("\u{1F600}.png", "%F0%9F%98%80.png");
+        assert_round_trip("été", "été");
+    }
+
+    #[test]
+    fn percent_signs_are_kept_unless_they_look_like_escapes() {
+        assert_round_trip("100%.txt", "100%.txt");
+        assert_round_trip("50%off", "50%off");
+        assert_round_trip("%41", "%41");
+        assert_round_trip("%", "%");
+    }
+
+    #[test]
+    fn names_looking_like_escapes_are_escaped() {
+        assert_round_trip("12%3A30.log", "12%253A30.log");
+        assert_round_trip("%253A", "%25253A");
+        assert_round_trip("%F0%9F%98%80", "%25F0%9F%98%80");
+    }
+
+    #[test]
+    fn escaped_and_written_names_do_not_collide() {
+        assert_ne!(escape("12:30.log"), escape("12%3A30.log"));
+        // Stored names `escape` doesn't make are listed as they are, and found again.
+        for stored in ["a%25b", "a%2525b", "%C3%A9", "%3", "%3G", "%E2%80"] {
+            assert_eq!(escape(&unescape(stored)), stored);
+        }
+        assert_ne!(unescape("a%25b"), unescape("a%b"));
+    }
+}
@@ -0,0 +1,163 @@
+//! This module matches the entries copied by `cp`, `pack` and `unpack` against the exclude
+//! patterns given with `--exclude`, or one per line in a file given with `--exclude-from`. They
+//! work like the lines of a `.gitignore` file, with the names in them matched like the glob
+//! patterns of the `glob` module, so they ignore case:
+//!
+//! * A pattern without a slash, or only one at its end, matches names at any depth, e.g. `*.o`.
+//! * Any other pattern matches paths below the entry copied, whether or not it starts with `/`,
+//!   e.g. `/build` only matches `build` next to the entries copied, and `doc/*.html` only the
+//!   HTML files in that `doc`.
+//! * A pattern ending in `/` only matches directories, e.g. `tmp/`.
+//! * A pattern starting with `!` takes back what patterns before it excluded, e.g. `!keep.o`,
+//!   unless a directory leading to the entry is excluded, as everything below it is.
+//! * In a file, empty lines and lines starting with `#` are skipped. A backslash in front of a
+//!   `#` or `!` takes it literally.
+use crate::glob::Pattern;
+use std::fs;
+use std::path::Path;
+
+/// Represent an exclude pattern.
+///
+/// # Members
+///
+/// * `pattern: Pattern` - The glob pattern the path below the entry copied is matched against.
+/// * `dir_only: bool` - Whether only directories are matched, as the pattern ends in `/`.
+/// * `negated: bool` - Whether matched entries are included again, as the pattern starts with
+///   `!`.
+#[derive(Clone, Debug)]
+pub struct Exclude {
+    pattern: Pattern,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Exclude {
+    /// Parse an exclude pattern.
+    ///
+    /// # Parameters
+    ///
+    /// * `text: &str` - The pattern, e.g. `*.o`, `/build` or `.git/`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Exclude, String>` - The pattern, or why it is malformed.
+    pub fn new(text: &str) -> Result<Exclude, String> {
+        let (negated, rest) = match text.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        if rest.trim_start_matches('/').is_empty() {
+            return Err(format!("Invalid exclude pattern '{}': it is empty", text));
+        }
+        let glob = if rest.contains('/') {
+            rest.to_string()
+        } else {
+            format!("**/{}", rest)
+        };
+        // The glob pattern is not what was given, so only the reason is kept from its error.
+        let pattern = Pattern::new(&glob).map_err(|e| {
+            let reason = e.split_once("': ").map_or(e.as_str(), |(_, reason)| reason);
+            format!("Invalid exclude pattern '{}': {}", text, reason)
+        })?;
+        Ok(Exclude {
+            pattern,
+            dir_only,
+            negated,
+        })
+    }
+}
+
+/// Read the exclude patterns in a file, one per line.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path of the file.
+///
+/// # Returns
+///
+/// * `Result<Vec<Exclude>, String>` - The patterns, or why the file can't be read or which line
+///   is malformed.
+pub fn read_excludes(path: &Path) -> Result<Vec<Exclude>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+    let mut excludes = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let exclude =
+            Exclude::new(line).map_err(|e| format!("{:?}, line {}: {}", path, index + 1, e))?;
+        excludes.push(exclude);
+    }
+    Ok(excludes)
+}
+
+/// Check whether an entry is left out by exclude patterns. The directories leading to it are
+/// checked by the caller, which leaves out everything below one that is.
+///
+/// # Parameters
+///
+/// * `excludes: &[Exclude]` - The patterns, in the order they were given.
+/// * `relative: &Path` - The path of the entry below the entry copied.
+/// * `is_dir: bool` - Whether the entry is a directory.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the entry is left out. The entry copied itself never is.
+pub fn is_excluded(excludes: &[Exclude], relative: &Path, is_dir: bool) -> bool {
+    if relative == Path::new("") {
+        return false;
+    }
+    // The last pattern matching decides, like in a `.gitignore` file.
+    let mut excluded = false;
+    for exclude in excludes {
+        if (is_dir || !exclude.dir_only) && exclude.pattern.matches(relative) {
+            excluded = !exclude.negated;
+        }
+    }
+    excluded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn patterns_work_like_gitignore_lines() {
+        let excludes: Vec<Exclude> = ["*.o", "!keep.o", "/build", "tmp/", "doc/*.html"]
+            .iter()
+            .map(|text| Exclude::new(text).unwrap())
+            .collect();
+        let excluded = |path: &str, is_dir: bool| is_excluded(&excludes, Path::new(path), is_dir);
+        assert!(excluded("main.o", false));
+        assert!(excluded("src/deep/MAIN.O", false));
+        assert!(!excluded("src/keep.o", false));
+        assert!(excluded("build", true));
+        assert!(!excluded("src/build", true));
+        assert!(excluded("src/tmp", true));
+        assert!(!excluded("src/tmp", false));
+        assert!(excluded("doc/index.html", false));
+        assert!(!excluded("src/doc/index.html", false));
+        assert!(!excluded("", true));
+        assert!(Exclude::new("/").is_err());
+        assert!(Exclude::new("[a").is_err());
+    }
+
+    #[test]
+    fn files_skip_comments_and_empty_lines() {
+        let path = env::temp_dir().join(format!("nufat-excludes-{}", std::process::id()));
+        fs::write(&path, "# build output\n*.o\n\n\\#notes\n.git/\n").unwrap();
+        let excludes = read_excludes(&path).unwrap();
+        assert_eq!(excludes.len(), 3);
+        assert!(is_excluded(&excludes, Path::new("#notes"), false));
+        assert!(is_excluded(&excludes, Path::new("sub/.git"), true));
+        fs::write(&path, "ok\n[broken\n").unwrap();
+        assert!(read_excludes(&path).unwrap_err().contains("line 2"));
+        fs::remove_file(&path).unwrap();
+    }
+}
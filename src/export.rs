@@ -0,0 +1,241 @@
+//! This module implements the `export` subcommand, which writes everything on a disk image that
+//! is not mounted to a tar archive, the reverse of `import`. Besides what tar keeps, every entry
+//! gets its attributes and its creation time in the records `NUFAT.attributes` and
+//! `NUFAT.crtime` of an extended header, written by the `tar` module, and the date it was last
+//! accessed in `atime`. FAT doesn't know owners, so entries are owned by root, or by the user
+//! and group given with `--owner`.
+use crate::attr::format_letters;
+use crate::copy::{Owner, Summary};
+use crate::filesystem::{fat_to_system_time, lock_image, open_dir};
+use crate::storage::Storage;
+use crate::tar::{self, Entry, Kind};
+use crate::tree;
+use fatfs::{FileAttributes, FileSystem as FatfsFileSystem, Time};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT export <disk.img> <archive.tar|-> [--force] [--owner UID[:GID]]";
+
+/// Run the `export` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <archive.tar|->
+///   [--force] [--owner UID[:GID]]`, where `-` writes the archive to the standard output.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or the archive can't
+///   be written, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut force = false;
+    let mut owner = None;
+    let mut paths = Vec::new();
+    let mut valid = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--owner" => match args.next().map(|arg| Owner::parse(arg)) {
+                Some(Ok(arg)) => owner = Some(arg),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            "-" => paths.push(arg.as_str()),
+            _ if arg.starts_with("--") => valid = false,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let ([disk_image_path, archive_path], true) = (&paths[..], valid) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let volume = File::open(disk_image_path).and_then(|image| {
+        lock_image(&image, false)?;
+        Ok(image)
+    });
+    let image = match volume {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            return 1;
+        }
+    };
+    if *archive_path == "-" {
+        let result = export(&image, owner, &mut BufWriter::new(io::stdout().lock()));
+        return match result {
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+                1
+            }
+        };
+    }
+    let archive = OpenOptions::new()
+        .write(true)
+        .create(force)
+        .truncate(force)
+        .create_new(!force)
+        .open(archive_path);
+    let result = archive.and_then(|archive| {
+        let mut out = BufWriter::new(archive);
+        let summary = export(&image, owner, &mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(summary)
+    });
+    match result {
+        Ok(summary) => {
+            println!("{}", summary.describe(false));
+            0
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(archive_path), e);
+            if e.kind() != ErrorKind::AlreadyExists {
+                let _ = fs::remove_file(archive_path);
+            }
+            1
+        }
+    }
+}
+
+/// Write everything on a volume to an archive.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `owner: Option<Owner>` - Who the entries are owned by in the archive, root if `None`.
+/// * `out: &mut impl Write` - The archive.
+///
+/// # Returns
+///
+/// * `io::Result<Summary>` - What was written, or an error if the volume can't be read or the
+///   archive can't be written.
+fn export(image: &File, owner: Option<Owner>, out: &mut impl Write) -> io::Result<Summary> {
+    let fs = tree::open_volume(image)?;
+    let mut summary = Summary::default();
+    export_dir(&fs, Path::new("/"), owner, out, &mut summary)?;
+    tar::finish(out)?;
+    Ok(summary)
+}
+
+/// Helper function to write the entries in a directory to an archive, every directory before the
+/// entries in it.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `dir: &Path` - The absolute path of the directory.
+/// * `owner: Option<Owner>` - Who the entries are owned by in the archive, root if `None`.
+/// * `out: &mut impl Write` - The archive.
+/// * `summary: &mut Summary` - What was written so far.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the directory can't be read or the archive can't be written.
+fn export_dir(
+    fs: &FatfsFileSystem<Storage>,
+    dir: &Path,
+    owner: Option<Owner>,
+    out: &mut impl Write,
+    summary: &mut Summary,
+) -> io::Result<()> {
+    for dir_entry in open_dir(fs, dir)?.iter() {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        let attributes = dir_entry.attributes();
+        if attributes.contains(FileAttributes::VOLUME_ID) || matches!(name.as_str(), "." | "..") {
+            continue;
+        }
+        let path = dir.join(&name);
+        let modified = dir_entry.modified();
+        let created = dir_entry.created();
+        let midnight = Time {
+            hour: 0,
+            min: 0,
+            sec: 0,
+            millis: 0,
+        };
+        let accessed = fat_to_system_time(dir_entry.accessed(), midnight);
+        let mut mode = if dir_entry.is_dir() { 0o755 } else { 0o644 };
+        if attributes.contains(FileAttributes::READ_ONLY) {
+            mode &= !0o222;
+        }
+        let entry = Entry {
+            path: path.strip_prefix("/").unwrap().display().to_string(),
+            kind: if dir_entry.is_dir() {
+                Kind::Dir
+            } else {
+                Kind::File
+            },
+            size: if dir_entry.is_dir() {
+                0
+            } else {
+                dir_entry.len()
+            },
+            mode,
+            uid: owner.map_or(0, |owner| owner.uid),
+            gid: owner.and_then(|owner| owner.gid).unwrap_or(0),
+            mtime: seconds(fat_to_system_time(modified.date, modified.time)).0,
+            records: vec![
+                ("atime".to_string(), seconds(accessed).0.to_string()),
+                ("NUFAT.attributes".to_string(), format_letters(attributes)),
+                (
+                    "NUFAT.crtime".to_string(),
+                    format_time(fat_to_system_time(created.date, created.time)),
+                ),
+            ],
+        };
+        tar::write_entry(out, &entry)?;
+        if dir_entry.is_dir() {
+            export_dir(fs, &path, owner, out, summary)?;
+        } else {
+            let copied = io::copy(&mut dir_entry.to_file(), out)?;
+            if copied != entry.size {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{:?}: the file is shorter than its size", path),
+                ));
+            }
+            tar::write_padding(out, copied)?;
+            summary.files += 1;
+            summary.bytes += copied;
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to split a time into the seconds and milliseconds since the Unix epoch.
+///
+/// # Parameters
+///
+/// * `time: SystemTime` - The time.
+///
+/// # Returns
+///
+/// * `(u64, u32)` - The seconds and the milliseconds, 0 before the epoch.
+fn seconds(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_millis())
+}
+
+/// Helper function to format a time the way extended headers hold it, in seconds since the Unix
+/// epoch with the 10 ms FAT keeps creation times in.
+///
+/// # Parameters
+///
+/// * `time: SystemTime` - The time.
+///
+/// # Returns
+///
+/// * `String` - The time, e.g. `1700000000.25`.
+fn format_time(time: SystemTime) -> String {
+    let (secs, millis) = seconds(time);
+    format!("{}.{:02}", secs, millis / 10)
+}
@@ -0,0 +1,174 @@
+//! This module implements the `extract` subcommand, which copies files, and with `--recursive`
+//! directories with everything in them, from a disk image that is not mounted to the host. Paths
+//! can be glob patterns, expanded by the `tree` module. Every entry is copied to its path below
+//! the directory its pattern starts in, e.g. `/EFI/BOOT/BOOTX64.EFI` found by `/EFI/**/*.efi` to
+//! `BOOT/BOOTX64.EFI` in the output directory, so files of the same name in different
+//! directories don't collide. The copying is done by the `copy` module. With `--json`, what was
+//! copied is printed as a JSON report.
+use crate::copy::{self, Conflicts, CopyOptions, Owner};
+use crate::filesystem::lock_image;
+use crate::tree;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT extract <disk.img> <PATTERN...> [--output DIR] [--recursive] [--dotify-hidden] [--force|--update] [--allow-empty] [--owner UID[:GID]] [--json]";
+
+/// Run the `extract` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <PATTERN...>
+///   [--output DIR] [--recursive] [--dotify-hidden] [--force|--update] [--allow-empty]
+///   [--owner UID[:GID]] [--json]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read, a pattern matches
+///   nothing or an entry can't be written, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut allow_empty = false;
+    let mut recursive = false;
+    let mut json = false;
+    let mut options = CopyOptions::default();
+    let mut output = Some(PathBuf::from("."));
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow-empty" => allow_empty = true,
+            "--recursive" => recursive = true,
+            "--dotify-hidden" => options.dotify_hidden = true,
+            "--force" => options.conflicts = Conflicts::Overwrite,
+            "--update" => options.conflicts = Conflicts::Update,
+            "--output" => output = args.next().map(PathBuf::from),
+            "--json" => json = true,
+            "--owner" => match args.next().map(|arg| Owner::parse(arg)) {
+                Some(Ok(owner)) => options.owner = Some(owner),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => output = None,
+            },
+            _ if arg.starts_with("--") => output = None,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let (Some(output), [disk_image_path, patterns @ ..]) = (output, &paths[..]) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    if patterns.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    let patterns = match tree::parse_patterns(patterns) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let result = File::open(disk_image_path).and_then(|image| {
+        lock_image(&image, false)?;
+        let fs = tree::open_volume(&image)?;
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        for entry in tree::expand(&fs, &patterns, allow_empty)? {
+            if !seen.insert(entry.path.clone()) {
+                continue;
+            }
+            if entry.is_dir && recursive {
+                let below = tree::walk(&fs, &entry)?;
+                entries.push(entry);
+                entries.extend(
+                    below
+                        .into_iter()
+                        .filter(|entry| seen.insert(entry.path.clone())),
+                );
+            } else if !entry.is_dir {
+                entries.push(entry);
+            } else if patterns
+                .iter()
+                .any(|pattern| !pattern.has_wildcards() && pattern.matches(&entry.path))
+            {
+                // Directories matched by wildcards are passed by, the files in them may be
+                // matched as well.
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} is a directory, --recursive extracts it with everything in it",
+                        entry.path.display()
+                    ),
+                ));
+            }
+        }
+        copy::copy_out(&fs, &entries, &output, &options)
+    });
+    match result {
+        Ok(summary) => {
+            if json {
+                println!("{}", summary.format_json(&output, options.owner));
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn matching_files_are_copied_below_the_base_of_their_pattern() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "EFI", "entries": [
+                    {"name": "BOOT", "entries": [{"name": "bootx64.efi", "size": 3000}]},
+                    {"name": "shell.efi", "size": 10, "fill": "ab"},
+                    {"name": "notes.txt"}
+                ]}
+            ]}"#,
+        );
+        let output = env::temp_dir().join(format!("nufat-extract-{}", std::process::id()));
+        let args = |args: &[&str]| {
+            let mut all = vec![image.options().disk_image_path.display().to_string()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            all.extend(["--output".to_string(), output.display().to_string()]);
+            all
+        };
+        assert_eq!(run(&args(&["/efi/**/*.EFI"])), 0);
+        assert_eq!(
+            fs::read(output.join("BOOT/bootx64.efi")).unwrap().len(),
+            3000
+        );
+        assert_eq!(fs::read(output.join("shell.efi")).unwrap(), b"ababababab");
+        assert!(!output.join("notes.txt").exists());
+        // Existing files are not overwritten.
+        assert_eq!(run(&args(&["/EFI/shell.efi"])), 1);
+        assert_eq!(run(&args(&["/EFI"])), 1);
+        assert_eq!(run(&args(&["*.cfg"])), 1);
+        assert_eq!(run(&args(&["*.cfg", "--allow-empty"])), 0);
+        fs::remove_dir_all(&output).unwrap();
+
+        // With `--recursive`, a directory is mirrored with everything in it.
+        assert_eq!(run(&args(&["/efi", "--recursive"])), 0);
+        assert!(output.join("EFI/BOOT/bootx64.efi").exists());
+        assert!(output.join("EFI/notes.txt").exists());
+        assert_eq!(run(&args(&["/efi", "--recursive"])), 1);
+        assert_eq!(run(&args(&["/efi", "--recursive", "--force"])), 0);
+        assert_eq!(run(&args(&["/efi", "--recursive", "--update"])), 0);
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
@@ -1,29 +1,382 @@
 //! This module implements the FUSE-API to access the FAT filesystem provided by the `fatfs` crate.
-use fatfs::{Dir, FileSystem as FatfsFileSystem, FsOptions};
+use crate::alias;
+#[cfg(feature = "metrics")]
+use crate::cache::CacheUsage;
+use crate::cache::{Caches, Footprint};
+use crate::direntry::{self, LargeFile};
+use crate::escape;
+use crate::floppy;
+use crate::info;
+use crate::label;
+use crate::locks::{Lock, LockTable};
+use crate::log::{self, Fields, Level};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::normalization;
+use crate::options::{
+    AliasStyle, CaseSensitivity, ErrorBehavior, HiddenBehavior, Normalization, Options,
+    ShortnameStyle,
+};
+use crate::serial;
+use crate::storage::Storage;
+use fatfs::{
+    Date, DateTime, Dir, DirEntry, File as FatfsFile, FileAttributes,
+    FileSystem as FatfsFileSystem, FsOptions, Time,
+};
 use fuser::{
-    FileAttr, FileType, Filesystem as FuseFilesystem, ReplyAttr, ReplyData, ReplyDirectory,
-    ReplyEntry, ReplyWrite, Request,
+    consts, FileAttr, FileType, Filesystem as FuseFilesystem, KernelConfig, Notifier, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl,
+    ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use libc::{EIO, ENOENT};
+use libc::{
+    c_int, EACCES, EAGAIN, EBADF, EBUSY, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA,
+    ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTTY, ENXIO, EOPNOTSUPP, EPERM, ERANGE, EROFS, ESTALE,
+    F_UNLCK, O_ACCMODE, O_APPEND, O_EXCL, O_RDONLY, O_TRUNC, R_OK, SEEK_DATA, SEEK_HOLE, SIGHUP,
+    S_IFMT, S_IFREG, W_OK, XATTR_CREATE, XATTR_REPLACE, X_OK,
+};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Deref;
+use std::os::fd::{AsRawFd, BorrowedFd};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The largest number of bytes read from or written to a file in one go. Larger requests are
+/// split up, so the buffers `fatfs` works on stay small.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// The `ioctl` reading the FAT attributes of a file, as supported by the `vfat` driver.
+const FAT_IOCTL_GET_ATTRIBUTES: u32 = 0x8004_7210;
+
+/// The `ioctl` changing the FAT attributes of a file, as supported by the `vfat` driver.
+const FAT_IOCTL_SET_ATTRIBUTES: u32 = 0x4004_7211;
+
+/// The extended attribute listing the FAT attributes of a file, e.g. `hidden,archive`.
+const ATTRIBUTES_XATTR: &str = "user.fat.attributes";
+
+/// The read-only extended attribute holding the 8.3 short name of a file, e.g. `LONGFI~1.TXT`.
+const SHORTNAME_XATTR: &str = "user.fat.shortname";
+
+/// The extended attribute of the root directory holding the volume label.
+const LABEL_XATTR: &str = "user.fat.label";
+
+/// The extended attribute of the root directory holding the volume serial number, e.g. `1234-ABCD`.
+const SERIAL_XATTR: &str = "user.fat.serial";
+
+/// The extended attribute of the root directory holding the FAT type, e.g. `FAT32`.
+const TYPE_XATTR: &str = "user.fat.type";
+
+/// The extended attribute of the root directory holding the OEM name from the boot sector.
+const OEM_NAME_XATTR: &str = "user.fat.oem_name";
+
+/// The read-only extended attributes of the root directory that describe the volume.
+const VOLUME_XATTRS: [&str; 3] = [SERIAL_XATTR, TYPE_XATTR, OEM_NAME_XATTR];
+
+/// The FAT attributes that can be changed, with the names used in `user.fat.attributes`.
+const ATTRIBUTE_NAMES: [(FileAttributes, &str); 4] = [
+    (FileAttributes::READ_ONLY, "readonly"),
+    (FileAttributes::HIDDEN, "hidden"),
+    (FileAttributes::SYSTEM, "system"),
+    (FileAttributes::ARCHIVE, "archive"),
+];
+
+/// Incremented by the `SIGHUP` handler to request all disk images to be reopened.
+static REOPEN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Install a handler for `SIGHUP`, which makes nuFAT reopen every disk image before serving its next
+/// request. Useful when removable media has been pulled and reinserted.
+pub fn install_reopen_handler() {
+    extern "C" fn handle_sighup(_signal: c_int) {
+        REOPEN_GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(SIGHUP, handle_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+/// Represent the mounted FAT volume.
+///
+/// Only empty while the disk image is being reopened, or if reopening it failed. Requests are not
+/// served in that state, so dereferencing it is always safe otherwise.
+struct Volume(Option<FatfsFileSystem<Storage>>);
+
+impl Deref for Volume {
+    type Target = FatfsFileSystem<Storage>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("Disk image is not open.")
+    }
+}
+
+/// Represent the activity on a mounted filesystem, shared with the thread watching for it to become
+/// idle and the one serving its metrics.
+///
+/// # Members
+///
+/// * `last_request: Mutex<Instant>` - When the last request was served.
+/// * `open_handles: AtomicU64` - The number of open file handles.
+/// * `corrupt_writes: AtomicU64` - The number of writes that failed verification.
+/// * `unsynced_since: Mutex<Option<Instant>>` - When the oldest modification the disk image
+///   wasn't synced for yet was made, or `None` if it is synced.
+/// * `image: Mutex<Option<File>>` - A handle on the disk image, to sync it by the main thread.
+/// * `locks: Mutex<LockTable>` - The `fcntl` locks held on the files, kept here so the main thread
+///   can time out the requests waiting for one.
+/// * `metrics: Metrics` - The counts of the requests served and the errors replied with.
+/// * `cache_usage: Arc<CacheUsage>` - The bytes taken up by every cache.
+pub struct Activity {
+    last_request: Mutex<Instant>,
+    open_handles: AtomicU64,
+    corrupt_writes: AtomicU64,
+    unsynced_since: Mutex<Option<Instant>>,
+    image: Mutex<Option<File>>,
+    locks: Mutex<LockTable>,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+    #[cfg(feature = "metrics")]
+    cache_usage: Arc<CacheUsage>,
+}
+
+/// Represent a change nuFAT made that the kernel may have cached the old state of, as it didn't
+/// ask for the change itself or can't see all of it.
+///
+/// # Variants
+///
+/// * `Entry(u64, OsString)` - A name in a directory, by the inode number of the directory.
+/// * `Inode(u64, i64, i64)` - The attributes and the data of a file, by its inode number, with the
+///   offset and length of the changed data. A length of 0 stands for the rest of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Invalidation {
+    Entry(u64, OsString),
+    Inode(u64, i64, i64),
+}
+
+impl Invalidation {
+    /// Tell the kernel to drop what it cached about the change.
+    ///
+    /// The kernel has to lock the cached entry first, which it may be holding while it waits for
+    /// nuFAT to reply to the request making the change. So this must not be called while serving
+    /// a request, but by a thread of its own.
+    ///
+    /// # Parameters
+    ///
+    /// * `notifier: &Notifier` - The notifier of the FUSE session.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if the kernel refused the notification, e.g. `ENOENT` if it
+    ///   had nothing cached.
+    pub fn send(&self, notifier: &Notifier) -> io::Result<()> {
+        match self {
+            Invalidation::Entry(parent, name) => notifier.inval_entry(*parent, name),
+            Invalidation::Inode(ino, offset, len) => notifier.inval_inode(*ino, *offset, *len),
+        }
+    }
+}
+
+impl Activity {
+    /// Check whether the filesystem has been idle for the given time.
+    ///
+    /// # Parameters
+    ///
+    /// * `timeout: Duration` - How long no request must have been served.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if no request was served within `timeout` and no file is open.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.open_handles.load(Ordering::SeqCst) == 0
+            && self.last_request.lock().unwrap().elapsed() >= timeout
+    }
+
+    /// Sync the disk image if a modification was made more than the given time ago, and it wasn't
+    /// synced since. Called by the main thread, so this happens even while files are kept open
+    /// and no request is served.
+    ///
+    /// # Parameters
+    ///
+    /// * `deadline: Duration` - How long after a modification the disk image is synced at the
+    ///   latest, as set by `-o flush=SECS`.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<bool>` - `true` if the disk image was synced, or the error syncing failed
+    ///   with, in which case it is tried again the next time.
+    pub fn flush_overdue(&self, deadline: Duration) -> io::Result<bool> {
+        let mut unsynced_since = self.unsynced_since.lock().unwrap();
+        if !unsynced_since.is_some_and(|since| since.elapsed() >= deadline) {
+            return Ok(false);
+        }
+        if let Some(image) = self.image.lock().unwrap().as_ref() {
+            image.sync_data()?;
+        }
+        *unsynced_since = None;
+        Ok(true)
+    }
+
+    /// Fail the requests that waited for a `fcntl` lock for too long with `EINTR`. Called by the
+    /// main thread, as the kernel can't interrupt a waiting request.
+    pub fn expire_lock_waits(&self) {
+        self.locks.lock().unwrap().expire();
+    }
+
+    /// Helper function to note that the disk image was modified, unless a modification before
+    /// already waits for it to be synced.
+    fn note_unsynced(&self) {
+        self.unsynced_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Helper function to note that the disk image was synced.
+    fn note_synced(&self) {
+        *self.unsynced_since.lock().unwrap() = None;
+    }
+
+    /// Get the number of open file handles.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The number of open file handles.
+    #[cfg(feature = "metrics")]
+    pub fn open_handles(&self) -> u64 {
+        self.open_handles.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of writes that failed verification with `-o verify_writes`.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The number of corrupt writes.
+    #[cfg(feature = "metrics")]
+    pub fn corrupt_writes(&self) -> u64 {
+        self.corrupt_writes.load(Ordering::SeqCst)
+    }
+
+    /// Get the counts of the requests served and the errors replied with.
+    ///
+    /// # Returns
+    ///
+    /// * `&Metrics` - The counts.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Get the bytes taken up by every cache.
+    ///
+    /// # Returns
+    ///
+    /// * `&CacheUsage` - The bytes.
+    #[cfg(feature = "metrics")]
+    pub fn cache_usage(&self) -> &CacheUsage {
+        &self.cache_usage
+    }
+}
+
+/// Represent an open file handle.
+///
+/// `fatfs` files borrow the volume, so they can't be kept open between requests, and `fatfs` has
+/// no way to open a file by its directory entry. The handle tracks the path of the file instead,
+/// which follows the file when it is renamed, and every read and write looks the file up by its
+/// path again, once for every chunk. Once the file is deleted, or replaced by a rename, the handle
+/// goes stale, so it can't reach another file that gets the same name later.
+///
+/// # Members
+///
+/// * `ino: u64` - The inode number of the opened file.
+/// * `path: Option<PathBuf>` - The current path of the opened file, or `None` once it is deleted.
+/// * `flags: i32` - The flags the file was opened with.
+/// * `scratch: Vec<u8>` - The buffer reads through the handle are filled in, kept so the next read
+///   doesn't have to allocate one again.
+struct OpenFile {
+    ino: u64,
+    path: Option<PathBuf>,
+    flags: i32,
+    scratch: Vec<u8>,
+}
+
+/// Represent an entry of a directory listing.
+///
+/// # Members
+///
+/// * `attr: FileAttr` - The attributes of the entry, including its inode number.
+/// * `name: String` - The name of the entry.
+/// * `path: PathBuf` - The path of the entry.
+struct ListedEntry {
+    attr: FileAttr,
+    name: String,
+    path: PathBuf,
+}
+
+impl Footprint for ListedEntry {
+    fn heap_size(&self) -> usize {
+        self.name.capacity() + self.path.capacity()
+    }
+}
 
 /// Represent FAT-Filesystem
 ///
 /// # Members
 ///
-/// * `fs: Arc<Mutex<fatfs::Filesystem<File>>>`
+/// * `fs: Mutex<Volume>` - The `fatfs::FileSystem` of the disk image.
+/// * `image: File` - A second handle on the disk image, used to sync it to the device.
+/// * `volume_id: u32` - The serial number of the volume, used to recognize it when reopening.
+/// * `image_failed: bool` - Set when reopening the disk image failed.
+/// * `dirty: bool` - Whether the volume was not cleanly unmounted before it was mounted.
+/// * `options: Options` - The options the filesystem was mounted with.
+/// * `read_only: AtomicBool` - Set when mounted read-only, or once writing failed with
+///   `-o errors=remount-ro`.
 /// * `inode_map: Mutex<HashMap<u64, PathBuf>>` - The map of all child nodes.
 /// * `nnode: Mutex<u64>` - The ID of the next inode.
+/// * `lookups: Mutex<HashMap<u64, u64>>` - How often the kernel looked up every inode it has not
+///   forgotten yet.
+/// * `handles: Mutex<HashMap<u64, OpenFile>>` - The open file handles.
+/// * `next_fh: AtomicU64` - The next file or directory handle to hand out.
+/// * `reopen_generation: u64` - The number of `SIGHUP`s handled so far.
+/// * `activity: Arc<Activity>` - The activity on the filesystem.
+/// * `request: &'static str` - The FUSE operation being served, for the metrics.
+/// * `uid: u32` - The owner of all files and directories, the user who mounted the filesystem.
+/// * `gid: u32` - The group of all files and directories, the group of the user who mounted it.
+/// * `writeback_cache: bool` - Whether the kernel agreed to cache writes before passing them on.
+/// * `caches: Mutex<Caches<ListedEntry>>` - The listings of the open directory handles, and the
+///   cluster chains of the FAT+ files larger than 4 GiB read so far, under the budget of
+///   `--cache-limit`.
+/// * `invalidations: Option<Sender<Invalidation>>` - Where to pass on the changes the kernel
+///   caches have to drop, once `invalidations` was called.
 pub struct FatFilesystem {
-    fs: Arc<Mutex<FatfsFileSystem<File>>>,
+    fs: Mutex<Volume>,
+    image: File,
+    volume_id: u32,
+    image_failed: bool,
+    dirty: bool,
+    options: Options,
+    read_only: AtomicBool,
     inode_map: Mutex<HashMap<u64, PathBuf>>,
     nnode: Mutex<u64>,
+    lookups: Mutex<HashMap<u64, u64>>,
+    handles: Mutex<HashMap<u64, OpenFile>>,
+    next_fh: AtomicU64,
+    reopen_generation: u64,
+    activity: Arc<Activity>,
+    #[cfg(feature = "metrics")]
+    request: &'static str,
+    uid: u32,
+    gid: u32,
+    writeback_cache: bool,
+    caches: Mutex<Caches<ListedEntry>>,
+    invalidations: Option<Sender<Invalidation>>,
 }
 
 impl FatFilesystem {
@@ -31,27 +384,134 @@ impl FatFilesystem {
     ///
     /// # Parameters
     ///
-    /// * `disk_image_path: &Path` - The path of the disk image to use.
+    /// * `options: Options` - The options to mount with, including the path of the disk image.
     ///
     /// # Returns
     ///
-    /// * `Self` - A new instance of a `FatFilesystem`.
-    pub fn new(disk_image_path: &Path) -> Self {
-        let img_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(disk_image_path)
-            .expect("Failed to open disk image.");
-        let fs = FatfsFileSystem::new(img_file, FsOptions::new())
-            .expect("Failed to create new FileSystem.");
+    /// * `io::Result<Self>` - A new instance of a `FatFilesystem`, or the error opening the disk
+    ///   image failed with.
+    pub fn new(options: Options) -> io::Result<Self> {
+        let image = open_image(&options)?;
+        lock_image(&image, !options.read_only)?;
+        let fs = open_volume(&image, &options)?;
+        let volume_id = fs.volume_id();
+        check_root(&fs, &options.root)?;
 
+        // Writing to a volume that was not cleanly unmounted risks compounding the damage.
+        let dirty = fs.read_status_flags().map(|f| f.dirty()).unwrap_or(false);
+        let read_only = options.read_only || (dirty && !options.force_rw);
+        if dirty && !options.read_only && !options.force_rw {
+            log::log(
+                Level::Warning,
+                module_path!(),
+                "The volume was not cleanly unmounted and may be damaged!",
+            );
+            log::log(Level::Warning, module_path!(), "Mounting read-only. Check the volume with fsck first, or pass --force-rw to write to it anyway.");
+        } else if dirty {
+            log::log(Level::Warning, module_path!(), "The volume was not cleanly unmounted and may be damaged! Check it with fsck before writing to it.");
+        }
+        // Copies of the FAT that differ tell of an interrupted write, or of a damaged device.
+        let fats_differ = direntry::compare_fats(&image)
+            .map(|mismatches| mismatches.iter().any(|(_, ranges)| !ranges.is_empty()))
+            .unwrap_or(false);
+        if fats_differ {
+            log::log(Level::Warning, module_path!(), "The copies of the FAT differ, the volume may be damaged! Check it with nuFAT fsck.");
+        }
+        // Writing with a wrongly guessed layout would overwrite the wrong sectors.
+        let guessed = !floppy::has_bpb(&image)?;
+        let read_only = read_only || (guessed && !options.force_rw);
+        if guessed && !options.read_only && !options.force_rw {
+            log::log(Level::Warning, module_path!(), "The layout of the volume was guessed from the size of the image. Mounting read-only, pass --force-rw to write to it anyway.");
+        }
         let mut inode_map = HashMap::new();
-        inode_map.insert(1, PathBuf::from("/"));
+        inode_map.insert(1, options.root.clone());
+        let caches = Caches::new(options.cache_limit);
+        let synced_image = image.try_clone().ok();
 
-        FatFilesystem {
-            fs: Arc::new(Mutex::new(fs)),
+        Ok(FatFilesystem {
+            fs: Mutex::new(Volume(Some(fs))),
+            image,
+            volume_id,
+            image_failed: false,
+            dirty,
+            options,
+            read_only: AtomicBool::new(read_only),
             inode_map: Mutex::new(inode_map),
             nnode: Mutex::new(2),
+            lookups: Mutex::new(HashMap::new()),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            reopen_generation: REOPEN_GENERATION.load(Ordering::SeqCst),
+            activity: Arc::new(Activity {
+                last_request: Mutex::new(Instant::now()),
+                open_handles: AtomicU64::new(0),
+                corrupt_writes: AtomicU64::new(0),
+                unsynced_since: Mutex::new(None),
+                image: Mutex::new(synced_image),
+                locks: Mutex::new(LockTable::default()),
+                #[cfg(feature = "metrics")]
+                metrics: Metrics::default(),
+                #[cfg(feature = "metrics")]
+                cache_usage: caches.usage(),
+            }),
+            #[cfg(feature = "metrics")]
+            request: "",
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            writeback_cache: false,
+            caches: Mutex::new(caches),
+            invalidations: None,
+        })
+    }
+
+    /// Check whether the filesystem refuses all modifications.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if mounted read-only, or if the mount was downgraded to read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Check whether the volume was not cleanly unmounted before it was mounted.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the dirty flag was set.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Get the activity on the filesystem, to find out when it becomes idle.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Activity>` - The activity on the filesystem.
+    pub fn activity(&self) -> Arc<Activity> {
+        Arc::clone(&self.activity)
+    }
+
+    /// Start passing on the changes the kernel caches have to drop, to be sent to the kernel by
+    /// another thread with `Invalidation::send`.
+    ///
+    /// # Returns
+    ///
+    /// * `Receiver<Invalidation>` - The changes, until the filesystem is dropped.
+    pub fn invalidations(&mut self) -> Receiver<Invalidation> {
+        let (sender, receiver) = mpsc::channel();
+        self.invalidations = Some(sender);
+        receiver
+    }
+
+    /// Helper function to pass on a change the kernel caches have to drop.
+    ///
+    /// # Parameters
+    ///
+    /// * `invalidation: Invalidation` - The change.
+    fn invalidate(&self, invalidation: Invalidation) {
+        if let Some(invalidations) = &self.invalidations {
+            // Nothing is cached anymore once the thread sending them is gone.
+            let _ = invalidations.send(invalidation);
         }
     }
 
@@ -83,413 +543,5185 @@ impl FatFilesystem {
             ino
         }
     }
-}
 
-impl FuseFilesystem for FatFilesystem {
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        // Attribute time to live
-        let ttl = Duration::from_secs(1);
+    /// Helper function to get the inode number to list an entry with, without creating an inode
+    /// for it. Listing a directory doesn't make the kernel look up its entries, so an inode is
+    /// only created once the kernel does, by `lookup` or `readdirplus`.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the entry.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The inode number of the entry if it has one, or else a hash of its path with the
+    ///   highest bit set, which no inode created by `get_or_create_inode` ever gets.
+    fn listed_inode(&self, path: &Path) -> u64 {
+        let inode_map = self.inode_map.lock().unwrap();
+        if let Some(&ino) = inode_map
+            .iter()
+            .find_map(|(ino, p)| if p == path { Some(ino) } else { None })
+        {
+            return ino;
+        }
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        hasher.finish() | 1 << 63
+    }
 
-        // Get path for given inode.
-        let path = {
-            let inode_map = self.inode_map.lock().unwrap();
-            inode_map.get(&parent).cloned()
-        };
+    /// Helper function to count a lookup of an inode by the kernel, which keeps the inode until
+    /// the kernel forgets it again.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number that was replied to the kernel.
+    fn remember_inode(&self, ino: u64) {
+        *self.lookups.lock().unwrap().entry(ino).or_insert(0) += 1;
+    }
 
-        let mut path = match path {
-            Some(path) => path,
-            None => {
-                reply.error(EIO);
-                return;
-            }
+    /// Helper function to get the path of an entry in a directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory.
+    /// * `name: &OsStr` - The name of the entry.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PathBuf)` - The path of the entry.
+    /// * `Err(c_int)` - `ENOENT` if the directory is unknown, `EINVAL` if the name is not valid
+    ///   UTF-8.
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        if name.to_str().is_none() {
+            return Err(EINVAL);
+        }
+        let path = match self.inode_map.lock().unwrap().get(&parent) {
+            Some(path) => path.join(self.escaped(name)),
+            None => return Err(ENOENT),
         };
-
-        path.push(name);
-
         let fs = self.fs.lock().unwrap();
+        let path = self.denormalize(&fs, path);
+        Ok(self.undot(&fs, path))
+    }
 
-        let ino = self.get_or_create_inode(&path);
+    /// Helper function to get the path to create an entry at, or to rename one to. With
+    /// `-o shortnames`, new entries get only a short name, so the name has to be a valid one,
+    /// which is stored uppercase, or with `-o shortnames=convert`, one is made up from the name.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory.
+    /// * `name: &OsStr` - The name of the entry.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PathBuf)` - The path of the entry.
+    /// * `Err(c_int)` - `ENOENT` if the directory is unknown, `EINVAL` if the name is not valid
+    ///   UTF-8, or with `-o shortnames` not a valid short name.
+    fn new_entry_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        let path = self.child_path(parent, name)?;
+        if !self.options.shortnames {
+            return Ok(path);
+        }
+        let name = file_name(&path);
+        let Some((prefix, ext)) = alias::tail_basis(name) else {
+            return Ok(path.with_file_name(name.to_ascii_uppercase()));
+        };
+        if !self.options.shortnames_convert {
+            return Err(EINVAL);
+        }
 
-        if let Ok(file) = fs.root_dir().open_file(path.to_str().unwrap()) {
-            let size = file.bytes().count() as u64;
-            let now = SystemTime::now();
-            let file_attr = FileAttr {
-                ino,
-                size,
-                blocks: ((size + 511) / 512),
-                atime: now,
-                mtime: now,
-                ctime: now,
-                crtime: now,
-                kind: FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 4096,
-            };
-            reply.entry(&ttl, &file_attr, 0);
-            return;
-        };
-        // HACK: Do a check whether it is a dir or not. May require stat to be implemented.
-        match fs.root_dir().open_dir(path.to_str().unwrap()) {
-            Ok(dir) => {
-                let size = 1_u64;
-                let now = SystemTime::now();
-                let file_attr = FileAttr {
-                    ino,
-                    size,
-                    blocks: ((size + 511) / 512),
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 1,
-                    uid: 501,
-                    gid: 20,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 4096,
-                };
-                reply.entry(&ttl, &file_attr, 0);
-            }
-            Err(_) => {
-                reply.error(libc::ENOENT);
-            }
+        let fs = self.fs.lock().unwrap();
+        let taken = open_dir(&fs, path.parent().unwrap())
+            .map_err(|_| ENOENT)?
+            .iter()
+            .filter_map(Result::ok)
+            .map(|entry| (entry.file_name(), entry.short_file_name()))
+            .collect::<Vec<_>>();
+        // Names that are all extension, e.g. `.profile`, make up the name from it.
+        let (prefix, ext) = if prefix.is_empty() {
+            (ext, Vec::new())
+        } else {
+            (prefix, ext)
         };
+        let numeric_tails = (1..1_000_000).map(|n| {
+            let tail = format!("~{}", n);
+            let base = String::from_utf8_lossy(&prefix[..prefix.len().min(8 - tail.len())]);
+            if ext.is_empty() {
+                format!("{}{}", base, tail)
+            } else {
+                format!("{}{}.{}", base, tail, String::from_utf8_lossy(&ext))
+            }
+        });
+        alias::candidates(name, self.options.alias)
+            .iter()
+            .map(|raw_name| {
+                String::from_utf8_lossy(&direntry::format_short_name(raw_name)).into_owned()
+            })
+            .chain(numeric_tails)
+            .find(|candidate| {
+                !taken.iter().any(|(long_name, short_name)| {
+                    eq_ignore_case(long_name, candidate) || eq_ignore_case(short_name, candidate)
+                })
+            })
+            .map(|candidate| path.with_file_name(candidate))
+            .ok_or(EEXIST)
     }
 
-    /// Get the attributes of a file or directory.
+    /// Helper function to delete the long name `fatfs` wrote for a new or renamed entry with
+    /// `-o shortnames`, so it has only its short name.
     ///
     /// # Parameters
     ///
-    /// * `_req: &Request` - The `fuser::Request` datastructure representing the request to the filesystem.
-    /// * `ino: u64` - The inode-number of the given filesystem object.
-    /// * `_fh: Option<u64>` - The File-Handle (optional).
-    /// * `reply: ReplyAttr` - A `fuser::ReplyAttr` instance for returning attributes.
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the entry.
     ///
     /// # Returns
     ///
-    /// This function does not return a value. It responds to the request with a reply or an error
-    /// code if the requested inode does not exist.
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        // Attribute time to live
-        let ttl = Duration::from_secs(1);
+    /// * `io::Result<()>` - An error if the entry couldn't be written.
+    fn drop_long_name(&self, fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<()> {
+        if !self.options.shortnames {
+            return Ok(());
+        }
+        direntry::drop_long_name(&self.image, &short_names(fs, path)?)
+    }
 
-        // Get path for given inode.
-        let path = {
-            let inode_map = self.inode_map.lock().unwrap();
-            inode_map.get(&ino).cloned()
-        };
+    /// Helper function to get the name an entry passed by the kernel is stored with, which is
+    /// escaped with `-o escape`.
+    ///
+    /// # Parameters
+    ///
+    /// * `name: &OsStr` - The name as passed by the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `OsString` - The name as stored.
+    fn escaped(&self, name: &OsStr) -> OsString {
+        match name.to_str() {
+            Some(name) if self.options.escape => escape::escape(name).into(),
+            _ => name.to_os_string(),
+        }
+    }
 
-        let path = match path {
-            Some(path) => path,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+    /// Helper function to find the entry a name listed by `-o normalize=` stands for, which may
+    /// be stored composed or decomposed.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: PathBuf` - The path as passed by the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - The path with the name of the entry as stored, or the path itself if there is
+    ///   an entry with that exact name, or none with the same normalized name.
+    fn denormalize(&self, fs: &FatfsFileSystem<Storage>, path: PathBuf) -> PathBuf {
+        if self.options.normalize == Normalization::Keep || find_entry(fs, &path).is_ok() {
+            return path;
+        }
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(OsStr::to_str))
+        else {
+            return path;
         };
-
-        if ino == 1 {
-            let now = SystemTime::now();
-            reply.attr(
-                &ttl,
-                &FileAttr {
-                    ino: 1,
-                    size: 0,
-                    blocks: 0,
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 2,
-                    uid: 501,
-                    gid: 20,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 4096,
-                },
-            );
-        } else {
-            let fs = self.fs.lock().unwrap();
-            if let Ok(file) = fs.root_dir().open_file(path.to_str().unwrap()) {
-                let size = file.bytes().count() as u64;
-                let now = SystemTime::now();
-                let file_attr = FileAttr {
-                    ino,
-                    size,
-                    blocks: ((size + 511) / 512),
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::RegularFile,
-                    perm: 0o644,
-                    nlink: 1,
-                    uid: 501,
-                    gid: 20,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 4096,
-                };
-                reply.attr(&ttl, &file_attr);
-                return;
-            };
-            // HACK: Do a check whether it is a dir or not. May require stat to be implemented.
-            match fs.root_dir().open_dir(path.to_str().unwrap()) {
-                Ok(dir) => {
-                    let size = 1_u64;
-                    let now = SystemTime::now();
-                    let file_attr = FileAttr {
-                        ino,
-                        size,
-                        blocks: ((size + 511) / 512),
-                        atime: now,
-                        mtime: now,
-                        ctime: now,
-                        crtime: now,
-                        kind: FileType::Directory,
-                        perm: 0o755,
-                        nlink: 1,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 4096,
-                    };
-                    reply.attr(&ttl, &file_attr);
-                }
-                Err(_) => {
-                    reply.error(libc::ENOENT);
-                }
-            };
+        let name = self.normalized(name);
+        let stored = open_dir(fs, parent).ok().and_then(|dir| {
+            dir.iter()
+                .filter_map(Result::ok)
+                .map(|entry| self.entry_name(&entry))
+                .find(|stored| eq_ignore_case(&self.normalized(stored), &name))
+        });
+        match stored {
+            Some(stored) => path.with_file_name(stored),
+            None => path,
         }
     }
 
-    /// Set attributes of given file or directory.
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _mode: Option<u32>,
+    /// Helper function to normalize a name as set by `-o normalize=`.
+    ///
+    /// # Parameters
+    ///
+    /// * `name: &str` - The name as stored.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The normalized name.
+    fn normalized(&self, name: &str) -> String {
+        match self.options.normalize {
+            Normalization::Keep => name.to_string(),
+            Normalization::Nfc => normalization::nfc(name),
+            Normalization::Nfd => normalization::nfd(name),
+        }
+    }
+
+    /// Helper function to check whether a name passed by the kernel only matches an entry when
+    /// ignoring case, which `-o case=strict` doesn't. FAT can't hold two names only differing in
+    /// case, so such a name can't be created either.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path as resolved by `child_path`.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if mounted with `-o case=strict` and the entry at the path has neither a
+    ///   long nor a short name in the exact case of the path.
+    fn differs_in_case(&self, fs: &FatfsFileSystem<Storage>, path: &Path) -> bool {
+        if self.options.case != CaseSensitivity::Strict {
+            return false;
+        }
+        let name = file_name(path);
+        find_entry(fs, path).is_ok_and(|entry| {
+            entry.file_name() != name
+                && entry.short_file_name() != name
+                && self.entry_name(&entry) != name
+        })
+    }
+
+    /// Helper function to find the entry a name listed by `-o hidden=dot` stands for.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: PathBuf` - The path as passed by the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - The path of the hidden entry listed as the `.` name of the path, or the path
+    ///   itself if there is an entry with that exact name, or no such hidden entry.
+    fn undot(&self, fs: &FatfsFileSystem<Storage>, path: PathBuf) -> PathBuf {
+        if self.options.hidden != HiddenBehavior::Dot {
+            return path;
+        }
+        let dotted = path.file_name().and_then(OsStr::to_str);
+        let hidden = match dotted.and_then(|name| name.strip_prefix('.')) {
+            Some(name) if !name.is_empty() && name != "." => {
+                self.denormalize(fs, path.with_file_name(name))
+            }
+            _ => return path,
+        };
+        if find_entry(fs, &path).is_err()
+            && find_entry(fs, &hidden)
+                .is_ok_and(|entry| entry.attributes().contains(FileAttributes::HIDDEN))
+        {
+            hidden
+        } else {
+            path
+        }
+    }
+
+    /// Helper function to drop the inodes of a deleted entry and everything below it, so a new
+    /// entry with the same name doesn't get them. The handles open on the deleted files go stale.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the deleted entry.
+    fn forget_inodes(&self, path: &Path) {
+        self.inode_map
+            .lock()
+            .unwrap()
+            .retain(|_, inode_path| !inode_path.starts_with(path));
+        for handle in self.handles.lock().unwrap().values_mut() {
+            if handle
+                .path
+                .as_ref()
+                .is_some_and(|handle_path| handle_path.starts_with(path))
+            {
+                handle.path = None;
+            }
+        }
+    }
+
+    /// Helper function to move the inodes of a renamed entry and everything below it to the new
+    /// path.
+    ///
+    /// # Parameters
+    ///
+    /// * `from: &Path` - The old path of the entry.
+    /// * `to: &Path` - The new path of the entry.
+    fn move_inodes(&self, from: &Path, to: &Path) {
+        let move_path = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = if rest.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(rest)
+                };
+            }
+        };
+        self.inode_map
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(move_path);
+        for handle in self.handles.lock().unwrap().values_mut() {
+            if let Some(path) = &mut handle.path {
+                move_path(path);
+            }
+        }
+    }
+
+    /// Helper function to open a new handle on a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file.
+    /// * `path: &Path` - The path of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The new file handle.
+    fn open_handle(&self, ino: u64, path: &Path, flags: i32) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().unwrap().insert(
+            fh,
+            OpenFile {
+                ino,
+                path: Some(path.to_path_buf()),
+                flags,
+                scratch: Vec::new(),
+            },
+        );
+        self.activity.open_handles.fetch_add(1, Ordering::SeqCst);
+        fh
+    }
+
+    /// Helper function to close a handle on a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file.
+    /// * `fh: u64` - The file handle.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if no other handle is open on the file.
+    fn close_handle(&self, ino: u64, fh: u64) -> bool {
+        let mut handles = self.handles.lock().unwrap();
+        if handles.remove(&fh).is_some() {
+            let _ =
+                self.activity
+                    .open_handles
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        }
+        !handles.values().any(|handle| handle.ino == ino)
+    }
+
+    /// Helper function to refuse deleting or replacing a file that is open, with
+    /// `-o deny_delete_open`.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the file or directory to delete.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - `EBUSY` if a handle is open on it, or on a file below it.
+    fn check_not_open(&self, path: &Path) -> Result<(), c_int> {
+        if !self.options.deny_delete_open {
+            return Ok(());
+        }
+        let handles = self.handles.lock().unwrap();
+        let open = handles.values().any(|handle| {
+            handle
+                .path
+                .as_ref()
+                .is_some_and(|handle_path| handle_path.starts_with(path))
+        });
+        if open {
+            return Err(EBUSY);
+        }
+        Ok(())
+    }
+
+    /// Helper function to write the directory entry of the file a handle is open on to the disk
+    /// image. `fatfs` writes data straight to the image, so nothing else is pending.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The file handle.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the file was flushed, is deleted already, or the filesystem is read-only.
+    /// * `Err(c_int)` - The error code to reply with.
+    fn flush_handle(&self, fh: u64) -> Result<(), c_int> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        let path = match self.handle_path(fh) {
+            Ok(path) => path,
+            // Deleted while open, there is nothing left to flush.
+            Err(ESTALE) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let fs = self.fs.lock().unwrap();
+        let file = fs.root_dir().open_file(path.to_str().unwrap());
+        match file {
+            Ok(mut file) => file.flush().map_err(|e| self.write_error("flush", e)),
+            Err(e) => Err(self.modify_error("flush", e)),
+        }
+    }
+
+    /// Helper function to list the entries of a directory, including `.` and `..`.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<ListedEntry>)` - The entries of the directory that are not hidden.
+    /// * `Err(c_int)` - `ENOENT` if the directory does not exist, `EIO` if reading it failed.
+    fn list_dir(&self, ino: u64) -> Result<Vec<ListedEntry>, c_int> {
+        let fs = self.fs.lock().unwrap();
+
+        let path = {
+            let inode_map = self.inode_map.lock().unwrap();
+            inode_map.get(&ino).cloned().ok_or(ENOENT)?
+        };
+
+        // Open dir and read entries.
+        let dir = match open_dir(&fs, &path) {
+            Ok(dir) => dir,
+            Err(_) => {
+                log::log_with(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Unable to open given dir! Path: {:?}", path),
+                    &Fields {
+                        op: Some("readdir"),
+                        path: Some(&path),
+                        inode: Some(ino),
+                        errno: Some(ENOENT),
+                    },
+                );
+                return Err(ENOENT);
+            }
+        };
+
+        // Iterate over all entries in the directory.
+        let large_files = self.large_files(&fs, &path).map_err(|_| EIO)?;
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let e = entry.map_err(|_| EIO)?;
+            if self.is_hidden_entry(&e) {
+                continue;
+            }
+            let name = self.entry_name(&e);
+
+            let entry_path = match name.as_str() {
+                "." => path.clone(),
+                // Never leave the directory mounted as the root of the filesystem.
+                ".." if ino == 1 => path.clone(),
+                ".." => path.parent().unwrap_or(&path).to_path_buf(),
+                name => path.join(name),
+            };
+            entries.push(ListedEntry {
+                attr: self.entry_attr(self.listed_inode(&entry_path), &e, &large_files),
+                name: self.listed_name(&e),
+                path: entry_path,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Helper function to take a fresh snapshot of the entries of an open directory, which may
+    /// evict the snapshots of other directories to stay within the budget of `--cache-limit`.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the directory.
+    /// * `fh: u64` - The handle the directory was opened with.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the directory can't be read.
+    fn refresh_listing(&self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let entries = self.list_dir(ino)?;
+        self.caches.lock().unwrap().set_listing(fh, entries);
+        Ok(())
+    }
+
+    /// Helper function to get the path of the file a handle is open on.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The file handle.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PathBuf)` - The current path of the file.
+    /// * `Err(c_int)` - `EBADF` if the handle is not open, `ESTALE` if the file was deleted.
+    fn handle_path(&self, fh: u64) -> Result<PathBuf, c_int> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(handle) => handle.path.clone().ok_or(ESTALE),
+            None => Err(EBADF),
+        }
+    }
+
+    /// Helper function to take the scratch buffer of a handle, to read into.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The file handle.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The buffer, or a new one if the handle is not open.
+    fn take_scratch(&self, fh: u64) -> Vec<u8> {
+        match self.handles.lock().unwrap().get_mut(&fh) {
+            Some(handle) => mem::take(&mut handle.scratch),
+            None => Vec::new(),
+        }
+    }
+
+    /// Helper function to give the scratch buffer taken by `take_scratch` back to its handle.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The file handle.
+    /// * `scratch: Vec<u8>` - The buffer. It is dropped if the handle was closed meanwhile.
+    fn keep_scratch(&self, fh: u64, scratch: Vec<u8>) {
+        if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+            handle.scratch = scratch;
+        }
+    }
+
+    /// Helper function to write data to the file a handle is open on.
+    ///
+    /// # Parameters
+    ///
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: u64` - The offset to write at, ignored if the file was opened with `O_APPEND`.
+    /// * `data: &[u8]` - The data to write.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the data can't be written.
+    fn write_handle(&self, fh: u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let (path, flags) = match self.handles.lock().unwrap().get(&fh) {
+            Some(OpenFile {
+                path: Some(path),
+                flags,
+                ..
+            }) => (path.clone(), *flags),
+            Some(_) => return Err(ESTALE),
+            None => return Err(EBADF),
+        };
+        // With the writeback cache the kernel appends by itself and passes on the right offsets.
+        let offset = if flags & O_APPEND != 0 && !self.writeback_cache {
+            let fs = self.fs.lock().unwrap();
+            let size = find_entry(&fs, &path).and_then(|entry| {
+                let large_file = self.large_file(&fs, &path)?;
+                Ok(large_file.map_or(entry.len(), |large_file| large_file.size))
+            });
+            match size {
+                Ok(size) => size,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Err(ENOENT),
+                Err(e) => return Err(self.write_error("write", e)),
+            }
+        } else {
+            offset
+        };
+        match self.write_chunked(&path, offset, data) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(ENOENT),
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => Err(EFBIG),
+            Err(e) => Err(self.write_error("write", e)),
+        }
+    }
+
+    /// Helper function to allocate space for the file a handle is open on, as `fallocate` does,
+    /// with the same checks as writing to it.
+    ///
+    /// # Parameters
+    ///
+    /// * `uid: u32` - The user allocating the space.
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: i64` - The start of the range to allocate.
+    /// * `length: i64` - The length of the range to allocate.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the space can't be allocated, e.g.
+    ///   `EINVAL` for a negative offset or a length that isn't positive, or `EFBIG` if the range
+    ///   ends beyond what a file can hold.
+    fn allocate_handle(&self, uid: u32, fh: u64, offset: i64, length: i64) -> Result<(), c_int> {
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+        if offset < 0 || length <= 0 {
+            return Err(EINVAL);
+        }
+        let end = offset.checked_add(length).ok_or(EFBIG)? as u64;
+
+        let (path, flags) = match self.handles.lock().unwrap().get(&fh) {
+            Some(OpenFile {
+                path: Some(path),
+                flags,
+                ..
+            }) => (path.clone(), *flags),
+            Some(_) => return Err(ESTALE),
+            None => return Err(EBADF),
+        };
+        if flags & O_ACCMODE == O_RDONLY {
+            return Err(EBADF);
+        }
+        self.check_write_access(uid, &path, flags)?;
+
+        let fs = self.fs.lock().unwrap();
+        let result = fs
+            .root_dir()
+            .open_file(path.to_str().unwrap())
+            .and_then(|mut file| {
+                if let Some(large_file) = self.large_file(&fs, &path)? {
+                    return match end <= large_file.size {
+                        true => Ok(()),
+                        false => Err(ErrorKind::FileTooLarge.into()),
+                    };
+                }
+                let size = file.seek(SeekFrom::End(0))?;
+                if end <= size {
+                    return Ok(());
+                }
+                if end > u32::MAX as u64 {
+                    return Err(ErrorKind::FileTooLarge.into());
+                }
+                if let Err(e) = resize(&mut file, end) {
+                    // Give the space that could be allocated back.
+                    let _ = resize(&mut file, size);
+                    return Err(e);
+                }
+                self.write_through(&mut file)
+            });
+        result.map_err(|e| self.modify_error("fallocate", e))
+    }
+
+    /// Helper function to delete a file or an empty directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory holding the entry.
+    /// * `name: &OsStr` - The name of the entry.
+    /// * `dir: bool` - Whether the entry must be a directory, or must not be one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the entry can't be deleted.
+    fn remove_entry(&self, parent: u64, name: &OsStr, dir: bool) -> Result<(), c_int> {
+        let op = if dir { "rmdir" } else { "unlink" };
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let path = self.child_path(parent, name)?;
+        let fs = self.fs.lock().unwrap();
+        if self.differs_in_case(&fs, &path) {
+            return Err(ENOENT);
+        }
+        match find_entry(&fs, &path) {
+            Ok(entry) if entry.is_dir() != dir => return Err(if dir { ENOTDIR } else { EISDIR }),
+            Ok(entry) if self.is_protected(&entry) => return Err(EPERM),
+            Ok(_) => {}
+            Err(e) => return Err(self.modify_error(op, e)),
+        }
+        self.check_not_open(&path)?;
+
+        remove(&fs, &path)
+            .and_then(|_| self.sync_image())
+            .map_err(|e| self.modify_error(op, e))?;
+        self.forget_inodes(&path);
+        // The clusters of a removed FAT+ file may be reused by other files.
+        self.caches.lock().unwrap().clear_cluster_chains();
+        self.invalidate(Invalidation::Entry(parent, name.to_owned()));
+        Ok(())
+    }
+
+    /// Helper function to rename or move a file or directory, replacing an existing file at the
+    /// destination, or an existing empty directory if a directory is moved.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory currently holding the entry.
+    /// * `name: &OsStr` - The current name of the entry.
+    /// * `newparent: u64` - The inode number of the directory to move the entry to.
+    /// * `newname: &OsStr` - The new name of the entry.
+    /// * `flags: u32` - `RENAME_NOREPLACE` or `RENAME_EXCHANGE`, the latter is not supported.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the entry can't be moved.
+    fn rename_entry(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(EINVAL);
+        }
+
+        let from = self.child_path(parent, name)?;
+        let to = self.new_entry_path(newparent, newname)?;
+
+        // A directory can't be moved into itself, but its name can change case.
+        if is_within(&to, &from) && to.components().count() != from.components().count() {
+            return Err(EINVAL);
+        }
+
+        let fs = self.fs.lock().unwrap();
+        if self.differs_in_case(&fs, &from) {
+            return Err(ENOENT);
+        }
+        let source = match find_entry(&fs, &from) {
+            Ok(source) if self.is_protected(&source) => return Err(EPERM),
+            Ok(source) => source,
+            Err(e) => return Err(self.modify_error("rename", e)),
+        };
+
+        // Renaming to a name only differing in case finds the source again.
+        let same_entry =
+            from.parent() == to.parent() && eq_ignore_case(file_name(&from), file_name(&to));
+        let replace = match find_entry(&fs, &to) {
+            Ok(_) if same_entry => false,
+            Ok(_) if self.differs_in_case(&fs, &to) => return Err(EEXIST),
+            Ok(_) if flags & libc::RENAME_NOREPLACE != 0 => return Err(EEXIST),
+            Ok(target) => {
+                if self.is_protected(&target) {
+                    return Err(EPERM);
+                }
+                if source.is_dir() != target.is_dir() {
+                    return Err(if target.is_dir() { EISDIR } else { ENOTDIR });
+                }
+                let empty = !target.is_dir()
+                    || target.to_dir().iter().all(|entry| {
+                        entry.is_ok_and(|entry| matches!(entry.file_name().as_str(), "." | ".."))
+                    });
+                if !empty {
+                    return Err(ENOTEMPTY);
+                }
+                self.check_not_open(&to)?;
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(self.modify_error("rename", e)),
+        };
+
+        // FAT can't replace an entry in one step. Move the source next to the target under a
+        // temporary name first, then the target out of the way under a second one, and only then
+        // the source in its place. Failing at any step moves everything back, and the target is
+        // only deleted once the source took its place. If nuFAT dies in between, the entries are
+        // left behind under their `.nufat-rename-` names. `fatfs` ignores renames only changing
+        // the case of a name, so they take the same detour.
+        let recase = same_entry && file_name(&from) != file_name(&to);
+        let temporary = || {
+            (0..)
+                .map(|n| to.with_file_name(format!(".nufat-rename-{}", n)))
+                .find(|path| find_entry(&fs, path).is_err())
+                .unwrap()
+        };
+        let staged = if replace || recase {
+            temporary()
+        } else {
+            to.clone()
+        };
+        let mut result = move_entry(&fs, &from, &staged, source.is_dir());
+        if (replace || recase) && result.is_ok() {
+            result = open_dir(&fs, to.parent().unwrap()).and_then(|dir| {
+                let backup = replace.then(temporary);
+                if let Some(backup) = &backup {
+                    dir.rename(file_name(&to), &dir, file_name(backup))?;
+                }
+                if let Err(e) = dir.rename(file_name(&staged), &dir, file_name(&to)) {
+                    if let Some(backup) = &backup {
+                        let _ = dir.rename(file_name(backup), &dir, file_name(&to));
+                    }
+                    return Err(e);
+                }
+                // The source already took the place of the target, so the rename stands even if
+                // the old target can't be deleted.
+                if let Some(backup) = &backup {
+                    let _ = remove(&fs, backup);
+                }
+                Ok(())
+            });
+            if result.is_err() {
+                let _ = move_entry(&fs, &staged, &from, source.is_dir());
+            }
+        }
+        let attributes = source.attributes();
+        result
+            .and_then(|_| self.sync_image())
+            .map_err(|e| self.modify_error("rename", e))?;
+        if replace {
+            self.forget_inodes(&to);
+        }
+        self.move_inodes(&from, &to);
+        // The rename itself succeeded, so keep the short names `fatfs` made up if this fails.
+        let moved_dir = source.is_dir() && from.parent() != to.parent();
+        let _ = self
+            .apply_alias_style(&fs, &to, moved_dir)
+            .and_then(|_| self.drop_long_name(&fs, &to))
+            .and_then(|_| self.sync_image());
+        drop(fs);
+        // With `-o hidden=dot`, a name without the `.` is meant to be listed as is, and with
+        // `-o dotfiles_hidden`, a dotfile losing its `.` is no dotfile anymore.
+        let dotted = file_name(&to).starts_with('.');
+        let hidden = if self.options.dotfiles_hidden && dotted {
+            true
+        } else if !dotted
+            && (self.options.hidden == HiddenBehavior::Dot
+                || (self.options.dotfiles_hidden && file_name(&from).starts_with('.')))
+        {
+            false
+        } else {
+            attributes.contains(FileAttributes::HIDDEN)
+        };
+        if hidden != attributes.contains(FileAttributes::HIDDEN) {
+            let mut attributes = attributes;
+            attributes.set(FileAttributes::HIDDEN, hidden);
+            // The rename itself succeeded, so keep the attributes if this fails.
+            let _ = self.set_fat_attributes(&to, attributes);
+        }
+        self.invalidate(Invalidation::Entry(parent, name.to_owned()));
+        self.invalidate(Invalidation::Entry(newparent, newname.to_owned()));
+        Ok(())
+    }
+
+    /// Helper function to create an empty file.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory to create the file in.
+    /// * `name: &OsStr` - The name of the new file.
+    /// * `mode: u32` - The permissions of the new file, which is made read-only if none of them
+    ///   allows writing.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((u64, PathBuf, FileAttr))` - The inode number, path and attributes of the new file.
+    /// * `Err(c_int)` - The error code to reply with, e.g. `EEXIST` if the name is already taken.
+    fn create_file(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> Result<(u64, PathBuf, FileAttr), c_int> {
+        self.check_image()?;
+
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let path = self.new_entry_path(parent, name)?;
+        {
+            let fs = self.fs.lock().unwrap();
+            if find_entry(&fs, &path).is_ok() {
+                return Err(EEXIST);
+            }
+
+            open_dir(&fs, path.parent().unwrap())
+                .and_then(|dir| dir.create_file(file_name(&path)))
+                .and_then(|mut file| self.write_through(&mut file))
+                .and_then(|_| self.apply_alias_style(&fs, &path, false))
+                .and_then(|_| self.drop_long_name(&fs, &path))
+                .map_err(|e| self.modify_error("create", e))?;
+        }
+        self.apply_new_attributes(&path, mode)?;
+        let fs = self.fs.lock().unwrap();
+        let ino = self.get_or_create_inode(&path);
+        let file_attr = self
+            .attr(&fs, ino, &path)
+            .map_err(|e| self.modify_error("create", e))?;
+        self.remember_inode(ino);
+        self.invalidate(Invalidation::Entry(parent, name.to_owned()));
+        Ok((ino, path, file_attr))
+    }
+
+    /// Helper function to create a new directory, as `mkdir` does.
+    ///
+    /// # Parameters
+    ///
+    /// * `parent: u64` - The inode number of the directory to create the directory in.
+    /// * `name: &OsStr` - The name of the new directory.
+    /// * `mode: u32` - The permissions of the new directory, which is made read-only if none of
+    ///   them allows writing.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((u64, PathBuf, FileAttr))` - The inode number, path and attributes of the new
+    ///   directory.
+    /// * `Err(c_int)` - The error code to reply with, e.g. `EEXIST` if the name is already taken.
+    fn create_dir(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> Result<(u64, PathBuf, FileAttr), c_int> {
+        self.check_image()?;
+
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let path = self.new_entry_path(parent, name)?;
+        {
+            let fs = self.fs.lock().unwrap();
+            if find_entry(&fs, &path).is_ok() {
+                return Err(EEXIST);
+            }
+
+            open_dir(&fs, path.parent().unwrap())
+                .and_then(|dir| dir.create_dir(file_name(&path)))
+                .and_then(|_| self.apply_alias_style(&fs, &path, false))
+                .and_then(|_| self.drop_long_name(&fs, &path))
+                .and_then(|_| self.sync_image())
+                .map_err(|e| self.modify_error("mkdir", e))?;
+        }
+        self.apply_new_attributes(&path, mode)?;
+        let fs = self.fs.lock().unwrap();
+        let ino = self.get_or_create_inode(&path);
+        let file_attr = self
+            .attr(&fs, ino, &path)
+            .map_err(|e| self.modify_error("mkdir", e))?;
+        self.remember_inode(ino);
+        self.invalidate(Invalidation::Entry(parent, name.to_owned()));
+        Ok((ino, path, file_attr))
+    }
+
+    /// Helper function to open an existing file in place of creating it, as `create` does without
+    /// `O_EXCL`.
+    ///
+    /// # Parameters
+    ///
+    /// * `uid: u32` - The user opening the file.
+    /// * `parent: u64` - The inode number of the directory holding the file.
+    /// * `name: &OsStr` - The name of the file.
+    /// * `flags: i32` - The flags to open the file with.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((u64, PathBuf, FileAttr))` - The inode number, path and attributes of the file.
+    /// * `Err(c_int)` - The error code to reply with, e.g. `EISDIR` if the name is a directory.
+    fn open_existing(
+        &mut self,
+        uid: u32,
+        parent: u64,
+        name: &OsStr,
+        flags: i32,
+    ) -> Result<(u64, PathBuf, FileAttr), c_int> {
+        let mut path = self.child_path(parent, name)?;
+        {
+            let fs = self.fs.lock().unwrap();
+            if self.differs_in_case(&fs, &path) {
+                return Err(EEXIST);
+            }
+            let entry = find_entry(&fs, &path).map_err(|e| self.modify_error("open", e))?;
+            if entry.is_dir() {
+                return Err(EISDIR);
+            }
+            // Names are case-insensitive, so use the one listed by readdir to get the same inode.
+            path.set_file_name(self.entry_name(&entry));
+        }
+
+        self.check_write_access(uid, &path, flags)?;
+        let ino = self.get_or_create_inode(&path);
+        self.truncate_on_open(ino, &path, flags)?;
+        let fs = self.fs.lock().unwrap();
+        let file_attr = self
+            .attr(&fs, ino, &path)
+            .map_err(|e| self.modify_error("open", e))?;
+        self.remember_inode(ino);
+        Ok((ino, path, file_attr))
+    }
+
+    /// Helper function to refuse opening a file with the Read-only attribute for writing. Like on
+    /// other filesystems, root may write to it anyway, but not to a protected file.
+    ///
+    /// # Parameters
+    ///
+    /// * `uid: u32` - The user opening the file.
+    /// * `path: &Path` - The path of the file.
+    /// * `flags: i32` - The flags the file is opened with.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - `EACCES` or `EPERM` if the file may not be written to.
+    fn check_write_access(&self, uid: u32, path: &Path, flags: i32) -> Result<(), c_int> {
+        if flags & O_ACCMODE == O_RDONLY || path == Path::new("/") {
+            return Ok(());
+        }
+        let fs = self.fs.lock().unwrap();
+        match find_entry(&fs, path) {
+            Ok(entry) if self.is_protected(&entry) => Err(EPERM),
+            Ok(entry) if uid != 0 && entry.attributes().contains(FileAttributes::READ_ONLY) => {
+                Err(EACCES)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Helper function to empty a file that is opened for writing with `O_TRUNC`.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file.
+    /// * `path: &Path` - The path of the file.
+    /// * `flags: i32` - The flags the file is opened with.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the file can't be truncated.
+    fn truncate_on_open(&self, ino: u64, path: &Path, flags: i32) -> Result<(), c_int> {
+        if flags & O_TRUNC == 0 || flags & O_ACCMODE == O_RDONLY {
+            return Ok(());
+        }
+
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let fs = self.fs.lock().unwrap();
+        let result = fs
+            .root_dir()
+            .open_file(path.to_str().unwrap())
+            .and_then(|mut file| {
+                if self.large_file(&fs, path)?.is_some() {
+                    return Err(ErrorKind::FileTooLarge.into());
+                }
+                resize(&mut file, 0)?;
+                self.write_through(&mut file)
+            });
+        result.map_err(|e| self.modify_error("open", e))?;
+        self.invalidate(Invalidation::Inode(ino, 0, 0));
+        Ok(())
+    }
+
+    /// Helper function to read the volume label from the disk image.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Option<String>)` - The label, or `None` if the volume has none.
+    /// * `Err(c_int)` - The error code to reply with.
+    fn volume_label(&mut self) -> Result<Option<String>, c_int> {
+        self.check_image()?;
+
+        // Keep `fatfs` from using the disk image meanwhile.
+        let _fs = self.fs.lock().unwrap();
+        label::read_label(&self.image).map_err(|_| EIO)
+    }
+
+    /// Helper function to change the volume label on the disk image.
+    ///
+    /// # Parameters
+    ///
+    /// * `new_label: Option<&[u8; 11]>` - The new label, or `None` to remove the label.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if changing the label failed.
+    fn set_volume_label(&mut self, new_label: Option<&[u8; 11]>) -> Result<(), c_int> {
+        self.check_image()?;
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let _fs = self.fs.lock().unwrap();
+        label::write_label(&self.image, new_label)
+            .and_then(|_| self.sync_image())
+            .map_err(|e| self.modify_error("setxattr", e))
+    }
+
+    /// Helper function to get the value of an extended attribute.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `name: &OsStr` - The name of the extended attribute.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The value of the extended attribute.
+    /// * `Err(c_int)` - The error code to reply with, `ENODATA` if there is no such attribute.
+    fn xattr_value(&mut self, ino: u64, name: &OsStr) -> Result<String, c_int> {
+        match name.to_str() {
+            Some(ATTRIBUTES_XATTR) => self
+                .fat_attributes(ino)
+                .map(|(_, attributes)| format_attributes(attributes)),
+            Some(SHORTNAME_XATTR) => self.short_name(ino)?.ok_or(ENODATA),
+            Some(LABEL_XATTR) if ino == 1 => self.volume_label()?.ok_or(ENODATA),
+            Some(SERIAL_XATTR) if ino == 1 => {
+                self.check_image()?;
+                let _fs = self.fs.lock().unwrap();
+                serial::read_serial(&self.image)
+                    .map(format_volume_id)
+                    .map_err(|_| EIO)
+            }
+            Some(TYPE_XATTR) if ino == 1 => {
+                self.check_image()?;
+                let fs = self.fs.lock().unwrap();
+                Ok(info::format_fat_type(fs.fat_type()).to_string())
+            }
+            Some(OEM_NAME_XATTR) if ino == 1 => {
+                self.check_image()?;
+                let _fs = self.fs.lock().unwrap();
+                info::read_oem_name(&self.image).map_err(|_| EIO)
+            }
+            _ => Err(ENODATA),
+        }
+    }
+
+    /// Helper function to get the 8.3 short name of a file or directory, which is all that
+    /// firmware without support for long file names sees.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file or directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Option<String>)` - The short name, or `None` for the root directory of the volume,
+    ///   which has no entry.
+    /// * `Err(c_int)` - The error code to reply with.
+    fn short_name(&mut self, ino: u64) -> Result<Option<String>, c_int> {
+        self.check_image()?;
+
+        let path = match self.inode_map.lock().unwrap().get(&ino).cloned() {
+            Some(path) => path,
+            None => return Err(ENOENT),
+        };
+        if path == Path::new("/") {
+            return Ok(None);
+        }
+        let fs = self.fs.lock().unwrap();
+        find_entry(&fs, &path)
+            .map(|entry| Some(entry.short_file_name()))
+            .map_err(|e| self.modify_error("getxattr", e))
+    }
+
+    /// Helper function to get the FAT attributes of a file or directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file or directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((PathBuf, FileAttributes))` - The path and the attributes of the file or directory.
+    /// * `Err(c_int)` - The error code to reply with.
+    fn fat_attributes(&mut self, ino: u64) -> Result<(PathBuf, FileAttributes), c_int> {
+        self.check_image()?;
+
+        let path = match self.inode_map.lock().unwrap().get(&ino).cloned() {
+            Some(path) => path,
+            None => return Err(ENOENT),
+        };
+        if path == Path::new("/") {
+            // The root directory has no entry, and so no attributes either.
+            return Ok((path, FileAttributes::DIRECTORY));
+        }
+        let fs = self.fs.lock().unwrap();
+        let attributes = find_entry(&fs, &path)
+            .map(|entry| entry.attributes())
+            .map_err(|e| self.modify_error("getxattr", e))?;
+        Ok((path, attributes))
+    }
+
+    /// Helper function to change the FAT attributes of a file or directory.
+    ///
+    /// `fatfs` can't change the attributes of an entry, so the entry is changed on the disk image
+    /// directly.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the file or directory.
+    /// * `attributes: FileAttributes` - The new attributes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the attributes can't be changed.
+    fn set_fat_attributes(&self, path: &Path, attributes: FileAttributes) -> Result<(), c_int> {
+        if self.is_read_only() {
+            return Err(EROFS);
+        }
+        if path == Path::new("/") {
+            return Err(EPERM);
+        }
+
+        let fs = self.fs.lock().unwrap();
+        self.check_protected(&fs, path)?;
+        short_names(&fs, path)
+            .and_then(|short_names| {
+                direntry::set_attributes(&self.image, &short_names, attributes.bits())
+            })
+            .and_then(|_| self.sync_image())
+            .map_err(|e| self.modify_error("setxattr", e))
+    }
+
+    /// Helper function to set the Read-only attribute of a file or directory from a mode, the way
+    /// FAT keeps permissions: modes without any write permission set it, the others clear it.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the file or directory.
+    /// * `mode: u32` - The new mode.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the attribute couldn't be written.
+    fn apply_mode(&self, path: &Path, mode: u32) -> Result<(), c_int> {
+        if path == Path::new("/") {
+            // The root directory has no entry to keep attributes in.
+            return Ok(());
+        }
+
+        let mut attributes = {
+            let fs = self.fs.lock().unwrap();
+            find_entry(&fs, path)
+                .map_err(|e| self.modify_error("setattr", e))?
+                .attributes()
+        };
+        let read_only = mode & 0o222 == 0;
+        if read_only == attributes.contains(FileAttributes::READ_ONLY) {
+            return Ok(());
+        }
+        attributes.set(FileAttributes::READ_ONLY, read_only);
+        self.set_fat_attributes(path, attributes)
+    }
+
+    /// Helper function to give a new entry whose name starts with a `.` the Hidden attribute, with
+    /// `-o dotfiles_hidden`.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the new file or directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the attribute couldn't be written.
+    fn hide_dotfile(&self, path: &Path) -> Result<(), c_int> {
+        if !self.options.dotfiles_hidden || !file_name(path).starts_with('.') {
+            return Ok(());
+        }
+
+        let attributes = {
+            let fs = self.fs.lock().unwrap();
+            find_entry(&fs, path)
+                .map_err(|e| self.modify_error("create", e))?
+                .attributes()
+        };
+        if attributes.contains(FileAttributes::HIDDEN) {
+            return Ok(());
+        }
+        self.set_fat_attributes(path, attributes | FileAttributes::HIDDEN)
+    }
+
+    /// Helper function to give a new file or directory the attributes its mode and name call for,
+    /// removing it again if they can't be written, so no failed creation leaves an entry behind.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the new file or directory.
+    /// * `mode: u32` - The permissions it was created with.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the attributes couldn't be written.
+    fn apply_new_attributes(&self, path: &Path, mode: u32) -> Result<(), c_int> {
+        let result = self
+            .apply_mode(path, mode)
+            .and_then(|_| self.hide_dotfile(path));
+        if result.is_err() {
+            let fs = self.fs.lock().unwrap();
+            // The error the attributes failed with is what is replied, whether this works or not.
+            let _ = remove(&fs, path).and_then(|_| self.sync_image());
+        }
+        result
+    }
+
+    /// Helper function to change the timestamps of a directory. `fatfs` can't change them, so
+    /// the directory entry is written directly. The root directory has no entry to keep them in,
+    /// so they are dropped.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the directory.
+    /// * `atime: Option<TimeOrNow>` - The new access time, kept as a date only.
+    /// * `mtime: Option<TimeOrNow>` - The new modification time, rounded down to two seconds.
+    /// * `crtime: Option<SystemTime>` - The new creation time, rounded down to 10 ms.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - The error code to reply with if the directory entry couldn't be
+    ///   written.
+    fn set_dir_times(
+        &self,
+        fs: &FatfsFileSystem<Storage>,
+        path: &Path,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        crtime: Option<SystemTime>,
+    ) -> Result<(), c_int> {
+        if path == Path::new("/") || (atime.is_none() && mtime.is_none() && crtime.is_none()) {
+            return Ok(());
+        }
+
+        short_names(fs, path)
+            .and_then(|short_names| {
+                direntry::set_times(
+                    &self.image,
+                    &short_names,
+                    atime.map(|atime| system_to_fat_time(time_or_now(atime)).date),
+                    mtime.map(|mtime| system_to_fat_time(time_or_now(mtime))),
+                    crtime.map(system_to_fat_time),
+                )
+            })
+            .and_then(|_| self.sync_image())
+            .map_err(|e| self.modify_error("setattr", e))
+    }
+
+    /// Helper function to replace the short name `fatfs` generated for a new or renamed entry
+    /// with one made up as set by `-o alias=`.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the entry.
+    /// * `recursive: bool` - Whether to replace the short names of everything in a directory as
+    ///   well, for directories moved to another parent, whose entries are created anew.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if a short name couldn't be written.
+    fn apply_alias_style(
+        &self,
+        fs: &FatfsFileSystem<Storage>,
+        path: &Path,
+        recursive: bool,
+    ) -> io::Result<()> {
+        if self.options.alias == AliasStyle::NumericTail {
+            return Ok(());
+        }
+        let entry = find_entry(fs, path)?;
+        let (name, short_name) = (entry.file_name(), entry.short_file_name_as_bytes().to_vec());
+        // Names that are valid short names are kept, only made up ones are replaced.
+        if !eq_ignore_case(&name, &entry.short_file_name()) {
+            let others = open_dir(fs, path.parent().unwrap())?
+                .iter()
+                .filter_map(Result::ok)
+                .filter(|other| other.short_file_name_as_bytes() != short_name)
+                .collect::<Vec<_>>();
+            let replacement = alias::candidates(&name, self.options.alias)
+                .into_iter()
+                .find(|raw_name| {
+                    let candidate = direntry::format_short_name(raw_name);
+                    let candidate = String::from_utf8_lossy(&candidate);
+                    !others.iter().any(|other| {
+                        eq_ignore_case(&other.short_file_name(), &candidate)
+                            || eq_ignore_case(&other.file_name(), &candidate)
+                    })
+                });
+            if let Some(raw_name) = replacement {
+                if direntry::format_short_name(&raw_name) != short_name {
+                    direntry::set_short_name(&self.image, &short_names(fs, path)?, &raw_name)?;
+                }
+            }
+        }
+        if recursive && entry.is_dir() {
+            for child in entry.to_dir().iter() {
+                let child = child?.file_name();
+                if child != "." && child != ".." {
+                    self.apply_alias_style(fs, &path.join(child), true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to find the files of a directory larger than 4 GiB, with `-o fatplus`.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `dir: &Path` - The path of the directory.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<HashMap<Vec<u8>, LargeFile>>` - The files by their short name, always empty
+    ///   without `-o fatplus`.
+    fn large_files(
+        &self,
+        fs: &FatfsFileSystem<Storage>,
+        dir: &Path,
+    ) -> io::Result<HashMap<Vec<u8>, LargeFile>> {
+        if !self.options.fat_plus {
+            return Ok(HashMap::new());
+        }
+        direntry::large_files(&self.image, &short_names(fs, dir)?)
+    }
+
+    /// Helper function to check whether a file is larger than 4 GiB, with `-o fatplus`.
+    ///
+    /// `fatfs` only knows the lower 32 bits of the size of such a file, so it is read and
+    /// overwritten on the disk image directly, and its size can't be changed.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<LargeFile>>` - The size and first cluster of the file, or `None` if
+    ///   `fatfs` can access it by itself.
+    fn large_file(
+        &self,
+        fs: &FatfsFileSystem<Storage>,
+        path: &Path,
+    ) -> io::Result<Option<LargeFile>> {
+        if !self.options.fat_plus || path == Path::new("/") {
+            return Ok(None);
+        }
+        let entry = find_entry(fs, path)?;
+        Ok(self
+            .large_files(fs, path.parent().unwrap())?
+            .remove(entry.short_file_name_as_bytes()))
+    }
+
+    /// Helper function to read from or write to a FAT+ file larger than 4 GiB, following its
+    /// cluster chain only the first time, unless it was evicted from the caches since.
+    ///
+    /// # Parameters
+    ///
+    /// * `large_file: &LargeFile` - The file.
+    /// * `access: impl FnOnce(&[u32]) -> io::Result<T>` - Reads or writes the file, given its
+    ///   cluster chain.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<T>` - What `access` returned, or an error if the chain can't be followed.
+    fn access_large_file<T>(
+        &self,
+        large_file: &LargeFile,
+        access: impl FnOnce(&[u32]) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut caches = self.caches.lock().unwrap();
+        let chain = caches.cluster_chain(large_file.first_cluster, || {
+            direntry::cluster_chain(&self.image, large_file.first_cluster)
+        })?;
+        access(chain)
+    }
+
+    /// Helper function to get the attributes of a file or directory from its directory entry.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `path: &Path` - The path of the file or directory.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<FileAttr>` - The attributes, or a `NotFound` error if there is no such entry.
+    fn attr(&self, fs: &FatfsFileSystem<Storage>, ino: u64, path: &Path) -> io::Result<FileAttr> {
+        if path == Path::new("/") {
+            // The root directory has no entry, and so no timestamps either.
+            let now = SystemTime::now();
+            return Ok(self.make_attr(ino, FileType::Directory, 0, [now; 3]));
+        }
+
+        let large_files = self.large_files(fs, path.parent().unwrap())?;
+        Ok(self.entry_attr(ino, &find_entry(fs, path)?, &large_files))
+    }
+
+    /// Helper function to build the attributes of a file or directory from its directory entry.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+    /// * `large_files: &HashMap<Vec<u8>, LargeFile>` - The files of the directory holding the
+    ///   entry that are larger than 4 GiB, as returned by `large_files`.
+    ///
+    /// # Returns
+    ///
+    /// * `FileAttr` - The attributes of the entry.
+    fn entry_attr(
+        &self,
+        ino: u64,
+        entry: &DirEntry<'_, Storage>,
+        large_files: &HashMap<Vec<u8>, LargeFile>,
+    ) -> FileAttr {
+        let (kind, size) = if entry.is_dir() {
+            (FileType::Directory, 0)
+        } else {
+            let large_file = large_files.get(entry.short_file_name_as_bytes());
+            (
+                FileType::RegularFile,
+                large_file.map_or(entry.len(), |large_file| large_file.size),
+            )
+        };
+        let accessed = Time {
+            hour: 0,
+            min: 0,
+            sec: 0,
+            millis: 0,
+        };
+        let mut attr = self.make_attr(
+            ino,
+            kind,
+            size,
+            [
+                fat_to_system_time(entry.accessed(), accessed),
+                fat_to_system_time(entry.modified().date, entry.modified().time),
+                fat_to_system_time(entry.created().date, entry.created().time),
+            ],
+        );
+        if entry.attributes().contains(FileAttributes::READ_ONLY) || self.is_protected(entry) {
+            attr.perm &= !0o222;
+        }
+        attr
+    }
+
+    /// Helper function to build the attributes of a file or directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the entry.
+    /// * `kind: FileType` - Whether the entry is a file or a directory.
+    /// * `size: u64` - The size of the entry.
+    /// * `[atime, mtime, crtime]: [SystemTime; 3]` - When the entry was last accessed, last
+    ///   modified and created. FAT keeps no change time, so the modification time is used for it.
+    ///
+    /// # Returns
+    ///
+    /// * `FileAttr` - The attributes of the entry.
+    fn make_attr(
+        &self,
+        ino: u64,
+        kind: FileType,
+        size: u64,
+        [atime, mtime, crtime]: [SystemTime; 3],
+    ) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime,
+            mtime,
+            ctime: mtime,
+            crtime,
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    /// Helper function to get the name of a directory entry as listed by readdir.
+    ///
+    /// # Parameters
+    ///
+    /// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The short name with `-o shortnames`, the long name otherwise. Short names are
+    ///   lowercase with `-o shortname=lower`, and entries without a long name are listed in the
+    ///   case set by `-o shortname=`.
+    fn entry_name(&self, entry: &DirEntry<'_, Storage>) -> String {
+        let short_only = self.options.shortnames || !has_long_name(entry);
+        match self.options.shortname {
+            ShortnameStyle::Lower if short_only => entry.short_file_name().to_lowercase(),
+            ShortnameStyle::Win95 if short_only => entry.short_file_name(),
+            _ if self.options.shortnames => entry.short_file_name(),
+            _ => entry.file_name(),
+        }
+    }
+
+    /// Helper function to get the name an entry is listed with by `readdir`.
+    ///
+    /// # Parameters
+    ///
+    /// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The name of the entry, normalized as set by `-o normalize=`, with escaped
+    ///   characters turned back with `-o escape`, and with a `.` in front if it has the Hidden
+    ///   attribute and mounted with `-o hidden=dot`.
+    fn listed_name(&self, entry: &DirEntry<'_, Storage>) -> String {
+        let mut name = self.normalized(&self.entry_name(entry));
+        if self.options.escape {
+            name = escape::unescape(&name);
+        }
+        if self.options.hidden == HiddenBehavior::Dot
+            && entry.attributes().contains(FileAttributes::HIDDEN)
+            && !name.starts_with('.')
+        {
+            format!(".{}", name)
+        } else {
+            name
+        }
+    }
+
+    /// Helper function to check whether a directory entry is left out of listings. Volume labels
+    /// always are, entries with the System attribute with `-o hide_system`.
+    ///
+    /// # Parameters
+    ///
+    /// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the entry is hidden.
+    fn is_hidden_entry(&self, entry: &DirEntry<'_, Storage>) -> bool {
+        let attributes = entry.attributes();
+        attributes.contains(FileAttributes::VOLUME_ID)
+            || (self.options.hide_system && attributes.contains(FileAttributes::SYSTEM))
+            || (self.options.hidden == HiddenBehavior::Hide
+                && attributes.contains(FileAttributes::HIDDEN))
+    }
+
+    /// Helper function to check whether the entry at a path is hidden, so lookups agree with the
+    /// listings of its directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the entry.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the entry exists and is hidden.
+    fn is_hidden(&self, fs: &FatfsFileSystem<Storage>, path: &Path) -> bool {
+        find_entry(fs, path).is_ok_and(|entry| self.is_hidden_entry(&entry))
+    }
+
+    /// Helper function to check whether an entry must not be changed, as it has the System
+    /// attribute and the filesystem is not mounted with `-o allow_system_write`.
+    ///
+    /// # Parameters
+    ///
+    /// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the entry is protected.
+    fn is_protected(&self, entry: &DirEntry<'_, Storage>) -> bool {
+        !self.options.allow_system_write && entry.attributes().contains(FileAttributes::SYSTEM)
+    }
+
+    /// Helper function to refuse changing a protected entry.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+    /// * `path: &Path` - The path of the entry.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - `EPERM` if the entry is protected.
+    fn check_protected(&self, fs: &FatfsFileSystem<Storage>, path: &Path) -> Result<(), c_int> {
+        match find_entry(fs, path) {
+            Ok(entry) if self.is_protected(&entry) => Err(EPERM),
+            _ => Ok(()),
+        }
+    }
+
+    /// Helper function to record that a request is being served, for the metrics. Called at the
+    /// start of every request. Does nothing without the `metrics` feature.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: &'static str` - The FUSE operation, e.g. `read`.
+    fn begin_request(&mut self, op: &'static str) {
+        #[cfg(feature = "metrics")]
+        {
+            self.request = op;
+            self.activity.metrics.record_request(op);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = op;
+    }
+
+    /// Helper function to record that the request being served failed, for the metrics. Does
+    /// nothing without the `metrics` feature.
+    ///
+    /// # Parameters
+    ///
+    /// * `errno: c_int` - The error code the request is replied with.
+    ///
+    /// # Returns
+    ///
+    /// * `c_int` - The error code, to reply with.
+    fn failed(&self, errno: c_int) -> c_int {
+        #[cfg(feature = "metrics")]
+        self.activity.metrics.record_error(self.request, errno);
+        errno
+    }
+
+    /// Helper function to check whether requests can be served, reopening the disk image first if
+    /// that was requested by `SIGHUP`. Called at the start of every request, which is recorded as
+    /// activity on the filesystem.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the disk image is open.
+    /// * `Err(c_int)` - The error code to reply with.
+    fn check_image(&mut self) -> Result<(), c_int> {
+        *self.activity.last_request.lock().unwrap() = Instant::now();
+
+        let generation = REOPEN_GENERATION.load(Ordering::SeqCst);
+        if generation != self.reopen_generation {
+            self.reopen_generation = generation;
+            self.reopen_image();
+        }
+
+        if self.image_failed {
+            Err(EIO)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Helper function to reopen the disk image, e.g. after removable media has been reinserted.
+    ///
+    /// The image is only used again if it still holds the same volume. Otherwise, all requests
+    /// fail until the right volume is back and `SIGHUP` is sent again.
+    fn reopen_image(&mut self) {
+        let name = self.options.image_name();
+        log::log(
+            Level::Info,
+            module_path!(),
+            &format!(
+                "Reopening disk image {}, volume serial {}...",
+                name,
+                format_volume_id(self.volume_id)
+            ),
+        );
+
+        // Flush what can be flushed, the old handle may already be dead.
+        let _ = self.image.sync_all();
+
+        // The volume may be gone after an earlier failed reopen, so its FAT type is taken from the
+        // new boot sector.
+        let result = open_image(&self.options).and_then(|image| {
+            let volume_id = read_volume_id(&image)?;
+            Ok((image, volume_id))
+        });
+        let volume = self.fs.get_mut().unwrap();
+        let image = match result {
+            Ok((_, volume_id)) if volume_id != self.volume_id => {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!(
+                        "Disk image {} now holds volume serial {}, expected {}. Refusing to use it!",
+                        name,
+                        format_volume_id(volume_id),
+                        format_volume_id(self.volume_id)
+                    ),
+                );
+                self.image_failed = true;
+                return;
+            }
+            Ok((image, _)) => image,
+            Err(e) => {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to reopen disk image {}: {}", name, e),
+                );
+                self.image_failed = true;
+                return;
+            }
+        };
+        // The old handle still holds the lock, which would keep the new one from taking it.
+        unsafe { libc::flock(self.image.as_raw_fd(), libc::LOCK_UN) };
+        if let Err(e) = lock_image(&image, !self.options.read_only) {
+            log::log(
+                Level::Error,
+                module_path!(),
+                &format!("Failed to reopen disk image {}: {}", name, e),
+            );
+            self.image_failed = true;
+            return;
+        }
+
+        // Drop the old volume first, so its last writes can't interfere with the new one.
+        volume.0 = None;
+        match open_volume(&image, &self.options) {
+            Ok(fs) => {
+                log::log(
+                    Level::Info,
+                    module_path!(),
+                    &format!(
+                        "Reopened disk image {}, volume serial {}.",
+                        name,
+                        format_volume_id(fs.volume_id())
+                    ),
+                );
+                volume.0 = Some(fs);
+                *self.activity.image.lock().unwrap() = image.try_clone().ok();
+                self.image = image;
+                self.image_failed = false;
+                self.caches.get_mut().unwrap().clear_cluster_chains();
+            }
+            Err(e) => {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to reopen disk image {}: {}", name, e),
+                );
+                self.image_failed = true;
+            }
+        }
+    }
+
+    /// Helper function to read from a file in chunks of at most `CHUNK_SIZE` bytes, opening the
+    /// file anew for every chunk. The buffer only grows by a chunk at a time, so a read past the
+    /// end of a file doesn't allocate what it asked for.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the file.
+    /// * `offset: u64` - The offset in the file to start reading at.
+    /// * `size: usize` - The number of bytes to read.
+    /// * `data: &mut Vec<u8>` - The buffer to read into, e.g. the scratch buffer of a handle.
+    ///   Whatever it held before is dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if reading failed. The data read is shorter than `size` if
+    ///   the end of the file was reached.
+    fn read_chunked(
+        &self,
+        path: &Path,
+        offset: u64,
+        size: usize,
+        data: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        data.clear();
+        let large_file = self.large_file(&self.fs.lock().unwrap(), path)?;
+        if let Some(large_file) = large_file {
+            let end = cmp::min(offset.saturating_add(size as u64), large_file.size);
+            while (data.len() as u64) < end.saturating_sub(offset) {
+                let start = data.len();
+                let len = cmp::min(end - offset - start as u64, CHUNK_SIZE as u64) as usize;
+                data.resize(start + len, 0);
+                self.access_large_file(&large_file, |clusters| {
+                    direntry::read_data(
+                        &self.image,
+                        clusters,
+                        offset + start as u64,
+                        &mut data[start..],
+                    )
+                })?;
+            }
+            return Ok(());
+        }
+
+        while data.len() < size {
+            let mut filled = data.len();
+            let end = cmp::min(filled + CHUNK_SIZE, size);
+            data.resize(end, 0);
+            let fs = self.fs.lock().unwrap();
+            let mut file = fs.root_dir().open_file(path.to_str().unwrap())?;
+            file.seek(SeekFrom::Start(offset + filled as u64))?;
+            while filled < end {
+                match file.read(&mut data[filled..end]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) => {
+                        data.truncate(filled);
+                        return Err(e);
+                    }
+                }
+            }
+            data.truncate(filled);
+            if filled < end {
+                // Reached the end of the file.
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to write to a file in chunks of at most `CHUNK_SIZE` bytes, opening the
+    /// file anew for every chunk. The file is only written through once the last chunk is written.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The path of the file.
+    /// * `offset: u64` - The offset in the file to start writing at.
+    /// * `data: &[u8]` - The data to write.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if writing any of the chunks failed.
+    fn write_chunked(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let large_file = self.large_file(&self.fs.lock().unwrap(), path)?;
+        if let Some(large_file) = large_file {
+            // Only the data can be overwritten, `fatfs` would cut off the size.
+            if offset + data.len() as u64 > large_file.size {
+                return Err(ErrorKind::FileTooLarge.into());
+            }
+            self.access_large_file(&large_file, |clusters| {
+                direntry::write_data(&self.image, clusters, offset, data)
+            })?;
+            return self.sync_image();
+        }
+
+        let chunks = data.chunks(CHUNK_SIZE).count();
+        for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let fs = self.fs.lock().unwrap();
+            let mut file = fs.root_dir().open_file(path.to_str().unwrap())?;
+            let chunk_offset = offset + (index * CHUNK_SIZE) as u64;
+            let end = file.seek(SeekFrom::Start(chunk_offset))?;
+            if end < chunk_offset {
+                // `fatfs` doesn't seek past the end of a file, so fill the gap with zeros. Happens
+                // e.g. when the kernel writes cached pages back out of order.
+                io::copy(&mut io::repeat(0).take(chunk_offset - end), &mut file)?;
+            }
+            file.write_all(chunk)?;
+            if self.options.verify_writes {
+                file.flush()?;
+                self.verify_write(&fs, path, chunk_offset, chunk)?;
+            } else if index + 1 == chunks {
+                self.write_through(&mut file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to read data back from the device after writing it with
+    /// `-o verify_writes`.
+    ///
+    /// The disk image is synced and dropped from the page cache first, so the data is actually
+    /// read from the device.
+    ///
+    /// # Parameters
+    ///
+    /// * `fs: &FatfsFileSystem<Storage>` - The volume the data was written to.
+    /// * `path: &Path` - The path of the file.
+    /// * `offset: u64` - The offset in the file the data was written at.
+    /// * `data: &[u8]` - The data that was written.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An `EIO` error if the data read back differs.
+    fn verify_write(
+        &self,
+        fs: &FatfsFileSystem<Storage>,
+        path: &Path,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.image.sync_data()?;
+        unsafe {
+            libc::posix_fadvise(self.image.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+
+        let mut file = fs.root_dir().open_file(path.to_str().unwrap())?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut written = vec![0; data.len()];
+        file.read_exact(&mut written)?;
+
+        let differing = written.iter().zip(data).filter(|(a, b)| a != b).count();
+        if differing == 0 {
+            return Ok(());
+        }
+        let corrupt_writes = self.activity.corrupt_writes.fetch_add(1, Ordering::SeqCst) + 1;
+        log::log_with(
+            Level::Error,
+            module_path!(),
+            &format!(
+                "Verifying write to {:?} at offset {} failed, {} of {} bytes differ! {} corrupt writes so far.",
+                path,
+                offset,
+                differing,
+                data.len(),
+                corrupt_writes
+            ),
+            &Fields {
+                op: Some("write"),
+                path: Some(path),
+                errno: Some(EIO),
+                ..Fields::default()
+            },
+        );
+        Err(io::Error::from_raw_os_error(EIO))
+    }
+
+    /// Helper function to write a modified file through to the disk image when mounted with
+    /// `-o sync`. Does nothing otherwise.
+    ///
+    /// # Parameters
+    ///
+    /// * `file: &mut FatfsFile<Storage>` - The file that has been modified.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if flushing the file or syncing the image failed.
+    fn write_through(&self, file: &mut FatfsFile<Storage>) -> io::Result<()> {
+        if self.options.sync {
+            file.flush()?;
+            self.image.sync_data()?;
+        } else {
+            self.activity.note_unsynced();
+        }
+        Ok(())
+    }
+
+    /// Helper function to sync the disk image after modifying a directory when mounted with
+    /// `-o sync`. Does nothing otherwise.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if syncing failed.
+    fn sync_image(&self) -> io::Result<()> {
+        if self.options.sync {
+            self.image.sync_data()?;
+        } else {
+            self.activity.note_unsynced();
+        }
+        Ok(())
+    }
+
+    /// Helper function to get the error code for a failed modification of a directory. Errors
+    /// not caused by the request itself are handled according to the `errors=` mount option.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: &str` - The FUSE operation the modification was requested by, for the log.
+    /// * `error: io::Error` - The error the modification failed with.
+    ///
+    /// # Returns
+    ///
+    /// * `c_int` - The error code to reply with.
+    fn modify_error(&self, op: &str, error: io::Error) -> c_int {
+        match error.kind() {
+            ErrorKind::NotFound => return ENOENT,
+            ErrorKind::AlreadyExists => return EEXIST,
+            // The fixed size root directory of FAT12 and FAT16 volumes ends without a free entry.
+            ErrorKind::UnexpectedEof | ErrorKind::WriteZero => return ENOSPC,
+            ErrorKind::FileTooLarge => return EFBIG,
+            _ => {}
+        }
+        // `fatfs` reports most errors as `ErrorKind::Other`, with the message telling them apart.
+        match error.to_string().as_str() {
+            "Directory not empty" => ENOTEMPTY,
+            "No space left on device" => ENOSPC,
+            "File name too long" => ENAMETOOLONG,
+            "File name is empty" | "File name contains unsupported characters" => EINVAL,
+            "Is a directory" => EISDIR,
+            "Not a directory" => ENOTDIR,
+            _ => self.write_error(op, error),
+        }
+    }
+
+    /// Helper function to handle a failed write to the disk image according to the `errors=`
+    /// mount option.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: &str` - The FUSE operation the write was requested by, for the log.
+    /// * `error: io::Error` - The error writing failed with.
+    ///
+    /// # Returns
+    ///
+    /// * `c_int` - The error code to reply with.
+    fn write_error(&self, op: &str, error: io::Error) -> c_int {
+        let fields = Fields {
+            op: Some(op),
+            errno: Some(error.raw_os_error().unwrap_or(EIO)),
+            ..Fields::default()
+        };
+        match self.options.errors {
+            ErrorBehavior::Continue => {}
+            ErrorBehavior::RemountRo => {
+                if !self.read_only.swap(true, Ordering::SeqCst) {
+                    log::log_with(
+                        Level::Error,
+                        module_path!(),
+                        &format!(
+                            "Writing to the disk image failed: {}\nRemounting read-only, all further modifications are refused!",
+                            error
+                        ),
+                        &fields,
+                    );
+                }
+            }
+            ErrorBehavior::Panic => {
+                log::log_with(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Writing to the disk image failed: {}\nAborting!", error),
+                    &fields,
+                );
+                process::exit(1);
+            }
+        }
+        EIO
+    }
+}
+
+/// Open the disk image for reading, and for writing unless mounted read-only.
+///
+/// An image passed as a file descriptor is duplicated, so the descriptor itself stays open for
+/// reopening the image later.
+///
+/// # Parameters
+///
+/// * `options: &Options` - The options holding the path or file descriptor of the disk image.
+///
+/// # Returns
+///
+/// * `io::Result<File>` - The opened disk image.
+fn open_image(options: &Options) -> io::Result<File> {
+    let Some(fd) = options.image_fd else {
+        return OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .open(&options.disk_image_path);
+    };
+
+    let mut image = match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+        Ok(image) => File::from(image),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("fd {}: {}", fd, e))),
+    };
+    let access_mode = unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_ACCMODE;
+    if access_mode == libc::O_WRONLY {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} is not open for reading.", fd),
+        ));
+    }
+    if access_mode == libc::O_RDONLY && !options.read_only {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} is not open for writing, mount with -o ro.", fd),
+        ));
+    }
+    if let Err(e) = image.rewind() {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("fd {} is not seekable: {}", fd, e),
+        ));
+    }
+    Ok(image)
+}
+
+/// Lock the disk image against other nuFAT processes, so it isn't mounted twice, or changed by a
+/// subcommand while it is mounted. The lock is released when the disk image is closed.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `exclusive: bool` - Whether to take the lock for writing, or one that other readers can take
+///   as well.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - A `WouldBlock` error if another process holds a conflicting lock.
+pub fn lock_image(image: &File, exclusive: bool) -> io::Result<()> {
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if unsafe { libc::flock(image.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == ErrorKind::WouldBlock => Err(io::Error::new(
+            ErrorKind::WouldBlock,
+            "The disk image is in use, it is mounted or being changed by another nuFAT process.",
+        )),
+        // Disk images on filesystems without locks, like some network filesystems, can't be
+        // protected.
+        _ => Ok(()),
+    }
+}
+
+/// Open the FAT volume on the disk image.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `options: &Options` - The options to mount with.
+///
+/// # Returns
+///
+/// * `io::Result<FatfsFileSystem<Storage>>` - The opened volume.
+fn open_volume(image: &File, options: &Options) -> io::Result<FatfsFileSystem<Storage>> {
+    check_supported(image)?;
+    let boot_sector = floppy::patched_boot_sector(image)?;
+    if boot_sector.is_some() {
+        if floppy::is_atari_image(image)? {
+            log::log(
+                Level::Info,
+                module_path!(),
+                "Found an Atari ST boot sector. Making up the missing parts for DOS.",
+            );
+        } else {
+            log::log(
+                Level::Warning,
+                module_path!(),
+                "The boot sector is incomplete, as on old floppies. Making up the missing parts.",
+            );
+        }
+    }
+    let storage = Storage::new(image.try_clone()?, options.io_retries, boot_sector);
+    let mut fs_options = FsOptions::new();
+    if let Some(codepage) = options.codepage {
+        fs_options = fs_options.oem_cp_converter(codepage);
+    }
+    FatfsFileSystem::new(storage, fs_options)
+}
+
+/// Check that the disk image doesn't hold an exFAT volume, which `fatfs` can't read and would
+/// only report as a damaged FAT volume.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An `Unsupported` error if the volume is exFAT.
+pub fn check_supported(image: &File) -> io::Result<()> {
+    if info::read_oem_name(image)? == "EXFAT" {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "exFAT volumes are not supported, only FAT12, FAT16 and FAT32.",
+        ));
+    }
+    Ok(())
+}
+
+/// Open a directory of the volume.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The path of the directory.
+///
+/// # Returns
+///
+/// * `io::Result<Dir<'_, Storage>>` - The opened directory.
+pub fn open_dir<'a>(fs: &'a FatfsFileSystem<Storage>, path: &Path) -> io::Result<Dir<'a, Storage>> {
+    if path == Path::new("/") {
+        Ok(fs.root_dir())
+    } else {
+        fs.root_dir().open_dir(path.to_str().unwrap())
+    }
+}
+
+/// Get the name of the entry at a path, which is known to be valid UTF-8.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path of the entry, as returned by `child_path`.
+///
+/// # Returns
+///
+/// * `&str` - The name of the entry.
+fn file_name(path: &Path) -> &str {
+    path.file_name().and_then(OsStr::to_str).unwrap()
+}
+
+/// Check whether a path lies within a directory, ignoring case like FAT does.
+///
+/// # Parameters
+///
+/// * `path: &Path` - The path to check.
+/// * `dir: &Path` - The path of the directory.
+///
+/// # Returns
+///
+/// * `bool` - `true` if `path` is `dir` or below it.
+fn is_within(path: &Path, dir: &Path) -> bool {
+    let mut components = path.components();
+    dir.components().all(|dir_component| {
+        components.next().is_some_and(|component| {
+            let (component, dir_component) = (component.as_os_str(), dir_component.as_os_str());
+            match (component.to_str(), dir_component.to_str()) {
+                (Some(component), Some(dir_component)) => eq_ignore_case(component, dir_component),
+                _ => component == dir_component,
+            }
+        })
+    })
+}
+
+/// Delete a file or an empty directory.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The path of the entry.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be deleted.
+pub fn remove(fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<()> {
+    open_dir(fs, path.parent().unwrap())?.remove(file_name(path))
+}
+
+/// Move a file or directory to another path.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `from: &Path` - The path of the file or directory to move.
+/// * `to: &Path` - The path to move it to, which must not exist.
+/// * `dir: bool` - Whether the entry is a directory.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the entry could not be moved.
+fn move_entry(fs: &FatfsFileSystem<Storage>, from: &Path, to: &Path, dir: bool) -> io::Result<()> {
+    let src_dir = open_dir(fs, from.parent().unwrap())?;
+    let dst_dir = open_dir(fs, to.parent().unwrap())?;
+    if dir && from.parent() != to.parent() {
+        move_dir(&src_dir, file_name(from), &dst_dir, file_name(to))
+    } else {
+        src_dir.rename(file_name(from), &dst_dir, file_name(to))
+    }
+}
+
+/// Move a directory to another parent directory.
+///
+/// `fatfs` doesn't update the `..` entry of a directory moved to another parent, so the directory
+/// is created anew at the destination, its entries are moved over one by one, and the empty
+/// directory is deleted. Files keep their data, only moved directories get new timestamps.
+///
+/// # Parameters
+///
+/// * `src_dir: &Dir<'_, Storage>` - The directory holding the directory to move.
+/// * `src_name: &str` - The name of the directory to move.
+/// * `dst_dir: &Dir<'_, Storage>` - The directory to move it to.
+/// * `dst_name: &str` - The new name of the directory.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if moving failed, possibly leaving the entries split up between
+///   both directories.
+fn move_dir(
+    src_dir: &Dir<'_, Storage>,
+    src_name: &str,
+    dst_dir: &Dir<'_, Storage>,
+    dst_name: &str,
+) -> io::Result<()> {
+    let src = src_dir.open_dir(src_name)?;
+    let dst = dst_dir.create_dir(dst_name)?;
+    let entries = src.iter().collect::<io::Result<Vec<_>>>()?;
+    for entry in entries {
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        if entry.is_dir() {
+            move_dir(&src, &name, &dst, &name)?;
+        } else {
+            src.rename(&name, &dst, &name)?;
+        }
+    }
+    src_dir.remove(src_name)
+}
+
+/// Change the size of a file, filling it up with zeros when it grows, and update its modification
+/// time.
+///
+/// # Parameters
+///
+/// * `file: &mut FatfsFile<Storage>` - The file to resize.
+/// * `size: u64` - The new size of the file.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if resizing the file failed.
+fn resize(file: &mut FatfsFile<Storage>, size: u64) -> io::Result<()> {
+    let end = file.seek(SeekFrom::Start(size))?;
+    if end < size {
+        // `fatfs` doesn't seek past the end of a file.
+        io::copy(&mut io::repeat(0).take(size - end), file)?;
+        Ok(())
+    } else {
+        file.truncate()?;
+        // `fatfs` only updates the modification time when writing.
+        #[allow(deprecated)]
+        file.set_modified(system_to_fat_time(SystemTime::now()));
+        Ok(())
+    }
+}
+
+/// Convert a timestamp of a directory entry, which FAT keeps in local time, to a `SystemTime`.
+///
+/// # Parameters
+///
+/// * `date: Date` - The date of the timestamp.
+/// * `time: Time` - The time of the timestamp.
+///
+/// # Returns
+///
+/// * `SystemTime` - The timestamp, or the Unix epoch if it is invalid.
+pub fn fat_to_system_time(date: Date, time: Time) -> SystemTime {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date.year as c_int - 1900;
+    tm.tm_mon = date.month as c_int - 1;
+    tm.tm_mday = date.day as c_int;
+    tm.tm_hour = time.hour as c_int;
+    tm.tm_min = time.min as c_int;
+    tm.tm_sec = time.sec as c_int;
+    // Let `mktime` find out whether daylight saving time was in effect.
+    tm.tm_isdst = -1;
+    let secs = unsafe { libc::mktime(&mut tm) };
+    u64::try_from(secs).map_or(UNIX_EPOCH, |secs| {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(time.millis as u64)
+    })
+}
+
+/// Resolve a time given to `setattr`.
+///
+/// # Parameters
+///
+/// * `time: TimeOrNow` - The time, or the current time.
+///
+/// # Returns
+///
+/// * `SystemTime` - The time.
+fn time_or_now(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+/// Convert a `SystemTime` to a timestamp for a directory entry, in local time.
+///
+/// # Parameters
+///
+/// * `time: SystemTime` - The time to convert.
+///
+/// # Returns
+///
+/// * `DateTime` - The timestamp, clamped to the years 1980 to 2107 FAT can represent.
+pub fn system_to_fat_time(time: SystemTime) -> DateTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+    }
+
+    let year = tm.tm_year + 1900;
+    if year < 1980 {
+        return DateTime {
+            date: Date {
+                year: 1980,
+                month: 1,
+                day: 1,
+            },
+            time: Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        };
+    } else if year > 2107 {
+        return DateTime {
+            date: Date {
+                year: 2107,
+                month: 12,
+                day: 31,
+            },
+            time: Time {
+                hour: 23,
+                min: 59,
+                sec: 58,
+                millis: 0,
+            },
+        };
+    }
+    DateTime {
+        date: Date {
+            year: year as u16,
+            month: (tm.tm_mon + 1) as u16,
+            day: tm.tm_mday as u16,
+        },
+        time: Time {
+            hour: tm.tm_hour as u16,
+            min: tm.tm_min as u16,
+            // FAT can't represent leap seconds.
+            sec: cmp::min(tm.tm_sec, 59) as u16,
+            millis: since_epoch.subsec_millis() as u16,
+        },
+    }
+}
+
+/// Find the directory entry of a file or directory, which holds its metadata.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The path of the file or directory.
+///
+/// # Returns
+///
+/// * `io::Result<DirEntry<'_, Storage>>` - The entry, or a `NotFound` error if there is none. The
+///   root directory has no entry.
+pub fn find_entry<'a>(
+    fs: &'a FatfsFileSystem<Storage>,
+    path: &Path,
+) -> io::Result<DirEntry<'a, Storage>> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(OsStr::to_str))
+    else {
+        return Err(ErrorKind::NotFound.into());
+    };
+    for entry in open_dir(fs, parent)?.iter() {
+        let entry = entry?;
+        if eq_ignore_case(&entry.file_name(), name)
+            || eq_ignore_case(&entry.short_file_name(), name)
+        {
+            return Ok(entry);
+        }
+    }
+    Err(ErrorKind::NotFound.into())
+}
+
+/// Check whether a directory entry has a long name. `fatfs` falls back to the short name, with
+/// the name or extension lowercase where Windows NT marked them as such, if there is none.
+///
+/// # Parameters
+///
+/// * `entry: &DirEntry<'_, Storage>` - The directory entry.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the name of the entry differs in more than the case marked by Windows NT
+///   from its short name.
+fn has_long_name(entry: &DirEntry<'_, Storage>) -> bool {
+    let (name, short_name) = (entry.file_name(), entry.short_file_name());
+    let (base, ext) = name.split_once('.').unwrap_or((&name, ""));
+    let (short_base, short_ext) = short_name.split_once('.').unwrap_or((&short_name, ""));
+    let nt_cased = |part: &str, short_part: &str| {
+        part == short_part || part == short_part.to_ascii_lowercase()
+    };
+    !(nt_cased(base, short_base) && nt_cased(ext, short_ext))
+}
+
+/// Compare two names ignoring case, like FAT does. Unlike DOS, which only knew the case of ASCII
+/// letters, Windows ignores the case of all letters, e.g. `ПАПКА` and `папка` are the same name.
+///
+/// # Parameters
+///
+/// * `a: &str` - The first name.
+/// * `b: &str` - The second name.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the names only differ in case.
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// Get the short names of the directories leading to a file or directory and of the entry
+/// itself, for finding it on the disk image with the `direntry` module.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The path of the file or directory.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Vec<u8>>>` - The short names, starting below the root directory, or a
+///   `NotFound` error if there is no such entry.
+pub fn short_names(fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let mut short_names = Vec::new();
+    let mut prefix = PathBuf::from("/");
+    for component in path.components().skip(1) {
+        prefix.push(component);
+        short_names.push(find_entry(fs, &prefix)?.short_file_name_as_bytes().to_vec());
+    }
+    Ok(short_names)
+}
+
+/// Check that the directory to mount as the root of the filesystem exists on the volume.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `root: &Path` - The directory given with `-o root=`.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if `root` is missing or not a directory.
+fn check_root(fs: &FatfsFileSystem<Storage>, root: &Path) -> io::Result<()> {
+    if root == Path::new("/") {
+        return Ok(());
+    }
+
+    let path = root.to_str().unwrap();
+    match fs.root_dir().open_dir(path) {
+        Ok(_) => Ok(()),
+        Err(_) if fs.root_dir().open_file(path).is_ok() => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Root {:?} is not a directory.", root),
+        )),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("Root {:?} does not exist on the volume.", root),
+        )),
+    }
+}
+
+/// Read the volume serial number straight from the boot sector, without opening the volume.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<u32>` - The serial number, or 0 like `fatfs` reports it if the boot sector has
+///   no extended boot signature.
+fn read_volume_id(image: &File) -> io::Result<u32> {
+    // Like `fatfs`, tell the layout of FAT32 boot sectors apart by their missing 16 bit FAT size.
+    let sector = floppy::boot_sector(image)?;
+    let offset = if sector[0x16..0x18] == [0, 0] {
+        0x42
+    } else {
+        0x26
+    };
+    if sector[offset] != 0x29 {
+        return Ok(0);
+    }
+    Ok(u32::from_le_bytes(
+        sector[offset + 1..offset + 5].try_into().unwrap(),
+    ))
+}
+
+/// Format a volume serial number the way DOS and Windows show it, e.g. `1234-ABCD`.
+///
+/// # Parameters
+///
+/// * `volume_id: u32` - The serial number.
+///
+/// # Returns
+///
+/// * `String` - The formatted serial number.
+pub fn format_volume_id(volume_id: u32) -> String {
+    format!("{:04X}-{:04X}", volume_id >> 16, volume_id & 0xFFFF)
+}
+
+/// Format FAT attributes as listed in `user.fat.attributes`.
+///
+/// # Parameters
+///
+/// * `attributes: FileAttributes` - The attributes of an entry.
+///
+/// # Returns
+///
+/// * `String` - The comma separated names of the attributes that are set, e.g. `hidden,archive`.
+fn format_attributes(attributes: FileAttributes) -> String {
+    ATTRIBUTE_NAMES
+        .iter()
+        .filter(|(attribute, _)| attributes.contains(*attribute))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse FAT attributes as given to `user.fat.attributes`.
+///
+/// # Parameters
+///
+/// * `value: &[u8]` - The comma separated names of the attributes to set.
+///
+/// # Returns
+///
+/// * `Option<FileAttributes>` - The attributes, or `None` if a name is unknown.
+fn parse_attributes(value: &[u8]) -> Option<FileAttributes> {
+    let value = std::str::from_utf8(value).ok()?;
+    value
+        .trim_end_matches(['\n', '\0'])
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .try_fold(FileAttributes::empty(), |attributes, name| {
+            let (attribute, _) = ATTRIBUTE_NAMES
+                .iter()
+                .find(|(_, known)| *known == name.trim())?;
+            Some(attributes | *attribute)
+        })
+}
+
+impl FuseFilesystem for FatFilesystem {
+    /// Initialize the filesystem, marking the volume as in use and negotiating the capabilities
+    /// of the kernel.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `config: &mut KernelConfig` - The capabilities and limits negotiated with the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), c_int>` - `EIO` if the volume can't be marked as in use, capabilities the
+    ///   kernel lacks are not required.
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Mark the volume as in use, so it is recognized as not cleanly unmounted if nuFAT crashes.
+        // This waits until the volume is mounted, so a mount that fails leaves it clean.
+        if !self.is_read_only() {
+            if let Err(e) =
+                direntry::set_dirty_flag(&self.image, true).and_then(|_| self.image.sync_data())
+            {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to mark the volume as in use: {}", e),
+                );
+                return Err(EIO);
+            }
+        }
+        // `fuser` already asks for the largest writes its buffers can take, and big writes.
+        // Let the kernel fetch attributes together with the entries when it lists a directory.
+        let _ =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO);
+        // Requests are served one at a time anyway, but lookups in one directory needn't wait for
+        // a listing of another to finish in the kernel.
+        let _ = config.add_capabilities(consts::FUSE_PARALLEL_DIROPS);
+        // Pass `O_TRUNC` on to `open` instead of truncating with a separate `setattr`.
+        let _ = config.add_capabilities(consts::FUSE_ATOMIC_O_TRUNC);
+        // Keep `fcntl` locks here, so `F_GETLK` can report the process holding a lock.
+        let _ = config.add_capabilities(consts::FUSE_POSIX_LOCKS);
+        // FAT keeps modification times in steps of 2 seconds, the closest the kernel can do is 1.
+        let _ = config.set_time_granularity(Duration::from_secs(1));
+        if self.options.writeback_cache && !self.is_read_only() {
+            self.writeback_cache = config
+                .add_capabilities(consts::FUSE_WRITEBACK_CACHE)
+                .is_ok();
+            if !self.writeback_cache {
+                log::log(Level::Warning, module_path!(), "The kernel does not support the writeback cache, writes are passed on right away.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Clean up the filesystem when it is unmounted, so no data is lost.
+    ///
+    /// Files that are still open are flushed, the volume is unmounted, which writes out the free
+    /// cluster count of FAT32 volumes, the dirty flag is cleared and the disk image is synced.
+    ///
+    /// The dirty flag is left set if the volume was already dirty when it was mounted, or if
+    /// writing to it failed, so it still gets checked.
+    fn destroy(&mut self) {
+        // Without a usable disk image, modifications were refused and there is nothing to write.
+        if self.image_failed {
+            return;
+        }
+
+        let name = self.options.image_name();
+
+        let handles: Vec<u64> = self.handles.lock().unwrap().keys().copied().collect();
+        for fh in handles {
+            if self.flush_handle(fh).is_err() {
+                let path = self.handle_path(fh).unwrap_or_default();
+                log::log_with(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to flush {:?} on disk image {}.", path, name),
+                    &Fields {
+                        op: Some("destroy"),
+                        path: Some(&path),
+                        ..Fields::default()
+                    },
+                );
+            }
+        }
+
+        let mut clean = !self.dirty;
+        if let Some(fs) = self.fs.get_mut().unwrap().0.take() {
+            if let Err(e) = fs.unmount() {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to unmount the volume on disk image {}: {}", name, e),
+                );
+                clean = false;
+            }
+        }
+        if !self.is_read_only() {
+            if clean {
+                if let Err(e) = direntry::set_dirty_flag(&self.image, false) {
+                    log::log(
+                        Level::Error,
+                        module_path!(),
+                        &format!(
+                            "Failed to clear the dirty flag on disk image {}: {}",
+                            name, e
+                        ),
+                    );
+                }
+            }
+            if let Err(e) = self.image.sync_all() {
+                log::log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("Failed to sync disk image {}: {}", name, e),
+                );
+            }
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.begin_request("lookup");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        // Attribute time to live
+        let ttl = Duration::from_secs(1);
+
+        // Get path for given inode.
+        let path = {
+            let inode_map = self.inode_map.lock().unwrap();
+            inode_map.get(&parent).cloned()
+        };
+
+        let mut path = match path {
+            Some(path) => path,
+            None => {
+                reply.error(self.failed(EIO));
+                return;
+            }
+        };
+
+        if name == ".." {
+            // Never leave the directory mounted as the root of the filesystem.
+            if parent != 1 {
+                path.pop();
+            }
+        } else {
+            path.push(self.escaped(name));
+        }
+
+        let fs = self.fs.lock().unwrap();
+        let path = self.denormalize(&fs, path);
+        let mut path = self.undot(&fs, path);
+
+        if self.is_hidden(&fs, &path) || (name != ".." && self.differs_in_case(&fs, &path)) {
+            reply.error(self.failed(ENOENT));
+            return;
+        }
+
+        // Names are case-insensitive, so use the one listed by readdir to get the same inode.
+        if name != ".." {
+            if let Ok(entry) = find_entry(&fs, &path) {
+                path.set_file_name(self.entry_name(&entry));
+            }
+        }
+
+        let ino = self.get_or_create_inode(&path);
+        match self.attr(&fs, ino, &path) {
+            Ok(file_attr) => {
+                self.remember_inode(ino);
+                reply.entry(&ttl, &file_attr, 0);
+            }
+            Err(_) => reply.error(self.failed(ENOENT)),
+        }
+    }
+
+    /// Forget about an inode once the kernel dropped all references to it, so the inode map
+    /// doesn't grow for as long as the filesystem is mounted. `batch_forget` falls back to this.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number to forget.
+    /// * `nlookup: u64` - The number of lookups to forget.
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.begin_request("forget");
+        let mut lookups = self.lookups.lock().unwrap();
+        let Some(count) = lookups.get_mut(&ino) else {
+            return;
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count == 0 {
+            lookups.remove(&ino);
+            // The root is never forgotten, its inode number is fixed.
+            if ino != 1 {
+                self.inode_map.lock().unwrap().remove(&ino);
+            }
+        }
+    }
+
+    /// Get the attributes of a file or directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request` - The `fuser::Request` datastructure representing the request to the filesystem.
+    /// * `ino: u64` - The inode-number of the given filesystem object.
+    /// * `_fh: Option<u64>` - The File-Handle (optional).
+    /// * `reply: ReplyAttr` - A `fuser::ReplyAttr` instance for returning attributes.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with a reply or an error
+    /// code if the requested inode does not exist.
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        self.begin_request("getattr");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        // Attribute time to live
+        let ttl = Duration::from_secs(1);
+
+        // Get path for given inode.
+        let path = {
+            let inode_map = self.inode_map.lock().unwrap();
+            inode_map.get(&ino).cloned()
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+
+        let fs = self.fs.lock().unwrap();
+        match self.attr(&fs, ino, &path) {
+            Ok(file_attr) => reply.attr(&ttl, &file_attr),
+            Err(_) => reply.error(self.failed(ENOENT)),
+        }
+    }
+
+    /// Set attributes of given file or directory.
+    ///
+    /// Only the size, the access, modification and creation times and the write permission of
+    /// files can be changed, and the times and write permission of directories. FAT keeps the
+    /// access time as a date only, the modification time to two seconds and no write permission
+    /// as the Read-only attribute. The root directory has no directory entry, so its times can't
+    /// be changed, and setting them does nothing.
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
         _uid: Option<u32>,
         _gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         fh: Option<u64>,
-        _crtime: Option<SystemTime>,
+        crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        // Get path for given inode.
-        let path = self.inode_map.lock().unwrap().get(&ino).cloned().unwrap();
-        {
-            let fs = self.fs.lock().unwrap();
-            match fs.root_dir().open_file(path.to_str().unwrap()) {
-                Ok(mut file) => {
-                    if let Some(size) = size {
-                        file.seek(SeekFrom::Start(size)).unwrap();
-                        file.truncate().unwrap();
-                    }
+        self.begin_request("setattr");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let times = atime.is_some() || mtime.is_some() || crtime.is_some();
+        if (size.is_some() || times || mode.is_some()) && self.is_read_only() {
+            reply.error(self.failed(EROFS));
+            return;
+        }
+
+        // Get path for given inode.
+        let path = match self.inode_map.lock().unwrap().get(&ino).cloned() {
+            Some(path) => path,
+            None => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+        {
+            let fs = self.fs.lock().unwrap();
+            let (is_dir, attributes) = if path == Path::new("/") {
+                (true, FileAttributes::DIRECTORY)
+            } else {
+                match find_entry(&fs, &path) {
+                    Ok(entry) if self.is_protected(&entry) && (size.is_some() || times) => {
+                        reply.error(self.failed(EPERM));
+                        return;
+                    }
+                    Ok(entry) => (entry.is_dir(), entry.attributes()),
+                    Err(_) => {
+                        reply.error(self.failed(ENOENT));
+                        return;
+                    }
+                }
+            };
+
+            if is_dir {
+                if size.is_some() {
+                    reply.error(self.failed(EISDIR));
+                    return;
+                }
+                if let Err(e) = self.set_dir_times(&fs, &path, atime, mtime, crtime) {
+                    reply.error(self.failed(e));
+                    return;
+                }
+            } else {
+                if size.is_some()
+                    && req.uid() != 0
+                    && attributes.contains(FileAttributes::READ_ONLY)
+                {
+                    reply.error(self.failed(EACCES));
+                    return;
+                }
+                let result =
+                    fs.root_dir()
+                        .open_file(path.to_str().unwrap())
+                        .and_then(|mut file| {
+                            if let Some(size) = size {
+                                if self.large_file(&fs, &path)?.is_some() {
+                                    return Err(ErrorKind::FileTooLarge.into());
+                                }
+                                resize(&mut file, size)?;
+                            }
+                            // Setting the timestamps is deprecated in favour of a custom
+                            // `TimeProvider`, which can only hand out the current time.
+                            #[allow(deprecated)]
+                            {
+                                if let Some(atime) = atime {
+                                    file.set_accessed(system_to_fat_time(time_or_now(atime)).date);
+                                }
+                                if let Some(mtime) = mtime {
+                                    file.set_modified(system_to_fat_time(time_or_now(mtime)));
+                                }
+                                if let Some(crtime) = crtime {
+                                    file.set_created(system_to_fat_time(crtime));
+                                }
+                            }
+                            self.write_through(&mut file)
+                        });
+                if let Err(e) = result {
+                    reply.error(self.failed(self.modify_error("setattr", e)));
+                    return;
+                }
+            }
+        }
+
+        if let Some(mode) = mode {
+            if let Err(e) = self.apply_mode(&path, mode) {
+                reply.error(self.failed(e));
+                return;
+            }
+        }
+        self.getattr(req, ino, fh, reply)
+    }
+
+    /// Open a directory. Its entries are listed by `readdir` from a snapshot taken when it reads
+    /// from the start.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the directory to open.
+    /// * `_flags: i32` - The open flags. (not used in this implementation)
+    /// * `reply: ReplyOpen` - A `fuser::ReplyOpen` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with a new handle on the
+    /// directory, or an error code if it can't be read.
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.begin_request("opendir");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let Some(path) = self.inode_map.lock().unwrap().get(&ino).cloned() else {
+            reply.error(self.failed(ENOENT));
+            return;
+        };
+        if open_dir(&self.fs.lock().unwrap(), &path).is_err() {
+            reply.error(self.failed(ENOENT));
+            return;
+        }
+        let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
+        self.caches.lock().unwrap().open_listing(fh);
+        reply.opened(fh, 0);
+    }
+
+    /// Read the contents of a directory.
+    ///
+    /// The entries are listed from a snapshot taken when reading from the start, so they stay
+    /// consistent while the directory is being read.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request` - The `fuser::Request` datastructure representing the request to the filesystem.
+    /// * `ino: u64` - The inode number of the requested file or directory.
+    /// * `fh: u64` - The handle the directory was opened with.
+    /// * `offset: i64` - The offset of the entries in Bytes from Reply.
+    /// * `reply: ReplyDirectory` - A `fuser::ReplyDirectory` instance for returning directory contents.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with directory entries or an error code.
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.begin_request("readdir");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        // Reading from the start, after `opendir` or `rewinddir`, lists the current entries, as
+        // does reading on once the snapshot was evicted.
+        if offset == 0 || self.caches.lock().unwrap().lacks_listing(fh) {
+            if let Err(e) = self.refresh_listing(ino, fh) {
+                reply.error(self.failed(e));
+                return;
+            }
+        }
+
+        let mut caches = self.caches.lock().unwrap();
+        let Some(entries) = caches.listing(fh) else {
+            reply.error(self.failed(EBADF));
+            return;
+        };
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let buffer_full: bool = reply.add(
+                entry.attr.ino,
+                index as i64 + 1,
+                entry.attr.kind,
+                entry.name.as_str(),
+            );
+
+            if buffer_full {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    /// Read the contents of a directory together with the attributes of the entries, so listing
+    /// them in detail doesn't take a `lookup` per entry.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request` - The `fuser::Request` datastructure representing the request to the filesystem.
+    /// * `ino: u64` - The inode number of the requested directory.
+    /// * `fh: u64` - The handle the directory was opened with.
+    /// * `offset: i64` - The offset of the entries in Bytes from Reply.
+    /// * `reply: ReplyDirectoryPlus` - A `fuser::ReplyDirectoryPlus` instance for returning
+    ///   directory contents.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with directory entries
+    /// and their attributes, or an error code.
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.begin_request("readdirplus");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        // Reading from the start, after `opendir` or `rewinddir`, lists the current entries, as
+        // does reading on once the snapshot was evicted.
+        if offset == 0 || self.caches.lock().unwrap().lacks_listing(fh) {
+            if let Err(e) = self.refresh_listing(ino, fh) {
+                reply.error(self.failed(e));
+                return;
+            }
+        }
+
+        // Attribute time to live
+        let ttl = Duration::from_secs(1);
+
+        let mut caches = self.caches.lock().unwrap();
+        let Some(entries) = caches.listing(fh) else {
+            reply.error(self.failed(EBADF));
+            return;
+        };
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            // The snapshot doesn't create inodes, and they may have been forgotten since it was
+            // taken.
+            let ino = self.get_or_create_inode(&entry.path);
+            let attr = FileAttr { ino, ..entry.attr };
+            let buffer_full: bool =
+                reply.add(ino, index as i64 + 1, entry.name.as_str(), &ttl, &attr, 0);
+
+            if buffer_full {
+                break;
+            }
+            // The kernel counts every entry as a lookup, except for `.` and `..`.
+            if entry.name != "." && entry.name != ".." {
+                self.remember_inode(ino);
+            }
+        }
+        reply.ok();
+    }
+
+    /// Release an open directory, dropping its snapshot.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the directory. (not used in this implementation)
+    /// * `fh: u64` - The handle to release.
+    /// * `_flags: i32` - The flags the directory was opened with. (not used in this
+    ///   implementation)
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It always responds with an empty reply.
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_request("releasedir");
+        self.caches.lock().unwrap().close_listing(fh);
+        reply.ok();
+    }
+
+    /// Read data from a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request` - The `fuse::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the file to read. (not used in this implementation)
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: i64` - Offset in the file where reading starts.
+    /// * `size: u32` - Number of bytes to read.
+    /// * `_flags: i32` - Additional flags. (Not used in this implementation)
+    /// * `_lock_owner: Option<u64>` - (Not used in this implementation)
+    /// * `reply: ReplyData` - A `fuse::ReplyData` instance for returning file data.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with a Reply or an error
+    /// code if the requested inode does not exist.
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.begin_request("read");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let path = match self.handle_path(fh) {
+            Ok(path) => path,
+            Err(e) => {
+                reply.error(self.failed(e));
+                return;
+            }
+        };
+        let mut data = self.take_scratch(fh);
+        match self.read_chunked(&path, offset as u64, size as usize, &mut data) {
+            Ok(()) => {
+                #[cfg(feature = "metrics")]
+                self.activity.metrics.record_read(data.len() as u64);
+                reply.data(&data)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => reply.error(self.failed(ENOENT)),
+            Err(_) => reply.error(self.failed(EIO)),
+        };
+        self.keep_scratch(fh, data);
+    }
+
+    /// Write data to file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the file to write. (not used in this implementation)
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: i64` - The offset to write at, ignored if the file was opened with `O_APPEND`.
+    /// * `data: &[u8]` - The data to write as bytes.
+    /// * `write_flags: u32` - Specific flags to set while writing. (not used in this
+    ///   implementation)
+    /// * `flags: i32` - Additional flags. (not used in this implementation)
+    /// * `lock_owner: Option<u64>`
+    /// * `reply: ReplyWrite` - A `fuser::ReplyWrite` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with a Reply or an error
+    /// code if the requested inode cannot be written to.
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.begin_request("write");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        match self.write_handle(fh, offset as u64, data) {
+            Ok(()) => {
+                #[cfg(feature = "metrics")]
+                self.activity.metrics.record_written(data.len() as u64);
+                reply.written(data.len() as u32)
+            }
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Open a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file to open.
+    /// * `flags: i32` - The open flags, kept with the handle. The file is truncated with `O_TRUNC`.
+    /// * `reply: ReplyOpen` - A `fuser::ReplyOpen` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with a new handle on the
+    /// file, or an error code if the inode does not exist.
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.begin_request("open");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let path = match self.inode_map.lock().unwrap().get(&ino).cloned() {
+            Some(path) => path,
+            None => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+
+        let result = self
+            .check_write_access(req.uid(), &path, flags)
+            .and_then(|_| self.truncate_on_open(ino, &path, flags));
+        if let Err(e) = result {
+            reply.error(self.failed(e));
+            return;
+        }
+        reply.opened(self.open_handle(ino, &path, flags), 0);
+    }
+
+    /// Release an open file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file to release.
+    /// * `fh: u64` - The handle to release.
+    /// * `_flags: i32` - The flags the file was opened with. (not used in this implementation)
+    /// * `_lock_owner: Option<u64>` - (not used in this implementation)
+    /// * `_flush: bool` - (not used in this implementation)
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. When mounted with `-o flush` and this was the last
+    /// handle on the file, the disk image is synced before replying.
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_request("release");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let last_handle = self.close_handle(ino, fh);
+
+        // `fatfs` writes straight to the image, so syncing it flushes both the data and the
+        // directory entry of the file.
+        if self.options.flush && last_handle {
+            if let Err(e) = self.image.sync_data() {
+                reply.error(self.failed(self.write_error("release", e)));
+                return;
+            }
+            self.activity.note_synced();
+        }
+        reply.ok();
+    }
+
+    /// Flush a file when a handle on it is closed.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file.
+    /// * `fh: u64` - The handle that is closed.
+    /// * `lock_owner: u64` - The process closing the handle, whose locks on the file are released.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request once the data and the
+    /// directory entry of the file are written to the disk image, so `close()` can report errors.
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.begin_request("flush");
+        // Closing any descriptor of a file releases the locks the process holds on it.
+        self.activity
+            .locks
+            .lock()
+            .unwrap()
+            .release_owner(ino, lock_owner);
+
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        match self.flush_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Test for a lock on a file, as done by `F_GETLK`.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file.
+    /// * `_fh: u64` - The handle the file was opened with. (not used in this implementation)
+    /// * `lock_owner: u64` - The process testing for the lock.
+    /// * `start: u64` - The first byte of the range to lock.
+    /// * `end: u64` - The last byte of the range to lock.
+    /// * `typ: i32` - The type of the lock, `F_RDLCK` or `F_WRLCK`.
+    /// * `pid: u32` - The process id of the caller.
+    /// * `reply: ReplyLock` - A `fuser::ReplyLock` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the first lock that
+    /// keeps the lock from being taken, or with `F_UNLCK` if there is none.
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.begin_request("getlk");
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            typ,
+        };
+        match self.activity.locks.lock().unwrap().conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, F_UNLCK, pid),
+        }
+    }
+
+    /// Take or release a lock on a file, as done by `F_SETLK` and `F_SETLKW`.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file.
+    /// * `_fh: u64` - The handle the file was opened with. (not used in this implementation)
+    /// * `lock_owner: u64` - The process taking the lock.
+    /// * `start: u64` - The first byte of the range to lock.
+    /// * `end: u64` - The last byte of the range to lock.
+    /// * `typ: i32` - The type of the lock, `F_RDLCK`, `F_WRLCK` or `F_UNLCK`.
+    /// * `pid: u32` - The process id of the caller.
+    /// * `sleep: bool` - Whether to wait for conflicting locks to be released.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request once the lock is taken,
+    /// or with `EAGAIN` if another process holds a conflicting lock and `sleep` is not set. With
+    /// `sleep`, it responds with `EINTR` if the lock can't be taken within 30 seconds.
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_request("setlk");
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            typ,
+        };
+        let mut locks = self.activity.locks.lock().unwrap();
+        if typ == F_UNLCK || locks.conflict(ino, &lock).is_none() {
+            locks.set(ino, lock);
+            reply.ok();
+        } else if sleep {
+            // Requests are served one at a time, so the reply is sent once the lock is released,
+            // or with `EINTR` once the wait times out.
+            locks.wait(ino, lock, reply);
+        } else {
+            reply.error(self.failed(EAGAIN));
+        }
+    }
+
+    /// Write a file through to the device.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the file. (not used in this implementation)
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `datasync: bool` - Whether only the data needs to be synced, not the metadata of the
+    ///   disk image.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request once the disk image is
+    /// synced, or with an error code if that failed.
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.begin_request("fsync");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        if let Err(e) = self.flush_handle(fh) {
+            reply.error(self.failed(e));
+            return;
+        }
+        // The directory entry is part of the image's data, so `sync_data` covers the file's size
+        // and timestamps as well.
+        let result = if datasync {
+            self.image.sync_data()
+        } else {
+            self.image.sync_all()
+        };
+        match result {
+            Ok(()) => {
+                self.activity.note_synced();
+                reply.ok()
+            }
+            Err(e) => reply.error(self.failed(self.write_error("fsync", e))),
+        }
+    }
+
+    /// Preallocate space for a file by extending it with zeros, so running out of space is
+    /// noticed up front.
+    ///
+    /// FAT can't allocate clusters beyond the end of a file, so only the default mode is
+    /// supported. If the volume fills up, the file keeps its old size.
+    ///
+    /// # Parameters
+    ///
+    /// * `req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the file. (not used in this implementation)
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: i64` - The start of the range to allocate.
+    /// * `length: i64` - The length of the range to allocate.
+    /// * `mode: i32` - The mode of `fallocate(2)`, e.g. `FALLOC_FL_KEEP_SIZE`.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with an empty reply
+    /// once the space is allocated, or with `EOPNOTSUPP` for any mode other than the default.
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_request("fallocate");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        if self.is_read_only() {
+            reply.error(self.failed(EROFS));
+            return;
+        }
+
+        if mode != 0 {
+            reply.error(self.failed(EOPNOTSUPP));
+            return;
+        }
+
+        match self.allocate_handle(req.uid(), fh, offset, length) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Find data or a hole in a file, for `lseek` with `SEEK_DATA` or `SEEK_HOLE`. The kernel
+    /// handles the other kinds of seeking by itself.
+    ///
+    /// FAT has no sparse files, so all data is in one piece followed by the hole at the end.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of the file. (not used in this implementation)
+    /// * `fh: u64` - The handle the file was opened with.
+    /// * `offset: i64` - The offset to start looking at.
+    /// * `whence: i32` - `SEEK_DATA` or `SEEK_HOLE`.
+    /// * `reply: ReplyLseek` - A `fuser::ReplyLseek` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the offset found,
+    /// or `ENXIO` if `offset` is beyond the end of the file.
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.begin_request("lseek");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let path = match self.handle_path(fh) {
+            Ok(path) => path,
+            Err(e) => {
+                reply.error(self.failed(e));
+                return;
+            }
+        };
+        let fs = self.fs.lock().unwrap();
+        let size = find_entry(&fs, &path).and_then(|entry| {
+            let large_file = self.large_file(&fs, &path)?;
+            Ok(large_file.map_or(entry.len(), |large_file| large_file.size))
+        });
+        drop(fs);
+        let size = match size {
+            Ok(size) => size as i64,
+            Err(_) => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+
+        if offset < 0 {
+            reply.error(self.failed(EINVAL));
+        } else if offset >= size {
+            reply.error(self.failed(ENXIO));
+        } else if whence == SEEK_DATA {
+            reply.offset(offset);
+        } else if whence == SEEK_HOLE {
+            reply.offset(size);
+        } else {
+            reply.error(self.failed(EINVAL));
+        }
+    }
+
+    /// Copy a range of one file to another within the volume, without passing the data through
+    /// the kernel.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino_in: u64` - The inode number of the source file. (not used in this implementation)
+    /// * `fh_in: u64` - The handle the source file was opened with.
+    /// * `offset_in: i64` - The offset in the source file to copy from.
+    /// * `ino_out: u64` - The inode number of the destination file, whose cached data is dropped.
+    /// * `fh_out: u64` - The handle the destination file was opened with.
+    /// * `offset_out: i64` - The offset in the destination file to copy to.
+    /// * `len: u64` - The number of bytes to copy.
+    /// * `_flags: u32` - The flags of `copy_file_range(2)`. (not used in this implementation)
+    /// * `reply: ReplyWrite` - A `fuser::ReplyWrite` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the number of bytes
+    /// copied, which is less than `len` if the end of the source file was reached.
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        _ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.begin_request("copy_file_range");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        if self.is_read_only() {
+            reply.error(self.failed(EROFS));
+            return;
+        }
+
+        let (from, to) = match (self.handle_path(fh_in), self.handle_path(fh_out)) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(e), _) | (_, Err(e)) => {
+                reply.error(self.failed(e));
+                return;
+            }
+        };
+
+        // The number of bytes copied is replied as a `u32`.
+        let len = cmp::min(len, u32::MAX as u64);
+        let mut copied = 0;
+        let mut data = self.take_scratch(fh_in);
+        while copied < len {
+            let size = cmp::min(len - copied, CHUNK_SIZE as u64) as usize;
+            match self.read_chunked(&from, offset_in as u64 + copied, size, &mut data) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    reply.error(self.failed(ENOENT));
+                    return;
                 }
                 Err(_) => {
-                    reply.error(libc::ENOENT);
+                    reply.error(self.failed(EIO));
                     return;
                 }
             };
-            // TODO: Check if object is directory.
+            if data.is_empty() {
+                // Reached the end of the source file.
+                break;
+            }
+            match self.write_chunked(&to, offset_out as u64 + copied, &data) {
+                Ok(()) => copied += data.len() as u64,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    reply.error(self.failed(ENOENT));
+                    return;
+                }
+                Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                    reply.error(self.failed(EFBIG));
+                    return;
+                }
+                Err(e) => {
+                    reply.error(self.failed(self.write_error("copy_file_range", e)));
+                    return;
+                }
+            }
+        }
+        self.keep_scratch(fh_in, data);
+        // The data is copied past the page cache of the destination file.
+        if copied > 0 {
+            self.invalidate(Invalidation::Inode(ino_out, offset_out, copied as i64));
+        }
+        reply.written(copied as u32);
+    }
+
+    /// Create and open a new file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory to create the file in.
+    /// * `name: &OsStr` - The name of the new file.
+    /// * `mode: u32` - The permissions of the new file. Without any write permission, the file is
+    ///   made read-only, but it can still be written through the handle it is opened with.
+    /// * `umask: u32` - The umask of the calling process, applied to `mode`.
+    /// * `flags: i32` - The flags to open the file with, kept with the handle.
+    /// * `reply: ReplyCreate` - A `fuser::ReplyCreate` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the attributes and
+    /// handle of the new file. If the name is already taken, it opens the existing file instead,
+    /// or responds with `EEXIST` if `O_EXCL` is set.
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.begin_request("create");
+        let result = match self.create_file(parent, name, mode & !umask) {
+            Err(EEXIST) if flags & O_EXCL == 0 => {
+                self.open_existing(req.uid(), parent, name, flags)
+            }
+            result => result,
+        };
+        match result {
+            Ok((ino, path, file_attr)) => {
+                let ttl = Duration::from_secs(1);
+                reply.created(&ttl, &file_attr, 0, self.open_handle(ino, &path, flags), 0);
+            }
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Create a file node. Only regular files can be stored on a FAT volume.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory to create the file in.
+    /// * `name: &OsStr` - The name of the new file.
+    /// * `mode: u32` - The file type and permissions of the new file. Without any write
+    ///   permission, the file is made read-only.
+    /// * `umask: u32` - The umask of the calling process, applied to `mode`.
+    /// * `_rdev: u32` - The device number of a device file. (not used in this implementation)
+    /// * `reply: ReplyEntry` - A `fuser::ReplyEntry` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the attributes of the
+    /// new file, or `EPERM` if the node is not a regular file.
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.begin_request("mknod");
+        if mode & S_IFMT != S_IFREG {
+            reply.error(self.failed(EPERM));
+            return;
+        }
+
+        match self.create_file(parent, name, mode & !umask) {
+            Ok((_, _, file_attr)) => reply.entry(&Duration::from_secs(1), &file_attr, 0),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Create a new directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory to create the directory in.
+    /// * `name: &OsStr` - The name of the new directory.
+    /// * `mode: u32` - The permissions of the new directory. Without any write permission, the
+    ///   directory is made read-only.
+    /// * `umask: u32` - The umask of the calling process, applied to `mode`.
+    /// * `reply: ReplyEntry` - A `fuser::ReplyEntry` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the attributes of the
+    /// new directory, or `EEXIST` if the name is already taken.
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.begin_request("mkdir");
+        match self.create_dir(parent, name, mode & !umask) {
+            Ok((_, _, file_attr)) => reply.entry(&Duration::from_secs(1), &file_attr, 0),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Delete a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory holding the file.
+    /// * `name: &OsStr` - The name of the file.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with an empty reply or an
+    /// error code if the file can't be deleted.
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.begin_request("unlink");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        match self.remove_entry(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Delete an empty directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory holding the directory.
+    /// * `name: &OsStr` - The name of the directory.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with an empty reply, or
+    /// `ENOTEMPTY` if the directory still has entries.
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.begin_request("rmdir");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        match self.remove_entry(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Rename or move a file or directory. An existing file at the destination is replaced, as is
+    /// an existing empty directory if a directory is moved.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `parent: u64` - The inode number of the directory currently holding the entry.
+    /// * `name: &OsStr` - The current name of the entry.
+    /// * `newparent: u64` - The inode number of the directory to move the entry to.
+    /// * `newname: &OsStr` - The new name of the entry.
+    /// * `flags: u32` - `RENAME_NOREPLACE` or `RENAME_EXCHANGE`, the latter is not supported.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with an empty reply or an
+    /// error code if the entry can't be moved.
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_request("rename");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// Get the statistics of the filesystem, e.g. for `df`.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `_ino: u64` - The inode number of any file or directory. (not used in this implementation)
+    /// * `reply: ReplyStatfs` - A `fuser::ReplyStatfs` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the size of the
+    /// volume and its free space in clusters, or an error code if the FAT can't be read.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        self.begin_request("statfs");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let fs = self.fs.lock().unwrap();
+        let stats = match fs.stats() {
+            Ok(stats) => stats,
+            Err(_) => {
+                reply.error(self.failed(EIO));
+                return;
+            }
+        };
+        // FAT has no inodes, so there is no limit on the number of files to report.
+        reply.statfs(
+            stats.total_clusters() as u64,
+            stats.free_clusters() as u64,
+            stats.free_clusters() as u64,
+            0,
+            0,
+            stats.cluster_size(),
+            255,
+            stats.cluster_size(),
+        );
+    }
+
+    /// Check whether the calling process may access a file or directory, e.g. for `test -w`.
+    ///
+    /// The permissions are the ones reported by `getattr`. Files with the read-only attribute
+    /// can't be written, and nothing can be written on a read-only mount.
+    ///
+    /// # Parameters
+    ///
+    /// * `req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `mask: i32` - The access to check, `F_OK` or a combination of `R_OK`, `W_OK` and `X_OK`.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with an empty reply if
+    /// access is granted, or with `EACCES`, `EROFS` or `ENOENT` otherwise.
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.begin_request("access");
+        if let Err(e) = self.check_image() {
+            reply.error(self.failed(e));
+            return;
+        }
+
+        let path = match self.inode_map.lock().unwrap().get(&ino).cloned() {
+            Some(path) => path,
+            None => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+        let attr = match self.attr(&self.fs.lock().unwrap(), ino, &path) {
+            Ok(attr) => attr,
+            Err(_) => {
+                reply.error(self.failed(ENOENT));
+                return;
+            }
+        };
+
+        if mask & W_OK != 0 && self.is_read_only() {
+            reply.error(self.failed(EROFS));
+            return;
+        }
+        let granted = if req.uid() == 0 {
+            // Like on other filesystems, root may read and write anything, but only execute what
+            // anybody may execute.
+            if attr.perm & 0o111 != 0 {
+                0o7
+            } else {
+                0o6
+            }
+        } else if req.uid() == attr.uid {
+            attr.perm >> 6
+        } else if req.gid() == attr.gid {
+            attr.perm >> 3
+        } else {
+            attr.perm
+        } & 0o7;
+        if (mask & (R_OK | W_OK | X_OK)) as u16 & !granted != 0 {
+            reply.error(self.failed(EACCES));
+        } else {
+            reply.ok();
+        }
+    }
+
+    /// Get an extended attribute. The FAT attributes of an entry are listed in
+    /// `user.fat.attributes` and its short name is in `user.fat.shortname`, and the root directory
+    /// also has the volume label in `user.fat.label`, the serial number in `user.fat.serial`, the
+    /// FAT type in `user.fat.type` and the OEM name in `user.fat.oem_name`.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `name: &OsStr` - The name of the extended attribute.
+    /// * `size: u32` - The size of the buffer for the value, or 0 to ask for the size needed.
+    /// * `reply: ReplyXattr` - A `fuser::ReplyXattr` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the value or its
+    /// size, or `ENODATA` if there is no such extended attribute.
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.begin_request("getxattr");
+        let value = match self.xattr_value(ino, name) {
+            Ok(value) => value,
+            Err(e) => {
+                reply.error(self.failed(e));
+                return;
+            }
+        };
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if (size as usize) < value.len() {
+            reply.error(self.failed(ERANGE));
+        } else {
+            reply.data(value.as_bytes());
         }
-        self.getattr(_req, ino, fh, reply)
     }
 
-    /// Read the contents of a directory.
+    /// Set an extended attribute. Only `user.fat.attributes` can be set, to change the FAT
+    /// attributes of an entry, and `user.fat.label` of the root directory, to change the volume
+    /// label.
     ///
     /// # Parameters
     ///
-    /// * `_req: &Request` - The `fuser::Request` datastructure representing the request to the filesystem.
-    /// * `ino: u64` - The inode number of the requested file or directory.
-    /// * `_fh: u64` - The file handle, if given.
-    /// * `offset: i64` - The offset of the entries in Bytes from Reply.
-    /// * `reply: ReplyDirectory` - A `fuser::ReplyDirectory` instance for returning directory contents.
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `name: &OsStr` - The name of the extended attribute.
+    /// * `value: &[u8]` - The comma separated names of the FAT attributes to set, e.g. `hidden`,
+    ///   or the new volume label.
+    /// * `flags: i32` - `XATTR_CREATE` or `XATTR_REPLACE`.
+    /// * `_position: u32` - (not used in this implementation)
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
     ///
     /// # Returns
     ///
-    /// This function does not return a value. It responds to the request with directory entries or an error code.
-    fn readdir(
+    /// This function does not return a value. It responds to the request with an empty reply, or
+    /// `EINVAL` if an attribute name or the label is invalid.
+    fn setxattr(
         &mut self,
-        _req: &Request,
+        _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
     ) {
-        let fs = self.fs.lock().unwrap();
+        self.begin_request("setxattr");
+        if ino == 1 && name == LABEL_XATTR {
+            let result = match std::str::from_utf8(value).ok().and_then(label::parse_label) {
+                Some(new_label) => self.volume_label().and_then(|old_label| match old_label {
+                    Some(_) if flags & XATTR_CREATE != 0 => Err(EEXIST),
+                    None if flags & XATTR_REPLACE != 0 => Err(ENODATA),
+                    _ => self.set_volume_label(Some(&new_label)),
+                }),
+                None => Err(EINVAL),
+            };
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(self.failed(e)),
+            }
+            return;
+        }
+        if name == SHORTNAME_XATTR || (ino == 1 && VOLUME_XATTRS.iter().any(|xattr| name == *xattr))
+        {
+            reply.error(self.failed(EPERM));
+            return;
+        }
+        if name != ATTRIBUTES_XATTR {
+            reply.error(self.failed(EOPNOTSUPP));
+            return;
+        }
+        // The FAT attributes always exist.
+        if flags & XATTR_CREATE != 0 {
+            reply.error(self.failed(EEXIST));
+            return;
+        }
+        let requested = match parse_attributes(value) {
+            Some(requested) => requested,
+            None => {
+                reply.error(self.failed(EINVAL));
+                return;
+            }
+        };
 
-        let path = {
-            let inode_map = self.inode_map.lock().unwrap();
-            match inode_map.get(&ino).cloned() {
-                Some(path) => path,
-                None => {
-                    eprintln!(
-                        "Unable to get inode when initializing path!\nMap: {:?}",
-                        inode_map
-                    );
-                    reply.error(ENOENT);
-                    return;
-                }
+        let result = self.fat_attributes(ino).and_then(|(path, attributes)| {
+            let changeable = ATTRIBUTE_NAMES
+                .iter()
+                .fold(FileAttributes::empty(), |all, (attribute, _)| {
+                    all | *attribute
+                });
+            self.set_fat_attributes(&path, (attributes - changeable) | requested)
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+
+    /// List the extended attributes of a file or directory.
+    ///
+    /// # Parameters
+    ///
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
+    ///   filesystem.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `size: u32` - The size of the buffer for the list, or 0 to ask for the size needed.
+    /// * `reply: ReplyXattr` - A `fuser::ReplyXattr` instance.
+    ///
+    /// # Returns
+    ///
+    /// This function does not return a value. It responds to the request with the list of names,
+    /// each terminated by a null byte, or its size.
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.begin_request("listxattr");
+        let path = match self.fat_attributes(ino) {
+            Ok((path, _)) => path,
+            Err(e) => {
+                reply.error(self.failed(e));
+                return;
             }
         };
 
-        // Open dir and read entries.
-        let dir: Dir<'_, File> = if path == PathBuf::from("/") {
-            println!("Root directory detected:");
-            fs.root_dir()
-        } else {
-            match fs.root_dir().open_dir(path.to_str().unwrap()) {
-                Ok(dir) => dir,
-                Err(_) => {
-                    eprintln!(
-                        "Unable to open given dir! Path: {:?}",
-                        path.to_str().unwrap()
-                    );
-                    reply.error(ENOENT);
+        let mut list = format!("{}\0", ATTRIBUTES_XATTR);
+        if path != Path::new("/") {
+            list.push_str(&format!("{}\0", SHORTNAME_XATTR));
+        }
+        if ino == 1 {
+            match self.volume_label() {
+                Ok(Some(_)) => list.push_str(&format!("{}\0", LABEL_XATTR)),
+                Ok(None) => {}
+                Err(e) => {
+                    reply.error(self.failed(e));
                     return;
                 }
             }
-        };
-
-        // Iterate over all entries in the directory.
-        for (index, entry) in dir.iter().skip(offset as usize).enumerate() {
-            println!("Entry: {:?}", entry);
-            let e = entry.unwrap();
-            let file_name = e.file_name();
-            let kind = if e.is_dir() {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            };
-
-            // Create an inode for every file
-            let entry_path = path.join(file_name.as_str());
-            let entry_inode = self.get_or_create_inode(&entry_path);
-
-            let buffer_full: bool = reply.add(
-                entry_inode,
-                offset + index as i64 + 1,
-                kind,
-                file_name.as_str(),
-            );
-
-            if buffer_full {
-                break;
+            for xattr in VOLUME_XATTRS {
+                list.push_str(&format!("{}\0", xattr));
             }
         }
-        println!("reply: {:?}", reply);
-        reply.ok();
+        if size == 0 {
+            reply.size(list.len() as u32);
+        } else if (size as usize) < list.len() {
+            reply.error(self.failed(ERANGE));
+        } else {
+            reply.data(list.as_bytes());
+        }
     }
 
-    /// Read data from a file.
+    /// Remove an extended attribute. Only `user.fat.label` of the root directory can be removed,
+    /// to remove the volume label.
     ///
     /// # Parameters
     ///
-    /// * `_req: &Request` - The `fuse::Request` datastructure representing the request to the
+    /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
     ///   filesystem.
-    /// * `ino: u64` - The inode number of the file to read.
-    /// * `_fh: u64` - File handle (not used in this implementation).
-    /// * `offset: i64` - Offset in the file where reading starts.
-    /// * `size: u32` - Number of bytes to read.
-    /// * `_flags: i32` - Additional flags. (Not used in this implementation)
-    /// * `_lock_owner: Option<u64>` - (Not used in this implementation)
-    /// * `reply: ReplyData` - A `fuse::ReplyData` instance for returning file data.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `name: &OsStr` - The name of the extended attribute.
+    /// * `reply: ReplyEmpty` - A `fuser::ReplyEmpty` instance.
     ///
     /// # Returns
     ///
-    /// This function does not return a value. It responds to the request with a Reply or an error
-    /// code if the requested inode does not exist.
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        // Get path for given inode.
-        let path = self.inode_map.lock().unwrap().get(&ino).cloned().unwrap();
-        let fs = self.fs.lock().unwrap();
-        match fs.root_dir().open_file(path.to_str().unwrap()) {
-            Ok(file) => {
-                let file_bytes = file
-                    .bytes()
-                    .skip(offset as usize)
-                    .take(size as usize)
-                    .map(|c| c.expect("Why no data?"))
-                    .collect::<Vec<u8>>();
-                reply.data(&file_bytes);
-            }
-            Err(_) => {
-                reply.error(libc::ENOENT);
-            }
+    /// This function does not return a value. It responds to the request with an empty reply, or
+    /// `EPERM` if the extended attribute can't be removed.
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.begin_request("removexattr");
+        let result = if ino == 1 && name == LABEL_XATTR {
+            self.volume_label().and_then(|old_label| match old_label {
+                Some(_) => self.set_volume_label(None),
+                None => Err(ENODATA),
+            })
+        } else if name == ATTRIBUTES_XATTR
+            || name == SHORTNAME_XATTR
+            || (ino == 1 && VOLUME_XATTRS.iter().any(|xattr| name == *xattr))
+        {
+            Err(EPERM)
+        } else {
+            Err(ENODATA)
         };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.failed(e)),
+        }
     }
 
-    /// Write data to file.
+    /// Control a file, supporting the `ioctl`s of the `vfat` driver for the FAT attributes.
     ///
     /// # Parameters
     ///
     /// * `_req: &Request<'_>` - The `fuser::Request` datastructure representing the request to the
     ///   filesystem.
-    /// * `ino: u64` - The inode number of the file to read.
-    /// * `fh: u64` - The file handle.
-    /// * `offset: i64`
-    /// * `data: &[u8]` - The data to write as bytes.
-    /// * `write_flags: u32` - Specific flags to set while writing. (not used in this
-    ///   implementation)
-    /// * `flags: i32` - Additional flags. (not used in this implementation)
-    /// * `lock_owner: Option<u64>`
-    /// * `reply: ReplyWrite` - A `fuser::ReplyWrite` instance.
+    /// * `ino: u64` - The inode number of the file or directory.
+    /// * `_fh: u64` - The handle the file was opened with. (not used in this implementation)
+    /// * `_flags: u32` - The flags of the request. (not used in this implementation)
+    /// * `cmd: u32` - The `ioctl` to run.
+    /// * `in_data: &[u8]` - The argument passed to the `ioctl`.
+    /// * `_out_size: u32` - The size of the result expected. (not used in this implementation)
+    /// * `reply: ReplyIoctl` - A `fuser::ReplyIoctl` instance.
     ///
     /// # Returns
     ///
-    /// This function does not return a value. It responds to the request with a Reply or an error
-    /// code if the requested inode cannot be written to.
-    fn write(
+    /// This function does not return a value. It responds to the request with the attributes, or
+    /// `ENOTTY` if the `ioctl` is not supported.
+    fn ioctl(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
         _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
     ) {
-        // Get path for given inode.
-        let path = self.inode_map.lock().unwrap().get(&ino).cloned().unwrap();
-        let fs = self.fs.lock().unwrap();
-        match fs.root_dir().open_file(path.to_str().unwrap()) {
-            Ok(mut file) => {
-                file.seek(SeekFrom::Start(offset as u64)).unwrap();
-                file.write_all(data).unwrap();
-                reply.written(data.len() as u32)
+        self.begin_request("ioctl");
+        if cmd != FAT_IOCTL_GET_ATTRIBUTES && cmd != FAT_IOCTL_SET_ATTRIBUTES {
+            reply.error(self.failed(ENOTTY));
+            return;
+        }
+
+        let (path, attributes) = match self.fat_attributes(ino) {
+            Ok(found) => found,
+            Err(e) => {
+                reply.error(self.failed(e));
+                return;
+            }
+        };
+
+        if cmd == FAT_IOCTL_GET_ATTRIBUTES {
+            reply.ioctl(0, &(attributes.bits() as u32).to_ne_bytes());
+            return;
+        }
+
+        let requested = match in_data.try_into() {
+            Ok(bytes) => FileAttributes::from_bits_truncate(u32::from_ne_bytes(bytes) as u8),
+            Err(_) => {
+                reply.error(self.failed(EINVAL));
+                return;
+            }
+        };
+        // Like the `vfat` driver, keep the bits that tell files and directories apart.
+        let fixed = FileAttributes::DIRECTORY | FileAttributes::VOLUME_ID;
+        if requested - fixed == attributes - fixed {
+            reply.ioctl(0, &[]);
+            return;
+        }
+        match self.set_fat_attributes(&path, (attributes & fixed) | (requested - fixed)) {
+            Ok(()) => reply.ioctl(0, &[]),
+            Err(e) => reply.error(self.failed(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+    use libc::O_RDWR;
+
+    /// Open a handle on a file, like the kernel does after looking it up.
+    fn open(fs: &FatFilesystem, path: &str) -> u64 {
+        let path = Path::new(path);
+        fs.open_handle(fs.get_or_create_inode(path), path, O_RDWR)
+    }
+
+    /// Read from a file, as `read` does.
+    fn read_file(fs: &FatFilesystem, path: &Path, offset: u64, size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        fs.read_chunked(path, offset, size, &mut data).unwrap();
+        data
+    }
+
+    /// Make up the contents of a file, different at every offset of a few chunks.
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn reading_in_pages_gives_the_contents() {
+        // Larger than a chunk, and not a multiple of the page size.
+        let data = contents(CHUNK_SIZE + 70_000);
+        for fat_type in [FatType::Fat12, FatType::Fat32] {
+            let image = TestImage::with_files(fat_type, &[("big.bin", &data)]);
+            let fs = FatFilesystem::new(image.options()).unwrap();
+            let path = Path::new("/big.bin");
+
+            // The kernel reads files page by page.
+            let mut read = Vec::new();
+            loop {
+                let page = read_file(&fs, path, read.len() as u64, 4096);
+                if page.is_empty() {
+                    break;
+                }
+                assert!(page.len() == 4096 || read.len() + page.len() == data.len());
+                read.extend(page);
+            }
+            assert_eq!(read, data);
+
+            // Reads across the end of a chunk, and past the end of the file.
+            let offset = CHUNK_SIZE - 100;
+            assert_eq!(
+                read_file(&fs, path, offset as u64, 4096),
+                &data[offset..offset + 4096]
+            );
+            assert_eq!(read_file(&fs, path, 0, data.len() + 4096), data);
+            assert!(read_file(&fs, path, data.len() as u64 + 1, 4096).is_empty());
+        }
+    }
+
+    #[test]
+    fn reads_reuse_the_scratch_buffer_of_the_handle() {
+        let data = contents(3 * CHUNK_SIZE);
+        let image = TestImage::with_files(FatType::Fat32, &[("big.bin", &data)]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let path = Path::new("/big.bin");
+        let fh = open(&fs, "/big.bin");
+
+        let mut scratch = fs.take_scratch(fh);
+        fs.read_chunked(path, 0, 2 * CHUNK_SIZE, &mut scratch)
+            .unwrap();
+        assert_eq!(scratch, &data[..2 * CHUNK_SIZE]);
+        let buffer = scratch.as_ptr();
+        fs.keep_scratch(fh, scratch);
+
+        // The next read fills the same buffer, and only as far as the file goes.
+        let mut scratch = fs.take_scratch(fh);
+        assert_eq!(scratch.as_ptr(), buffer);
+        fs.read_chunked(
+            path,
+            2 * CHUNK_SIZE as u64 + 10,
+            2 * CHUNK_SIZE,
+            &mut scratch,
+        )
+        .unwrap();
+        assert_eq!(scratch, &data[2 * CHUNK_SIZE + 10..]);
+        assert_eq!(scratch.as_ptr(), buffer);
+        fs.keep_scratch(fh, scratch);
+        assert!(fs.close_handle(2, fh));
+        assert!(fs.take_scratch(fh).is_empty());
+    }
+
+    #[test]
+    fn stat_reports_sizes_from_the_directory_entries() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "empty"},
+                {"name": "small.txt", "size": 5, "fill": "hello"},
+                {"name": "page.bin", "size": 4097, "fill": 1},
+                {"name": "docs", "entries": []}
+            ]}"#,
+        );
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let volume = fs.fs.lock().unwrap();
+        let stat = |path: &str| fs.attr(&volume, 2, Path::new(path)).unwrap();
+
+        for (path, size, blocks) in [
+            ("/empty", 0, 0),
+            ("/small.txt", 5, 1),
+            ("/page.bin", 4097, 9),
+        ] {
+            let attr = stat(path);
+            assert_eq!(
+                (attr.kind, attr.size, attr.blocks),
+                (FileType::RegularFile, size, blocks)
+            );
+            assert_eq!((attr.perm, attr.nlink), (0o644, 1));
+        }
+        let attr = stat("/docs");
+        assert_eq!(
+            (attr.kind, attr.size, attr.perm, attr.nlink),
+            (FileType::Directory, 0, 0o755, 2)
+        );
+        assert_eq!((attr.uid, attr.gid), unsafe {
+            (libc::getuid(), libc::getgid())
+        });
+        assert_eq!(attr.ino, 2);
+        assert!(fs.attr(&volume, 3, Path::new("/missing")).is_err());
+    }
+
+    #[test]
+    fn stat_reports_timestamps_from_the_directory_entries() {
+        let image = TestImage::with_files(FatType::Fat32, &[("a.txt", b"a")]);
+        // FAT keeps modification times to two seconds, creation times to ten milliseconds, and
+        // access times as a date only.
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let created = UNIX_EPOCH + Duration::from_millis(1_500_000_001_230);
+        {
+            let volume = image.volume();
+            let mut file = volume.root_dir().open_file("a.txt").unwrap();
+            #[allow(deprecated)]
+            {
+                file.set_modified(system_to_fat_time(modified));
+                file.set_created(system_to_fat_time(created));
+                file.set_accessed(system_to_fat_time(modified).date);
             }
-            Err(_) => reply.error(libc::ENOENT),
+            file.flush().unwrap();
+        }
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let volume = fs.fs.lock().unwrap();
+        let attr = fs.attr(&volume, 2, Path::new("/a.txt")).unwrap();
+
+        assert_eq!(attr.mtime, modified);
+        assert_eq!(attr.ctime, modified);
+        assert_eq!(attr.crtime, created);
+        let day = modified.duration_since(attr.atime).unwrap();
+        assert!(day < Duration::from_secs(24 * 60 * 60));
+        assert_eq!(system_to_fat_time(attr.atime).time.hour, 0);
+    }
+
+    /// List the names of the entries of a directory, as `readdir` does.
+    fn names(fs: &FatFilesystem, ino: u64) -> Vec<String> {
+        let mut names: Vec<String> = fs
+            .list_dir(ino)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn nested_files_and_directories_are_created_and_deleted() {
+        let image = TestImage::new(FatType::Fat16);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+
+        let (docs, _, attr) = fs.create_dir(1, OsStr::new("docs"), 0o755).unwrap();
+        assert_eq!(attr.kind, FileType::Directory);
+        let (sub, path, _) = fs.create_dir(docs, OsStr::new("sub"), 0o755).unwrap();
+        assert_eq!(path, Path::new("/docs/sub"));
+        let (_, path, attr) = fs.create_file(sub, OsStr::new("note.txt"), 0o644).unwrap();
+        assert_eq!(path, Path::new("/docs/sub/note.txt"));
+        assert_eq!((attr.kind, attr.size), (FileType::RegularFile, 0));
+        assert_eq!(fs.create_dir(docs, OsStr::new("sub"), 0o755), Err(EEXIST));
+        assert_eq!(
+            fs.create_file(sub, OsStr::new("note.txt"), 0o644).err(),
+            Some(EEXIST)
+        );
+
+        assert_eq!(names(&fs, 1), ["docs"]);
+        assert_eq!(names(&fs, docs), [".", "..", "sub"]);
+        assert_eq!(names(&fs, sub), [".", "..", "note.txt"]);
+
+        assert_eq!(
+            fs.remove_entry(docs, OsStr::new("sub"), true),
+            Err(ENOTEMPTY)
+        );
+        assert_eq!(
+            fs.remove_entry(sub, OsStr::new("note.txt"), true),
+            Err(ENOTDIR)
+        );
+        assert_eq!(fs.remove_entry(docs, OsStr::new("sub"), false), Err(EISDIR));
+        assert_eq!(fs.remove_entry(sub, OsStr::new("note.txt"), false), Ok(()));
+        assert_eq!(names(&fs, sub), [".", ".."]);
+        assert_eq!(fs.remove_entry(docs, OsStr::new("sub"), true), Ok(()));
+        assert_eq!(names(&fs, docs), [".", ".."]);
+        assert_eq!(fs.remove_entry(docs, OsStr::new("sub"), true), Err(ENOENT));
+        drop(fs);
+
+        // The changes reached the disk image.
+        let volume = image.volume();
+        let dir = volume.root_dir().open_dir("docs").unwrap();
+        assert_eq!(dir.iter().count(), 2);
+    }
+
+    #[test]
+    fn directory_times_are_set_to_fat_precision() {
+        let image = TestImage::new(FatType::Fat16);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+        let (docs, _, _) = fs.create_dir(1, OsStr::new("docs"), 0o755).unwrap();
+        fs.create_dir(docs, OsStr::new("sub"), 0o755).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_millis(1_600_000_001_500);
+        let created = UNIX_EPOCH + Duration::from_millis(1_500_000_001_234);
+
+        let volume = fs.fs.lock().unwrap();
+        let path = Path::new("/docs/sub");
+        fs.set_dir_times(
+            &volume,
+            path,
+            Some(TimeOrNow::SpecificTime(modified)),
+            Some(TimeOrNow::SpecificTime(modified)),
+            Some(created),
+        )
+        .unwrap();
+        let attr = fs.attr(&volume, 3, path).unwrap();
+        assert_eq!(attr.mtime, modified - Duration::from_millis(1_500));
+        assert_eq!(attr.crtime, created - Duration::from_millis(4));
+        assert_eq!(
+            system_to_fat_time(attr.atime).date,
+            system_to_fat_time(modified).date
+        );
+
+        // Listing the directory keeps its times, unlike creating a file in it.
+        drop(volume);
+        fs.list_dir(3).unwrap();
+        let volume = fs.fs.lock().unwrap();
+        assert_eq!(fs.attr(&volume, 3, path).unwrap().mtime, attr.mtime);
+        drop(volume);
+        fs.create_file(3, OsStr::new("a.txt"), 0o644).unwrap();
+        let volume = fs.fs.lock().unwrap();
+        assert!(fs.attr(&volume, 3, path).unwrap().mtime > attr.mtime);
+        // The parent directory is left alone.
+        assert_ne!(
+            fs.attr(&volume, 2, Path::new("/docs")).unwrap().mtime,
+            attr.mtime
+        );
+        // The root directory has no entry to keep them in.
+        assert_eq!(
+            fs.set_dir_times(&volume, Path::new("/"), None, Some(TimeOrNow::Now), None),
+            Ok(())
+        );
+    }
+
+    /// Get the FAT attributes of an entry.
+    fn attributes(fs: &FatFilesystem, path: &str) -> FileAttributes {
+        find_entry(&fs.fs.lock().unwrap(), Path::new(path))
+            .unwrap()
+            .attributes()
+    }
+
+    #[test]
+    fn modes_without_write_permission_make_entries_read_only() {
+        let image = TestImage::new(FatType::Fat16);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+
+        // Like `install -m 444`.
+        let (ino, path, attr) = fs.create_file(1, OsStr::new("a.txt"), 0o444).unwrap();
+        assert_eq!(attr.perm, 0o444);
+        assert!(attributes(&fs, "/a.txt").contains(FileAttributes::READ_ONLY));
+        // The file can still be written through the handle it was created with.
+        let fh = fs.open_handle(ino, &path, O_RDWR);
+        assert_eq!(fs.write_handle(fh, 0, b"data"), Ok(()));
+        assert_eq!(read_file(&fs, &path, 0, 16), b"data");
+
+        // Like creating it with umask 077, or any other write permission.
+        let (_, _, attr) = fs.create_file(1, OsStr::new("b.txt"), 0o600).unwrap();
+        assert_eq!(attr.perm, 0o644);
+        assert!(!attributes(&fs, "/b.txt").contains(FileAttributes::READ_ONLY));
+
+        let (_, _, attr) = fs.create_dir(1, OsStr::new("docs"), 0o555).unwrap();
+        assert_eq!(attr.perm, 0o555);
+        assert!(attributes(&fs, "/docs").contains(FileAttributes::READ_ONLY));
+    }
+
+    #[test]
+    fn chmod_sets_and_clears_the_read_only_attribute() {
+        let image = TestImage::with_files(FatType::Fat12, &[("a.txt", b"a")]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let path = Path::new("/a.txt");
+        let perm = || fs.attr(&fs.fs.lock().unwrap(), 2, path).unwrap().perm;
+
+        let before = attributes(&fs, "/a.txt");
+        assert_eq!(fs.apply_mode(path, 0o444), Ok(()));
+        assert_eq!(perm(), 0o444);
+        // Like `chmod +w`, and the other attributes are kept.
+        assert_eq!(fs.apply_mode(path, 0o644), Ok(()));
+        assert_eq!(perm(), 0o644);
+        assert_eq!(attributes(&fs, "/a.txt"), before);
+        assert_eq!(fs.apply_mode(Path::new("/"), 0o555), Ok(()));
+    }
+
+    #[test]
+    fn dotfiles_hidden_hides_new_dotfiles() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.txt", b"a")]);
+        let options = Options {
+            dotfiles_hidden: true,
+            ..image.options()
+        };
+        let mut fs = FatFilesystem::new(options).unwrap();
+        let hidden =
+            |fs: &FatFilesystem, path| attributes(fs, path).contains(FileAttributes::HIDDEN);
+
+        fs.create_file(1, OsStr::new(".gitignore"), 0o644).unwrap();
+        fs.create_dir(1, OsStr::new(".config"), 0o755).unwrap();
+        fs.create_file(1, OsStr::new("b.txt"), 0o644).unwrap();
+        assert!(hidden(&fs, "/.gitignore"));
+        assert!(hidden(&fs, "/.config"));
+        assert!(!hidden(&fs, "/b.txt"));
+        // They are still listed, by their own names.
+        assert_eq!(names(&fs, 1), [".config", ".gitignore", "a.txt", "b.txt"]);
+
+        fs.rename_entry(1, OsStr::new("a.txt"), 1, OsStr::new(".a.txt"), 0)
+            .unwrap();
+        assert!(hidden(&fs, "/.a.txt"));
+        fs.rename_entry(1, OsStr::new(".a.txt"), 1, OsStr::new("a.txt"), 0)
+            .unwrap();
+        assert!(!hidden(&fs, "/a.txt"));
+        fs.rename_entry(1, OsStr::new(".config"), 1, OsStr::new(".settings"), 0)
+            .unwrap();
+        assert!(hidden(&fs, "/.settings"));
+    }
+
+    #[test]
+    fn renames_keep_the_hidden_attribute_of_other_entries() {
+        let image = TestImage::with_files(FatType::Fat16, &[("desktop.ini", b"")]);
+        let options = Options {
+            dotfiles_hidden: true,
+            ..image.options()
+        };
+        let fs = FatFilesystem::new(options).unwrap();
+        let path = Path::new("/desktop.ini");
+        fs.set_fat_attributes(
+            path,
+            attributes(&fs, "/desktop.ini") | FileAttributes::HIDDEN,
+        )
+        .unwrap();
+
+        fs.rename_entry(
+            1,
+            OsStr::new("desktop.ini"),
+            1,
+            OsStr::new("Desktop.ini"),
+            0,
+        )
+        .unwrap();
+        assert!(attributes(&fs, "/Desktop.ini").contains(FileAttributes::HIDDEN));
+    }
+
+    #[test]
+    fn dotfiles_hidden_composes_with_hidden_hide() {
+        let image = TestImage::new(FatType::Fat12);
+        let options = Options {
+            dotfiles_hidden: true,
+            hidden: HiddenBehavior::Hide,
+            ..image.options()
+        };
+        let mut fs = FatFilesystem::new(options).unwrap();
+
+        fs.create_file(1, OsStr::new(".profile"), 0o644).unwrap();
+        fs.create_file(1, OsStr::new("notes"), 0o644).unwrap();
+        assert_eq!(names(&fs, 1), ["notes"]);
+    }
+
+    #[test]
+    fn changes_to_names_are_passed_on_to_the_kernel() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.txt", b"a"), ("b.txt", b"b")]);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+        let invalidations = fs.invalidations();
+        let entry = |parent, name: &str| Invalidation::Entry(parent, OsString::from(name));
+
+        let (docs, _, _) = fs.create_dir(1, OsStr::new("docs"), 0o755).unwrap();
+        fs.create_file(docs, OsStr::new("c.txt"), 0o644).unwrap();
+        fs.rename_entry(1, OsStr::new("a.txt"), docs, OsStr::new("c.txt"), 0)
+            .unwrap();
+        fs.remove_entry(1, OsStr::new("b.txt"), false).unwrap();
+        // Failed changes change nothing.
+        assert!(fs.remove_entry(1, OsStr::new("b.txt"), false).is_err());
+
+        assert_eq!(
+            invalidations.try_iter().collect::<Vec<_>>(),
+            [
+                entry(1, "docs"),
+                entry(docs, "c.txt"),
+                entry(1, "a.txt"),
+                entry(docs, "c.txt"),
+                entry(1, "b.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncating_on_open_is_passed_on_to_the_kernel() {
+        let image = TestImage::with_files(FatType::Fat12, &[("a.txt", b"data")]);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+        let invalidations = fs.invalidations();
+        let path = Path::new("/a.txt");
+
+        fs.truncate_on_open(2, path, O_RDWR).unwrap();
+        assert!(invalidations.try_recv().is_err());
+        fs.truncate_on_open(2, path, O_RDWR | O_TRUNC).unwrap();
+        assert_eq!(invalidations.try_recv(), Ok(Invalidation::Inode(2, 0, 0)));
+        assert!(read_file(&fs, path, 0, 16).is_empty());
+    }
+
+    #[test]
+    fn handles_follow_renamed_files() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.txt", b"old")]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let fh = open(&fs, "/a.txt");
+
+        // nuFAT serves the requests of all processes one at a time, so another process renaming
+        // the file gets in between two writes.
+        fs.rename_entry(1, OsStr::new("a.txt"), 1, OsStr::new("b.txt"), 0)
+            .unwrap();
+        fs.write_handle(fh, 0, b"new").unwrap();
+
+        assert_eq!(fs.handle_path(fh), Ok(PathBuf::from("/b.txt")));
+        assert_eq!(read_file(&fs, Path::new("/b.txt"), 0, 16), b"new");
+        assert!(find_entry(&fs.fs.lock().unwrap(), Path::new("/a.txt")).is_err());
+    }
+
+    #[test]
+    fn handles_on_replaced_files_go_stale() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.txt", b"a"), ("b.txt", b"b")]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let fh = open(&fs, "/b.txt");
+
+        fs.rename_entry(1, OsStr::new("a.txt"), 1, OsStr::new("b.txt"), 0)
+            .unwrap();
+
+        assert_eq!(fs.write_handle(fh, 0, b"x"), Err(ESTALE));
+        assert_eq!(fs.flush_handle(fh), Ok(()));
+        assert_eq!(read_file(&fs, Path::new("/b.txt"), 0, 16), b"a");
+    }
+
+    #[test]
+    fn handles_on_deleted_files_miss_new_files() {
+        let image = TestImage::with_files(FatType::Fat12, &[("a.txt", b"a")]);
+        let mut fs = FatFilesystem::new(image.options()).unwrap();
+        let fh = open(&fs, "/a.txt");
+
+        fs.remove_entry(1, OsStr::new("a.txt"), false).unwrap();
+        fs.create_file(1, OsStr::new("a.txt"), 0o644).unwrap();
+
+        assert_eq!(fs.handle_path(fh), Err(ESTALE));
+        assert_eq!(fs.write_handle(fh, 0, b"x"), Err(ESTALE));
+        assert!(read_file(&fs, Path::new("/a.txt"), 0, 16).is_empty());
+    }
+
+    #[test]
+    fn open_files_can_be_deleted_by_default() {
+        let image = TestImage::with_files(
+            FatType::Fat16,
+            &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")],
+        );
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        open(&fs, "/a.txt");
+        open(&fs, "/b.txt");
+
+        assert_eq!(fs.remove_entry(1, OsStr::new("a.txt"), false), Ok(()));
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("c.txt"), 1, OsStr::new("b.txt"), 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn deny_delete_open_refuses_deleting_open_files() {
+        let image = TestImage::with_files(
+            FatType::Fat16,
+            &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")],
+        );
+        let options = Options {
+            deny_delete_open: true,
+            ..image.options()
         };
+        let fs = FatFilesystem::new(options).unwrap();
+        let fh = open(&fs, "/a.txt");
+        let ino = fs.get_or_create_inode(Path::new("/a.txt"));
+
+        assert_eq!(fs.remove_entry(1, OsStr::new("a.txt"), false), Err(EBUSY));
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("b.txt"), 1, OsStr::new("a.txt"), 0),
+            Err(EBUSY)
+        );
+        // Renaming the open file itself is fine.
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("a.txt"), 1, OsStr::new("d.txt"), 0),
+            Ok(())
+        );
+        assert_eq!(fs.remove_entry(1, OsStr::new("d.txt"), false), Err(EBUSY));
+        assert_eq!(read_file(&fs, Path::new("/d.txt"), 0, 16), b"a");
+
+        assert!(fs.close_handle(ino, fh));
+        assert_eq!(fs.remove_entry(1, OsStr::new("d.txt"), false), Ok(()));
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("b.txt"), 1, OsStr::new("c.txt"), 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn listing_every_directory_stays_within_the_cache_limit() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "directory {}", "repeat": 40, "entries": [
+                    {"name": "a file with a long name {}.txt", "repeat": 50}
+                ]}
+            ]}"#,
+        );
+        let limit = 64 * 1024;
+        let fs = FatFilesystem::new(Options {
+            cache_limit: limit,
+            ..image.options()
+        })
+        .unwrap();
+
+        // Every directory is opened and read, and none of them is released.
+        let mut handles = Vec::new();
+        for i in 1..=40 {
+            let ino = fs.get_or_create_inode(Path::new(&format!("/directory {}", i)));
+            let fh = fs.next_fh.fetch_add(1, Ordering::SeqCst);
+            fs.caches.lock().unwrap().open_listing(fh);
+            fs.refresh_listing(ino, fh).unwrap();
+            assert!(fs.caches.lock().unwrap().usage().total() <= limit);
+            handles.push((ino, fh));
+        }
+
+        // The first listings were evicted, and are taken again when their directory is read on.
+        let (ino, fh) = handles[0];
+        assert!(fs.caches.lock().unwrap().lacks_listing(fh));
+        fs.refresh_listing(ino, fh).unwrap();
+        assert_eq!(fs.caches.lock().unwrap().listing(fh).unwrap().len(), 52);
+        assert!(fs.caches.lock().unwrap().usage().total() <= limit);
     }
 
-    // TODO: mkdir, etc.
+    #[test]
+    fn listing_a_directory_creates_no_inodes() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "photos", "entries": [
+                    {"name": "IMG_{}.JPG", "repeat": 500, "size": 1}
+                ]}
+            ]}"#,
+        );
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let photos = fs.get_or_create_inode(Path::new("/photos"));
+        let entries = fs.list_dir(photos).unwrap();
+        assert_eq!(entries.len(), 502);
+        assert_eq!(fs.inode_map.lock().unwrap().len(), 2);
+
+        // Entries are listed with their inode once they have one, and never with another's.
+        assert_eq!(entries[0].attr.ino, photos);
+        let inos: std::collections::HashSet<u64> =
+            entries[2..].iter().map(|entry| entry.attr.ino).collect();
+        assert_eq!(inos.len(), 500);
+        assert!(inos.iter().all(|ino| ino >> 63 == 1));
+        let ino = fs.get_or_create_inode(Path::new("/photos/IMG_7.JPG"));
+        let entries = fs.list_dir(photos).unwrap();
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == "IMG_7.JPG")
+            .unwrap();
+        assert_eq!(entry.attr.ino, ino);
+        assert_eq!(fs.inode_map.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn shortnames_only_creates_short_names() {
+        let image = TestImage::new(FatType::Fat16);
+        let mut fs = FatFilesystem::new(Options {
+            shortnames: true,
+            ..image.options()
+        })
+        .unwrap();
+        let long_name = OsStr::new("Long Name.txt");
+        assert_eq!(fs.create_file(1, long_name, 0o644).err(), Some(EINVAL));
+        assert_eq!(
+            fs.create_dir(1, OsStr::new("My Documents"), 0o755).err(),
+            Some(EINVAL)
+        );
+        let (_, path, _) = fs.create_file(1, OsStr::new("readme.txt"), 0o644).unwrap();
+        assert_eq!(path, Path::new("/README.TXT"));
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("README.TXT"), 1, long_name, 0),
+            Err(EINVAL)
+        );
+        assert_eq!(names(&fs, 1), ["README.TXT"]);
+        drop(fs);
+
+        // With `shortnames=convert`, short names are made up instead.
+        let mut fs = FatFilesystem::new(Options {
+            shortnames: true,
+            shortnames_convert: true,
+            ..image.options()
+        })
+        .unwrap();
+        let (_, path, _) = fs.create_file(1, long_name, 0o644).unwrap();
+        assert_eq!(path, Path::new("/LONGNA~1.TXT"));
+        let (_, path, _) = fs
+            .create_file(1, OsStr::new("Long Names.txt"), 0o644)
+            .unwrap();
+        assert_eq!(path, Path::new("/LONGNA~2.TXT"));
+        let (_, path, _) = fs.create_dir(1, OsStr::new("My Documents"), 0o755).unwrap();
+        assert_eq!(path, Path::new("/MYDOCU~1"));
+        assert_eq!(
+            fs.rename_entry(1, OsStr::new("README.TXT"), 1, OsStr::new("Read Me.txt"), 0),
+            Ok(())
+        );
+        assert_eq!(
+            names(&fs, 1),
+            ["LONGNA~1.TXT", "LONGNA~2.TXT", "MYDOCU~1", "README~1.TXT"]
+        );
+        drop(fs);
+
+        let volume = image.volume();
+        for entry in volume.root_dir().iter() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.file_name(), entry.short_file_name());
+        }
+    }
+
+    #[test]
+    fn writes_are_synced_by_the_flush_deadline_while_the_file_is_open() {
+        let image = TestImage::with_files(FatType::Fat16, &[("log.txt", b"")]);
+        let fs = FatFilesystem::new(Options {
+            flush: true,
+            flush_deadline: Some(Duration::from_secs(5)),
+            ..image.options()
+        })
+        .unwrap();
+        let activity = fs.activity();
+        let fh = open(&fs, "/log.txt");
+        assert!(!activity.flush_overdue(Duration::ZERO).unwrap());
+
+        fs.write_handle(fh, 0, b"first").unwrap();
+        assert!(!activity.flush_overdue(Duration::from_secs(5)).unwrap());
+        // Later writes don't put off the deadline of the first one.
+        *activity.unsynced_since.lock().unwrap() = Some(Instant::now() - Duration::from_secs(6));
+        fs.write_handle(fh, 5, b" second").unwrap();
+        assert!(activity.flush_overdue(Duration::from_secs(5)).unwrap());
+        assert!(!activity.flush_overdue(Duration::ZERO).unwrap());
+        assert_eq!(
+            read_file(&fs, Path::new("/log.txt"), 0, 32),
+            b"first second"
+        );
+    }
+
+    #[test]
+    fn fallocate_checks_the_range_and_the_handle() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.bin", b"data"), ("b.bin", b"")]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let fh = open(&fs, "/a.bin");
+        assert_eq!(fs.allocate_handle(1000, fh, -1, 10), Err(EINVAL));
+        assert_eq!(fs.allocate_handle(1000, fh, 0, 0), Err(EINVAL));
+        assert_eq!(fs.allocate_handle(1000, fh, 0, -10), Err(EINVAL));
+        assert_eq!(fs.allocate_handle(1000, fh, i64::MAX, 1), Err(EFBIG));
+        assert_eq!(fs.allocate_handle(1000, fh, 0, 1 << 32), Err(EFBIG));
+        assert_eq!(fs.allocate_handle(1000, fh, 2, 4094), Ok(()));
+        assert_eq!(read_file(&fs, Path::new("/a.bin"), 0, 8192).len(), 4096);
+
+        let path = Path::new("/b.bin");
+        let read_only = fs.open_handle(fs.get_or_create_inode(path), path, O_RDONLY);
+        assert_eq!(fs.allocate_handle(1000, read_only, 0, 10), Err(EBADF));
+        let fh = open(&fs, "/b.bin");
+        fs.set_fat_attributes(path, FileAttributes::READ_ONLY)
+            .unwrap();
+        assert_eq!(fs.allocate_handle(1000, fh, 0, 10), Err(EACCES));
+        assert_eq!(fs.allocate_handle(0, fh, 0, 10), Ok(()));
+        fs.set_fat_attributes(path, FileAttributes::SYSTEM).unwrap();
+        assert_eq!(fs.allocate_handle(0, fh, 0, 20), Err(EPERM));
+        assert_eq!(read_file(&fs, path, 0, 64).len(), 10);
+    }
+
+    #[test]
+    fn entries_whose_attributes_fail_are_removed() {
+        let image = TestImage::with_files(FatType::Fat16, &[("a.bin", b"data")]);
+        let fs = FatFilesystem::new(image.options()).unwrap();
+        let path = Path::new("/a.bin");
+        assert_eq!(fs.apply_new_attributes(path, 0o644), Ok(()));
+        fs.set_fat_attributes(path, FileAttributes::SYSTEM).unwrap();
+        assert_eq!(fs.apply_new_attributes(path, 0o444), Err(EPERM));
+        assert!(find_entry(&fs.fs.lock().unwrap(), path).is_err());
+    }
 }
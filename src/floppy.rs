@@ -0,0 +1,420 @@
+//! This module works around the boot sectors of old floppy disk images. Floppies formatted by DOS
+//! 1.x have no BIOS parameter block at all, and others lack the boot sector signature `fatfs`
+//! insists on, like those of the Atari ST, so nuFAT makes up what is missing from the size of the
+//! image and the media descriptor in the FAT. The boot sector on the image itself is never changed.
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+/// The size of a boot sector.
+pub const BOOT_SECTOR_SIZE: usize = 512;
+
+/// The checksum of the boot sectors an Atari ST runs on boot.
+pub const ATARI_EXECUTABLE: u16 = 0x1234;
+
+/// Represent the layout of a standard floppy disk format.
+///
+/// # Members
+///
+/// * `size: u64` - The size of the image in bytes.
+/// * `media: u8` - The media descriptor, which is also the first byte of the FAT.
+/// * `sectors_per_cluster: u8` - The number of 512 byte sectors per cluster.
+/// * `root_entries: u16` - The number of entries of the root directory.
+/// * `sectors_per_fat: u16` - The number of sectors of each of the two FATs.
+/// * `sectors_per_track: u16` - The number of sectors per track.
+/// * `heads: u16` - The number of sides.
+struct Geometry {
+    size: u64,
+    media: u8,
+    sectors_per_cluster: u8,
+    root_entries: u16,
+    sectors_per_fat: u16,
+    sectors_per_track: u16,
+    heads: u16,
+}
+
+/// The standard formats of 5.25" and 3.5" floppies, from 160 KiB to 2.88 MiB.
+const GEOMETRIES: [Geometry; 8] = [
+    Geometry {
+        size: 160 * 1024,
+        media: 0xFE,
+        sectors_per_cluster: 1,
+        root_entries: 64,
+        sectors_per_fat: 1,
+        sectors_per_track: 8,
+        heads: 1,
+    },
+    Geometry {
+        size: 180 * 1024,
+        media: 0xFC,
+        sectors_per_cluster: 1,
+        root_entries: 64,
+        sectors_per_fat: 2,
+        sectors_per_track: 9,
+        heads: 1,
+    },
+    Geometry {
+        size: 320 * 1024,
+        media: 0xFF,
+        sectors_per_cluster: 2,
+        root_entries: 112,
+        sectors_per_fat: 1,
+        sectors_per_track: 8,
+        heads: 2,
+    },
+    Geometry {
+        size: 360 * 1024,
+        media: 0xFD,
+        sectors_per_cluster: 2,
+        root_entries: 112,
+        sectors_per_fat: 2,
+        sectors_per_track: 9,
+        heads: 2,
+    },
+    Geometry {
+        size: 720 * 1024,
+        media: 0xF9,
+        sectors_per_cluster: 2,
+        root_entries: 112,
+        sectors_per_fat: 3,
+        sectors_per_track: 9,
+        heads: 2,
+    },
+    Geometry {
+        size: 1200 * 1024,
+        media: 0xF9,
+        sectors_per_cluster: 1,
+        root_entries: 224,
+        sectors_per_fat: 7,
+        sectors_per_track: 15,
+        heads: 2,
+    },
+    Geometry {
+        size: 1440 * 1024,
+        media: 0xF0,
+        sectors_per_cluster: 1,
+        root_entries: 224,
+        sectors_per_fat: 9,
+        sectors_per_track: 18,
+        heads: 2,
+    },
+    Geometry {
+        size: 2880 * 1024,
+        media: 0xF0,
+        sectors_per_cluster: 2,
+        root_entries: 240,
+        sectors_per_fat: 9,
+        sectors_per_track: 36,
+        heads: 2,
+    },
+];
+
+/// Read the boot sector of the volume as `fatfs` and nuFAT should see it.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<[u8; BOOT_SECTOR_SIZE]>` - The boot sector, as patched by `patched_boot_sector`
+///   if needed.
+pub fn boot_sector(image: &File) -> io::Result<[u8; BOOT_SECTOR_SIZE]> {
+    match patched_boot_sector(image)? {
+        Some(sector) => Ok(sector),
+        None => {
+            let mut sector = [0; BOOT_SECTOR_SIZE];
+            image.read_exact_at(&mut sector, 0)?;
+            Ok(sector)
+        }
+    }
+}
+
+/// Make up the missing parts of the boot sector of an old floppy disk image.
+///
+/// A boot sector with a usable BIOS parameter block only gets the boot sector signature if it is
+/// missing. Without one, the parameters of the standard format with the size of the image are
+/// used, as long as the media descriptor in the FAT agrees. The fields after the BIOS parameter
+/// block of DOS 3.0 are only valid with an extended boot signature, and are cleared otherwise, as
+/// they may hold boot code instead, which they always do on the Atari ST.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Option<[u8; BOOT_SECTOR_SIZE]>>` - The patched boot sector, or `None` if the boot
+///   sector on the image can be used as it is, or is no FAT boot sector nuFAT can make sense of.
+pub fn patched_boot_sector(image: &File) -> io::Result<Option<[u8; BOOT_SECTOR_SIZE]>> {
+    let size = image.metadata()?.len();
+    if size < 2 * BOOT_SECTOR_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut sector = [0; BOOT_SECTOR_SIZE];
+    image.read_exact_at(&mut sector, 0)?;
+    let mut fat_start = [0; 3];
+    let u16_at =
+        |sector: &[u8], offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+
+    let mut patched = sector;
+    if has_usable_bpb(&sector) {
+        // The fields after the BPB are only valid with an extended boot signature, or on FAT32.
+        let extended = !is_atari(&sector)
+            && (matches!(sector[0x26], 0x28 | 0x29) || u16_at(&sector, 0x16) == 0);
+        if sector[510..512] == [0x55, 0xAA] && extended {
+            return Ok(None);
+        }
+        // The FAT has to start with a media descriptor, or this is no FAT volume. The Atari ST
+        // doesn't care whether it matches the one in the BPB.
+        let fat_offset = u16_at(&sector, 0x0E) as u64 * u16_at(&sector, 0x0B) as u64;
+        image.read_exact_at(&mut fat_start, fat_offset)?;
+        if fat_start[0] < 0xF0 || fat_start[1..] != [0xFF, 0xFF] {
+            return Ok(None);
+        }
+        if !extended && u16_at(&sector, 0x13) != 0 {
+            // Only the 16 bit number of hidden sectors, and nothing after it, is valid.
+            patched[0x1E..0x3E].fill(0);
+        } else if !extended {
+            patched[0x24..0x3E].fill(0);
+        }
+    } else {
+        image.read_exact_at(&mut fat_start, BOOT_SECTOR_SIZE as u64)?;
+        let geometry = match GEOMETRIES
+            .iter()
+            .find(|geometry| geometry.size == size && [geometry.media, 0xFF, 0xFF] == fat_start)
+        {
+            Some(geometry) => geometry,
+            None => return Ok(None),
+        };
+        patched[0x0B..0x0D].copy_from_slice(&(BOOT_SECTOR_SIZE as u16).to_le_bytes());
+        patched[0x0D] = geometry.sectors_per_cluster;
+        patched[0x0E..0x10].copy_from_slice(&1u16.to_le_bytes());
+        patched[0x10] = 2;
+        patched[0x11..0x13].copy_from_slice(&geometry.root_entries.to_le_bytes());
+        let total_sectors = (geometry.size / BOOT_SECTOR_SIZE as u64) as u16;
+        patched[0x13..0x15].copy_from_slice(&total_sectors.to_le_bytes());
+        patched[0x15] = geometry.media;
+        patched[0x16..0x18].copy_from_slice(&geometry.sectors_per_fat.to_le_bytes());
+        patched[0x18..0x1A].copy_from_slice(&geometry.sectors_per_track.to_le_bytes());
+        patched[0x1A..0x1C].copy_from_slice(&geometry.heads.to_le_bytes());
+        patched[0x1C..0x3E].fill(0);
+    }
+    patched[510..512].copy_from_slice(&[0x55, 0xAA]);
+    Ok(Some(patched))
+}
+
+/// Check whether the boot sector of the volume holds a BIOS parameter block, or the layout of the
+/// volume has to be guessed by `patched_boot_sector`.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - `true` if the boot sector on the image describes the volume.
+pub fn has_bpb(image: &File) -> io::Result<bool> {
+    let mut sector = [0; BOOT_SECTOR_SIZE];
+    image.read_exact_at(&mut sector, 0)?;
+    Ok(has_usable_bpb(&sector))
+}
+
+/// Check whether the boot sector on the disk image was written by an Atari ST.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - `true` if the boot sector looks like it comes from an Atari ST.
+pub fn is_atari_image(image: &File) -> io::Result<bool> {
+    let mut sector = [0; BOOT_SECTOR_SIZE];
+    image.read_exact_at(&mut sector, 0)?;
+    Ok(is_atari(&sector))
+}
+
+/// Check whether a boot sector was written by an Atari ST. It has no boot sector signature, and
+/// either starts with a 68000 instead of an x86 jump, or makes the checksum of executable Atari
+/// boot sectors come out right.
+///
+/// # Parameters
+///
+/// * `sector: &[u8; BOOT_SECTOR_SIZE]` - The boot sector as stored on the disk image.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the boot sector looks like it comes from an Atari ST.
+pub fn is_atari(sector: &[u8; BOOT_SECTOR_SIZE]) -> bool {
+    sector[510..512] != [0x55, 0xAA]
+        && (!matches!(sector[0], 0xEB | 0xE9) || atari_checksum(sector) == ATARI_EXECUTABLE)
+}
+
+/// Compute the checksum of an Atari ST boot sector, the sum of its big endian 16 bit words.
+///
+/// # Parameters
+///
+/// * `sector: &[u8; BOOT_SECTOR_SIZE]` - The boot sector.
+///
+/// # Returns
+///
+/// * `u16` - The checksum, `ATARI_EXECUTABLE` for boot sectors the Atari ST runs.
+pub fn atari_checksum(sector: &[u8; BOOT_SECTOR_SIZE]) -> u16 {
+    sector.chunks_exact(2).fold(0u16, |sum, word| {
+        sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]))
+    })
+}
+
+/// Check whether a boot sector holds a BIOS parameter block that describes a FAT volume.
+///
+/// # Parameters
+///
+/// * `sector: &[u8; BOOT_SECTOR_SIZE]` - The boot sector.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the sector and cluster sizes are powers of two, and there are reserved
+///   sectors, FATs and sectors at all.
+fn has_usable_bpb(sector: &[u8; BOOT_SECTOR_SIZE]) -> bool {
+    let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+    let bytes_per_sector = u16_at(0x0B);
+    let total_sectors_32 = u32::from_le_bytes(sector[0x20..0x24].try_into().unwrap());
+    (512..=4096).contains(&bytes_per_sector)
+        && bytes_per_sector.is_power_of_two()
+        && sector[0x0D].is_power_of_two()
+        && u16_at(0x0E) != 0
+        && matches!(sector[0x10], 1 | 2)
+        && (u16_at(0x13) != 0 || total_sectors_32 != 0)
+        && sector[0x15] >= 0xF0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::test_image::TestImage;
+    use fatfs::{FileSystem, FsOptions};
+
+    /// Read a little endian 16 bit field of a boot sector.
+    fn u16_at(sector: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([sector[offset], sector[offset + 1]])
+    }
+
+    /// Make up a floppy image as formatted by DOS 1.x, without a BIOS parameter block.
+    fn dos1_image(size: u64, media: u8) -> TestImage {
+        let image = TestImage::blank(size);
+        image
+            .open()
+            .write_all_at(&[media, 0xFF, 0xFF], BOOT_SECTOR_SIZE as u64)
+            .unwrap();
+        image
+    }
+
+    /// Check that `fatfs` accepts a volume with the patched boot sector.
+    fn assert_mountable(image: &TestImage, sector: [u8; BOOT_SECTOR_SIZE]) {
+        let storage = Storage::new(image.open(), 0, Some(sector));
+        let fs = FileSystem::new(storage, FsOptions::new()).unwrap();
+        assert_eq!(fs.root_dir().iter().count(), 0);
+        assert!(fs.stats().unwrap().free_clusters() > 0);
+    }
+
+    #[test]
+    fn standard_formats_are_recognized_by_size() {
+        // Size in KiB, media descriptor, sectors per cluster, root entries, sectors per FAT,
+        // sectors per track and heads.
+        let formats = [
+            (160, 0xFE, 1, 64, 1, 8, 1),
+            (180, 0xFC, 1, 64, 2, 9, 1),
+            (320, 0xFF, 2, 112, 1, 8, 2),
+            (360, 0xFD, 2, 112, 2, 9, 2),
+            (720, 0xF9, 2, 112, 3, 9, 2),
+            (1440, 0xF0, 1, 224, 9, 18, 2),
+        ];
+        for (kib, media, cluster, root, fat, track, heads) in formats {
+            let image = dos1_image(kib * 1024, media);
+            let file = image.open();
+            assert!(!has_bpb(&file).unwrap(), "{} KiB", kib);
+
+            let sector = patched_boot_sector(&file).unwrap().unwrap();
+            assert_eq!(u16_at(&sector, 0x0B), 512, "{} KiB", kib);
+            assert_eq!(sector[0x0D], cluster, "{} KiB", kib);
+            assert_eq!(u16_at(&sector, 0x11), root, "{} KiB", kib);
+            assert_eq!(u16_at(&sector, 0x13) as u64, kib * 2, "{} KiB", kib);
+            assert_eq!(sector[0x15], media, "{} KiB", kib);
+            assert_eq!(u16_at(&sector, 0x16), fat, "{} KiB", kib);
+            assert_eq!(u16_at(&sector, 0x18), track, "{} KiB", kib);
+            assert_eq!(u16_at(&sector, 0x1A), heads, "{} KiB", kib);
+            assert_eq!(sector[510..512], [0x55, 0xAA], "{} KiB", kib);
+            assert_mountable(&image, sector);
+        }
+    }
+
+    #[test]
+    fn unknown_formats_are_left_alone() {
+        // The media descriptor of 360 KiB floppies on an image of 720 KiB.
+        let file = dos1_image(720 * 1024, 0xFD).open();
+        assert!(patched_boot_sector(&file).unwrap().is_none());
+        let file = TestImage::blank(1000 * 1024).open();
+        assert!(patched_boot_sector(&file).unwrap().is_none());
+    }
+
+    #[test]
+    fn formatted_volumes_are_used_as_they_are() {
+        let file = TestImage::new(fatfs::FatType::Fat12).open();
+        assert!(has_bpb(&file).unwrap());
+        assert!(!is_atari_image(&file).unwrap());
+        assert!(patched_boot_sector(&file).unwrap().is_none());
+    }
+
+    /// Make up the boot sector of a 720 KiB floppy formatted by an Atari ST.
+    fn atari_boot_sector() -> [u8; BOOT_SECTOR_SIZE] {
+        let mut sector = [0; BOOT_SECTOR_SIZE];
+        // A 68000 branch over the BIOS parameter block, then the OEM name holding the serial.
+        sector[0..2].copy_from_slice(&[0x60, 0x38]);
+        sector[2..11].copy_from_slice(b"Loader\x12\x34\x56");
+        sector[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+        sector[0x0D] = 2;
+        sector[0x0E..0x10].copy_from_slice(&1u16.to_le_bytes());
+        sector[0x10] = 2;
+        sector[0x11..0x13].copy_from_slice(&112u16.to_le_bytes());
+        sector[0x13..0x15].copy_from_slice(&1440u16.to_le_bytes());
+        sector[0x15] = 0xF9;
+        sector[0x16..0x18].copy_from_slice(&5u16.to_le_bytes());
+        sector[0x18..0x1A].copy_from_slice(&9u16.to_le_bytes());
+        sector[0x1A..0x1C].copy_from_slice(&2u16.to_le_bytes());
+        // Boot code where DOS expects the fields after the BIOS parameter block.
+        sector[0x1E..0x40].fill(0x4E);
+        sector
+    }
+
+    #[test]
+    fn atari_boot_sectors_get_a_signature() {
+        let image = TestImage::blank(720 * 1024);
+        let file = image.open();
+        file.write_all_at(&atari_boot_sector(), 0).unwrap();
+        // The Atari ST doesn't care whether the media descriptor in the FAT matches the BPB.
+        file.write_all_at(&[0xF7, 0xFF, 0xFF], BOOT_SECTOR_SIZE as u64)
+            .unwrap();
+
+        assert!(has_bpb(&file).unwrap());
+        assert!(is_atari_image(&file).unwrap());
+        let sector = patched_boot_sector(&file).unwrap().unwrap();
+        assert_eq!(sector[..0x1E], atari_boot_sector()[..0x1E]);
+        assert!(sector[0x1E..0x3E].iter().all(|byte| *byte == 0));
+        assert_eq!(sector[510..512], [0x55, 0xAA]);
+        assert_eq!(boot_sector(&file).unwrap(), sector);
+        assert_mountable(&image, sector);
+    }
+
+    #[test]
+    fn executable_atari_boot_sectors_are_recognized_by_their_checksum() {
+        let mut sector = atari_boot_sector();
+        sector[0..2].copy_from_slice(&[0xEB, 0x3C]);
+        assert!(!is_atari(&sector));
+        let rest = atari_checksum(&sector);
+        sector[510..512].copy_from_slice(&ATARI_EXECUTABLE.wrapping_sub(rest).to_be_bytes());
+        assert_eq!(atari_checksum(&sector), ATARI_EXECUTABLE);
+        assert!(is_atari(&sector));
+    }
+}
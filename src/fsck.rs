@@ -0,0 +1,520 @@
+//! This module implements the `fsck` subcommand, which checks the volume on a disk image that is
+//! not mounted for damage and optionally repairs it. Most volumes keep two copies of the FAT,
+//! which are compared with each other, and the copy that matches the directory tree is copied over
+//! the others if they differ. Cluster chains that no file or directory is stored in are looked for
+//! as well, and freed or recovered as files. The FAT and the directory tree are read through the
+//! `direntry` module.
+use crate::direntry;
+use crate::filesystem::{check_supported, lock_image};
+use crate::floppy;
+use crate::storage::Storage;
+use fatfs::{FileSystem as FatfsFileSystem, FsOptions};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT fsck <disk.img> [--repair [--mode free|recover]]";
+
+/// The most ranges of clusters or lost chains listed, before the rest are only counted.
+const MAX_RANGES: usize = 10;
+
+/// The directory in the root directory that lost cluster chains are recovered to.
+const FOUND_DIR: &str = "FOUND.000";
+
+/// Represent what to do with lost cluster chains when repairing the volume.
+///
+/// # Variants
+///
+/// * `Free` - Free their clusters.
+/// * `Recover` - Make each of them a file in `FOUND.000`, like `fsck.vfat` does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LostChainMode {
+    Free,
+    Recover,
+}
+
+/// Run the `fsck` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand:
+///   `<disk.img> [--repair [--mode free|recover]]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 if the volume is fine or was repaired, 1 if damage is left or the
+///   disk image can't be read or written, and 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut disk_image_path = None;
+    let mut repair = false;
+    let mut mode = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            "--mode" if mode.is_none() => match args.next().map(String::as_str) {
+                Some("free") => mode = Some(LostChainMode::Free),
+                Some("recover") => mode = Some(LostChainMode::Recover),
+                _ => {
+                    disk_image_path = None;
+                    break;
+                }
+            },
+            _ if disk_image_path.is_none() && !arg.starts_with("--") => {
+                disk_image_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                disk_image_path = None;
+                break;
+            }
+        }
+    }
+    let Some(disk_image_path) = disk_image_path.filter(|_| repair || mode.is_none()) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let mode = repair.then_some(mode.unwrap_or(LostChainMode::Recover));
+
+    let result = OpenOptions::new()
+        .read(true)
+        .write(repair)
+        .open(&disk_image_path)
+        .and_then(|image| {
+            // Checking a volume that is mounted read-write would find changes half done.
+            lock_image(&image, repair)?;
+            check_supported(&image)?;
+            let backup_path = repair.then(|| disk_image_path.clone());
+            let fats_ok = check_fats(&image, backup_path.as_deref())?;
+            // Lost chains are only found with a FAT matching the directory tree.
+            let chains_ok = (fats_ok || !repair) && check_lost_chains(&image, mode)?;
+            if repair {
+                image.sync_all()?;
+            }
+            Ok(fats_ok && chains_ok)
+        });
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Compare the copies of the FAT, and copy the one matching the directory tree over the others if
+/// asked to. The contents of every copy that is overwritten are saved next to the disk image
+/// first, e.g. as `disk.img.fat2.bak` for the second copy.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, opened for writing to repair it.
+/// * `repair: Option<&Path>` - The path of the disk image, to save the overwritten copies next to,
+///   or `None` to only report the differences.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - Whether the copies are the same, or were made the same.
+fn check_fats(image: &File, repair: Option<&Path>) -> io::Result<bool> {
+    let mismatches = direntry::compare_fats(image)?;
+    if mismatches.iter().all(|(_, ranges)| ranges.is_empty()) {
+        println!("The copies of the FAT are the same.");
+        return Ok(true);
+    }
+    for (copy, ranges) in mismatches.iter().filter(|(_, ranges)| !ranges.is_empty()) {
+        println!(
+            "FAT #{} differs from FAT #1 in cluster{} {}.",
+            copy + 1,
+            if ranges.len() == 1 && ranges[0].len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            format_ranges(ranges)
+        );
+    }
+    let Some(disk_image_path) = repair else {
+        println!("Run with --repair to copy the FAT matching the directory tree over the others.");
+        return Ok(false);
+    };
+
+    // Usually, an interrupted write left the directory tree matching the first copy.
+    let copies = mismatches.len() as u64 + 1;
+    let mut matching = None;
+    for copy in 0..copies {
+        if direntry::fat_matches_tree(image, copy)? {
+            matching = Some(copy);
+            break;
+        }
+    }
+    let Some(source) = matching else {
+        println!("None of the copies of the FAT matches the directory tree, not repairing them.");
+        return Ok(false);
+    };
+    println!("FAT #{} matches the directory tree.", source + 1);
+
+    let (_, fat) = direntry::read_fat_copy(image, source)?;
+    for target in (0..copies).filter(|copy| *copy != source) {
+        let (offset, old) = direntry::read_fat_copy(image, target)?;
+        if old == fat {
+            continue;
+        }
+        let mut backup_path = disk_image_path.as_os_str().to_owned();
+        backup_path.push(format!(".fat{}.bak", target + 1));
+        let backup_path = PathBuf::from(backup_path);
+        // An earlier backup may be the only intact copy left.
+        let mut backup = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", backup_path, e)))?;
+        backup.write_all(&old)?;
+        backup.sync_all()?;
+        direntry::write_fat_copy(image, target, &fat)?;
+        println!(
+            "Copied FAT #{} over FAT #{}, which was saved to {:?} from offset {}.",
+            source + 1,
+            target + 1,
+            backup_path,
+            offset
+        );
+    }
+    Ok(true)
+}
+
+/// Look for cluster chains that the FAT marks as used, but that no file or directory is stored in,
+/// and free or recover them if asked to.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, opened for writing to repair it.
+/// * `repair: Option<LostChainMode>` - What to do with the lost chains, or `None` to only report
+///   them.
+///
+/// # Returns
+///
+/// * `io::Result<bool>` - Whether there are no lost chains, or they were freed or recovered.
+fn check_lost_chains(image: &File, repair: Option<LostChainMode>) -> io::Result<bool> {
+    let chains = match direntry::lost_chains(image) {
+        Ok(chains) => chains,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            println!(
+                "The directory tree can't be walked, as some of its cluster chains are broken."
+            );
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+    if chains.is_empty() {
+        println!("There are no lost cluster chains.");
+        return Ok(true);
+    }
+    let cluster_size = direntry::cluster_map(image)?.cluster_size;
+    let clusters: usize = chains.iter().map(Vec::len).sum();
+    println!(
+        "Found {} lost cluster chain{} of {} clusters, {} bytes in total:",
+        chains.len(),
+        if chains.len() == 1 { "" } else { "s" },
+        clusters,
+        clusters as u64 * cluster_size
+    );
+    for chain in chains.iter().take(MAX_RANGES) {
+        println!(
+            "  starting at cluster {}: {} clusters, {} bytes",
+            chain[0],
+            chain.len(),
+            chain.len() as u64 * cluster_size
+        );
+    }
+    if chains.len() > MAX_RANGES {
+        println!("  and {} more", chains.len() - MAX_RANGES);
+    }
+
+    match repair {
+        None => {
+            println!(
+                "Run with --repair to recover them to {}, or with --mode free to free them.",
+                FOUND_DIR
+            );
+            Ok(false)
+        }
+        Some(LostChainMode::Free) => {
+            direntry::free_chains(image, &chains)?;
+            println!("Freed {} clusters.", clusters);
+            Ok(true)
+        }
+        Some(LostChainMode::Recover) => {
+            let names = recover_chains(image, &chains)?;
+            match names.as_slice() {
+                [name] => println!("Recovered it as /{}/{}.", FOUND_DIR, name),
+                [first, .., last] => {
+                    println!("Recovered them as /{}/{} to {}.", FOUND_DIR, first, last)
+                }
+                [] => {}
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Make each lost cluster chain a file in `FOUND.000`, named `FILE0000.CHK`, `FILE0001.CHK` and so
+/// on, skipping the names already taken. The files take up all clusters of their chain, as the
+/// size of what was stored in them is lost.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `chains: &[Vec<u32>]` - The lost chains, as returned by `direntry::lost_chains`.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<String>>` - The names of the files, one for each chain.
+fn recover_chains(image: &File, chains: &[Vec<u32>]) -> io::Result<Vec<String>> {
+    // The files are created by `fatfs`, which expects to start at the boot sector, but shares the
+    // offset with `image`.
+    let mut fatfs_image = image.try_clone()?;
+    fatfs_image.rewind()?;
+    let storage = Storage::new(fatfs_image, 0, floppy::patched_boot_sector(image)?);
+    let fs = FatfsFileSystem::new(storage, FsOptions::new())?;
+    let names = {
+        let root = fs.root_dir();
+        let found = match root.open_dir(FOUND_DIR) {
+            Ok(found) => found,
+            Err(e) if e.kind() == ErrorKind::NotFound => root.create_dir(FOUND_DIR)?,
+            Err(e) => return Err(e),
+        };
+        let taken = found
+            .iter()
+            .map(|entry| entry.map(|entry| entry.short_file_name()))
+            .collect::<io::Result<HashSet<String>>>()?;
+        let mut names = Vec::new();
+        let mut numbers = (0..10000)
+            .map(|number| format!("FILE{:04}.CHK", number))
+            .filter(|name| !taken.contains(name));
+        for _ in chains {
+            let name = numbers
+                .next()
+                .ok_or_else(|| io::Error::other(format!("{} is full.", FOUND_DIR)))?;
+            found.create_file(&name)?;
+            names.push(name);
+        }
+        names
+    };
+    fs.unmount()?;
+
+    for (chain, name) in chains.iter().zip(&names) {
+        let short_names = [FOUND_DIR.as_bytes().to_vec(), name.as_bytes().to_vec()];
+        direntry::attach_chain(image, &short_names, chain)?;
+    }
+    Ok(names)
+}
+
+/// Format ranges of clusters for display, e.g. `2-5, 9`.
+///
+/// # Parameters
+///
+/// * `ranges: &[Range<u32>]` - The ranges, in order.
+///
+/// # Returns
+///
+/// * `String` - The first few ranges, followed by how many more there are.
+pub fn format_ranges(ranges: &[Range<u32>]) -> String {
+    let mut formatted = ranges
+        .iter()
+        .take(MAX_RANGES)
+        .map(|range| match range.len() {
+            1 => range.start.to_string(),
+            _ => format!("{}-{}", range.start, range.end - 1),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    if ranges.len() > MAX_RANGES {
+        formatted.push_str(&format!(" and {} more ranges", ranges.len() - MAX_RANGES));
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+    use std::process;
+
+    /// Make up a path for the backups of a test, next to the disk images in the temporary
+    /// directory, and remove the backups left behind by earlier runs.
+    fn backup_base(name: &str) -> PathBuf {
+        let base = env::temp_dir().join(format!("nufat-test-{}-{}.img", process::id(), name));
+        for copy in 1..=2 {
+            let _ = fs::remove_file(format!("{}.fat{}.bak", base.display(), copy));
+        }
+        base
+    }
+
+    /// Change a 16 bit entry of a copy of the FAT of a FAT16 volume.
+    fn set_fat16_entry(file: &File, copy: u64, cluster: usize, value: u16) {
+        let (_, mut fat) = direntry::read_fat_copy(file, copy).unwrap();
+        fat[cluster * 2..cluster * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        direntry::write_fat_copy(file, copy, &fat).unwrap();
+    }
+
+    #[test]
+    fn check_fats_repairs_the_second_copy() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        assert!(check_fats(&file, None).unwrap());
+
+        let (_, original) = direntry::read_fat_copy(&file, 1).unwrap();
+        set_fat16_entry(&file, 1, 100, 0xFFFF);
+        set_fat16_entry(&file, 1, 101, 0xFFFF);
+        set_fat16_entry(&file, 1, 200, 0x1234);
+        let (_, corrupted) = direntry::read_fat_copy(&file, 1).unwrap();
+        assert_eq!(
+            direntry::compare_fats(&file).unwrap(),
+            [(1, vec![100..102, 200..201])]
+        );
+        assert!(!check_fats(&file, None).unwrap());
+
+        let base = backup_base("fat2");
+        assert!(check_fats(&file, Some(&base)).unwrap());
+        assert_eq!(direntry::read_fat_copy(&file, 1).unwrap().1, original);
+        assert_eq!(direntry::compare_fats(&file).unwrap(), [(1, vec![])]);
+        let backup_path = format!("{}.fat2.bak", base.display());
+        assert_eq!(fs::read(&backup_path).unwrap(), corrupted);
+        fs::remove_file(backup_path).unwrap();
+    }
+
+    #[test]
+    fn check_fats_keeps_the_copy_matching_the_tree() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        let first = direntry::first_cluster_of(&file, &[b"FILE.BIN".to_vec()])
+            .unwrap()
+            .unwrap();
+        // The chain of the file ends too early in the first copy.
+        set_fat16_entry(&file, 0, first as usize + 1, 0xFFFF);
+        assert!(!direntry::fat_matches_tree(&file, 0).unwrap());
+        assert!(direntry::fat_matches_tree(&file, 1).unwrap());
+
+        let base = backup_base("fat1");
+        assert!(check_fats(&file, Some(&base)).unwrap());
+        assert!(direntry::fat_matches_tree(&file, 0).unwrap());
+        let mut data = Vec::new();
+        image
+            .volume()
+            .root_dir()
+            .open_file("file.bin")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [7; 20000]);
+        fs::remove_file(format!("{}.fat1.bak", base.display())).unwrap();
+    }
+
+    #[test]
+    fn check_fats_refuses_when_no_copy_matches() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        let first = direntry::first_cluster_of(&file, &[b"FILE.BIN".to_vec()])
+            .unwrap()
+            .unwrap();
+        set_fat16_entry(&file, 0, first as usize, 0);
+        set_fat16_entry(&file, 1, first as usize + 1, 0);
+        let base = backup_base("none");
+        assert!(!check_fats(&file, Some(&base)).unwrap());
+        assert!(!Path::new(&format!("{}.fat2.bak", base.display())).exists());
+    }
+
+    /// Make up two lost cluster chains, marked as used in both copies of the FAT of a FAT16
+    /// volume: clusters 300 to 302, and cluster 400, which points into the first one.
+    fn lose_chains(file: &File) {
+        for copy in 0..2 {
+            set_fat16_entry(file, copy, 300, 301);
+            set_fat16_entry(file, copy, 301, 302);
+            set_fat16_entry(file, copy, 302, 0xFFFF);
+            set_fat16_entry(file, copy, 400, 301);
+        }
+    }
+
+    #[test]
+    fn check_lost_chains_frees_lost_chains() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        assert!(check_lost_chains(&file, None).unwrap());
+        let free_clusters = image.volume().stats().unwrap().free_clusters();
+
+        lose_chains(&file);
+        assert_eq!(
+            direntry::lost_chains(&file).unwrap(),
+            [vec![300, 301, 302], vec![400]]
+        );
+        assert!(!check_lost_chains(&file, None).unwrap());
+        assert!(check_lost_chains(&file, Some(LostChainMode::Free)).unwrap());
+        assert!(direntry::lost_chains(&file).unwrap().is_empty());
+        assert!(check_fats(&file, None).unwrap());
+        assert_eq!(
+            image.volume().stats().unwrap().free_clusters(),
+            free_clusters
+        );
+    }
+
+    #[test]
+    fn check_lost_chains_recovers_lost_chains_as_files() {
+        let image = TestImage::with_files(FatType::Fat16, &[("file.bin", &[7; 20000])]);
+        let file = image.open();
+        lose_chains(&file);
+        let cluster_size = direntry::cluster_map(&file).unwrap().cluster_size;
+        let data = [0xA5; 100];
+        for cluster in [300, 400] {
+            let offset =
+                direntry::cluster_map(&file).unwrap().data_start + (cluster - 2) * cluster_size;
+            file.write_all_at(&data, offset).unwrap();
+        }
+
+        assert!(check_lost_chains(&file, Some(LostChainMode::Recover)).unwrap());
+        assert!(direntry::lost_chains(&file).unwrap().is_empty());
+        assert!(direntry::fat_matches_tree(&file, 0).unwrap());
+        assert!(check_fats(&file, None).unwrap());
+        let fs = image.volume();
+        let found = fs.root_dir().open_dir(FOUND_DIR).unwrap();
+        let files: Vec<(String, u64)> = found
+            .iter()
+            .map(Result::unwrap)
+            .filter(|entry| entry.is_file())
+            .map(|entry| (entry.file_name(), entry.len()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("FILE0000.CHK".to_string(), 3 * cluster_size),
+                ("FILE0001.CHK".to_string(), cluster_size)
+            ]
+        );
+        for name in ["FILE0000.CHK", "FILE0001.CHK"] {
+            let mut contents = Vec::new();
+            found
+                .open_file(name)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(contents[..100], data);
+        }
+    }
+
+    #[test]
+    fn format_ranges_lists_single_clusters_and_ranges() {
+        assert_eq!(format_ranges(&[2..6, 9..10]), "2-5, 9");
+        let ranges: Vec<Range<u32>> = (0..12).map(|n| n * 10..n * 10 + 1).collect();
+        assert_eq!(
+            format_ranges(&ranges),
+            "0, 10, 20, 30, 40, 50, 60, 70, 80, 90 and 2 more ranges"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! This module matches the glob patterns the subcommands take for paths on the volume, like
+//! `/EFI/**/*.efi` or `*.cfg`. Like FAT, patterns ignore case. `*` matches any characters of a
+//! name, `?` any one character, `[abc]`, `[a-z]` and `[!abc]` one character of a set, and a `**`
+//! component any number of directories, including none. A backslash takes the next character
+//! literally. Patterns start in the root directory, whether or not they start with `/`.
+use std::path::{Component as PathComponent, Path, PathBuf};
+
+/// Represent a part of a name in a pattern.
+///
+/// # Variants
+///
+/// * `Char(char)` - A character, in uppercase.
+/// * `Any` - `*`, any characters.
+/// * `One` - `?`, any one character.
+/// * `Set { negated: bool, ranges: Vec<(char, char)> }` - `[...]`, one character out of the
+///   ranges, in uppercase, or with `negated` one character out of none of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    One,
+    Set {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+/// Represent a component of a pattern, between slashes.
+///
+/// # Variants
+///
+/// * `Recursive` - `**`, any number of directories.
+/// * `Name(Vec<Token>)` - A pattern for one name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Component {
+    Recursive,
+    Name(Vec<Token>),
+}
+
+/// Represent a parsed glob pattern.
+///
+/// # Members
+///
+/// * `text: String` - The pattern as given, for messages.
+/// * `components: Vec<Component>` - The components of the pattern, starting below the root
+///   directory.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    text: String,
+    components: Vec<Component>,
+}
+
+impl Pattern {
+    /// Parse a pattern.
+    ///
+    /// # Parameters
+    ///
+    /// * `text: &str` - The pattern, e.g. `/EFI/**/*.efi`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Pattern, String>` - The pattern, or why it is malformed.
+    pub fn new(text: &str) -> Result<Pattern, String> {
+        let components = text
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(|component| match component {
+                "**" => Ok(Component::Recursive),
+                _ => parse_name(component).map(Component::Name),
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|e| format!("Invalid pattern '{}': {}", text, e))?;
+        Ok(Pattern {
+            text: text.to_string(),
+            components,
+        })
+    }
+
+    /// Get the pattern as it was given.
+    ///
+    /// # Returns
+    ///
+    /// * `&str` - The pattern.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Check whether the pattern has wildcards, or only names one path.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if it has `*`, `?` or `[...]`.
+    pub fn has_wildcards(&self) -> bool {
+        self.literal_len() < self.components.len()
+    }
+
+    /// Get the directory the pattern starts matching in: the path made of its leading components
+    /// without wildcards, e.g. `/EFI` for `/EFI/**/*.efi`. If the pattern has no wildcards, it is
+    /// the parent directory of the path it names.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - The absolute path of the directory, in uppercase.
+    pub fn base(&self) -> PathBuf {
+        let len = self.literal_len();
+        let len = if len == self.components.len() {
+            len.saturating_sub(1)
+        } else {
+            len
+        };
+        let mut base = PathBuf::from("/");
+        for component in &self.components[..len] {
+            if let Component::Name(tokens) = component {
+                base.push(literal_text(tokens));
+            }
+        }
+        base
+    }
+
+    /// Get the path a pattern without wildcards names.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PathBuf>` - The absolute path, in uppercase, or `None` if the pattern has
+    ///   wildcards.
+    pub fn literal_path(&self) -> Option<PathBuf> {
+        if self.has_wildcards() {
+            return None;
+        }
+        Some(
+            self.base()
+                .join(
+                    self.components
+                        .last()
+                        .map_or(String::new(), |component| match component {
+                            Component::Name(tokens) => literal_text(tokens),
+                            Component::Recursive => unreachable!(),
+                        }),
+                ),
+        )
+    }
+
+    /// Check whether a path on the volume matches the pattern, ignoring case.
+    ///
+    /// # Parameters
+    ///
+    /// * `path: &Path` - The absolute path.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the path matches.
+    pub fn matches(&self, path: &Path) -> bool {
+        let names: Vec<Vec<char>> = path
+            .components()
+            .filter_map(|component| match component {
+                PathComponent::Normal(name) => {
+                    Some(name.to_string_lossy().chars().map(fold_case).collect())
+                }
+                _ => None,
+            })
+            .collect();
+        match_components(&self.components, &names)
+    }
+
+    /// Helper function to count the leading components without wildcards.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The number of components.
+    fn literal_len(&self) -> usize {
+        self.components
+            .iter()
+            .take_while(|component| match component {
+                Component::Name(tokens) => {
+                    tokens.iter().all(|token| matches!(token, Token::Char(_)))
+                }
+                Component::Recursive => false,
+            })
+            .count()
+    }
+}
+
+/// Parse the pattern for one name.
+///
+/// # Parameters
+///
+/// * `text: &str` - The component of the pattern.
+///
+/// # Returns
+///
+/// * `Result<Vec<Token>, String>` - The tokens, or why they are malformed.
+fn parse_name(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' => Token::Any,
+            '?' => Token::One,
+            '\\' => Token::Char(fold_case(chars.next().ok_or("it ends with a backslash")?)),
+            '[' => {
+                let negated = chars.next_if(|c| matches!(c, '!' | '^')).is_some();
+                let mut ranges = Vec::new();
+                // A `]` right after the opening bracket is part of the set.
+                let mut first = true;
+                loop {
+                    let start = match chars.next() {
+                        Some(']') if !first => break,
+                        Some('\\') => chars.next().ok_or("it ends with a backslash")?,
+                        Some(c) => c,
+                        None => return Err("a '[' is not closed".to_string()),
+                    };
+                    first = false;
+                    let end = if chars.peek() == Some(&'-') {
+                        chars.next();
+                        match chars.next() {
+                            // A `-` before the closing bracket is taken literally.
+                            Some(']') => {
+                                ranges.push((fold_case(start), fold_case(start)));
+                                ranges.push(('-', '-'));
+                                break;
+                            }
+                            Some(end) => end,
+                            None => return Err("a '[' is not closed".to_string()),
+                        }
+                    } else {
+                        start
+                    };
+                    ranges.push((fold_case(start), fold_case(end)));
+                }
+                Token::Set { negated, ranges }
+            }
+            c => Token::Char(fold_case(c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Get the text of a name without wildcards.
+///
+/// # Parameters
+///
+/// * `tokens: &[Token]` - The tokens, all characters.
+///
+/// # Returns
+///
+/// * `String` - The name, in uppercase.
+fn literal_text(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Char(c) => Some(*c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Fold a character to uppercase for comparing names like FAT does. Characters that uppercase to
+/// more than one, like `ß`, are kept.
+///
+/// # Parameters
+///
+/// * `c: char` - The character.
+///
+/// # Returns
+///
+/// * `char` - The character in uppercase.
+fn fold_case(c: char) -> char {
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(upper), None) => upper,
+        _ => c,
+    }
+}
+
+/// Match the components of a path against those of a pattern.
+///
+/// # Parameters
+///
+/// * `components: &[Component]` - The components of the pattern.
+/// * `names: &[Vec<char>]` - The names in the path, folded to uppercase.
+///
+/// # Returns
+///
+/// * `bool` - `true` if they match.
+fn match_components(components: &[Component], names: &[Vec<char>]) -> bool {
+    match components.split_first() {
+        None => names.is_empty(),
+        Some((Component::Recursive, rest)) => {
+            (0..=names.len()).any(|skip| match_components(rest, &names[skip..]))
+        }
+        Some((Component::Name(tokens), rest)) => names
+            .split_first()
+            .is_some_and(|(name, names)| match_name(tokens, name) && match_components(rest, names)),
+    }
+}
+
+/// Match a name against the pattern for it.
+///
+/// # Parameters
+///
+/// * `tokens: &[Token]` - The pattern.
+/// * `name: &[char]` - The name, folded to uppercase.
+///
+/// # Returns
+///
+/// * `bool` - `true` if they match.
+fn match_name(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Any, rest)) => (0..=name.len()).any(|skip| match_name(rest, &name[skip..])),
+        Some((token, rest)) => name.split_first().is_some_and(|(c, name)| {
+            let matches = match token {
+                Token::Char(expected) => c == expected,
+                Token::One => true,
+                Token::Set { negated, ranges } => {
+                    ranges.iter().any(|(start, end)| (start..=end).contains(&c)) != *negated
+                }
+                Token::Any => unreachable!(),
+            };
+            matches && match_name(rest, name)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_ignoring_case() {
+        let matches =
+            |pattern: &str, path: &str| Pattern::new(pattern).unwrap().matches(Path::new(path));
+        assert!(matches("/EFI/**/*.efi", "/efi/boot/BOOTX64.EFI"));
+        assert!(matches("/EFI/**/*.efi", "/EFI/grub.efi"));
+        assert!(!matches("/EFI/**/*.efi", "/EFI/boot/grub.cfg"));
+        assert!(!matches("/EFI/**/*.efi", "/other/grub.efi"));
+        assert!(matches("*.cfg", "/Syslinux.CFG"));
+        assert!(!matches("*.cfg", "/boot/syslinux.cfg"));
+        assert!(matches("**/*.cfg", "/boot/syslinux.cfg"));
+        assert!(matches("/file?.[a-c0-9]", "/FILE1.B"));
+        assert!(!matches("/file?.[!a-c]", "/file1.b"));
+        assert!(matches("/[]x]", "/]"));
+        assert!(matches("/a\\*", "/A*"));
+        assert!(!matches("/a\\*", "/ab"));
+        assert!(matches("/Über*", "/übersicht.txt"));
+        assert!(Pattern::new("/a[bc").is_err());
+    }
+
+    #[test]
+    fn bases_are_the_leading_literal_components() {
+        let pattern = Pattern::new("/EFI/Boot/*.efi").unwrap();
+        assert!(pattern.has_wildcards());
+        assert_eq!(pattern.base(), Path::new("/EFI/BOOT"));
+        assert_eq!(pattern.literal_path(), None);
+
+        let pattern = Pattern::new("docs/notes.txt").unwrap();
+        assert!(!pattern.has_wildcards());
+        assert_eq!(pattern.base(), Path::new("/DOCS"));
+        assert_eq!(
+            pattern.literal_path(),
+            Some(PathBuf::from("/DOCS/NOTES.TXT"))
+        );
+        assert_eq!(
+            Pattern::new("/").unwrap().literal_path(),
+            Some(PathBuf::from("/"))
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! This module implements the `hash` subcommand, which prints the SHA-256 checksums of files on a
+//! disk image that is not mounted, in the format of `sha256sum`, so they can be compared with
+//! those of the files on the host. Paths can be glob patterns, expanded by the `tree` module.
+use crate::filesystem::lock_image;
+use crate::tree;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT hash <disk.img> <PATTERN...> [--allow-empty]";
+
+/// The most bytes read from a file in one go.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The round constants of SHA-256.
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Run the `hash` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <PATTERN...>
+///   [--allow-empty]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or a pattern matches
+///   nothing, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut allow_empty = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--allow-empty" => allow_empty = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let [disk_image_path, patterns @ ..] = &paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let patterns = match tree::parse_patterns(patterns) {
+        Ok(patterns) if !patterns.is_empty() => patterns,
+        Ok(_) => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let result = File::open(disk_image_path).and_then(|image| {
+        lock_image(&image, false)?;
+        let fs = tree::open_volume(&image)?;
+        let mut files = Vec::new();
+        for entry in tree::expand(&fs, &patterns, allow_empty)? {
+            // Directories stand for the files below them.
+            if entry.is_dir {
+                files.extend(
+                    tree::walk(&fs, &entry)?
+                        .into_iter()
+                        .filter(|entry| !entry.is_dir),
+                );
+            } else {
+                files.push(entry);
+            }
+        }
+        let mut hashed = Vec::new();
+        for entry in files {
+            if hashed.contains(&entry.path) {
+                continue;
+            }
+            let mut file = fs.root_dir().open_file(entry.path.to_str().unwrap())?;
+            println!(
+                "{}  {}",
+                format_digest(&sha256(&mut file)?),
+                entry.path.display()
+            );
+            hashed.push(entry.path);
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+/// Compute the SHA-256 digest of what a reader holds.
+///
+/// # Parameters
+///
+/// * `reader: &mut impl Read` - The reader, read to its end.
+///
+/// # Returns
+///
+/// * `io::Result<[u8; 32]>` - The digest, or an error if reading failed.
+pub fn sha256(reader: &mut impl Read) -> io::Result<[u8; 32]> {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut pending = Vec::with_capacity(CHUNK_SIZE + 64);
+    let mut len: u64 = 0;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        len += read as u64;
+        pending.extend_from_slice(&buf[..read]);
+        let whole = pending.len() / 64 * 64;
+        for block in pending[..whole].chunks_exact(64) {
+            compress(&mut state, block);
+        }
+        pending.drain(..whole);
+    }
+    // The padding: a one bit, zeros, and the length in bits.
+    pending.push(0x80);
+    while pending.len() % 64 != 56 {
+        pending.push(0);
+    }
+    pending.extend_from_slice(&(len * 8).to_be_bytes());
+    for block in pending.chunks_exact(64) {
+        compress(&mut state, block);
+    }
+    let mut digest = [0; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    Ok(digest)
+}
+
+/// Process a block of 64 bytes, as in FIPS 180-4.
+///
+/// # Parameters
+///
+/// * `state: &mut [u32; 8]` - The hash state, updated with the block.
+/// * `block: &[u8]` - The block.
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut schedule = [0u32; 64];
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = schedule[i - 15].rotate_right(7)
+            ^ schedule[i - 15].rotate_right(18)
+            ^ (schedule[i - 15] >> 3);
+        let s1 = schedule[i - 2].rotate_right(17)
+            ^ schedule[i - 2].rotate_right(19)
+            ^ (schedule[i - 2] >> 10);
+        schedule[i] = schedule[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(schedule[i - 7])
+            .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(choice)
+            .wrapping_add(*constant)
+            .wrapping_add(word);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(majority);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// Format a digest as lowercase hex digits.
+///
+/// # Parameters
+///
+/// * `digest: &[u8]` - The digest.
+///
+/// # Returns
+///
+/// * `String` - The hex digits.
+fn format_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        let digest = |data: &[u8]| format_digest(&sha256(&mut &data[..]).unwrap());
+        assert_eq!(
+            digest(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn files_below_matched_directories_are_hashed() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "dir", "entries": [{"name": "abc.txt", "size": 3, "fill": "abc"}]}
+            ]}"#,
+        );
+        let hash = |args: &[&str]| {
+            let mut all = vec![image.options().disk_image_path.display().to_string()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            run(&all)
+        };
+        assert_eq!(hash(&["/dir", "/**/*.txt"]), 0);
+        assert_eq!(hash(&["/dir/*.bin"]), 1);
+        assert_eq!(hash(&[]), 2);
+    }
+}
@@ -0,0 +1,545 @@
+//! This module implements the `import` subcommand, which copies everything in a tar archive to
+//! the root directory of a disk image that is not mounted, the reverse of `export`. Entries get
+//! the attributes and creation times `export` keeps in the records `NUFAT.attributes` and
+//! `NUFAT.crtime` of extended headers. Archives written by other tools don't have them, and
+//! their entries get the Read-only attribute if no one may write them, and are created now. An
+//! archive in a file is checked completely before the volume is changed, one read from the
+//! standard input only as far as it has been read.
+use crate::attr::parse_letters;
+use crate::copy::{self, Summary};
+use crate::direntry;
+use crate::filesystem::{lock_image, open_dir, short_names, system_to_fat_time};
+use crate::storage::Storage;
+use crate::tar::{Entry, Kind, Reader};
+use crate::tree;
+use fatfs::{Date, DateTime, FileAttributes, FileSystem as FatfsFileSystem};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT import <disk.img> <archive.tar|-> [--force]";
+
+/// Represent what to give an entry imported to the volume once `fatfs` is done with it.
+///
+/// # Members
+///
+/// * `short_names: Vec<Vec<u8>>` - The short names leading to the entry, as `direntry` finds it.
+/// * `accessed: Date` - When the entry was last accessed.
+/// * `modified: DateTime` - When the entry was last modified.
+/// * `created: Option<DateTime>` - When the entry was created, or `None` if the archive doesn't
+///   say.
+/// * `attributes: Option<FileAttributes>` - The attributes of the entry, or `None` to keep those
+///   `fatfs` gave it.
+struct Imported {
+    short_names: Vec<Vec<u8>>,
+    accessed: Date,
+    modified: DateTime,
+    created: Option<DateTime>,
+    attributes: Option<FileAttributes>,
+}
+
+/// Run the `import` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <archive.tar|->
+///   [--force]`, where `-` reads the archive from the standard input.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image or the archive can't be read, an
+///   entry is in the way or can't be written, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut force = false;
+    let mut paths = Vec::new();
+    let mut valid = true;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            "-" => paths.push(arg.as_str()),
+            _ if arg.starts_with("--") => valid = false,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let ([disk_image_path, archive_path], true) = (&paths[..], valid) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(disk_image_path)
+        .and_then(|image| {
+            lock_image(&image, true)?;
+            let fs = tree::open_volume(&image)?;
+            if *archive_path == "-" {
+                let input = BufReader::new(io::stdin().lock());
+                return import(&image, fs, &mut Reader::new(input), force);
+            }
+            let open = || {
+                File::open(archive_path)
+                    .map(BufReader::new)
+                    .map(Reader::new)
+            };
+            let archive = open().map_err(|e| in_archive(archive_path, e))?;
+            check(&fs, archive, force)?;
+            let mut archive = open().map_err(|e| in_archive(archive_path, e))?;
+            import(&image, fs, &mut archive, force)
+        });
+    match result {
+        Ok(summary) => {
+            println!("{}", summary.describe(false));
+            0
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+/// Check that everything in an archive can be imported, before anything is.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `archive: Reader<impl Read>` - The archive.
+/// * `force: bool` - Whether files on the volume are replaced.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the archive is malformed or an entry is in the way.
+fn check(
+    fs: &FatfsFileSystem<Storage>,
+    mut archive: Reader<impl Read>,
+    force: bool,
+) -> io::Result<()> {
+    while let Some(entry) = archive.next_entry()? {
+        let Some(path) = target_path(&entry)? else {
+            continue;
+        };
+        let is_dir = match entry.kind {
+            Kind::File => false,
+            Kind::Dir => true,
+            Kind::Other(_) => continue,
+        };
+        match tree::find(fs, &path, Path::new("/")) {
+            Ok(found) if found.is_dir != is_dir => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} exists and is {}",
+                        path.display(),
+                        if found.is_dir {
+                            "a directory"
+                        } else {
+                            "a file"
+                        }
+                    ),
+                ));
+            }
+            Ok(found) if !found.is_dir && !force => {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists, --force replaces it", path.display()),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copy everything in an archive to the volume.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, locked for writing.
+/// * `fs: FatfsFileSystem<Storage>` - The volume on it, unmounted once the entries are copied.
+/// * `archive: &mut Reader<impl Read>` - The archive.
+/// * `force: bool` - Whether files on the volume are replaced.
+///
+/// # Returns
+///
+/// * `io::Result<Summary>` - What was copied, or an error if the archive is malformed, an entry
+///   is in the way or the volume can't be written.
+fn import(
+    image: &File,
+    fs: FatfsFileSystem<Storage>,
+    archive: &mut Reader<impl Read>,
+    force: bool,
+) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let mut imported = Vec::new();
+    while let Some(entry) = archive.next_entry()? {
+        let Some(path) = target_path(&entry)? else {
+            continue;
+        };
+        if let Kind::Other(type_flag) = entry.kind {
+            eprintln!(
+                "{:?}: skipped, FAT only holds files and directories, not entries of type '{}'",
+                path, type_flag as char
+            );
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            copy::create_dirs(&fs, parent)?;
+        }
+        let dir = open_dir(&fs, path.parent().unwrap())?;
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if entry.kind == Kind::Dir {
+            dir.create_dir(name)?;
+        } else {
+            if !force && tree::find(&fs, &path, Path::new("/")).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists, --force replaces it", path.display()),
+                ));
+            }
+            let mut file = dir.create_file(name)?;
+            file.truncate()?;
+            summary.bytes += archive.copy_data(&mut file)?;
+            summary.files += 1;
+        }
+        imported.push(imported_entry(&fs, &path, &entry)?);
+    }
+    fs.unmount()?;
+    for entry in &imported {
+        direntry::set_times(
+            image,
+            &entry.short_names,
+            Some(entry.accessed),
+            Some(entry.modified),
+            entry.created,
+        )?;
+        if let Some(attributes) = entry.attributes {
+            direntry::set_attributes(image, &entry.short_names, attributes.bits())?;
+        }
+    }
+    image.sync_all()?;
+    Ok(summary)
+}
+
+/// Helper function to find what to give an entry imported once `fatfs` is done with it.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The absolute path of the entry on the volume.
+/// * `entry: &Entry` - The entry in the archive.
+///
+/// # Returns
+///
+/// * `io::Result<Imported>` - The times and attributes, or an `InvalidData` error if a record
+///   `export` writes is malformed.
+fn imported_entry(
+    fs: &FatfsFileSystem<Storage>,
+    path: &Path,
+    entry: &Entry,
+) -> io::Result<Imported> {
+    let malformed = |key: &str| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?}: malformed {} record", path, key),
+        )
+    };
+    let modified = UNIX_EPOCH + Duration::from_secs(entry.mtime);
+    let accessed = match entry.record("atime") {
+        Some(value) => parse_time(value).ok_or_else(|| malformed("atime"))?,
+        None => modified,
+    };
+    let created = match entry.record("NUFAT.crtime") {
+        Some(value) => Some(parse_time(value).ok_or_else(|| malformed("NUFAT.crtime"))?),
+        None => None,
+    };
+    let kind = if entry.kind == Kind::Dir {
+        FileAttributes::DIRECTORY
+    } else {
+        FileAttributes::empty()
+    };
+    let attributes = match entry.record("NUFAT.attributes") {
+        Some(value) => {
+            Some(parse_letters(value).ok_or_else(|| malformed("NUFAT.attributes"))? | kind)
+        }
+        // Without the records of `export`, only whether the entry can be written is known.
+        None if entry.mode & 0o222 == 0 => {
+            Some(find_attributes(fs, path)? | FileAttributes::READ_ONLY)
+        }
+        None => None,
+    };
+    Ok(Imported {
+        short_names: short_names(fs, path)?,
+        accessed: system_to_fat_time(accessed).date,
+        modified: system_to_fat_time(modified),
+        created: created.map(system_to_fat_time),
+        attributes,
+    })
+}
+
+/// Helper function to find the attributes an entry on the volume has.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The absolute path of the entry.
+///
+/// # Returns
+///
+/// * `io::Result<FileAttributes>` - The attributes, or an error if the entry can't be found.
+fn find_attributes(fs: &FatfsFileSystem<Storage>, path: &Path) -> io::Result<FileAttributes> {
+    Ok(tree::find(fs, path, Path::new("/"))?.attributes)
+}
+
+/// Helper function to find the path on the volume to import an entry to.
+///
+/// # Parameters
+///
+/// * `entry: &Entry` - The entry in the archive.
+///
+/// # Returns
+///
+/// * `io::Result<Option<PathBuf>>` - The absolute path, `None` for the directory the archive is
+///   extracted to, or an `InvalidData` error if the path leads out of it.
+fn target_path(entry: &Entry) -> io::Result<Option<PathBuf>> {
+    let mut path = PathBuf::from("/");
+    for component in Path::new(&entry.path).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir | Component::RootDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{:?}: the path leads out of the archive", entry.path),
+                ))
+            }
+        }
+    }
+    Ok((path != Path::new("/")).then_some(path))
+}
+
+/// Helper function to parse a time the way extended headers hold it, in seconds since the Unix
+/// epoch with an optional fraction.
+///
+/// # Parameters
+///
+/// * `value: &str` - The time, e.g. `1700000000.25`.
+///
+/// # Returns
+///
+/// * `Option<SystemTime>` - The time, or `None` if it is malformed or before the epoch.
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let secs: u64 = secs.parse().ok()?;
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    // Nanoseconds are the first 9 digits, padded with zeros.
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)]);
+    Some(UNIX_EPOCH + Duration::new(secs, nanos.parse().ok()?))
+}
+
+/// Helper function to say which archive an error opening it is about.
+///
+/// # Parameters
+///
+/// * `archive_path: &str` - The path of the archive.
+/// * `e: io::Error` - The error.
+///
+/// # Returns
+///
+/// * `io::Error` - The error, with the path in its message.
+fn in_archive(archive_path: &str, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("{:?}: {}", PathBuf::from(archive_path), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+    use crate::test_image::TestImage;
+    use fatfs::{Dir, FatType};
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    /// Describe everything in a directory, with what FAT keeps about it.
+    fn describe(dir: &Dir<File>, path: &str, lines: &mut Vec<String>) {
+        for entry in dir.iter() {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            if matches!(name.as_str(), "." | "..") {
+                continue;
+            }
+            let path = format!("{}/{}", path, name);
+            let mut contents = Vec::new();
+            if entry.is_file() {
+                entry.to_file().read_to_end(&mut contents).unwrap();
+            }
+            lines.push(format!(
+                "{} {:?} {:?} {:?} {:?} {:?}",
+                path,
+                entry.attributes(),
+                entry.created(),
+                entry.modified(),
+                entry.accessed(),
+                contents
+            ));
+            if entry.is_dir() {
+                describe(&entry.to_dir(), &path, lines);
+            }
+        }
+    }
+
+    #[test]
+    fn exports_are_imported_with_attributes_and_creation_times() {
+        let long_name = format!("Ünïcode {}.txt", "long name ".repeat(12));
+        let source = TestImage::from_spec(&format!(
+            r#"{{"size": "8M", "entries": [
+                {{"name": "Read Me.txt", "size": 10, "fill": "abc", "modified": "1999-12-31 23:59:58", "created": "1999-12-30 10:11:12"}},
+                {{"name": "Docs", "attributes": "h", "created": "2001-02-03 04:05:06", "entries": [
+                    {{"name": "{}", "size": 700, "fill": "xyz", "attributes": "a"}}
+                ]}},
+                {{"name": "SYSTEM.DAT", "attributes": "rs", "accessed": "2020-06-01"}},
+                {{"name": "plain.bin", "size": 1, "attributes": ""}}
+            ]}}"#,
+            long_name
+        ));
+        // Creation times are kept to 10 ms.
+        let created = DateTime {
+            date: Date {
+                year: 2003,
+                month: 4,
+                day: 5,
+            },
+            time: fatfs::Time {
+                hour: 6,
+                min: 7,
+                sec: 9,
+                millis: 230,
+            },
+        };
+        direntry::set_times(
+            &source.open(),
+            &[b"SYSTEM.DAT".to_vec()],
+            None,
+            None,
+            Some(created),
+        )
+        .unwrap();
+        let dir = env::temp_dir().join(format!("nufat-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("volume.tar").display().to_string();
+        let path = |image: &TestImage| image.options().disk_image_path.display().to_string();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(export::run(&args(&[&path(&source), &archive])), 0);
+        assert_eq!(export::run(&args(&[&path(&source), &archive])), 1);
+
+        let target = TestImage::new(FatType::Fat16);
+        assert_eq!(run(&args(&[&path(&target), &archive])), 0);
+        let mut expected = Vec::new();
+        describe(&source.volume().root_dir(), "", &mut expected);
+        let mut imported = Vec::new();
+        describe(&target.volume().root_dir(), "", &mut imported);
+        assert_eq!(imported, expected);
+        assert!(expected.iter().any(|line| line.contains("millis: 230")));
+
+        // Files are only replaced with `--force`, and nothing is imported otherwise.
+        let checksum = target.checksum();
+        assert_eq!(run(&args(&[&path(&target), &archive])), 1);
+        assert_eq!(target.checksum(), checksum);
+        assert_eq!(run(&args(&[&path(&target), &archive, "--force"])), 0);
+
+        // GNU tar extracts the archive, skipping what it doesn't know.
+        let output = dir.join("output");
+        fs::create_dir_all(&output).unwrap();
+        let extracted = Command::new("tar")
+            .arg("-xf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&output)
+            .status();
+        if let Ok(status) = extracted {
+            assert!(status.success());
+            assert_eq!(fs::read(output.join("Read Me.txt")).unwrap(), b"abcabcabca");
+            assert_eq!(
+                fs::read(output.join("Docs").join(&long_name))
+                    .unwrap()
+                    .len(),
+                700
+            );
+            assert!(!output.join("PaxHeaders").exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archives_of_other_tools_keep_what_they_can() {
+        let mut archive = Vec::new();
+        let entry = |path: &str, kind, size, mode| Entry {
+            path: path.to_string(),
+            kind,
+            size,
+            mode,
+            uid: 1000,
+            gid: 1000,
+            mtime: 1_600_000_000,
+            records: Vec::new(),
+        };
+        crate::tar::write_entry(&mut archive, &entry("./etc", Kind::Dir, 0, 0o755)).unwrap();
+        crate::tar::write_entry(&mut archive, &entry("etc/motd", Kind::File, 3, 0o444)).unwrap();
+        archive.extend(b"hi\n");
+        crate::tar::write_padding(&mut archive, 3).unwrap();
+        crate::tar::write_entry(
+            &mut archive,
+            &entry("etc/link", Kind::Other(b'2'), 0, 0o777),
+        )
+        .unwrap();
+        crate::tar::finish(&mut archive).unwrap();
+        let dir = env::temp_dir().join(format!("nufat-import-other-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive_path = dir.join("other.tar");
+        fs::write(&archive_path, &archive).unwrap();
+
+        let target = TestImage::new(FatType::Fat12);
+        let image_path = target.options().disk_image_path;
+        let args = vec![
+            image_path.display().to_string(),
+            archive_path.display().to_string(),
+        ];
+        assert_eq!(run(&args), 0);
+        let volume = target.volume();
+        let motd = volume
+            .root_dir()
+            .iter()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.file_name() == "etc")
+            .unwrap()
+            .to_dir()
+            .iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name() != "." && entry.file_name() != "..")
+            .collect::<Vec<_>>();
+        assert_eq!(motd.len(), 1);
+        assert!(motd[0].attributes().contains(FileAttributes::READ_ONLY));
+        assert_eq!(
+            system_to_fat_time(UNIX_EPOCH + Duration::from_secs(1_600_000_000)).date,
+            motd[0].modified().date
+        );
+
+        // A path leading out of the archive is refused.
+        let mut archive = Vec::new();
+        crate::tar::write_entry(&mut archive, &entry("../evil", Kind::File, 0, 0o644)).unwrap();
+        crate::tar::finish(&mut archive).unwrap();
+        fs::write(&archive_path, &archive).unwrap();
+        assert_eq!(run(&args), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! This module implements the `info` subcommand, which shows how the volume on a disk image
+//! identifies itself, without mounting it.
+use crate::filesystem::{check_supported, format_volume_id};
+use crate::floppy;
+use crate::label::read_label;
+use crate::serial::read_serial;
+use crate::storage::Storage;
+use fatfs::{FatType, FileSystem as FatfsFileSystem, FsOptions};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// Run the `info` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img>`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 otherwise.
+pub fn run(args: &[String]) -> i32 {
+    let disk_image_path = match args {
+        [disk_image_path] if !disk_image_path.starts_with("--") => PathBuf::from(disk_image_path),
+        _ => {
+            eprintln!("Usage: nuFAT info <disk.img>");
+            return 1;
+        }
+    };
+
+    match File::open(&disk_image_path).and_then(|image| print_info(&image)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Print the FAT type, serial number, label and OEM name of the volume, and whether it is dirty.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the image holds no FAT volume.
+fn print_info(image: &File) -> io::Result<()> {
+    check_supported(image)?;
+    // The image is opened read-only, so `fatfs` can't change it.
+    let storage = Storage::new(image.try_clone()?, 0, floppy::patched_boot_sector(image)?);
+    let fs = FatfsFileSystem::new(storage, FsOptions::new())?;
+    let dirty = fs.read_status_flags()?.dirty();
+
+    println!("FAT type: {}", format_fat_type(fs.fat_type()));
+    // Floppies formatted by DOS 3.x and older have no serial number.
+    let serial = read_serial(image).map_or("none".to_string(), format_volume_id);
+    println!("Serial:   {}", serial);
+    println!("Label:    {}", read_label(image)?.unwrap_or_default());
+    println!("OEM name: {}", read_oem_name(image)?);
+    println!("Dirty:    {}", if dirty { "yes" } else { "no" });
+    Ok(())
+}
+
+/// Format a FAT type the way it is commonly written, e.g. `FAT32`.
+///
+/// # Parameters
+///
+/// * `fat_type: FatType` - The FAT type.
+///
+/// # Returns
+///
+/// * `&'static str` - The name of the FAT type.
+pub fn format_fat_type(fat_type: FatType) -> &'static str {
+    match fat_type {
+        FatType::Fat12 => "FAT12",
+        FatType::Fat16 => "FAT16",
+        FatType::Fat32 => "FAT32",
+    }
+}
+
+/// Read the OEM name from the boot sector, which names the system that formatted the volume,
+/// e.g. `mkfs.fat` or `MSWIN4.1`. On the Atari ST, it is only 6 bytes long and comes right after
+/// the 68000 jump.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<String>` - The OEM name without padding.
+pub fn read_oem_name(image: &File) -> io::Result<String> {
+    let mut oem_name = [0; 8];
+    if floppy::is_atari_image(image)? {
+        image.read_exact_at(&mut oem_name[..6], 2)?;
+    } else {
+        image.read_exact_at(&mut oem_name, 3)?;
+    }
+    Ok(String::from_utf8_lossy(&oem_name)
+        .trim_end_matches([' ', '\0'])
+        .to_string())
+}
@@ -0,0 +1,409 @@
+//! This module reads and writes the little JSON nuFAT deals with: the specs of the `mkimage`
+//! subcommand, and the output of `map --json` and `--log-format json`. It is only as complete as
+//! that needs, numbers in particular are kept as `f64`.
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Represent a parsed JSON value.
+///
+/// # Variants
+///
+/// * `Null` - `null`.
+/// * `Bool(bool)` - `true` or `false`.
+/// * `Number(f64)` - A number.
+/// * `String(String)` - A string, with its escapes resolved.
+/// * `Array(Vec<Value>)` - An array.
+/// * `Object(Vec<(String, Value)>)` - An object, with its members in the order they were given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Get a member of an object.
+    ///
+    /// # Parameters
+    ///
+    /// * `key: &str` - The name of the member.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Value>` - The value of the member, or `None` if there is none or this is not an
+    ///   object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Get a description of the kind of value, for error messages.
+    ///
+    /// # Returns
+    ///
+    /// * `&'static str` - The kind of value, e.g. "a string".
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+}
+
+/// Parse a JSON document.
+///
+/// # Parameters
+///
+/// * `text: &str` - The document.
+///
+/// # Returns
+///
+/// * `Result<Value, String>` - The value, or a message saying what is wrong and on which line.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("unexpected text after the value")),
+    }
+}
+
+/// Quote a string for JSON.
+///
+/// # Parameters
+///
+/// * `value: &str` - The string.
+///
+/// # Returns
+///
+/// * `String` - The string in double quotes, with quotes, backslashes and control characters
+///   escaped.
+pub fn string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Represent the state of parsing a JSON document.
+///
+/// # Members
+///
+/// * `chars: Peekable<Chars<'a>>` - The rest of the document.
+/// * `line: usize` - The line parsing is on, for error messages.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    /// Helper function to make up an error message for the current line.
+    ///
+    /// # Parameters
+    ///
+    /// * `message: &str` - What is wrong.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The message, with the line.
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON on line {}: {}", self.line, message)
+    }
+
+    /// Helper function to take the next character, keeping track of the line.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<char>` - The character, or `None` at the end of the document.
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Helper function to skip whitespace between tokens.
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.next();
+        }
+    }
+
+    /// Helper function to take a character that must come next.
+    ///
+    /// # Parameters
+    ///
+    /// * `expected: char` - The character.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - An error if something else comes next.
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(&format!("expected {:?}, found {:?}", expected, c))),
+            None => Err(self.error(&format!("expected {:?} before the end", expected))),
+        }
+    }
+
+    /// Helper function to parse the value that comes next.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Value, String>` - The value, or an error if it is malformed.
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek().copied() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Value::String),
+            Some('-' | '0'..='9') => self.number(),
+            Some('a'..='z') => {
+                let mut word = String::new();
+                while let Some(c @ 'a'..='z') = self.chars.peek().copied() {
+                    word.push(c);
+                    self.next();
+                }
+                match word.as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => Err(self.error(&format!("unknown word {:?}", word))),
+                }
+            }
+            Some(c) => Err(self.error(&format!("unexpected {:?}", c))),
+            None => Err(self.error("expected a value before the end")),
+        }
+    }
+
+    /// Helper function to parse an object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Value, String>` - The object, or an error if it is malformed.
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut members: Vec<(String, Value)> = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek() != Some(&'"') {
+                return Err(self.error("expected the name of a member in double quotes"));
+            }
+            let name = self.string()?;
+            if members.iter().any(|(other, _)| *other == name) {
+                return Err(self.error(&format!("member {:?} is given twice", name)));
+            }
+            self.expect(':')?;
+            members.push((name, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err(self.error("expected ',' or '}' after a member")),
+            }
+        }
+    }
+
+    /// Helper function to parse an array.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Value, String>` - The array, or an error if it is malformed.
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected ',' or ']' after a value")),
+            }
+        }
+    }
+
+    /// Helper function to parse a string.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, String>` - The string with its escapes resolved, or an error if it is
+    ///   malformed.
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode_escape()?,
+                        _ => return Err(self.error("unknown escape in a string")),
+                    };
+                    string.push(c);
+                }
+                Some(c) if c.is_control() => {
+                    return Err(self.error("control character in a string"))
+                }
+                Some(c) => string.push(c),
+                None => return Err(self.error("string is not closed")),
+            }
+        }
+    }
+
+    /// Helper function to parse the digits of a `\u` escape, and of the second half of a
+    /// surrogate pair if they start one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<char, String>` - The character, or an error if the escape is malformed.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex_digits()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.next() != Some('\\') || self.next() != Some('u') {
+                return Err(self.error("unpaired surrogate in a string"));
+            }
+            let low = self.hex_digits()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate in a string"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate in a string"))
+    }
+
+    /// Helper function to parse the four hex digits of a `\u` escape.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u32, String>` - The code unit, or an error if the digits are malformed.
+    fn hex_digits(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("expected four hex digits after \\u"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    /// Helper function to parse a number.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Value, String>` - The number, or an error if it is malformed.
+    fn number(&mut self) -> Result<Value, String> {
+        let mut number = String::new();
+        while let Some(c @ ('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) = self.chars.peek().copied() {
+            number.push(c);
+            self.next();
+        }
+        number
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| self.error(&format!("malformed number {:?}", number)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_nested_values() {
+        let value = parse(
+            "{\"name\": \"A \\\"B\\\" \\u00e9\\ud83d\\ude00\", \"size\": 1.5e3,\n\
+             \"entries\": [true, false, null, {}, []], \"n\": -2}",
+        )
+        .unwrap();
+        assert_eq!(
+            value.get("name"),
+            Some(&Value::String("A \"B\" \u{e9}\u{1f600}".to_string()))
+        );
+        assert_eq!(value.get("size"), Some(&Value::Number(1500.0)));
+        assert_eq!(value.get("n"), Some(&Value::Number(-2.0)));
+        assert_eq!(
+            value.get("entries"),
+            Some(&Value::Array(vec![
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Null,
+                Value::Object(Vec::new()),
+                Value::Array(Vec::new()),
+            ]))
+        );
+        assert_eq!(value.get("missing"), None);
+
+        // What `string` writes is read back.
+        let text = "quote \" backslash \\ tab \t";
+        assert_eq!(parse(&string(text)), Ok(Value::String(text.to_string())));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_documents() {
+        assert_eq!(
+            parse("{\"a\": 1,\n\"a\": 2}"),
+            Err("Invalid JSON on line 2: member \"a\" is given twice".to_string())
+        );
+        for text in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"open",
+            "nope",
+            "1 2",
+            "\"\\ud800\"",
+        ] {
+            assert!(parse(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! This module implements the `label` subcommand, which reads and sets the volume label on a disk
+//! image that is not mounted. The label is stored twice, in the boot sector and as an entry in the
+//! root directory, and both are kept in sync. With `--dry-run`, the disk image is only opened for
+//! reading, and the change is printed by the `dry_run` module instead.
+use crate::direntry;
+use crate::dry_run::{print_action, Arg};
+use crate::filesystem::format_volume_id;
+use crate::serial::{read_boot_sector, read_serial};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The label stored in the boot sector of volumes without a label.
+const NO_NAME: &[u8; 11] = b"NO NAME    ";
+
+/// The characters DOS and Windows don't allow in a volume label.
+const FORBIDDEN_CHARS: &str = "\"*+,./:;<=>?[\\]|";
+
+/// Run the `label` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> [LABEL|--clear]
+///   [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 otherwise.
+pub fn run(args: &[String]) -> i32 {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--dry-run")
+        .cloned()
+        .collect();
+    let (disk_image_path, new_label) = match &args[..] {
+        [disk_image_path] => (PathBuf::from(disk_image_path), None),
+        [disk_image_path, label] if label == "--clear" => {
+            (PathBuf::from(disk_image_path), Some(None))
+        }
+        [disk_image_path, label] if !label.starts_with("--") => match parse_label(label) {
+            Some(label) => (PathBuf::from(disk_image_path), Some(Some(label))),
+            None => {
+                eprintln!(
+                    "Invalid volume label '{}', expected up to 11 ASCII characters, none of {}.",
+                    label, FORBIDDEN_CHARS
+                );
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("Usage: nuFAT label <disk.img> [LABEL|--clear] [--dry-run]");
+            return 1;
+        }
+    };
+
+    let image = OpenOptions::new()
+        .read(true)
+        .write(new_label.is_some() && !dry_run)
+        .open(&disk_image_path);
+    let result = image.and_then(|image| match new_label {
+        Some(label) if dry_run => {
+            let old_label = read_label(&image)?;
+            print_action(
+                "label",
+                &[
+                    Arg::Text(&old_label.unwrap_or_default()),
+                    Arg::Text(&label.map(|label| format_label(&label)).unwrap_or_default()),
+                ],
+            );
+            Ok(())
+        }
+        Some(label) => {
+            let old_label = read_label(&image)?;
+            write_label(&image, label.as_ref())?;
+            image.sync_all()?;
+            println!(
+                "Changed volume label from {} to {}.",
+                describe_label(old_label),
+                describe_label(label.map(|label| format_label(&label)))
+            );
+            Ok(())
+        }
+        None => {
+            let label = read_label(&image)?;
+            let serial = read_serial(&image).map_or("none".to_string(), format_volume_id);
+            println!("Label:  {}", label.unwrap_or_default());
+            println!("Serial: {}", serial);
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Parse a volume label. Like DOS and Windows, lowercase letters are stored in uppercase.
+///
+/// # Parameters
+///
+/// * `label: &str` - The label to parse.
+///
+/// # Returns
+///
+/// * `Option<[u8; 11]>` - The label padded with spaces, or `None` if it is empty, too long or
+///   contains characters a label can't hold.
+pub fn parse_label(label: &str) -> Option<[u8; 11]> {
+    if label.is_empty()
+        || label.len() > 11
+        || label.starts_with(' ')
+        || !label
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_control() && !FORBIDDEN_CHARS.contains(c))
+    {
+        return None;
+    }
+    let mut raw = [b' '; 11];
+    raw[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+    Some(raw)
+}
+
+/// Read the volume label. The entry in the root directory takes precedence over the boot sector,
+/// as it does for DOS and Windows.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<Option<String>>` - The label without padding, or `None` if the volume has none.
+pub fn read_label(mut image: &File) -> io::Result<Option<String>> {
+    if let Some(label) = direntry::read_volume_label(image)? {
+        return Ok(Some(format_label(&label)));
+    }
+
+    let label_offset = match read_boot_sector(image)?.label_offset {
+        Some(label_offset) => label_offset,
+        None => return Ok(None),
+    };
+    let mut label = [0; 11];
+    image.seek(SeekFrom::Start(label_offset))?;
+    image.read_exact(&mut label)?;
+    if &label == NO_NAME || label.iter().all(|c| matches!(c, b' ' | 0)) {
+        return Ok(None);
+    }
+    Ok(Some(format_label(&label)))
+}
+
+/// Write a new volume label to the root directory and the boot sector, and to the backup boot
+/// sector of FAT32 volumes so all stay consistent. Boot sectors without room for a label are left
+/// alone.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `label: Option<&[u8; 11]>` - The new label as returned by `parse_label`, or `None` to remove
+///   the label.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing the label failed.
+pub fn write_label(mut image: &File, label: Option<&[u8; 11]>) -> io::Result<()> {
+    let boot_sector = read_boot_sector(image)?;
+    // The root directory may be full, so change it before the boot sector.
+    direntry::write_volume_label(image, label)?;
+
+    let label_offset = match boot_sector.label_offset {
+        Some(label_offset) => label_offset,
+        None => return Ok(()),
+    };
+    let label = label.unwrap_or(NO_NAME);
+    image.seek(SeekFrom::Start(label_offset))?;
+    image.write_all(label)?;
+    if let Some(backup_boot_sector) = boot_sector.backup_boot_sector {
+        image.seek(SeekFrom::Start(backup_boot_sector + label_offset))?;
+        image.write_all(label)?;
+    }
+    image.rewind()?;
+    Ok(())
+}
+
+/// Format a volume label as stored, without its padding.
+///
+/// # Parameters
+///
+/// * `raw_label: &[u8; 11]` - The label padded with spaces.
+///
+/// # Returns
+///
+/// * `String` - The label.
+fn format_label(raw_label: &[u8; 11]) -> String {
+    String::from_utf8_lossy(raw_label).trim_end().to_string()
+}
+
+/// Describe a volume label for messages.
+///
+/// # Parameters
+///
+/// * `label: Option<String>` - The label, or `None` if there is none.
+///
+/// # Returns
+///
+/// * `String` - The quoted label, or `no label`.
+fn describe_label(label: Option<String>) -> String {
+    match label {
+        Some(label) => format!("'{}'", label),
+        None => "no label".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    #[test]
+    fn dry_runs_leave_the_label_alone() {
+        let image = TestImage::from_spec(r#"{"size": "16M", "label": "OLD"}"#);
+        let path = image.options().disk_image_path.display().to_string();
+        let checksum = image.checksum();
+        assert_eq!(
+            run(&[path.clone(), "NEW".to_string(), "--dry-run".to_string()]),
+            0
+        );
+        assert_eq!(
+            run(&[path.clone(), "--clear".to_string(), "--dry-run".to_string()]),
+            0
+        );
+        assert_eq!(image.checksum(), checksum);
+        assert_eq!(run(&[path, "NEW".to_string()]), 0);
+        assert_eq!(read_label(&image.open()).unwrap().as_deref(), Some("NEW"));
+    }
+}
@@ -0,0 +1,216 @@
+//! This module implements POSIX record locks as taken with `fcntl`. FAT has no notion of locks, so
+//! they are only kept in memory and are lost when the filesystem is unmounted. `fuser` doesn't
+//! pass interrupts on, so a process waiting for a lock couldn't be interrupted by a signal, and
+//! the wait is given up with `EINTR` after `WAIT_TIMEOUT` instead.
+use fuser::ReplyEmpty;
+use libc::{EINTR, F_UNLCK, F_WRLCK};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a request waits for a lock before it fails with `EINTR`.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Represent a lock on a range of a file.
+///
+/// # Members
+///
+/// * `owner: u64` - The lock owner as passed by the kernel, standing for the locking process.
+/// * `pid: u32` - The process that took the lock, reported to `F_GETLK`.
+/// * `start: u64` - The first byte of the range.
+/// * `end: u64` - The last byte of the range.
+/// * `typ: i32` - The type of the lock, `F_RDLCK` or `F_WRLCK`.
+#[derive(Clone, Copy, Debug)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+}
+
+impl Lock {
+    /// Check whether this lock keeps another one from being taken.
+    ///
+    /// # Parameters
+    ///
+    /// * `other: &Lock` - The lock to take.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if both locks have different owners, overlap and either is a write lock.
+    fn conflicts_with(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.start <= other.end
+            && other.start <= self.end
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// Represent a request for a lock that waits for conflicting locks to be released.
+///
+/// # Members
+///
+/// * `ino: u64` - The inode number of the file to lock.
+/// * `lock: Lock` - The lock to take.
+/// * `reply: ReplyEmpty` - The reply to send once the lock is taken.
+/// * `since: Instant` - When the request started waiting.
+struct Waiter {
+    ino: u64,
+    lock: Lock,
+    reply: ReplyEmpty,
+    since: Instant,
+}
+
+/// Represent the locks held on all files.
+///
+/// # Members
+///
+/// * `locks: HashMap<u64, Vec<Lock>>` - The locks held on every inode.
+/// * `waiters: Vec<Waiter>` - The requests waiting for a lock, oldest first.
+#[derive(Default)]
+pub struct LockTable {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter>,
+}
+
+impl LockTable {
+    /// Find a lock keeping another one from being taken.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file to lock.
+    /// * `lock: &Lock` - The lock to take.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Lock>` - The first conflicting lock, or `None` if the lock can be taken.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts_with(lock))
+            .copied()
+    }
+
+    /// Take a lock, or release a range with `F_UNLCK`. Locks of the same owner on the range are
+    /// replaced, so a lock can be up- or downgraded in place.
+    ///
+    /// Requests waiting for a lock are granted once nothing conflicts with them anymore.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file to lock.
+    /// * `lock: Lock` - The lock to take, which must not conflict with any held lock.
+    pub fn set(&mut self, ino: u64, lock: Lock) {
+        self.apply(ino, lock);
+        self.wake();
+    }
+
+    /// Queue a request for a lock until the conflicting locks are released, or the wait times
+    /// out.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file to lock.
+    /// * `lock: Lock` - The lock to take.
+    /// * `reply: ReplyEmpty` - The reply to send once the lock is taken.
+    pub fn wait(&mut self, ino: u64, lock: Lock, reply: ReplyEmpty) {
+        self.waiters.push(Waiter {
+            ino,
+            lock,
+            reply,
+            since: Instant::now(),
+        });
+    }
+
+    /// Fail the requests that waited for a lock for longer than `WAIT_TIMEOUT` with `EINTR`, as
+    /// if the waiting process had been interrupted.
+    pub fn expire(&mut self) {
+        let (expired, waiting) = self
+            .waiters
+            .drain(..)
+            .partition(|waiter| waiter.since.elapsed() >= WAIT_TIMEOUT);
+        self.waiters = waiting;
+        for waiter in expired {
+            waiter.reply.error(EINTR);
+        }
+    }
+
+    /// Release all locks an owner holds on a file, and cancel its waiting requests, as happens
+    /// when the owner closes the file.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file.
+    /// * `owner: u64` - The lock owner.
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        let (cancelled, waiting) = self
+            .waiters
+            .drain(..)
+            .partition(|waiter| waiter.ino == ino && waiter.lock.owner == owner);
+        self.waiters = waiting;
+        for waiter in cancelled {
+            waiter.reply.error(EINTR);
+        }
+
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|held| held.owner != owner);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+        self.wake();
+    }
+
+    /// Helper function to take a lock or release a range without waking waiting requests.
+    ///
+    /// # Parameters
+    ///
+    /// * `ino: u64` - The inode number of the file to lock.
+    /// * `lock: Lock` - The lock to take, or the range to release.
+    fn apply(&mut self, ino: u64, lock: Lock) {
+        let locks = self.locks.remove(&ino).unwrap_or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 1);
+        for held in locks {
+            if held.owner != lock.owner || held.end < lock.start || lock.end < held.start {
+                kept.push(held);
+                continue;
+            }
+            // Keep the parts of the held lock outside of the range.
+            if held.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..held
+                });
+            }
+            if lock.end < held.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..held
+                });
+            }
+        }
+        if lock.typ != F_UNLCK {
+            kept.push(lock);
+        }
+
+        if !kept.is_empty() {
+            self.locks.insert(ino, kept);
+        }
+    }
+
+    /// Helper function to grant the waiting requests nothing conflicts with anymore, oldest first.
+    fn wake(&mut self) {
+        let mut index = 0;
+        while index < self.waiters.len() {
+            let Waiter { ino, lock, .. } = self.waiters[index];
+            if self.conflict(ino, &lock).is_some() {
+                index += 1;
+                continue;
+            }
+            let waiter = self.waiters.remove(index);
+            self.apply(ino, lock);
+            waiter.reply.ok();
+        }
+    }
+}
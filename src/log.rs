@@ -0,0 +1,284 @@
+//! This module writes the messages nuFAT logs while serving filesystems to standard error. They
+//! are plain text by default. With `--log-format json`, every message is a JSON object on a line
+//! of its own for log collectors, with the time, level, module and message, and the operation,
+//! path, inode and error code where those are known. Records describe what happened, they never
+//! hold the contents of files.
+use crate::json;
+use crate::options::LogFormat;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether messages are logged as JSON, as set by `--log-format`.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Represent how severe a logged message is.
+///
+/// # Variants
+///
+/// * `Info` - Something happened as it should, e.g. the disk image was reopened.
+/// * `Warning` - Something may be wrong, but nuFAT carries on, e.g. the volume is dirty.
+/// * `Error` - Something failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Represent the details a logged message may carry besides its text.
+///
+/// # Members
+///
+/// * `op: Option<&str>` - The FUSE operation that was served, e.g. `write`.
+/// * `path: Option<&Path>` - The path of the entry on the volume.
+/// * `inode: Option<u64>` - The inode number of the entry.
+/// * `errno: Option<i32>` - The error code the operation failed with.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fields<'a> {
+    pub op: Option<&'a str>,
+    pub path: Option<&'a Path>,
+    pub inode: Option<u64>,
+    pub errno: Option<i32>,
+}
+
+/// Set how messages are logged from now on.
+///
+/// # Parameters
+///
+/// * `format: LogFormat` - The format, as given with `--log-format`.
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+/// Log a message without details.
+///
+/// # Parameters
+///
+/// * `level: Level` - How severe the message is.
+/// * `target: &str` - The module logging it, as given by `module_path!()`.
+/// * `message: &str` - The message.
+pub fn log(level: Level, target: &str, message: &str) {
+    log_with(level, target, message, &Fields::default());
+}
+
+/// Log a message with details. In plain text, the details are left out, the message names what
+/// matters of them.
+///
+/// # Parameters
+///
+/// * `level: Level` - How severe the message is.
+/// * `target: &str` - The module logging it, as given by `module_path!()`.
+/// * `message: &str` - The message.
+/// * `fields: &Fields` - The details.
+pub fn log_with(level: Level, target: &str, message: &str, fields: &Fields) {
+    let format = if JSON.load(Ordering::SeqCst) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    };
+    let record = format_record(format, SystemTime::now(), level, target, message, fields);
+    // There is nowhere left to report a failure to write to standard error.
+    let _ = write_record(&mut io::stderr().lock(), &record);
+}
+
+/// Write a record and the newline ending it in one go, so records of several threads don't mix.
+///
+/// # Parameters
+///
+/// * `out: &mut impl Write` - Where to write the record.
+/// * `record: &str` - The record.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing failed.
+fn write_record(out: &mut impl Write, record: &str) -> io::Result<()> {
+    out.write_all(format!("{}\n", record).as_bytes())
+}
+
+/// Format a record to log.
+///
+/// # Parameters
+///
+/// * `format: LogFormat` - Whether to format it as plain text or JSON.
+/// * `time: SystemTime` - When the message was logged.
+/// * `level: Level` - How severe the message is.
+/// * `target: &str` - The module logging it.
+/// * `message: &str` - The message.
+/// * `fields: &Fields` - The details.
+///
+/// # Returns
+///
+/// * `String` - The record, without a newline.
+fn format_record(
+    format: LogFormat,
+    time: SystemTime,
+    level: Level,
+    target: &str,
+    message: &str,
+    fields: &Fields,
+) -> String {
+    if format == LogFormat::Text {
+        return match level {
+            Level::Warning => format!("WARNING: {}", message),
+            Level::Info | Level::Error => message.to_string(),
+        };
+    }
+
+    let level = match level {
+        Level::Info => "info",
+        Level::Warning => "warning",
+        Level::Error => "error",
+    };
+    let mut record = format!(
+        "{{\"timestamp\": {}, \"level\": \"{}\", \"target\": {}, \"message\": {}",
+        json::string(&format_timestamp(time)),
+        level,
+        json::string(target),
+        json::string(message)
+    );
+    if let Some(op) = fields.op {
+        record.push_str(&format!(", \"op\": {}", json::string(op)));
+    }
+    if let Some(path) = fields.path {
+        record.push_str(&format!(
+            ", \"path\": {}",
+            json::string(&path.to_string_lossy())
+        ));
+    }
+    if let Some(inode) = fields.inode {
+        record.push_str(&format!(", \"inode\": {}", inode));
+    }
+    if let Some(errno) = fields.errno {
+        record.push_str(&format!(", \"errno\": {}", errno));
+    }
+    record.push('}');
+    record
+}
+
+/// Format a time as in RFC 3339, in UTC with milliseconds, e.g. `2024-05-01T12:34:56.789Z`.
+///
+/// # Parameters
+///
+/// * `time: SystemTime` - The time.
+///
+/// # Returns
+///
+/// * `String` - The formatted time.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    // The civil date of a day since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Value;
+    use std::time::Duration;
+
+    #[test]
+    fn json_records_are_one_parsable_line() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_294_096_789);
+        let fields = Fields {
+            op: Some("write"),
+            path: Some(Path::new("/DOCS/\"quoted\".txt")),
+            inode: Some(42),
+            errno: Some(5),
+        };
+        let record = format_record(
+            LogFormat::Json,
+            time,
+            Level::Error,
+            "nuFAT::filesystem",
+            "Writing to the disk image failed: Input/output error\nAborting!",
+            &fields,
+        );
+        let mut captured = Vec::new();
+        write_record(&mut captured, &record).unwrap();
+        let captured = String::from_utf8(captured).unwrap();
+        let line = captured.strip_suffix('\n').unwrap();
+        assert!(!line.contains('\n'));
+
+        let value = json::parse(line).unwrap();
+        let string = |key| match value.get(key) {
+            Some(Value::String(string)) => string.as_str(),
+            other => panic!("{}: {:?}", key, other),
+        };
+        assert_eq!(string("timestamp"), "2024-03-01T11:54:56.789Z");
+        assert_eq!(string("level"), "error");
+        assert_eq!(string("target"), "nuFAT::filesystem");
+        assert_eq!(
+            string("message"),
+            "Writing to the disk image failed: Input/output error\nAborting!"
+        );
+        assert_eq!(string("op"), "write");
+        assert_eq!(string("path"), "/DOCS/\"quoted\".txt");
+        assert_eq!(value.get("inode"), Some(&Value::Number(42.0)));
+        assert_eq!(value.get("errno"), Some(&Value::Number(5.0)));
+
+        // Details that are not known are left out.
+        let record = format_record(
+            LogFormat::Json,
+            time,
+            Level::Warning,
+            "nuFAT",
+            "dirty",
+            &Fields::default(),
+        );
+        let value = json::parse(&record).unwrap();
+        assert_eq!(value.get("level"), Some(&Value::String("warning".into())));
+        assert_eq!(value.get("op"), None);
+        assert_eq!(value.get("errno"), None);
+    }
+
+    #[test]
+    fn text_records_are_unchanged_messages() {
+        let text = |level| {
+            format_record(
+                LogFormat::Text,
+                UNIX_EPOCH,
+                level,
+                "nuFAT",
+                "The volume is dirty.",
+                &Fields::default(),
+            )
+        };
+        assert_eq!(text(Level::Warning), "WARNING: The volume is dirty.");
+        assert_eq!(text(Level::Error), "The volume is dirty.");
+        assert_eq!(text(Level::Info), "The volume is dirty.");
+    }
+
+    #[test]
+    fn format_timestamp_gives_the_civil_date() {
+        let at = |secs| format_timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(4_102_444_799), "2099-12-31T23:59:59.000Z");
+    }
+}
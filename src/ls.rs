@@ -0,0 +1,146 @@
+//! This module implements the `ls` subcommand, which lists files and directories on a disk image
+//! that is not mounted, with their attributes, sizes and modification times. Paths can be glob
+//! patterns, expanded by the `tree` module.
+use crate::attr::format_letters;
+use crate::filesystem::lock_image;
+use crate::tree::{self, Found};
+use std::fs::File;
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT ls <disk.img> [PATTERN...] [--allow-empty]";
+
+/// Run the `ls` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> [PATTERN...]
+///   [--allow-empty]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or a pattern matches
+///   nothing, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut allow_empty = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--allow-empty" => allow_empty = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let Some((disk_image_path, patterns)) = paths.split_first() else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let patterns = if patterns.is_empty() {
+        &["/"][..]
+    } else {
+        patterns
+    };
+    let patterns = match tree::parse_patterns(patterns) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let result = File::open(disk_image_path).and_then(|image| {
+        lock_image(&image, false)?;
+        let fs = tree::open_volume(&image)?;
+        let found = tree::expand(&fs, &patterns, allow_empty)?;
+        for entry in &found {
+            // A directory named without wildcards is listed with what is in it, like `ls` does.
+            let named = patterns
+                .iter()
+                .any(|pattern| !pattern.has_wildcards() && pattern.matches(&entry.path));
+            if entry.is_dir && named {
+                for child in tree::list(&fs, entry)? {
+                    println!("{}", format_entry(&child));
+                }
+            } else {
+                println!("{}", format_entry(entry));
+            }
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+/// Format an entry as listed, e.g. `-h-a       1234 2024-05-01 12:34:56 /docs/notes.txt`.
+/// Directories end in a slash.
+///
+/// # Parameters
+///
+/// * `entry: &Found` - The entry.
+///
+/// # Returns
+///
+/// * `String` - The line to print.
+fn format_entry(entry: &Found) -> String {
+    format!(
+        "{} {:>10} {} {}{}",
+        format_letters(entry.attributes),
+        entry.size,
+        tree::format_time(entry.modified),
+        entry.path.display(),
+        if entry.is_dir && entry.path.parent().is_some() {
+            "/"
+        } else {
+            ""
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    #[test]
+    fn entries_are_formatted_with_their_attributes() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "boot", "modified": "2024-05-01 12:34:56", "entries": []},
+                {"name": "notes.txt", "size": 1234, "attributes": "ha",
+                 "modified": "2023-01-02 03:04:06"}
+            ]}"#,
+        );
+        let file = image.open();
+        let fs = tree::open_volume(&file).unwrap();
+        let root = tree::find(&fs, &PathBuf::from("/"), &PathBuf::from("/")).unwrap();
+        let lines: Vec<String> = tree::list(&fs, &root)
+            .unwrap()
+            .iter()
+            .map(format_entry)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "----          0 2024-05-01 12:34:56 /boot/",
+                "-h-a       1234 2023-01-02 03:04:06 /notes.txt"
+            ]
+        );
+        let args = |args: &[&str]| {
+            let mut all = vec![image.options().disk_image_path.display().to_string()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            all
+        };
+        assert_eq!(run(&args(&["*.TXT"])), 0);
+        assert_eq!(run(&args(&["*.cfg"])), 1);
+        assert_eq!(run(&args(&["*.cfg", "--allow-empty"])), 0);
+        assert_eq!(run(&args(&["--long"])), 2);
+    }
+}
@@ -1,25 +1,470 @@
-use fuser::mount2;
+use fuser::{MountOption, Session, SessionUnmounter};
+use libc::{c_int, SIGINT, SIGTERM};
 use std::env;
+use std::fs;
+use std::io;
+#[cfg(feature = "metrics")]
+use std::net::TcpListener;
 use std::path::Path;
+#[cfg(feature = "metrics")]
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
+mod alias;
+mod attr;
+mod badblocks;
+mod cache;
+mod codepage;
+mod copy;
+mod cp;
+mod direntry;
+mod dry_run;
+mod escape;
+mod exclude;
+mod export;
+mod extract;
 mod filesystem;
-use filesystem::FatFilesystem;
+mod floppy;
+mod fsck;
+mod glob;
+mod hash;
+mod import;
+mod info;
+mod json;
+mod label;
+mod locks;
+mod log;
+mod ls;
+mod map;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mkimage;
+mod normalization;
+mod notify;
+mod options;
+mod pack;
+mod rm;
+mod serial;
+mod storage;
+mod tar;
+#[cfg(test)]
+mod test_image;
+mod tree;
+mod umount;
+mod unpack;
+mod wipe;
+use filesystem::{install_reopen_handler, Activity, FatFilesystem};
+use log::Level;
+use notify::notify;
+use options::Options;
+
+/// Set by the `SIGINT` and `SIGTERM` handler to unmount all filesystems.
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+/// Install a handler for `SIGINT` and `SIGTERM`, which makes nuFAT unmount all filesystems and
+/// exit.
+fn install_terminate_handler() {
+    extern "C" fn handle_terminate(_signal: c_int) {
+        TERMINATE.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(SIGINT, handle_terminate as *const () as libc::sighandler_t);
+        libc::signal(SIGTERM, handle_terminate as *const () as libc::sighandler_t);
+    }
+}
+
+/// Represent a mounted disk image, as seen by the main thread.
+///
+/// # Members
+///
+/// * `state: String` - The disk image, mount point and state of the volume, for status reports.
+/// * `idle_timeout: Option<Duration>` - After how long without requests to unmount.
+/// * `flush_deadline: Option<Duration>` - How long after a modification the disk image is synced
+///   at the latest.
+/// * `activity: Arc<Activity>` - The activity on the filesystem.
+/// * `unmounter: SessionUnmounter` - Used to unmount the filesystem.
+/// * `mount_point: PathBuf` - The directory the filesystem is mounted on, to label its metrics.
+struct Mounted {
+    state: String,
+    idle_timeout: Option<Duration>,
+    flush_deadline: Option<Duration>,
+    activity: Arc<Activity>,
+    unmounter: SessionUnmounter,
+    #[cfg(feature = "metrics")]
+    mount_point: PathBuf,
+}
+
+/// Check that the mount point is empty, so mounting doesn't hide any files in it.
+///
+/// # Parameters
+///
+/// * `mount_point: &Path` - The directory to mount the filesystem on.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error naming some of the entries in the mount point if it is not empty.
+fn check_mount_point(mount_point: &Path) -> io::Result<()> {
+    let entries = fs::read_dir(mount_point)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut shadowed = entries
+        .iter()
+        .take(3)
+        .map(|name| format!("{:?}", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if entries.len() > 3 {
+        shadowed.push_str(&format!(" and {} more", entries.len() - 3));
+    }
+    Err(io::Error::other(format!(
+        "Mount point is not empty, mounting would hide {}. Pass -o nonempty to mount anyway.",
+        shadowed
+    )))
+}
+
+/// Mount a disk image.
+///
+/// # Parameters
+///
+/// * `options: Options` - The options to mount with, including the disk image and mount point.
+///
+/// # Returns
+///
+/// * `io::Result<(Session<FatFilesystem>, Mounted)>` - The FUSE session serving the mounted
+///   filesystem, and the handle for the main thread.
+fn mount(options: Options) -> io::Result<(Session<FatFilesystem>, Mounted)> {
+    let mount_point = options.mount_point.clone();
+    #[cfg(feature = "metrics")]
+    let session_mount_point = mount_point.clone();
+    let idle_timeout = options.idle_timeout;
+    let flush_deadline = options.flush_deadline;
+    if !options.nonempty {
+        check_mount_point(&mount_point)?;
+    }
+    let mut state = format!("{} on {:?}", options.image_name(), mount_point);
+    let mut mount_options = options.kernel_mount_options();
+    let mut filesystem = FatFilesystem::new(options)?;
+    let activity = filesystem.activity();
+    let invalidations = filesystem.invalidations();
+
+    if filesystem.is_dirty() {
+        state.push_str(", dirty");
+    }
+    if filesystem.is_read_only() {
+        mount_options.push(MountOption::RO);
+        state.push_str(", read-only");
+    }
+
+    let mut session = Session::new(filesystem, mount_point, &mount_options)?;
+    // The kernel may wait for the thread serving the filesystem while it takes in a notification,
+    // so they are sent by a thread of their own. It ends with the session.
+    let notifier = session.notifier();
+    thread::spawn(move || {
+        for invalidation in invalidations {
+            // Fails if the kernel had nothing cached.
+            let _ = invalidation.send(&notifier);
+        }
+    });
+    let mounted = Mounted {
+        state,
+        idle_timeout,
+        flush_deadline,
+        activity,
+        unmounter: session.unmount_callable(),
+        #[cfg(feature = "metrics")]
+        mount_point: session_mount_point,
+    };
+    Ok((session, mounted))
+}
 
 fn main() {
     // Collect and parse CLI arguments
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 3 {
-        eprint!("Usage: {} <disk.img> <mount_point>", args[0]);
-        process::exit(1);
+    if args.get(1).map(String::as_str) == Some("umount") {
+        process::exit(umount::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("serial") {
+        process::exit(serial::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("label") {
+        process::exit(label::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("attr") {
+        process::exit(attr::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("map") {
+        process::exit(map::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("info") {
+        process::exit(info::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("badblocks") {
+        process::exit(badblocks::run(&args[2..]));
     }
+    if args.get(1).map(String::as_str) == Some("fsck") {
+        process::exit(fsck::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("wipe-free") {
+        process::exit(wipe::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("mkimage") {
+        process::exit(mkimage::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("ls") {
+        process::exit(ls::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("extract") {
+        process::exit(extract::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("rm") {
+        process::exit(rm::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("hash") {
+        process::exit(hash::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("cp") {
+        process::exit(cp::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("pack") {
+        process::exit(pack::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("unpack") {
+        process::exit(unpack::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("export") {
+        process::exit(export::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("import") {
+        process::exit(import::run(&args[2..]));
+    }
+
+    let mounts = match Options::parse(&args[1..]) {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: {} [-o option[,option...]] [--io-retries N] [--force-rw] [--idle-timeout SECS] [--cache-limit BYTES] [--log-format text|json] [--metrics-listen [ADDR:]PORT] <disk.img | --image-fd N> <mount_point>",
+                args[0]
+            );
+            eprintln!(
+                "       {} [options] [--keep-going] --image <disk.img> --at <mount_point> [--image <disk.img> --at <mount_point>...]",
+                args[0]
+            );
+            eprintln!(
+                "       {} umount <mount_point> [--timeout SECS] [--force]",
+                args[0]
+            );
+            eprintln!(
+                "       {} serial <disk.img> [XXXX-XXXX|HEX|--random] [--dry-run]",
+                args[0]
+            );
+            eprintln!(
+                "       {} label <disk.img> [LABEL|--clear] [--dry-run]",
+                args[0]
+            );
+            eprintln!("       {} info <disk.img>", args[0]);
+            eprintln!("       {} map <disk.img> [--file PATH] [--json]", args[0]);
+            eprintln!(
+                "       {} attr <disk.img> <path> [--recursive] [+r|-r] [+h|-h] [+s|-s] [+a|-a] [--dry-run]",
+                args[0]
+            );
+            eprintln!(
+                "       {} fsck <disk.img> [--repair [--mode free|recover]]",
+                args[0]
+            );
+            eprintln!(
+                "       {} badblocks <disk.img|device> [--write-test] [--mark]",
+                args[0]
+            );
+            eprintln!(
+                "       {} wipe-free <disk.img> [--pattern BYTE] [--scrub-dirents]",
+                args[0]
+            );
+            eprintln!(
+                "       {} mkimage --spec <SPEC.json|-> <disk.img> [--force]",
+                args[0]
+            );
+            eprintln!(
+                "       {} ls <disk.img> [PATTERN...] [--allow-empty]",
+                args[0]
+            );
+            eprintln!(
+                "       {} extract <disk.img> <PATTERN...> [--output DIR] [--recursive] [--dotify-hidden] [--force|--update] [--allow-empty] [--owner UID[:GID]] [--json]",
+                args[0]
+            );
+            eprintln!(
+                "       {} rm <disk.img> <PATTERN...> [--recursive] [--allow-empty] [--dry-run]",
+                args[0]
+            );
+            eprintln!(
+                "       {} hash <disk.img> <PATTERN...> [--allow-empty]",
+                args[0]
+            );
+            eprintln!(
+                "       {} cp [-r|--recursive] <disk.img> <host:SRC|img:SRC> <img:DST|host:DST> [--force|--update] [--exclude GLOB]... [--exclude-from FILE]... [--dotify-hidden] [--dry-run]",
+                args[0]
+            );
+            eprintln!(
+                "       {} pack <DIR> <disk.img> --size SIZE|auto [--fat-type FAT12|FAT16|FAT32] [--label LABEL] [--force] [--exclude GLOB]... [--exclude-from FILE]... [--shortname-policy auto|warn|strict] [--shortname-map FILE] [--dry-run]",
+                args[0]
+            );
+            eprintln!(
+                "       {} unpack <disk.img> <DIR> [--force|--update] [--exclude GLOB]... [--exclude-from FILE]... [--dotify-hidden] [--owner UID[:GID]] [--json]",
+                args[0]
+            );
+            eprintln!(
+                "       {} export <disk.img> <archive.tar|-> [--force] [--owner UID[:GID]]",
+                args[0]
+            );
+            eprintln!(
+                "       {} import <disk.img> <archive.tar|-> [--force]",
+                args[0]
+            );
+            process::exit(2);
+        }
+    };
+    let keep_going = mounts[0].keep_going;
+    log::set_format(mounts[0].log_format);
+    // Binding may fail, e.g. if the port is taken, which is better found out before mounting.
+    #[cfg(feature = "metrics")]
+    let metrics_listener = match mounts[0].metrics_listen.map(TcpListener::bind) {
+        Some(Ok(listener)) => Some(listener),
+        Some(Err(e)) => {
+            eprintln!("Failed to listen for metrics scrapes: {}", e);
+            process::exit(1);
+        }
+        None => None,
+    };
 
-    let disk_image_path = Path::new(&args[1]);
-    let mount_point = Path::new(&args[2]);
+    install_reopen_handler();
+    install_terminate_handler();
+
+    // Every filesystem is served by its own thread. `fatfs` can't be moved between threads, so
+    // the filesystem is created by the thread serving it.
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = Vec::new();
+    for options in mounts {
+        let sender = sender.clone();
+        threads.push(thread::spawn(move || {
+            let description = format!("{} on {:?}", options.image_name(), options.mount_point);
+            match mount(options) {
+                Ok((mut session, mounted)) => {
+                    let _ = sender.send(Ok(mounted));
+                    match session.run() {
+                        Ok(()) => true,
+                        Err(e) => {
+                            log::log(
+                                Level::Error,
+                                module_path!(),
+                                &format!("Failed to serve filesystem {}: {}", description, e),
+                            );
+                            false
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(format!(
+                        "Failed to mount filesystem {}: {}",
+                        description, e
+                    )));
+                    false
+                }
+            }
+        }));
+    }
+
+    let mut mounted: Vec<Mounted> = Vec::new();
+    let mut failed = false;
+    for result in receiver.iter().take(threads.len()) {
+        match result {
+            Ok(m) => mounted.push(m),
+            Err(e) => {
+                log::log(Level::Error, module_path!(), &e);
+                failed = true;
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(listener) = metrics_listener {
+        let sources = mounted
+            .iter()
+            .map(|m| (m.mount_point.display().to_string(), Arc::clone(&m.activity)))
+            .collect();
+        metrics::serve(listener, sources);
+    }
+
+    // Unless asked to keep going, don't leave some of the images mounted if others failed.
+    if failed && !keep_going {
+        TERMINATE.store(true, Ordering::SeqCst);
+    } else {
+        let states: Vec<&str> = mounted.iter().map(|m| m.state.as_str()).collect();
+        notify(&format!("READY=1\nSTATUS=Mounted {}", states.join("; ")));
+    }
+
+    while !TERMINATE.load(Ordering::SeqCst) && !threads.iter().all(|t| t.is_finished()) {
+        thread::sleep(Duration::from_millis(100));
+
+        for m in &mut mounted {
+            m.activity.expire_lock_waits();
+            // Files kept open for long don't hold back syncing what was written to them.
+            if let Some(deadline) = m.flush_deadline {
+                if let Err(e) = m.activity.flush_overdue(deadline) {
+                    log::log(
+                        Level::Error,
+                        module_path!(),
+                        &format!("Failed to sync {}: {}", m.state, e),
+                    );
+                }
+            }
+            if let Some(idle_timeout) = m.idle_timeout.filter(|t| m.activity.is_idle(*t)) {
+                log::log(
+                    Level::Info,
+                    module_path!(),
+                    &format!(
+                        "Unmounting {} after {} seconds of inactivity.",
+                        m.state,
+                        idle_timeout.as_secs()
+                    ),
+                );
+                if let Err(e) = m.unmounter.unmount() {
+                    log::log(
+                        Level::Error,
+                        module_path!(),
+                        &format!("Failed to unmount filesystem: {}", e),
+                    );
+                }
+                m.idle_timeout = None;
+            }
+        }
+    }
+    notify("STOPPING=1");
+    for m in &mut mounted {
+        if let Err(e) = m.unmounter.unmount() {
+            log::log(
+                Level::Error,
+                module_path!(),
+                &format!("Failed to unmount filesystem: {}", e),
+            );
+        }
+    }
+    for thread in threads {
+        if !thread.join().unwrap_or(false) {
+            failed = true;
+        }
+    }
 
-    if let Err(e) = mount2(FatFilesystem::new(disk_image_path), mount_point, &[]) {
-        eprintln!("Failed to mount filesystem: {}", e);
+    if failed {
         process::exit(1);
     }
 }
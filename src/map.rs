@@ -0,0 +1,359 @@
+//! This module implements the `map` subcommand, which shows which clusters of a disk image that
+//! is not mounted are free, used or bad, e.g. to see how fragmented the volume or a file is. The
+//! FAT is read directly, through the `direntry` module, and drawn as a grid of characters that
+//! each stand for as many clusters as it takes to fit the volume into the terminal.
+use crate::direntry::{self, ClusterMap, ClusterState};
+use crate::filesystem::{check_supported, find_entry, short_names};
+use crate::floppy;
+use crate::json;
+use crate::storage::Storage;
+use fatfs::{FileSystem as FatfsFileSystem, FsOptions};
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::{self, ErrorKind, Seek};
+use std::path::{Path, PathBuf};
+
+/// The most rows the grid takes up, so it fits into a terminal with the legend above it.
+const MAX_ROWS: u64 = 20;
+
+/// The width of the grid if the width of the terminal is unknown.
+const DEFAULT_COLUMNS: usize = 80;
+
+/// Represent a file whose clusters are highlighted.
+///
+/// # Members
+///
+/// * `path: PathBuf` - The path of the file, with its long name.
+/// * `clusters: Vec<u32>` - The cluster chain of the file, in order.
+struct Highlight {
+    path: PathBuf,
+    clusters: Vec<u32>,
+}
+
+/// Run the `map` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> [--file PATH] [--json]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 otherwise.
+pub fn run(args: &[String]) -> i32 {
+    let mut disk_image_path = None;
+    let mut file_path = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--file" if file_path.is_none() => match args.next() {
+                Some(path) => file_path = Some(Path::new("/").join(path.trim_start_matches('/'))),
+                None => disk_image_path = None,
+            },
+            _ if disk_image_path.is_none() && !arg.starts_with("--") => {
+                disk_image_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                disk_image_path = None;
+                break;
+            }
+        }
+    }
+    let Some(disk_image_path) = disk_image_path else {
+        eprintln!("Usage: nuFAT map <disk.img> [--file PATH] [--json]");
+        return 1;
+    };
+
+    let result = File::open(&disk_image_path).and_then(|image| {
+        let map = direntry::cluster_map(&image)?;
+        let highlight = match &file_path {
+            Some(path) => Some(find_highlight(&image, path)?),
+            None => None,
+        };
+        if json {
+            println!("{}", format_json(&map, highlight.as_ref()));
+        } else {
+            let file_clusters = highlight
+                .as_ref()
+                .map(|highlight| highlight.clusters.iter().copied().collect())
+                .unwrap_or_default();
+            let (per_char, rows) = draw_grid(&map, &file_clusters, terminal_columns());
+            println!(
+                "Each character stands for {} cluster{} of {} bytes:",
+                per_char,
+                if per_char == 1 { "" } else { "s" },
+                map.cluster_size
+            );
+            let mut legend = "R reserved, # used, + partly used, . free, B bad".to_string();
+            if let Some(highlight) = &highlight {
+                legend.push_str(&format!(", @ {}", highlight.path.display()));
+            }
+            println!("{}", legend);
+            for row in rows {
+                println!("{}", row);
+            }
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Find the clusters of the file or directory to highlight.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+/// * `path: &Path` - The absolute path of the file or directory.
+///
+/// # Returns
+///
+/// * `io::Result<Highlight>` - The file and its clusters, or a `NotFound` error if there is none.
+fn find_highlight(image: &File, path: &Path) -> io::Result<Highlight> {
+    check_supported(image)?;
+    // The short names are looked up with `fatfs`, which only reads the volume. It expects to
+    // start at the boot sector, but shares the offset with `image`.
+    let mut fatfs_image = image.try_clone()?;
+    fatfs_image.rewind()?;
+    let storage = Storage::new(fatfs_image, 0, floppy::patched_boot_sector(image)?);
+    let fs = FatfsFileSystem::new(storage, FsOptions::new())?;
+    let path = match path.parent() {
+        Some(parent) => {
+            let entry = find_entry(&fs, path).map_err(|e| match e.kind() {
+                ErrorKind::NotFound => {
+                    io::Error::new(e.kind(), format!("{} not found", path.display()))
+                }
+                _ => e,
+            })?;
+            // The name as stored, in case it was given in another case.
+            parent.join(entry.file_name())
+        }
+        None => path.to_path_buf(),
+    };
+    let clusters = match direntry::first_cluster_of(image, &short_names(&fs, &path)?)? {
+        Some(first_cluster) => direntry::cluster_chain(image, first_cluster)?,
+        None => Vec::new(),
+    };
+    Ok(Highlight { path, clusters })
+}
+
+/// Draw the clusters of the volume as a grid. The area before the first cluster, holding the boot
+/// sector, the FATs and the root directory of FAT12 and FAT16 volumes, is drawn as reserved
+/// clusters in front of it.
+///
+/// # Parameters
+///
+/// * `map: &ClusterMap` - The clusters of the volume.
+/// * `file_clusters: &HashSet<u32>` - The clusters to highlight.
+/// * `columns: usize` - The width of the grid.
+///
+/// # Returns
+///
+/// * `(u64, Vec<String>)` - How many clusters every character stands for, so the grid takes up
+///   at most `MAX_ROWS` rows, and the rows.
+fn draw_grid(map: &ClusterMap, file_clusters: &HashSet<u32>, columns: usize) -> (u64, Vec<String>) {
+    let columns = columns.max(1) as u64;
+    let reserved = map.data_start.div_ceil(map.cluster_size);
+    let total = reserved + map.states.len() as u64;
+    let per_char = total.div_ceil(columns * MAX_ROWS).max(1);
+
+    let cells = (0..total.div_ceil(per_char)).map(|cell| {
+        let (mut used, mut free, mut bad, mut highlighted) = (false, false, false, false);
+        for unit in cell * per_char..((cell + 1) * per_char).min(total) {
+            let Some(cluster) = unit.checked_sub(reserved).map(|index| index as u32 + 2) else {
+                continue;
+            };
+            highlighted |= file_clusters.contains(&cluster);
+            match map.states[cluster as usize - 2] {
+                ClusterState::Free => free = true,
+                ClusterState::Used => used = true,
+                ClusterState::Bad => bad = true,
+                ClusterState::Reserved => {}
+            }
+        }
+        match (highlighted, bad, used, free) {
+            (true, _, _, _) => '@',
+            (_, true, _, _) => 'B',
+            (_, _, true, true) => '+',
+            (_, _, true, false) => '#',
+            (_, _, false, true) => '.',
+            _ => 'R',
+        }
+    });
+    let cells: Vec<char> = cells.collect();
+    let rows = cells
+        .chunks(columns as usize)
+        .map(|row| row.iter().collect())
+        .collect();
+    (per_char, rows)
+}
+
+/// Format the clusters of the volume as JSON, as runs of clusters in the same state.
+///
+/// # Parameters
+///
+/// * `map: &ClusterMap` - The clusters of the volume.
+/// * `highlight: Option<&Highlight>` - The file given with `--file`, whose clusters are listed
+///   as runs of adjacent clusters in the order of its chain.
+///
+/// # Returns
+///
+/// * `String` - The JSON object.
+fn format_json(map: &ClusterMap, highlight: Option<&Highlight>) -> String {
+    let mut extents: Vec<(u32, u32, ClusterState)> = Vec::new();
+    for (index, state) in map.states.iter().enumerate() {
+        match extents.last_mut() {
+            Some((_, count, last)) if last == state => *count += 1,
+            _ => extents.push((index as u32 + 2, 1, *state)),
+        }
+    }
+    let extents: Vec<String> = extents
+        .iter()
+        .map(|(first, count, state)| {
+            let state = match state {
+                ClusterState::Free => "free",
+                ClusterState::Used => "used",
+                ClusterState::Bad => "bad",
+                ClusterState::Reserved => "reserved",
+            };
+            format!(
+                "    {{\"first\": {}, \"count\": {}, \"state\": \"{}\"}}",
+                first, count, state
+            )
+        })
+        .collect();
+
+    let mut json = format!(
+        "{{\n  \"cluster_size\": {},\n  \"data_start\": {},\n  \"clusters\": {},\n  \"extents\": [\n{}\n  ]",
+        map.cluster_size,
+        map.data_start,
+        map.states.len(),
+        extents.join(",\n")
+    );
+    if let Some(highlight) = highlight {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &cluster in &highlight.clusters {
+            match runs.last_mut() {
+                Some((first, count)) if *first + *count == cluster => *count += 1,
+                _ => runs.push((cluster, 1)),
+            }
+        }
+        let runs: Vec<String> = runs
+            .iter()
+            .map(|(first, count)| format!("{{\"first\": {}, \"count\": {}}}", first, count))
+            .collect();
+        json.push_str(&format!(
+            ",\n  \"file\": {{\"path\": {}, \"extents\": [{}]}}",
+            json::string(&highlight.path.to_string_lossy()),
+            runs.join(", ")
+        ));
+    }
+    json.push_str("\n}");
+    json
+}
+
+/// Get the width of the terminal, from the terminal itself or `COLUMNS` if the output is piped.
+///
+/// # Returns
+///
+/// * `usize` - The number of columns.
+fn terminal_columns() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+        && size.ws_col > 0
+    {
+        return size.ws_col as usize;
+    }
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+
+    /// Make up a cluster map without a reserved area in front of it.
+    fn map(states: &[ClusterState]) -> ClusterMap {
+        ClusterMap {
+            cluster_size: 512,
+            data_start: 0,
+            states: states.to_vec(),
+        }
+    }
+
+    #[test]
+    fn every_cluster_gets_a_character_if_they_fit() {
+        use ClusterState::*;
+        let map = ClusterMap {
+            data_start: 1024,
+            ..map(&[Used, Used, Free, Bad, Reserved, Used])
+        };
+        let (per_char, rows) = draw_grid(&map, &HashSet::from([3]), 4);
+        assert_eq!(per_char, 1);
+        assert_eq!(rows, ["RR#@", ".BR#"]);
+    }
+
+    #[test]
+    fn characters_stand_for_more_clusters_on_large_volumes() {
+        use ClusterState::*;
+        let mut states = vec![Free; 4_000_000];
+        states[..1_000_000].fill(Used);
+        states[2_000_000] = Used;
+        let (per_char, rows) = draw_grid(&map(&states), &HashSet::new(), 80);
+        assert_eq!(per_char, 4_000_000u64.div_ceil(80 * MAX_ROWS));
+        assert_eq!(rows.len() as u64, MAX_ROWS);
+        assert!(rows.iter().all(|row| row.len() == 80));
+        assert_eq!(&rows[0][..5], "#####");
+        assert_eq!(rows[10].chars().next(), Some('+'));
+        assert_eq!(rows[19].chars().last(), Some('.'));
+    }
+
+    #[test]
+    fn json_lists_runs_of_clusters() {
+        use ClusterState::*;
+        let highlight = Highlight {
+            path: PathBuf::from("/a \"b\".txt"),
+            clusters: vec![4, 5, 2],
+        };
+        let json = format_json(&map(&[Used, Free, Used, Used, Bad]), Some(&highlight));
+        assert!(json.contains("\"clusters\": 5,"));
+        assert!(json.contains("{\"first\": 2, \"count\": 1, \"state\": \"used\"},\n"));
+        assert!(json.contains("{\"first\": 4, \"count\": 2, \"state\": \"used\"},\n"));
+        assert!(json.contains("{\"first\": 6, \"count\": 1, \"state\": \"bad\"}\n  ]"));
+        assert!(json.contains(
+            "\"file\": {\"path\": \"/a \\\"b\\\".txt\", \"extents\": [{\"first\": 4, \"count\": 2}, {\"first\": 2, \"count\": 1}]}"
+        ));
+        assert!(!format_json(&map(&[Free]), None).contains("\"file\""));
+    }
+
+    #[test]
+    fn files_are_found_ignoring_case() {
+        for fat_type in [FatType::Fat12, FatType::Fat32] {
+            let image = TestImage::with_files(fat_type, &[("Some File.bin", &[1; 3000])]);
+            let file = image.open();
+            let highlight = find_highlight(&file, Path::new("/some file.BIN")).unwrap();
+            assert_eq!(highlight.path, Path::new("/Some File.bin"));
+            let map = direntry::cluster_map(&file).unwrap();
+            assert_eq!(
+                highlight.clusters.len() as u64,
+                3000u64.div_ceil(map.cluster_size)
+            );
+            let root = find_highlight(&file, Path::new("/")).unwrap();
+            assert_eq!(root.clusters.is_empty(), fat_type == FatType::Fat12);
+            let missing = find_highlight(&file, Path::new("/missing")).err().unwrap();
+            assert_eq!(missing.kind(), ErrorKind::NotFound);
+        }
+    }
+}
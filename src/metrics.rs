@@ -0,0 +1,329 @@
+//! This module counts the requests nuFAT serves and the errors it replies with, and serves the
+//! counts over HTTP in the text format of Prometheus with `--metrics-listen`, together with the
+//! open handles, the bytes read and written, the writes that failed verification and the bytes
+//! taken up by the caches. It is only built with the `metrics` feature, builds without it count
+//! nothing.
+use crate::filesystem::Activity;
+use libc::c_int;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Represent the counts of the requests a mounted filesystem served.
+///
+/// # Members
+///
+/// * `requests: Mutex<BTreeMap<&'static str, u64>>` - The number of requests, by FUSE operation.
+/// * `errors: Mutex<BTreeMap<(&'static str, c_int), u64>>` - The number of requests that failed,
+///   by FUSE operation and error code.
+/// * `bytes_read: AtomicU64` - The number of bytes read from files.
+/// * `bytes_written: AtomicU64` - The number of bytes written to files.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    errors: Mutex<BTreeMap<(&'static str, c_int), u64>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a request.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: &'static str` - The FUSE operation, e.g. `read`.
+    pub fn record_request(&self, op: &'static str) {
+        *self.requests.lock().unwrap().entry(op).or_default() += 1;
+    }
+
+    /// Count a request that failed.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: &'static str` - The FUSE operation.
+    /// * `errno: c_int` - The error code it was replied with.
+    pub fn record_error(&self, op: &'static str, errno: c_int) {
+        *self.errors.lock().unwrap().entry((op, errno)).or_default() += 1;
+    }
+
+    /// Count bytes read from a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `bytes: u64` - The number of bytes.
+    pub fn record_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes written to a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `bytes: u64` - The number of bytes.
+    pub fn record_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Parse the address to serve the metrics on.
+///
+/// # Parameters
+///
+/// * `address: &str` - The address as `ADDR:PORT`, or only the port to listen on localhost.
+///
+/// # Returns
+///
+/// * `Option<SocketAddr>` - The address, or `None` if it is malformed.
+pub fn parse_address(address: &str) -> Option<SocketAddr> {
+    match address.parse::<u16>() {
+        Ok(port) => Some(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => address.parse().ok(),
+    }
+}
+
+/// Serve the metrics of the mounted filesystems, one scrape after the other, until nuFAT exits.
+///
+/// # Parameters
+///
+/// * `listener: TcpListener` - The socket to accept scrapes on.
+/// * `mounts: Vec<(String, Arc<Activity>)>` - The mount point of every mounted filesystem, and
+///   the activity on it.
+pub fn serve(listener: TcpListener, mounts: Vec<(String, Arc<Activity>)>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            // A scraper that went away is no reason to stop serving the next one.
+            let _ = stream.and_then(|stream| respond(stream, &mounts));
+        }
+    });
+}
+
+/// Answer a scrape.
+///
+/// # Parameters
+///
+/// * `stream: TcpStream` - The connection of the scraper.
+/// * `mounts: &[(String, Arc<Activity>)]` - The mount points and the activity on them.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the connection failed.
+fn respond(mut stream: TcpStream, mounts: &[(String, Arc<Activity>)]) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but are read so the scraper doesn't see the connection reset.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split(' ').nth(1) {
+        Some("/metrics" | "/") => ("200 OK", format_metrics(mounts)),
+        _ => ("404 Not Found", "Not found, try /metrics.\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Format the metrics of the mounted filesystems in the text format of Prometheus, labelled
+/// with the mount point they belong to.
+///
+/// # Parameters
+///
+/// * `mounts: &[(String, Arc<Activity>)]` - The mount points and the activity on them.
+///
+/// # Returns
+///
+/// * `String` - The metrics.
+fn format_metrics(mounts: &[(String, Arc<Activity>)]) -> String {
+    let mut text = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for (labels, value) in samples {
+            text.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    };
+    let mount_point = |name: &str| format!("mount_point=\"{}\"", escape_label(name));
+
+    let mut requests = Vec::new();
+    let mut errors = Vec::new();
+    for (name, activity) in mounts {
+        for (op, count) in activity.metrics().requests.lock().unwrap().iter() {
+            requests.push((format!("{},op=\"{}\"", mount_point(name), op), *count));
+        }
+        for ((op, errno), count) in activity.metrics().errors.lock().unwrap().iter() {
+            errors.push((
+                format!("{},op=\"{}\",errno=\"{}\"", mount_point(name), op, errno),
+                *count,
+            ));
+        }
+    }
+    let each = |value: fn(&Activity) -> u64| {
+        mounts
+            .iter()
+            .map(|(name, activity)| (mount_point(name), value(activity)))
+            .collect()
+    };
+    family(
+        "nufat_requests_total",
+        "counter",
+        "FUSE requests served, by operation.",
+        requests,
+    );
+    family(
+        "nufat_errors_total",
+        "counter",
+        "FUSE requests that failed, by operation and error code.",
+        errors,
+    );
+    family(
+        "nufat_read_bytes_total",
+        "counter",
+        "Bytes read from files.",
+        each(|activity| activity.metrics().bytes_read.load(Ordering::Relaxed)),
+    );
+    family(
+        "nufat_written_bytes_total",
+        "counter",
+        "Bytes written to files.",
+        each(|activity| activity.metrics().bytes_written.load(Ordering::Relaxed)),
+    );
+    family(
+        "nufat_corrupt_writes_total",
+        "counter",
+        "Writes that failed verification with -o verify_writes.",
+        each(Activity::corrupt_writes),
+    );
+    family(
+        "nufat_open_handles",
+        "gauge",
+        "Open file handles.",
+        each(Activity::open_handles),
+    );
+    let mut cache_bytes = Vec::new();
+    for (name, activity) in mounts {
+        let usage = activity.cache_usage();
+        for (cache, bytes) in [
+            ("listings", &usage.listings),
+            ("cluster_chains", &usage.cluster_chains),
+        ] {
+            cache_bytes.push((
+                format!("{},cache=\"{}\"", mount_point(name), cache),
+                bytes.load(Ordering::Relaxed),
+            ));
+        }
+    }
+    family(
+        "nufat_cache_bytes",
+        "gauge",
+        "Bytes taken up by the caches, by cache, within the budget of --cache-limit.",
+        cache_bytes,
+    );
+    text
+}
+
+/// Escape the value of a label.
+///
+/// # Parameters
+///
+/// * `value: &str` - The value.
+///
+/// # Returns
+///
+/// * `String` - The value with backslashes, quotes and newlines escaped.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FatFilesystem;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+    use std::io::Read;
+
+    #[test]
+    fn format_metrics_lists_the_counts_of_every_image() {
+        let image = TestImage::new(FatType::Fat12);
+        let activity = FatFilesystem::new(image.options()).unwrap().activity();
+        activity.metrics().record_request("read");
+        activity.metrics().record_request("read");
+        activity.metrics().record_request("write");
+        activity.metrics().record_error("write", libc::ENOSPC);
+        activity.metrics().record_read(4096);
+        let mounts = vec![("/mnt/disk \"a\"".to_string(), Arc::clone(&activity))];
+
+        let text = format_metrics(&mounts);
+        assert!(text.contains("# TYPE nufat_requests_total counter\n"));
+        assert!(text
+            .contains("nufat_requests_total{mount_point=\"/mnt/disk \\\"a\\\"\",op=\"read\"} 2\n"));
+        assert!(text.contains(
+            "nufat_requests_total{mount_point=\"/mnt/disk \\\"a\\\"\",op=\"write\"} 1\n"
+        ));
+        assert!(text.contains(&format!(
+            "nufat_errors_total{{mount_point=\"/mnt/disk \\\"a\\\"\",op=\"write\",errno=\"{}\"}} 1\n",
+            libc::ENOSPC
+        )));
+        assert!(text.contains("nufat_read_bytes_total{mount_point=\"/mnt/disk \\\"a\\\"\"} 4096\n"));
+        assert!(
+            text.contains("nufat_corrupt_writes_total{mount_point=\"/mnt/disk \\\"a\\\"\"} 0\n")
+        );
+        assert!(text.contains("# TYPE nufat_open_handles gauge\n"));
+        assert!(text.contains(
+            "nufat_cache_bytes{mount_point=\"/mnt/disk \\\"a\\\"\",cache=\"listings\"} 0\n"
+        ));
+    }
+
+    #[test]
+    fn serve_answers_scrapes() {
+        let image = TestImage::new(FatType::Fat12);
+        let activity = FatFilesystem::new(image.options()).unwrap().activity();
+        activity.metrics().record_request("lookup");
+        let listener = TcpListener::bind(parse_address("0").unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.ip().is_loopback());
+        serve(listener, vec![("/mnt/test".to_string(), activity)]);
+
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(
+            response.contains("nufat_requests_total{mount_point=\"/mnt/test\",op=\"lookup\"} 1\n")
+        );
+        assert!(scrape("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn parse_address_defaults_to_localhost() {
+        assert_eq!(parse_address("9100"), "127.0.0.1:9100".parse().ok());
+        assert_eq!(parse_address("0.0.0.0:9100"), "0.0.0.0:9100".parse().ok());
+        assert_eq!(parse_address("[::1]:9100"), "[::1]:9100".parse().ok());
+        assert_eq!(parse_address("localhost"), None);
+    }
+}
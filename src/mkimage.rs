@@ -0,0 +1,917 @@
+//! This module implements the `mkimage` subcommand, which builds a disk image from a spec in JSON:
+//! the size and FAT variant of the volume, and a tree of files and directories with their sizes,
+//! contents, attributes and timestamps. Whatever the spec leaves out is fixed, down to the serial
+//! number and the times of the entries, so the same spec always gives the same image, byte for
+//! byte. The tests make their disk images this way, and a spec attached to a bug report rebuilds
+//! the image the bug showed up on.
+//!
+//! The volume is formatted and filled by `fatfs`. It doesn't set attributes or the times of
+//! directories, so those are written afterwards, through the `direntry` module.
+use crate::direntry;
+use crate::filesystem::lock_image;
+use crate::info::format_fat_type;
+use crate::json::{self, Value};
+use crate::label;
+use crate::serial;
+use fatfs::{
+    Date, DateTime, Dir, FatType, FileAttributes, FileSystem, FormatVolumeOptions, FsOptions, Time,
+    TimeProvider,
+};
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT mkimage --spec <SPEC.json|-> <disk.img> [--force]";
+
+/// The serial number of volumes whose spec gives none.
+const DEFAULT_SERIAL: u32 = 0x1234_5678;
+
+/// The most bytes of file contents written in one go.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The time `fatfs` stamps the entries it creates with, so they don't depend on when the image
+/// was built.
+static FIXED_TIME: FixedTime = FixedTime;
+
+/// Represent the spec of a disk image.
+///
+/// # Members
+///
+/// * `size: u64` - The size of the disk image in bytes.
+/// * `fat_type: Option<FatType>` - The FAT variant, or `None` to pick it by the size.
+/// * `cluster_size: Option<u32>` - The size of a cluster in bytes, or `None` to pick it by the
+///   size.
+/// * `serial: u32` - The serial number of the volume.
+/// * `label: Option<[u8; 11]>` - The label of the volume, or `None` for no label.
+/// * `entries: Vec<Entry>` - The entries of the root directory.
+pub struct Spec {
+    size: u64,
+    fat_type: Option<FatType>,
+    cluster_size: Option<u32>,
+    serial: u32,
+    label: Option<[u8; 11]>,
+    entries: Vec<Entry>,
+}
+
+impl Spec {
+    /// Make the spec of an empty volume, with the serial number of volumes whose spec gives none.
+    ///
+    /// # Parameters
+    ///
+    /// * `size: u64` - The size of the disk image in bytes.
+    /// * `fat_type: Option<FatType>` - The FAT variant, or `None` to pick it by the size.
+    /// * `label: Option<[u8; 11]>` - The label of the volume, or `None` for no label.
+    ///
+    /// # Returns
+    ///
+    /// * `Spec` - The spec.
+    pub fn blank(size: u64, fat_type: Option<FatType>, label: Option<[u8; 11]>) -> Self {
+        Spec {
+            size,
+            fat_type,
+            cluster_size: None,
+            serial: DEFAULT_SERIAL,
+            label,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// Represent a file or directory of a spec.
+///
+/// # Members
+///
+/// * `name: String` - The long name of the entry.
+/// * `contents: Contents` - What the entry holds.
+/// * `attributes: Option<FileAttributes>` - The attributes, or `None` to leave them as `fatfs`
+///   sets them.
+/// * `accessed: Option<Date>` - The access date, or `None` for the fixed one.
+/// * `modified: Option<DateTime>` - The modification time, or `None` for the fixed one.
+/// * `created: Option<DateTime>` - The creation time, or `None` for the fixed one.
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    contents: Contents,
+    attributes: Option<FileAttributes>,
+    accessed: Option<Date>,
+    modified: Option<DateTime>,
+    created: Option<DateTime>,
+}
+
+/// Represent what an entry of a spec holds.
+///
+/// # Variants
+///
+/// * `File { size: u64, fill: Vec<u8> }` - A file of `size` bytes, `fill` repeated.
+/// * `Dir(Vec<Entry>)` - A directory with these entries.
+#[derive(Clone)]
+enum Contents {
+    File { size: u64, fill: Vec<u8> },
+    Dir(Vec<Entry>),
+}
+
+/// Tell `fatfs` the same time whenever it asks, the earliest FAT can hold.
+#[derive(Debug)]
+struct FixedTime;
+
+impl TimeProvider for FixedTime {
+    fn get_current_date(&self) -> Date {
+        Date {
+            year: 1980,
+            month: 1,
+            day: 1,
+        }
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        DateTime {
+            date: self.get_current_date(),
+            time: Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        }
+    }
+}
+
+/// Run the `mkimage` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand:
+///   `--spec <SPEC.json|-> <disk.img> [--force]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the spec is invalid or the disk image can't be
+///   built, and 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut spec_path = None;
+    let mut disk_image_path = None;
+    let mut force = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--spec" if spec_path.is_none() => spec_path = args.next().cloned(),
+            _ if disk_image_path.is_none() && !arg.starts_with("--") => {
+                disk_image_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                disk_image_path = None;
+                break;
+            }
+        }
+    }
+    let (Some(spec_path), Some(disk_image_path)) = (spec_path, disk_image_path) else {
+        eprintln!("{}", USAGE);
+        eprintln!("Pass - as SPEC.json to read the spec from standard input.");
+        return 2;
+    };
+
+    let mut text = String::new();
+    let read = if spec_path == "-" {
+        io::stdin().read_to_string(&mut text)
+    } else {
+        File::open(&spec_path).and_then(|mut file| file.read_to_string(&mut text))
+    };
+    let spec = match read
+        .and_then(|_| parse_spec(&text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)))
+    {
+        Ok(spec) => spec,
+        Err(e) => {
+            eprintln!("{:?}: {}", spec_path, e);
+            return 1;
+        }
+    };
+
+    // The disk image is only truncated once it is locked, so a mounted one is left alone.
+    let image = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(force)
+        .create_new(!force)
+        .truncate(false)
+        .open(&disk_image_path)
+    {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            return 1;
+        }
+    };
+    match lock_image(&image, true).and_then(|_| build(&spec, &image)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            if e.kind() != ErrorKind::WouldBlock {
+                let _ = fs::remove_file(&disk_image_path);
+            }
+            1
+        }
+    }
+}
+
+/// Parse the spec of a disk image.
+///
+/// # Parameters
+///
+/// * `text: &str` - The spec in JSON.
+///
+/// # Returns
+///
+/// * `Result<Spec, String>` - The spec, or a message saying what is wrong with it and where.
+pub fn parse_spec(text: &str) -> Result<Spec, String> {
+    let value = json::parse(text)?;
+    check_members(
+        &value,
+        "spec",
+        &[
+            "size",
+            "fat_type",
+            "cluster_size",
+            "serial",
+            "label",
+            "entries",
+        ],
+    )?;
+
+    let size = match value.get("size") {
+        Some(size) => parse_size(size, "size")?,
+        None => return Err("spec: the size is missing".to_string()),
+    };
+    let fat_type = match value.get("fat_type") {
+        None => None,
+        Some(Value::String(fat_type)) => match parse_fat_type(fat_type) {
+            Some(fat_type) => Some(fat_type),
+            None => return Err("fat_type: expected \"FAT12\", \"FAT16\" or \"FAT32\"".to_string()),
+        },
+        Some(other) => {
+            return Err(format!(
+                "fat_type: expected a string, found {}",
+                other.kind()
+            ))
+        }
+    };
+    let cluster_size = match value.get("cluster_size") {
+        None => None,
+        Some(cluster_size) => match parse_size(cluster_size, "cluster_size")? {
+            size if size.is_power_of_two() && (512..=32768).contains(&size) => Some(size as u32),
+            _ => return Err("cluster_size: expected a power of two from 512 to 32K".to_string()),
+        },
+    };
+    let serial = match value.get("serial") {
+        None => DEFAULT_SERIAL,
+        Some(Value::String(serial)) => serial::parse_serial(serial)
+            .ok_or_else(|| "serial: expected XXXX-XXXX or 8 hex digits".to_string())?,
+        Some(other) => return Err(format!("serial: expected a string, found {}", other.kind())),
+    };
+    let label =
+        match value.get("label") {
+            None => None,
+            Some(Value::String(label)) => Some(label::parse_label(label).ok_or_else(|| {
+                "label: expected up to 11 characters a label can hold".to_string()
+            })?),
+            Some(other) => return Err(format!("label: expected a string, found {}", other.kind())),
+        };
+    let entries = parse_entries(value.get("entries"), "entries")?;
+    Ok(Spec {
+        size,
+        fat_type,
+        cluster_size,
+        serial,
+        label,
+        entries,
+    })
+}
+
+/// Build a disk image from a spec. Whatever the disk image held before is gone.
+///
+/// # Parameters
+///
+/// * `spec: &Spec` - The spec of the disk image.
+/// * `image: &File` - The disk image, opened for writing.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the volume doesn't fit the spec or writing it failed.
+pub fn build(spec: &Spec, mut image: &File) -> io::Result<()> {
+    image.set_len(0)?;
+    image.set_len(spec.size)?;
+    image.rewind()?;
+    let mut options = FormatVolumeOptions::new().volume_id(spec.serial);
+    if let Some(fat_type) = spec.fat_type {
+        options = options.fat_type(fat_type);
+    }
+    if let Some(cluster_size) = spec.cluster_size {
+        options = options.bytes_per_cluster(cluster_size);
+    }
+    fatfs::format_volume(image, options)?;
+    image.rewind()?;
+
+    let mut changes = Vec::new();
+    let fs = FileSystem::new(image, FsOptions::new().time_provider(&FIXED_TIME))?;
+    // `fatfs` picks the variant by the number of clusters, whatever it is asked for.
+    if let Some(fat_type) = spec.fat_type.filter(|fat_type| *fat_type != fs.fat_type()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "The volume has too many or too few clusters for {}, it would be {}. Change the \
+                 size or the cluster size.",
+                format_fat_type(fat_type),
+                format_fat_type(fs.fat_type())
+            ),
+        ));
+    }
+    add_entries(&fs.root_dir(), &spec.entries, &mut Vec::new(), &mut changes)?;
+    fs.unmount()?;
+
+    for (short_names, entry) in changes {
+        if let Some(attributes) = entry.attributes {
+            direntry::set_attributes(image, &short_names, attributes.bits())?;
+        }
+        direntry::set_times(
+            image,
+            &short_names,
+            entry.accessed,
+            entry.modified,
+            entry.created,
+        )?;
+    }
+    if let Some(label) = &spec.label {
+        label::write_label(image, Some(label))?;
+    }
+    image.sync_all()
+}
+
+/// Create the entries of a directory of the spec, and those below them.
+///
+/// # Parameters
+///
+/// * `dir: &Dir<&File>` - The directory to create the entries in.
+/// * `entries: &'a [Entry]` - The entries to create.
+/// * `parents: &mut Vec<Vec<u8>>` - The short names of the directories leading to `dir`.
+/// * `changes: &mut Vec<(Vec<Vec<u8>>, &'a Entry)>` - The entries `fatfs` can't finish, with
+///   their short names, to be changed once it is done.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if creating an entry failed.
+fn add_entries<'a>(
+    dir: &Dir<&File>,
+    entries: &'a [Entry],
+    parents: &mut Vec<Vec<u8>>,
+    changes: &mut Vec<(Vec<Vec<u8>>, &'a Entry)>,
+) -> io::Result<()> {
+    for entry in entries {
+        let sub_dir = match &entry.contents {
+            Contents::File { size, fill } => {
+                let mut file = dir.create_file(&entry.name)?;
+                write_fill(&mut file, *size, fill)?;
+                None
+            }
+            Contents::Dir(_) => Some(dir.create_dir(&entry.name)?),
+        };
+        let needs_change = entry.attributes.is_some()
+            || entry.accessed.is_some()
+            || entry.modified.is_some()
+            || entry.created.is_some();
+        if !needs_change && sub_dir.is_none() {
+            continue;
+        }
+
+        let short_name = dir
+            .iter()
+            .filter_map(Result::ok)
+            .find(|other| other.file_name() == entry.name)
+            .map(|other| other.short_file_name_as_bytes().to_vec())
+            .ok_or(ErrorKind::NotFound)?;
+        parents.push(short_name);
+        if needs_change {
+            changes.push((parents.clone(), entry));
+        }
+        if let (Some(sub_dir), Contents::Dir(sub_entries)) = (sub_dir, &entry.contents) {
+            add_entries(&sub_dir, sub_entries, parents, changes)?;
+        }
+        parents.pop();
+    }
+    Ok(())
+}
+
+/// Write the contents of a file: a pattern repeated until the file has its size.
+///
+/// # Parameters
+///
+/// * `file: &mut impl Write` - The file.
+/// * `size: u64` - The size of the file.
+/// * `fill: &[u8]` - The pattern.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if writing failed.
+fn write_fill(file: &mut impl Write, size: u64, fill: &[u8]) -> io::Result<()> {
+    // Every chunk holds the pattern a whole number of times, so each starts where it begins.
+    let chunk = fill.repeat(cmp::max(CHUNK_SIZE / fill.len(), 1));
+    let mut left = size;
+    while left > 0 {
+        let len = cmp::min(left, chunk.len() as u64) as usize;
+        file.write_all(&chunk[..len])?;
+        left -= len as u64;
+    }
+    Ok(())
+}
+
+/// Check that an object has no members the spec doesn't know, so typos don't go unnoticed.
+///
+/// # Parameters
+///
+/// * `value: &Value` - The object.
+/// * `path: &str` - Where the object is in the spec, for error messages.
+/// * `known: &[&str]` - The names of the members it may have.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - An error if it is not an object or has an unknown member.
+fn check_members(value: &Value, path: &str, known: &[&str]) -> Result<(), String> {
+    let Value::Object(members) = value else {
+        return Err(format!(
+            "{}: expected an object, found {}",
+            path,
+            value.kind()
+        ));
+    };
+    match members
+        .iter()
+        .find(|(name, _)| !known.contains(&name.as_str()))
+    {
+        Some((name, _)) => Err(format!("{}: unknown member {:?}", path, name)),
+        None => Ok(()),
+    }
+}
+
+/// Parse the entries of a directory of the spec.
+///
+/// # Parameters
+///
+/// * `value: Option<&Value>` - The array of entries, or `None` for an empty directory.
+/// * `path: &str` - Where the array is in the spec, for error messages.
+///
+/// # Returns
+///
+/// * `Result<Vec<Entry>, String>` - The entries, with repeated ones given one by one.
+fn parse_entries(value: Option<&Value>, path: &str) -> Result<Vec<Entry>, String> {
+    let values = match value {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(values)) => values,
+        Some(other) => {
+            return Err(format!(
+                "{}: expected an array, found {}",
+                path,
+                other.kind()
+            ))
+        }
+    };
+    let mut entries = Vec::new();
+    let mut names = HashSet::new();
+    for (index, value) in values.iter().enumerate() {
+        let path = format!("{}[{}]", path, index);
+        let (entry, repeat) = parse_entry(value, &path)?;
+        for number in 1..=repeat {
+            let mut entry = entry.clone();
+            if repeat > 1 || entry.name.contains("{}") {
+                entry.name = entry.name.replace("{}", &number.to_string());
+            }
+            // FAT compares names without regard to case.
+            if !names.insert(entry.name.to_lowercase()) {
+                return Err(format!(
+                    "{}: the name {:?} is given twice",
+                    path, entry.name
+                ));
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse an entry of the spec.
+///
+/// # Parameters
+///
+/// * `value: &Value` - The entry.
+/// * `path: &str` - Where the entry is in the spec, for error messages.
+///
+/// # Returns
+///
+/// * `Result<(Entry, u64), String>` - The entry, and how many times it is repeated.
+fn parse_entry(value: &Value, path: &str) -> Result<(Entry, u64), String> {
+    check_members(
+        value,
+        path,
+        &[
+            "name",
+            "repeat",
+            "entries",
+            "size",
+            "fill",
+            "attributes",
+            "accessed",
+            "modified",
+            "created",
+        ],
+    )?;
+    let name = match value.get("name") {
+        Some(Value::String(name)) if !name.is_empty() && !name.contains(['/', '\\']) => {
+            name.clone()
+        }
+        Some(Value::String(_)) => {
+            return Err(format!("{}.name: expected a name without slashes", path))
+        }
+        Some(other) => {
+            return Err(format!(
+                "{}.name: expected a string, found {}",
+                path,
+                other.kind()
+            ))
+        }
+        None => return Err(format!("{}: the name is missing", path)),
+    };
+    let repeat = match value.get("repeat") {
+        None => 1,
+        Some(repeat) => match parse_size(repeat, &format!("{}.repeat", path))? {
+            0 => return Err(format!("{}.repeat: expected at least 1", path)),
+            _ if !name.contains("{}") => {
+                return Err(format!(
+                    "{}.name: repeated entries need {{}} in their name",
+                    path
+                ))
+            }
+            repeat => repeat,
+        },
+    };
+
+    let contents = match value.get("entries") {
+        Some(entries) => {
+            if value.get("size").is_some() || value.get("fill").is_some() {
+                return Err(format!("{}: directories have no size or fill", path));
+            }
+            Contents::Dir(parse_entries(Some(entries), &format!("{}.entries", path))?)
+        }
+        None => {
+            let size = match value.get("size") {
+                Some(size) => parse_size(size, &format!("{}.size", path))?,
+                None => 0,
+            };
+            if size > u32::MAX as u64 {
+                return Err(format!("{}.size: FAT files hold at most 4 GiB - 1", path));
+            }
+            let fill = match value.get("fill") {
+                None => vec![0],
+                Some(Value::Number(byte))
+                    if (0.0..=255.0).contains(byte) && byte.fract() == 0.0 =>
+                {
+                    vec![*byte as u8]
+                }
+                Some(Value::String(text)) if !text.is_empty() => text.as_bytes().to_vec(),
+                Some(_) => {
+                    return Err(format!(
+                        "{}.fill: expected a byte from 0 to 255 or a string",
+                        path
+                    ))
+                }
+            };
+            Contents::File { size, fill }
+        }
+    };
+
+    let attributes = match value.get("attributes") {
+        None => None,
+        Some(Value::String(letters)) => {
+            let mut attributes = match contents {
+                Contents::File { .. } => FileAttributes::empty(),
+                Contents::Dir(_) => FileAttributes::DIRECTORY,
+            };
+            for letter in letters.chars() {
+                attributes |= match letter.to_ascii_lowercase() {
+                    'r' => FileAttributes::READ_ONLY,
+                    'h' => FileAttributes::HIDDEN,
+                    's' => FileAttributes::SYSTEM,
+                    'a' => FileAttributes::ARCHIVE,
+                    _ => {
+                        return Err(format!(
+                            "{}.attributes: expected letters out of \"rhsa\"",
+                            path
+                        ))
+                    }
+                };
+            }
+            Some(attributes)
+        }
+        Some(other) => {
+            return Err(format!(
+                "{}.attributes: expected a string, found {}",
+                path,
+                other.kind()
+            ))
+        }
+    };
+    let time = |key: &str| match value.get(key) {
+        None => Ok(None),
+        Some(Value::String(time)) => parse_time(time).map(Some).ok_or_else(|| {
+            format!(
+                "{}.{}: expected YYYY-MM-DD HH:MM:SS from 1980 to 2107",
+                path, key
+            )
+        }),
+        Some(other) => Err(format!(
+            "{}.{}: expected a string, found {}",
+            path,
+            key,
+            other.kind()
+        )),
+    };
+    let modified = time("modified")?;
+    let created = time("created")?;
+    let accessed =
+        match value.get("accessed") {
+            None => None,
+            Some(Value::String(date)) => Some(parse_date(date).ok_or_else(|| {
+                format!("{}.accessed: expected YYYY-MM-DD from 1980 to 2107", path)
+            })?),
+            Some(other) => {
+                return Err(format!(
+                    "{}.accessed: expected a string, found {}",
+                    path,
+                    other.kind()
+                ))
+            }
+        };
+
+    let entry = Entry {
+        name,
+        contents,
+        attributes,
+        accessed,
+        modified,
+        created,
+    };
+    Ok((entry, repeat))
+}
+
+/// Parse a FAT variant, e.g. `FAT32`, without regard to case.
+///
+/// # Parameters
+///
+/// * `fat_type: &str` - The variant.
+///
+/// # Returns
+///
+/// * `Option<FatType>` - The variant, or `None` if there is no such variant.
+pub fn parse_fat_type(fat_type: &str) -> Option<FatType> {
+    match fat_type.to_ascii_uppercase().as_str() {
+        "FAT12" => Some(FatType::Fat12),
+        "FAT16" => Some(FatType::Fat16),
+        "FAT32" => Some(FatType::Fat32),
+        _ => None,
+    }
+}
+
+/// Parse a size given on the command line, as a number of bytes or with a suffix, e.g. `1440K`.
+///
+/// # Parameters
+///
+/// * `size: &str` - The size.
+///
+/// # Returns
+///
+/// * `Result<u64, String>` - The size in bytes, or a message saying what is wrong with it.
+pub fn parse_size_arg(size: &str) -> Result<u64, String> {
+    parse_size(&Value::String(size.to_string()), "--size")
+}
+
+/// Parse a size, given as a number of bytes or as a string with a suffix, e.g. `"1440K"`.
+///
+/// # Parameters
+///
+/// * `value: &Value` - The size.
+/// * `path: &str` - Where the size is in the spec, for error messages.
+///
+/// # Returns
+///
+/// * `Result<u64, String>` - The size in bytes.
+fn parse_size(value: &Value, path: &str) -> Result<u64, String> {
+    let error = || {
+        format!(
+            "{}: expected a whole number, or one ending in K, M or G",
+            path
+        )
+    };
+    match value {
+        Value::Number(number) if *number >= 0.0 && number.fract() == 0.0 && *number < 2e15 => {
+            Ok(*number as u64)
+        }
+        Value::String(size) => {
+            let (digits, unit) = match size.char_indices().last() {
+                Some((index, 'K' | 'k')) => (&size[..index], 1 << 10),
+                Some((index, 'M' | 'm')) => (&size[..index], 1 << 20),
+                Some((index, 'G' | 'g')) => (&size[..index], 1 << 30),
+                _ => (size.as_str(), 1),
+            };
+            digits
+                .parse::<u64>()
+                .ok()
+                .and_then(|digits| digits.checked_mul(unit))
+                .ok_or_else(error)
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Parse a date given as `YYYY-MM-DD`.
+///
+/// # Parameters
+///
+/// * `date: &str` - The date.
+///
+/// # Returns
+///
+/// * `Option<Date>` - The date, or `None` if it is malformed or FAT can't hold it.
+fn parse_date(date: &str) -> Option<Date> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<u16>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    ((1980..=2107).contains(&year) && (1..=12).contains(&month) && (1..=31).contains(&day))
+        .then_some(Date { year, month, day })
+}
+
+/// Parse a time given as `YYYY-MM-DD HH:MM:SS`.
+///
+/// # Parameters
+///
+/// * `time: &str` - The time.
+///
+/// # Returns
+///
+/// * `Option<DateTime>` - The time, or `None` if it is malformed or FAT can't hold it.
+fn parse_time(time: &str) -> Option<DateTime> {
+    let (date, time) = time.split_once(' ')?;
+    let mut parts = time.splitn(3, ':').map(|part| part.parse::<u16>().ok());
+    let (hour, min, sec) = (parts.next()??, parts.next()??, parts.next()??);
+    (hour < 24 && min < 60 && sec < 60).then_some(DateTime {
+        date: parse_date(date)?,
+        time: Time {
+            hour,
+            min,
+            sec,
+            millis: 0,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    /// A spec using every kind of entry.
+    const SPEC: &str = r#"{
+        "size": "1440K",
+        "fat_type": "FAT12",
+        "serial": "ABCD-0123",
+        "label": "Fixture",
+        "entries": [
+            {"name": "Read Me.txt", "size": 10, "fill": "abc", "modified": "1999-12-31 23:59:58"},
+            {"name": "Docs", "attributes": "h", "created": "2001-02-03 04:05:06", "entries": [
+                {"name": "part{}.bin", "repeat": 3, "size": "2K", "fill": 255},
+                {"name": "Empty", "entries": []}
+            ]},
+            {"name": "SYSTEM.DAT", "attributes": "rs", "accessed": "2020-06-01"}
+        ]
+    }"#;
+
+    /// Find an entry of a directory by its long name.
+    fn entry<'a>(dir: &Dir<'a, File>, name: &str) -> fatfs::DirEntry<'a, File> {
+        dir.iter()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name() == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn build_makes_the_volume_of_the_spec() {
+        let image = TestImage::from_spec(SPEC);
+        let fs = image.volume();
+        assert_eq!(fs.fat_type(), FatType::Fat12);
+        assert_eq!(fs.volume_id(), 0xABCD_0123);
+        assert_eq!(
+            label::read_label(&image.open()).unwrap().as_deref(),
+            Some("FIXTURE")
+        );
+
+        let root = fs.root_dir();
+        let mut data = Vec::new();
+        root.open_file("Read Me.txt")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"abcabcabca");
+        let docs = root.open_dir("Docs").unwrap();
+        for name in ["part1.bin", "part2.bin", "part3.bin"] {
+            data.clear();
+            docs.open_file(name)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, [255; 2048]);
+        }
+        docs.open_dir("Empty").unwrap();
+
+        let read_me = entry(&root, "Read Me.txt");
+        assert_eq!(read_me.modified().date.year, 1999);
+        assert_eq!(read_me.modified().time.sec, 58);
+        let docs = entry(&root, "Docs");
+        assert_eq!(
+            docs.attributes(),
+            FileAttributes::DIRECTORY | FileAttributes::HIDDEN
+        );
+        assert_eq!(docs.created().time.hour, 4);
+        let system = entry(&root, "SYSTEM.DAT");
+        assert_eq!(
+            system.attributes(),
+            FileAttributes::READ_ONLY | FileAttributes::SYSTEM
+        );
+        assert_eq!(system.accessed().year, 2020);
+        // What the spec leaves out is fixed.
+        assert_eq!(system.modified(), FIXED_TIME.get_current_date_time());
+    }
+
+    #[test]
+    fn build_gives_the_same_image_every_time() {
+        let contents = |image: &TestImage| {
+            let mut contents = Vec::new();
+            image.open().read_to_end(&mut contents).unwrap();
+            contents
+        };
+        let first = TestImage::from_spec(SPEC);
+        let second = TestImage::from_spec(SPEC);
+        assert!(contents(&first) == contents(&second));
+
+        let fat32 = r#"{"size": "64M", "fat_type": "FAT32", "cluster_size": 512,
+            "entries": [{"name": "a", "size": 100000}]}"#;
+        let first = TestImage::from_spec(fat32);
+        assert_eq!(first.volume().fat_type(), FatType::Fat32);
+        assert_eq!(first.volume().cluster_size(), 512);
+        assert!(contents(&first) == contents(&TestImage::from_spec(fat32)));
+
+        // 64 MiB in clusters of 4 KiB are too few for FAT32.
+        let spec = parse_spec(&fat32.replace("512", "4096")).unwrap();
+        let error = build(&spec, &first.open()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parse_spec_rejects_invalid_specs() {
+        for (spec, error) in [
+            (r#"{"entries": []}"#, "spec: the size is missing"),
+            (r#"{"size": "1.44M"}"#, "size: expected a whole number"),
+            (
+                r#"{"size": 1, "fat": "FAT12"}"#,
+                "spec: unknown member \"fat\"",
+            ),
+            (
+                r#"{"size": 1, "cluster_size": 1000}"#,
+                "cluster_size: expected a power",
+            ),
+            (
+                r#"{"size": 1, "entries": [{"name": "a"}, {"name": "A"}]}"#,
+                "entries[1]: the name \"A\" is given twice",
+            ),
+            (
+                r#"{"size": 1, "entries": [{"name": "d", "entries": [{"size": 1}]}]}"#,
+                "entries[0].entries[0]: the name is missing",
+            ),
+            (
+                r#"{"size": 1, "entries": [{"name": "a", "repeat": 2}]}"#,
+                "entries[0].name: repeated entries need {} in their name",
+            ),
+            (
+                r#"{"size": 1, "entries": [{"name": "a", "modified": "1979-12-31 00:00:00"}]}"#,
+                "entries[0].modified: expected YYYY-MM-DD HH:MM:SS",
+            ),
+            (
+                r#"{"size": 1, "entries": [{"name": "a", "attributes": "x"}]}"#,
+                "entries[0].attributes: expected letters",
+            ),
+            (r#"{"size": 1,}"#, "Invalid JSON on line 1"),
+        ] {
+            match parse_spec(spec) {
+                Err(e) => assert!(e.starts_with(error), "{:?}: {}", spec, e),
+                Ok(_) => panic!("{:?} was accepted", spec),
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! This module implements the readiness notification protocol of systemd, so nuFAT can run as a
+//! `Type=notify` service.
+use crate::log::{self, Level};
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Send a state update to the service manager, e.g. `READY=1`.
+///
+/// Does nothing when not started by a service manager, i.e. when `NOTIFY_SOCKET` is not set.
+///
+/// # Parameters
+///
+/// * `state: &str` - The newline separated list of state assignments to send.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = socket_address(&socket.to_string_lossy()).and_then(|address| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+    if let Err(e) = result {
+        log::log(
+            Level::Error,
+            module_path!(),
+            &format!("Failed to notify service manager: {}", e),
+        );
+    }
+}
+
+/// Parse the address in `NOTIFY_SOCKET`, which is either a path or, if it starts with `@`, the name
+/// of an abstract socket.
+///
+/// # Parameters
+///
+/// * `socket: &str` - The value of `NOTIFY_SOCKET`.
+///
+/// # Returns
+///
+/// * `io::Result<SocketAddr>` - The address of the socket.
+fn socket_address(socket: &str) -> io::Result<SocketAddr> {
+    match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(socket),
+    }
+}
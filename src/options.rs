@@ -0,0 +1,521 @@
+//! This module parses the command line arguments and mount options passed to nuFAT.
+use crate::cache;
+use crate::codepage::Codepage;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::mkimage;
+use fuser::MountOption;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// Represent what to do when writing to the disk image fails, as set by `-o errors=`.
+///
+/// # Variants
+///
+/// * `Continue` - Fail the operation with `EIO` and carry on.
+/// * `RemountRo` - Fail the operation with `EIO` and refuse all further modifications.
+/// * `Panic` - Exit nuFAT with a non-zero exit code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorBehavior {
+    #[default]
+    Continue,
+    RemountRo,
+    Panic,
+}
+
+/// Represent how entries with the Hidden attribute are presented, as set by `-o hidden=`.
+///
+/// # Variants
+///
+/// * `Show` - List them like any other entry.
+/// * `Dot` - List them with a `.` in front of their name, like Unix dotfiles.
+/// * `Hide` - Don't list them at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HiddenBehavior {
+    #[default]
+    Show,
+    Dot,
+    Hide,
+}
+
+/// Represent how entries with only an 8.3 short name are listed, as set by `-o shortname=`, like
+/// the option of the same name of the Linux vfat driver.
+///
+/// # Variants
+///
+/// * `Lower` - List them all lowercase.
+/// * `Win95` - List them as they are stored, all uppercase.
+/// * `Winnt` - List them lowercase where Windows NT marked the name or extension as such.
+/// * `Mixed` - The same as `Winnt` when listing. The vfat driver only treats them differently
+///   when creating files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShortnameStyle {
+    Lower,
+    Win95,
+    Winnt,
+    #[default]
+    Mixed,
+}
+
+/// Represent how the 8.3 short names of new entries with long names are made up, as set by
+/// `-o alias=`.
+///
+/// # Variants
+///
+/// * `NumericTail` - Like Windows, e.g. `LONGFI~1.TXT`, with a checksum of the long name once
+///   there are more than four of them, e.g. `LO3F2A~1.TXT`.
+/// * `Hash` - Always with a checksum of the long name.
+/// * `NoTail` - Without a numeric tail, e.g. `LONGFILE.TXT`, unless that is taken, like the
+///   `nonumtail` option of the Linux vfat driver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AliasStyle {
+    #[default]
+    NumericTail,
+    Hash,
+    NoTail,
+}
+
+/// Represent how the case of names passed by programs is treated, as set by `-o case=`.
+///
+/// # Variants
+///
+/// * `Fold` - Find entries regardless of the case of their names, as DOS and Windows do.
+/// * `Strict` - Find entries only by their names in the case they are listed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaseSensitivity {
+    #[default]
+    Fold,
+    Strict,
+}
+
+/// Represent how long file names are normalized, as set by `-o normalize=`.
+///
+/// # Variants
+///
+/// * `Keep` - List names as they are stored.
+/// * `Nfc` - List names composed, as Linux and Windows mostly write them.
+/// * `Nfd` - List names decomposed, as macOS writes them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Normalization {
+    #[default]
+    Keep,
+    Nfc,
+    Nfd,
+}
+
+/// Represent how messages are logged to standard error, as set by `--log-format`.
+///
+/// # Variants
+///
+/// * `Text` - Log them as plain text, for people to read.
+/// * `Json` - Log every message as a JSON object on a line of its own, for log collectors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Represent the options nuFAT was started with.
+///
+/// # Members
+///
+/// * `disk_image_path: PathBuf` - The path of the disk image to mount.
+/// * `image_fd: Option<RawFd>` - The inherited file descriptor of the disk image, used instead of
+///   `disk_image_path`.
+/// * `mount_point: PathBuf` - The directory to mount the filesystem on.
+/// * `sync: bool` - Whether every modification is written through to the image before replying.
+/// * `flush: bool` - Whether the image is synced as soon as the last handle on a file is closed.
+/// * `flush_deadline: Option<Duration>` - How long after a modification the image is synced at the
+///   latest, even while files are open.
+/// * `errors: ErrorBehavior` - What to do when writing to the image fails.
+/// * `io_retries: u32` - How often a failed read or write on the image is retried.
+/// * `read_only: bool` - Whether the filesystem is mounted read-only.
+/// * `force_rw: bool` - Whether to mount read-write even if the volume is dirty, or its layout had
+///   to be guessed.
+/// * `dev: bool` - Whether the kernel interprets device files on the filesystem.
+/// * `suid: bool` - Whether the kernel honors set-user-ID and set-group-ID bits.
+/// * `exec: bool` - Whether the kernel permits executing files on the filesystem.
+/// * `keep_going: bool` - Whether to keep the other images mounted if mounting one fails.
+/// * `idle_timeout: Option<Duration>` - After how long without requests to unmount.
+/// * `cache_limit: u64` - The bytes the caches may take up together.
+/// * `log_format: LogFormat` - How messages are logged.
+/// * `metrics_listen: Option<SocketAddr>` - Where to serve the metrics, or `None` not to.
+/// * `root: PathBuf` - The directory of the volume that is mounted as the root of the filesystem.
+/// * `nonempty: bool` - Whether to mount over a mount point that is not empty.
+/// * `shortnames: bool` - Whether directories list the 8.3 short names instead of long names, and
+///   new entries get only a short name.
+/// * `shortnames_convert: bool` - Whether new entries with names that aren't valid short names
+///   get one made up from them with `-o shortnames`, instead of failing with `EINVAL`.
+/// * `verify_writes: bool` - Whether every write is read back from the image and compared.
+/// * `writeback_cache: bool` - Whether the kernel may cache writes before passing them on.
+/// * `hide_system: bool` - Whether entries with the System attribute are hidden.
+/// * `hidden: HiddenBehavior` - How entries with the Hidden attribute are presented.
+/// * `allow_system_write: bool` - Whether entries with the System attribute may be changed.
+/// * `fat_plus: bool` - Whether the upper bits of file sizes stored by the FAT+ extension are read.
+/// * `codepage: Option<&'static Codepage>` - The OEM code page short names are stored in, or `None`
+///   to show only their ASCII characters.
+/// * `normalize: Normalization` - How names are normalized when listed and looked up.
+/// * `shortname: ShortnameStyle` - How entries with only a short name are listed.
+/// * `escape: bool` - Whether characters long file names can't hold are stored as `%` escapes.
+/// * `alias: AliasStyle` - How the short names of new entries with long names are made up.
+/// * `case: CaseSensitivity` - Whether names have to match the case of the entry they look up.
+/// * `deny_delete_open: bool` - Whether deleting or replacing a file that is open fails with
+///   `EBUSY`, as on Windows.
+/// * `dotfiles_hidden: bool` - Whether new entries whose names start with a `.` get the Hidden
+///   attribute.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub disk_image_path: PathBuf,
+    pub image_fd: Option<RawFd>,
+    pub mount_point: PathBuf,
+    pub sync: bool,
+    pub flush: bool,
+    pub flush_deadline: Option<Duration>,
+    pub errors: ErrorBehavior,
+    pub io_retries: u32,
+    pub read_only: bool,
+    pub force_rw: bool,
+    pub dev: bool,
+    pub suid: bool,
+    pub exec: bool,
+    pub keep_going: bool,
+    pub idle_timeout: Option<Duration>,
+    pub cache_limit: u64,
+    pub log_format: LogFormat,
+    pub metrics_listen: Option<SocketAddr>,
+    pub root: PathBuf,
+    pub nonempty: bool,
+    pub shortnames: bool,
+    pub shortnames_convert: bool,
+    pub verify_writes: bool,
+    pub writeback_cache: bool,
+    pub hide_system: bool,
+    pub hidden: HiddenBehavior,
+    pub allow_system_write: bool,
+    pub fat_plus: bool,
+    pub codepage: Option<&'static Codepage>,
+    pub normalize: Normalization,
+    pub escape: bool,
+    pub alias: AliasStyle,
+    pub case: CaseSensitivity,
+    pub shortname: ShortnameStyle,
+    pub deny_delete_open: bool,
+    pub dotfiles_hidden: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            disk_image_path: PathBuf::new(),
+            image_fd: None,
+            mount_point: PathBuf::new(),
+            sync: false,
+            flush: false,
+            flush_deadline: None,
+            errors: ErrorBehavior::default(),
+            io_retries: 2,
+            read_only: false,
+            force_rw: false,
+            dev: false,
+            suid: false,
+            exec: true,
+            keep_going: false,
+            idle_timeout: None,
+            cache_limit: cache::default_limit(),
+            log_format: LogFormat::default(),
+            metrics_listen: None,
+            root: PathBuf::from("/"),
+            nonempty: false,
+            shortnames: false,
+            shortnames_convert: false,
+            verify_writes: false,
+            writeback_cache: false,
+            hide_system: false,
+            hidden: HiddenBehavior::default(),
+            allow_system_write: false,
+            fat_plus: false,
+            codepage: None,
+            normalize: Normalization::default(),
+            escape: false,
+            alias: AliasStyle::default(),
+            case: CaseSensitivity::default(),
+            shortname: ShortnameStyle::default(),
+            deny_delete_open: false,
+            dotfiles_hidden: false,
+        }
+    }
+}
+
+impl Options {
+    /// Parse the command line arguments.
+    ///
+    /// An image is given either as the two positional arguments, or as a pair of `--image` and
+    /// `--at`, which can be repeated to mount several images at once.
+    ///
+    /// # Parameters
+    ///
+    /// * `args: &[String]` - The command line arguments, without the program name.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Options>)` - The parsed options, one for every image to mount.
+    /// * `Err(String)` - A message describing why the arguments are invalid.
+    pub fn parse(args: &[String]) -> Result<Vec<Self>, String> {
+        let mut options = Options::default();
+        let mut positional: Vec<&String> = Vec::new();
+        let mut mounts: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-o" {
+                match args.next() {
+                    Some(list) => options.parse_mount_options(list)?,
+                    None => return Err("Option '-o' requires an argument.".to_string()),
+                }
+            } else if arg == "--io-retries" {
+                options.io_retries = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => return Err("Option '--io-retries' requires a number.".to_string()),
+                };
+            } else if arg == "--image-fd" {
+                options.image_fd = match args.next().map(|fd| fd.parse()) {
+                    Some(Ok(fd)) => Some(fd),
+                    _ => return Err("Option '--image-fd' requires a file descriptor.".to_string()),
+                };
+            } else if arg == "--force-rw" {
+                options.force_rw = true;
+            } else if arg == "--idle-timeout" {
+                options.idle_timeout = match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                    _ => {
+                        return Err(
+                            "Option '--idle-timeout' requires a number of seconds.".to_string()
+                        )
+                    }
+                };
+            } else if arg == "--cache-limit" {
+                options.cache_limit = match args.next().map(|size| mkimage::parse_size_arg(size)) {
+                    Some(Ok(size)) => size,
+                    _ => {
+                        return Err("Option '--cache-limit' requires a number of bytes, or one ending in K, M or G.".to_string())
+                    }
+                };
+            } else if arg == "--log-format" {
+                options.log_format = match args.next().map(String::as_str) {
+                    Some("text") => LogFormat::Text,
+                    Some("json") => LogFormat::Json,
+                    _ => return Err("Option '--log-format' requires text or json.".to_string()),
+                };
+            } else if arg == "--metrics-listen" {
+                options.metrics_listen = Some(parse_metrics_address(args.next())?);
+            } else if arg == "--keep-going" {
+                options.keep_going = true;
+            } else if arg == "--image" {
+                match (args.next(), args.next().map(String::as_str), args.next()) {
+                    (Some(disk_image_path), Some("--at"), Some(mount_point)) => {
+                        mounts.push((PathBuf::from(disk_image_path), PathBuf::from(mount_point)))
+                    }
+                    _ => {
+                        return Err("Expected '--image <disk.img> --at <mount_point>'.".to_string())
+                    }
+                }
+            } else if let Some(list) = arg.strip_prefix("-o") {
+                options.parse_mount_options(list)?;
+            } else if arg.starts_with('-') {
+                return Err(format!("Unknown option '{}'.", arg));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        if options.image_fd.is_some() && !mounts.is_empty() {
+            return Err("Option '--image-fd' can't be combined with '--image'.".to_string());
+        }
+        match (options.image_fd, positional.as_slice()) {
+            (_, []) => {}
+            (None, [disk_image_path, mount_point]) => mounts.insert(
+                0,
+                (PathBuf::from(disk_image_path), PathBuf::from(mount_point)),
+            ),
+            (Some(_), [mount_point]) => {
+                mounts.insert(0, (PathBuf::new(), PathBuf::from(mount_point)))
+            }
+            _ => return Err("Expected a disk image and a mount point.".to_string()),
+        }
+        if mounts.is_empty() {
+            return Err("Expected a disk image and a mount point.".to_string());
+        }
+
+        Ok(mounts
+            .into_iter()
+            .map(|(disk_image_path, mount_point)| Options {
+                disk_image_path,
+                mount_point,
+                ..options.clone()
+            })
+            .collect())
+    }
+
+    /// Parse a comma separated list of mount options as given to `-o`.
+    ///
+    /// # Parameters
+    ///
+    /// * `list: &str` - The list of mount options, e.g. `sync`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If every option in the list is known.
+    /// * `Err(String)` - A message naming the first option that is not.
+    fn parse_mount_options(&mut self, list: &str) -> Result<(), String> {
+        for option in list.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "sync" => self.sync = true,
+                "async" => self.sync = false,
+                "flush" => self.flush = true,
+                "ro" => self.read_only = true,
+                "rw" => self.read_only = false,
+                "dev" => self.dev = true,
+                "nodev" => self.dev = false,
+                "suid" => self.suid = true,
+                "nosuid" => self.suid = false,
+                "exec" => self.exec = true,
+                "noexec" => self.exec = false,
+                "nonempty" => self.nonempty = true,
+                "shortnames" => self.shortnames = true,
+                "shortnames=convert" => {
+                    self.shortnames = true;
+                    self.shortnames_convert = true;
+                }
+                "verify_writes" => self.verify_writes = true,
+                "writeback_cache" => self.writeback_cache = true,
+                "hide_system" => self.hide_system = true,
+                "allow_system_write" => self.allow_system_write = true,
+                "fatplus" => self.fat_plus = true,
+                "deny_delete_open" => self.deny_delete_open = true,
+                "dotfiles_hidden" => self.dotfiles_hidden = true,
+                "hidden=show" => self.hidden = HiddenBehavior::Show,
+                "hidden=dot" => self.hidden = HiddenBehavior::Dot,
+                "hidden=hide" => self.hidden = HiddenBehavior::Hide,
+                "shortname=lower" => self.shortname = ShortnameStyle::Lower,
+                "shortname=win95" => self.shortname = ShortnameStyle::Win95,
+                "shortname=winnt" => self.shortname = ShortnameStyle::Winnt,
+                "shortname=mixed" => self.shortname = ShortnameStyle::Mixed,
+                "escape" => self.escape = true,
+                "alias=numtail" => self.alias = AliasStyle::NumericTail,
+                "alias=hash" => self.alias = AliasStyle::Hash,
+                "alias=none" => self.alias = AliasStyle::NoTail,
+                "case=fold" => self.case = CaseSensitivity::Fold,
+                "case=strict" => self.case = CaseSensitivity::Strict,
+                "normalize=none" => self.normalize = Normalization::Keep,
+                "normalize=nfc" => self.normalize = Normalization::Nfc,
+                "normalize=nfd" => self.normalize = Normalization::Nfd,
+                "errors=continue" => self.errors = ErrorBehavior::Continue,
+                "errors=remount-ro" => self.errors = ErrorBehavior::RemountRo,
+                "errors=panic" | "errors=abort" => self.errors = ErrorBehavior::Panic,
+                _ if option.starts_with("flush=") => match option["flush=".len()..].parse() {
+                    Ok(secs) => {
+                        self.flush = true;
+                        self.flush_deadline = Some(Duration::from_secs(secs));
+                    }
+                    Err(_) => {
+                        return Err(
+                            "Mount option 'flush=' requires a number of seconds.".to_string()
+                        )
+                    }
+                },
+                _ if option.starts_with("codepage=") => {
+                    let number = &option["codepage=".len()..];
+                    match number.parse().ok().and_then(Codepage::find) {
+                        Some(codepage) => self.codepage = Some(codepage),
+                        None => {
+                            return Err(format!(
+                                "Unknown code page '{}', supported are {}.",
+                                number,
+                                Codepage::supported()
+                            ))
+                        }
+                    }
+                }
+                _ => match option.strip_prefix("root=") {
+                    Some(root) => {
+                        let root: PathBuf = Path::new("/").join(root).components().collect();
+                        if root.components().any(|c| c == Component::ParentDir) {
+                            return Err("Mount option 'root=' must not contain '..'.".to_string());
+                        }
+                        self.root = root;
+                    }
+                    None => return Err(format!("Unknown mount option '{}'.", option)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a name for the disk image to use in messages.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The quoted path of the disk image, or `fd N` if it was passed as a file
+    ///   descriptor.
+    pub fn image_name(&self) -> String {
+        match self.image_fd {
+            Some(fd) => format!("fd {}", fd),
+            None => format!("{:?}", self.disk_image_path),
+        }
+    }
+
+    /// Get the mount options that are enforced by the kernel.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<MountOption>` - The options to pass on when mounting.
+    pub fn kernel_mount_options(&self) -> Vec<MountOption> {
+        vec![
+            if self.dev {
+                MountOption::Dev
+            } else {
+                MountOption::NoDev
+            },
+            if self.suid {
+                MountOption::Suid
+            } else {
+                MountOption::NoSuid
+            },
+            if self.exec {
+                MountOption::Exec
+            } else {
+                MountOption::NoExec
+            },
+        ]
+    }
+}
+
+/// Parse the address to serve the metrics on, as given with `--metrics-listen`.
+///
+/// # Parameters
+///
+/// * `address: Option<&String>` - The address as `ADDR:PORT` or `PORT`, or `None` if it is
+///   missing.
+///
+/// # Returns
+///
+/// * `Ok(SocketAddr)` - The address.
+/// * `Err(String)` - A message describing why the address is invalid, or that nuFAT was built
+///   without the `metrics` feature.
+fn parse_metrics_address(address: Option<&String>) -> Result<SocketAddr, String> {
+    #[cfg(feature = "metrics")]
+    return address
+        .and_then(|address| metrics::parse_address(address))
+        .ok_or_else(|| "Option '--metrics-listen' requires ADDR:PORT or PORT.".to_string());
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = address;
+        Err(
+            "Option '--metrics-listen' requires nuFAT to be built with the metrics feature."
+                .to_string(),
+        )
+    }
+}
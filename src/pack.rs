@@ -0,0 +1,671 @@
+//! This module implements the `pack` subcommand, which builds a disk image holding everything in
+//! a directory on the host, the reverse of `unpack`. The volume is formatted like `mkimage` does
+//! it, and the directory is copied to it by the `copy` module, with the times of its entries.
+//! With `--size auto`, the smallest size it fits on is found by trying sizes on a scratch file,
+//! leaving out what is excluded. `--shortname-policy` picks what to do about the numeric tails
+//! `fatfs` gives the short names of long names, which some firmware only reads, and
+//! `--shortname-map` writes the short names of all entries with long names. With `--dry-run`, the volume is built on a scratch file as
+//! well, to check that everything fits and print what would be copied, and the disk image is not
+//! touched.
+use crate::alias;
+use crate::copy::{self, CopyOptions};
+use crate::dry_run::{print_action, Arg};
+use crate::exclude::{read_excludes, Exclude};
+use crate::filesystem::{lock_image, open_dir};
+use crate::info::format_fat_type;
+use crate::label;
+use crate::mkimage::{self, Spec};
+use crate::storage::Storage;
+use crate::tree;
+use fatfs::{FatType, FileAttributes, FileSystem as FatfsFileSystem};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT pack <DIR> <disk.img> --size SIZE|auto [--fat-type FAT12|FAT16|FAT32] [--label LABEL] [--force] [--exclude GLOB]... [--exclude-from FILE]... [--shortname-policy auto|warn|strict] [--shortname-map FILE] [--dry-run]";
+
+/// The steps `--size auto` picks sizes in, and the smallest it picks.
+const AUTO_SIZE_STEP: u64 = 64 * 1024;
+
+/// The largest size `--size auto` tries.
+const MAX_AUTO_SIZE: u64 = 2 << 40;
+
+/// The number of scratch files made so far, so every one has a name of its own.
+static SCRATCH_FILES: AtomicU32 = AtomicU32::new(0);
+
+/// Represent what `pack` does about short names with numeric tails, as set by
+/// `--shortname-policy`.
+///
+/// # Variants
+///
+/// * `Auto` - Let `fatfs` make them up, the default.
+/// * `Warn` - Print every short name made up with a numeric tail.
+/// * `Strict` - Fail if two entries in one directory would get short names told apart only by
+///   their numeric tails, which depend on the order the entries are created in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ShortNamePolicy {
+    #[default]
+    Auto,
+    Warn,
+    Strict,
+}
+
+/// Run the `pack` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<DIR> <disk.img> --size SIZE|auto
+///   [--fat-type FAT12|FAT16|FAT32] [--label LABEL] [--force] [--exclude GLOB]...
+///   [--exclude-from FILE]... [--shortname-policy auto|warn|strict] [--shortname-map FILE]
+///   [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the directory can't be read, the disk image
+///   can't be built, the directory doesn't fit on it or `--shortname-policy strict` refuses its
+///   names, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    // `Some(None)` stands for `--size auto`.
+    let mut size = None;
+    let mut fat_type = None;
+    let mut volume_label = None;
+    let mut force = false;
+    let mut policy = ShortNamePolicy::default();
+    let mut map_path = None;
+    let mut options = CopyOptions::default();
+    let mut paths = Vec::new();
+    let mut valid = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--force" => {
+                force = true;
+                Ok(())
+            }
+            "--dry-run" => {
+                options.dry_run = true;
+                Ok(())
+            }
+            "--size" => match args.next().map(String::as_str) {
+                Some("auto") => {
+                    size = Some(None);
+                    Ok(())
+                }
+                Some(arg) => mkimage::parse_size_arg(arg).map(|arg| size = Some(Some(arg))),
+                None => Err(String::new()),
+            },
+            "--fat-type" => args
+                .next()
+                .and_then(|arg| mkimage::parse_fat_type(arg))
+                .map(|arg| fat_type = Some(arg))
+                .ok_or_else(|| "--fat-type: expected FAT12, FAT16 or FAT32".to_string()),
+            "--label" => args
+                .next()
+                .and_then(|arg| label::parse_label(arg))
+                .map(|arg| volume_label = Some(arg))
+                .ok_or_else(|| {
+                    "--label: expected up to 11 characters a label can hold".to_string()
+                }),
+            "--exclude" => args
+                .next()
+                .map(|glob| Exclude::new(glob).map(|exclude| options.excludes.push(exclude)))
+                .unwrap_or(Err(String::new())),
+            "--exclude-from" => args
+                .next()
+                .map(|path| {
+                    read_excludes(Path::new(path)).map(|excludes| options.excludes.extend(excludes))
+                })
+                .unwrap_or(Err(String::new())),
+            "--shortname-policy" => args
+                .next()
+                .and_then(|arg| match arg.as_str() {
+                    "auto" => Some(ShortNamePolicy::Auto),
+                    "warn" => Some(ShortNamePolicy::Warn),
+                    "strict" => Some(ShortNamePolicy::Strict),
+                    _ => None,
+                })
+                .map(|arg| policy = arg)
+                .ok_or_else(|| "--shortname-policy: expected auto, warn or strict".to_string()),
+            "--shortname-map" => args
+                .next()
+                .map(|path| map_path = Some(PathBuf::from(path)))
+                .ok_or_else(String::new),
+            _ if arg.starts_with("--") => Err(String::new()),
+            _ => {
+                paths.push(arg.as_str());
+                Ok(())
+            }
+        };
+        if let Err(e) = parsed {
+            if !e.is_empty() {
+                eprintln!("{}", e);
+            }
+            valid = false;
+        }
+    }
+    let ([source, disk_image_path], Some(size), true) = (&paths[..], size, valid) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let source = Path::new(source);
+    let image_path = Path::new(disk_image_path);
+    if !source.is_dir() {
+        eprintln!("{:?}: not a directory", source);
+        return 1;
+    }
+    if let Err(e) = check_short_names(source, policy, &options) {
+        let would = if options.dry_run { "would fail: " } else { "" };
+        eprintln!("{:?}: {}{}", image_path, would, e);
+        return 1;
+    }
+
+    let size = match size {
+        Some(size) => size,
+        None => match auto_size(source, fat_type, &options) {
+            Ok(size) => {
+                println!("Picked a size of {} bytes.", size);
+                size
+            }
+            Err(e) if options.dry_run => {
+                eprintln!("{:?}: would fail: {}", image_path, e);
+                return 1;
+            }
+            Err(e) => {
+                eprintln!("{:?}: {}", image_path, e);
+                return 1;
+            }
+        },
+    };
+    let spec = Spec::blank(size, fat_type, volume_label);
+    if options.dry_run {
+        return match plan(&spec, source, image_path, force, &options) {
+            Ok(summary) => {
+                println!("{}", summary.describe(true));
+                0
+            }
+            Err(e) => {
+                eprintln!("{:?}: would fail: {}", image_path, e);
+                1
+            }
+        };
+    }
+
+    // The disk image is only truncated once it is locked, so a mounted one is left alone.
+    let image = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(force)
+        .create_new(!force)
+        .truncate(false)
+        .open(image_path)
+    {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{:?}: {}", image_path, e);
+            return 1;
+        }
+    };
+    let packed = lock_image(&image, true)
+        .and_then(|_| pack(&spec, source, &image, policy, map_path.as_deref(), &options));
+    match packed {
+        Ok(summary) => {
+            println!("{}", summary.describe(false));
+            0
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", image_path, e);
+            if e.kind() != ErrorKind::WouldBlock {
+                let _ = fs::remove_file(image_path);
+            }
+            1
+        }
+    }
+}
+
+/// Build a disk image and copy a directory to it.
+///
+/// # Parameters
+///
+/// * `spec: &Spec` - The spec of the empty volume.
+/// * `source: &Path` - The directory on the host.
+/// * `image: &File` - The disk image, opened for writing and locked.
+/// * `policy: ShortNamePolicy` - What to do about short names with numeric tails.
+/// * `map_path: Option<&Path>` - The file to write the short names of entries with long names
+///   to, if any.
+/// * `options: &CopyOptions` - How to copy the directory.
+///
+/// # Returns
+///
+/// * `io::Result<copy::Summary>` - What was copied, or an error if the volume can't be built, an
+///   entry can't be copied or the map can't be written.
+fn pack(
+    spec: &Spec,
+    source: &Path,
+    image: &File,
+    policy: ShortNamePolicy,
+    map_path: Option<&Path>,
+    options: &CopyOptions,
+) -> io::Result<copy::Summary> {
+    mkimage::build(spec, image)?;
+    let fs = tree::open_volume(image)?;
+    let (summary, stamps) = copy::copy_in(&fs, source, Path::new("/"), options)?;
+    let mut short_names = Vec::new();
+    if policy == ShortNamePolicy::Warn || map_path.is_some() {
+        find_short_names(&fs, Path::new("/"), &mut short_names)?;
+    }
+    fs.unmount()?;
+    copy::apply_stamps(image, &stamps)?;
+    image.sync_all()?;
+    if policy == ShortNamePolicy::Warn {
+        for (path, short_name) in &short_names {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if alias::tail_basis(name).is_some() {
+                eprintln!("{:?}: got the short name {:?}", path, short_name);
+            }
+        }
+    }
+    if let Some(map_path) = map_path {
+        let mut map = File::create(map_path)?;
+        for (path, short_name) in &short_names {
+            writeln!(map, "{:?} {:?}", path.display().to_string(), short_name)?;
+        }
+        map.sync_all()?;
+    }
+    Ok(summary)
+}
+
+/// Check the names of the entries in a directory on the host against a short name policy, before
+/// anything is copied. With `--dry-run`, `warn` prints the names that would get numeric tails.
+///
+/// # Parameters
+///
+/// * `source: &Path` - The directory on the host.
+/// * `policy: ShortNamePolicy` - What to do about short names with numeric tails.
+/// * `options: &CopyOptions` - How the directory would be copied.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the directory can't be read, or an `InvalidInput` error if
+///   `strict` refuses two names in one directory.
+fn check_short_names(
+    source: &Path,
+    policy: ShortNamePolicy,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    if policy == ShortNamePolicy::Auto || (policy == ShortNamePolicy::Warn && !options.dry_run) {
+        return Ok(());
+    }
+    let mut bases = HashMap::new();
+    for target in copy::host_targets(source, Path::new("/"), options)? {
+        let name = target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let Some((prefix, ext)) = alias::tail_basis(name) else {
+            continue;
+        };
+        if policy == ShortNamePolicy::Warn {
+            eprintln!("{:?}: would get a short name with a numeric tail", target);
+            continue;
+        }
+        let parent = target.parent().map(Path::to_path_buf);
+        let short_name = format!(
+            "{}~N{}{}",
+            String::from_utf8_lossy(&prefix),
+            if ext.is_empty() { "" } else { "." },
+            String::from_utf8_lossy(&ext)
+        );
+        if let Some(other) = bases.insert((parent, prefix, ext), target.clone()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} and {:?} would both get short names like {}, told apart only by \
+                     numeric tails",
+                    other, target, short_name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to find the short names of the entries with long names below a directory on
+/// the volume.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `dir: &Path` - The absolute path of the directory.
+/// * `short_names: &mut Vec<(PathBuf, String)>` - The paths of the entries found so far with
+///   their short names, which those below `dir` are added to.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if a directory can't be read.
+fn find_short_names(
+    fs: &FatfsFileSystem<Storage>,
+    dir: &Path,
+    short_names: &mut Vec<(PathBuf, String)>,
+) -> io::Result<()> {
+    for entry in open_dir(fs, dir)?.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.attributes().contains(FileAttributes::VOLUME_ID)
+            || matches!(name.as_str(), "." | "..")
+        {
+            continue;
+        }
+        let path = dir.join(&name);
+        let short_name = entry.short_file_name();
+        if short_name != name {
+            short_names.push((path.clone(), short_name));
+        }
+        if entry.is_dir() {
+            find_short_names(fs, &path, short_names)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check what packing a directory would do, on a scratch file in place of the disk image.
+///
+/// # Parameters
+///
+/// * `spec: &Spec` - The spec of the empty volume.
+/// * `source: &Path` - The directory on the host.
+/// * `disk_image_path: &Path` - The path of the disk image, which is not touched.
+/// * `force: bool` - Whether an existing disk image would be replaced.
+/// * `options: &CopyOptions` - How the directory would be copied.
+///
+/// # Returns
+///
+/// * `io::Result<copy::Summary>` - What would be copied, or why packing would fail.
+fn plan(
+    spec: &Spec,
+    source: &Path,
+    disk_image_path: &Path,
+    force: bool,
+    options: &CopyOptions,
+) -> io::Result<copy::Summary> {
+    if !force && disk_image_path.exists() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "the disk image exists, --force replaces it",
+        ));
+    }
+    on_scratch(spec, |scratch, fs| {
+        print_action(
+            "format",
+            &[
+                Arg::Text(&disk_image_path.display().to_string()),
+                Arg::Number(scratch.metadata()?.len()),
+                Arg::Text(format_fat_type(fs.fat_type())),
+            ],
+        );
+        copy::copy_in(fs, source, Path::new("/"), options).map(|(summary, _)| summary)
+    })
+}
+
+/// Find the smallest size of a disk image a directory fits on, for `--size auto`.
+///
+/// # Parameters
+///
+/// * `source: &Path` - The directory on the host.
+/// * `fat_type: Option<FatType>` - The FAT variant, or `None` for the one picked by the size.
+/// * `options: &CopyOptions` - How the directory would be copied, with what it leaves out.
+///
+/// # Returns
+///
+/// * `io::Result<u64>` - The size in bytes, a multiple of `AUTO_SIZE_STEP`, or an error if the
+///   directory can't be read or doesn't fit on the largest size tried.
+fn auto_size(source: &Path, fat_type: Option<FatType>, options: &CopyOptions) -> io::Result<u64> {
+    let (bytes, dirs) = copy::measure_host(source, options)?;
+    // A first guess, with clusters of 4K, and an eighth more for the FATs and the slack at the
+    // ends of files. The copy is then checked on volumes of growing sizes until it fits.
+    let mut size = (bytes + dirs * 4096) / 8 * 9;
+    loop {
+        size = size.div_ceil(AUTO_SIZE_STEP).max(1) * AUTO_SIZE_STEP;
+        if size > MAX_AUTO_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "the directory doesn't fit on a volume of {} bytes{}",
+                    MAX_AUTO_SIZE,
+                    fat_type.map_or(String::new(), |fat_type| format!(
+                        " or less formatted as {}",
+                        format_fat_type(fat_type)
+                    ))
+                ),
+            ));
+        }
+        let spec = Spec::blank(size, fat_type, None);
+        match on_scratch(&spec, |_, fs| {
+            copy::plan_in(fs, source, Path::new("/"), options).map(|_| ())
+        }) {
+            Ok(()) => return Ok(size),
+            Err(e) if e.kind() == ErrorKind::StorageFull => size += size / 8,
+            // The volume has too few clusters for the FAT variant asked for.
+            Err(e) if e.kind() == ErrorKind::InvalidInput && fat_type.is_some() => size *= 2,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Build a volume on a scratch file in the temporary directory, to check what a copy to it
+/// would do. The file is sparse, so only what the volume holds before copying takes space, and
+/// it is removed when done.
+///
+/// # Parameters
+///
+/// * `spec: &Spec` - The spec of the empty volume.
+/// * `check: impl FnOnce(&File, &FatfsFileSystem<Storage>) -> io::Result<T>` - What to do with
+///   the scratch file and the volume on it.
+///
+/// # Returns
+///
+/// * `io::Result<T>` - What `check` returned, or an error if the volume can't be built.
+fn on_scratch<T>(
+    spec: &Spec,
+    check: impl FnOnce(&File, &FatfsFileSystem<Storage>) -> io::Result<T>,
+) -> io::Result<T> {
+    let scratch_path = env::temp_dir().join(format!(
+        "nufat-pack-scratch-{}-{}.img",
+        process::id(),
+        SCRATCH_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let scratch = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&scratch_path)?;
+    let result = mkimage::build(spec, &scratch).and_then(|_| {
+        let fs = tree::open_volume(&scratch)?;
+        check(&scratch, &fs)
+    });
+    let _ = fs::remove_file(&scratch_path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpack;
+    use std::env;
+
+    #[test]
+    fn directories_survive_a_pack_and_an_unpack() {
+        let dir = env::temp_dir().join(format!("nufat-pack-{}", std::process::id()));
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("EFI/BOOT")).unwrap();
+        fs::write(source.join("EFI/BOOT/BOOTX64.EFI"), vec![7; 70_000]).unwrap();
+        fs::write(source.join("startup.nsh"), b"fs0:").unwrap();
+        fs::write(source.join("build.log"), b"log").unwrap();
+        let image = dir.join("disk.img").display().to_string();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let source_arg = source.display().to_string();
+
+        assert_eq!(run(&args(&[&source_arg, &image])), 2);
+        assert_eq!(
+            run(&args(&[
+                &source_arg,
+                &image,
+                "--size",
+                "8M",
+                "--label",
+                "BOOT",
+                "--exclude",
+                "*.log"
+            ])),
+            0
+        );
+        // A dry run leaves the image alone, and says if the directory wouldn't fit.
+        let checksum = || crate::hash::sha256(&mut File::open(&image).unwrap()).unwrap();
+        let before = checksum();
+        assert_eq!(
+            run(&args(&[
+                &source_arg,
+                &image,
+                "--size",
+                "8M",
+                "--force",
+                "--dry-run"
+            ])),
+            0
+        );
+        assert_eq!(
+            run(&args(&[&source_arg, &image, "--size", "8M", "--dry-run"])),
+            1
+        );
+        let small = dir.join("small.img").display().to_string();
+        assert_eq!(
+            run(&args(&[&source_arg, &small, "--size", "64K", "--dry-run"])),
+            1
+        );
+        assert_eq!(checksum(), before);
+        assert!(!Path::new(&small).exists());
+        // An image is only replaced with `--force`.
+        assert_eq!(run(&args(&[&source_arg, &image, "--size", "8M"])), 1);
+        assert!(Path::new(&image).exists());
+        // One that doesn't fit is not left behind.
+        assert_eq!(run(&args(&[&source_arg, &small, "--size", "64K"])), 1);
+        assert!(!Path::new(&small).exists());
+
+        let output = dir.join("output");
+        assert_eq!(
+            unpack::run(&args(&[&image, &output.display().to_string()])),
+            0
+        );
+        assert_eq!(
+            fs::read(output.join("EFI/BOOT/BOOTX64.EFI")).unwrap(),
+            vec![7; 70_000]
+        );
+        assert_eq!(fs::read(output.join("startup.nsh")).unwrap(), b"fs0:");
+        assert!(!output.join("build.log").exists());
+        // `--size auto` doesn't count what is excluded, which is not on the image either.
+        let build = source.join("build");
+        fs::create_dir_all(&build).unwrap();
+        File::create(build.join("big.o"))
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let excludes = dir.join("excludes");
+        fs::write(&excludes, "# build output\n/build/\n*.log\n").unwrap();
+        let auto = dir.join("auto.img").display().to_string();
+        assert_eq!(
+            run(&args(&[
+                &source_arg,
+                &auto,
+                "--size",
+                "auto",
+                "--exclude-from",
+                &excludes.display().to_string()
+            ])),
+            0
+        );
+        assert!(fs::metadata(&auto).unwrap().len() < 1 << 20);
+        let volume =
+            fatfs::FileSystem::new(File::open(&auto).unwrap(), fatfs::FsOptions::new()).unwrap();
+        let names: Vec<String> = volume
+            .root_dir()
+            .iter()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["EFI", "startup.nsh"]);
+        drop(volume);
+
+        let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+        let drift = modified(&source.join("startup.nsh"))
+            .duration_since(modified(&output.join("startup.nsh")))
+            .unwrap();
+        assert!(drift.as_secs() < 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_name_policies_catch_colliding_tails() {
+        let dir = env::temp_dir().join(format!("nufat-pack-short-{}", std::process::id()));
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("configuration_one.txt"), b"1").unwrap();
+        fs::write(source.join("configuration_two.txt"), b"2").unwrap();
+        // The same names in different directories don't collide.
+        fs::write(source.join("docs/configuration_one.txt"), b"1").unwrap();
+        fs::write(source.join("README.TXT"), b"read me").unwrap();
+        let image = dir.join("disk.img").display().to_string();
+        let map = dir.join("short-names");
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let source_arg = source.display().to_string();
+
+        let strict = [
+            &source_arg,
+            &image,
+            "--size",
+            "1M",
+            "--shortname-policy",
+            "strict",
+        ];
+        assert_eq!(run(&args(&strict)), 1);
+        assert!(!Path::new(&image).exists());
+        assert_eq!(
+            run(&args(&[&source_arg, &image, "--shortname-policy", "loose"])),
+            2
+        );
+        // Excluding one of the colliding names is enough.
+        let packed = run(&args(
+            &[&strict[..], &["--exclude", "/configuration_two.txt"]].concat(),
+        ));
+        assert_eq!(packed, 0);
+        fs::remove_file(&image).unwrap();
+
+        let map_arg = map.display().to_string();
+        let packed = run(&args(&[
+            &source_arg,
+            &image,
+            "--size",
+            "1M",
+            "--shortname-policy",
+            "warn",
+            "--shortname-map",
+            &map_arg,
+        ]));
+        assert_eq!(packed, 0);
+        assert_eq!(
+            fs::read_to_string(&map).unwrap(),
+            concat!(
+                "\"/configuration_one.txt\" \"CONFIG~1.TXT\"\n",
+                "\"/configuration_two.txt\" \"CONFIG~2.TXT\"\n",
+                "\"/docs\" \"DOCS\"\n",
+                "\"/docs/configuration_one.txt\" \"CONFIG~1.TXT\"\n",
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
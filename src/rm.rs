@@ -0,0 +1,185 @@
+//! This module implements the `rm` subcommand, which deletes files and directories on a disk image
+//! that is not mounted. Paths can be glob patterns, expanded by the `tree` module. With
+//! `--dry-run`, the disk image is only opened for reading, and what would be deleted is printed
+//! by the `dry_run` module.
+use crate::dry_run::{print_action, Arg};
+use crate::filesystem::{lock_image, remove};
+use crate::tree;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The usage of the subcommand.
+const USAGE: &str =
+    "Usage: nuFAT rm <disk.img> <PATTERN...> [--recursive] [--allow-empty] [--dry-run]";
+
+/// Run the `rm` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <PATTERN...>
+///   [--recursive] [--allow-empty] [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or written, a pattern
+///   matches nothing or a directory is matched without `--recursive`, 2 if the arguments are
+///   invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut allow_empty = false;
+    let mut recursive = false;
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--allow-empty" => allow_empty = true,
+            "--recursive" => recursive = true,
+            "--dry-run" => dry_run = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let [disk_image_path, patterns @ ..] = &paths[..] else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let patterns = match tree::parse_patterns(patterns) {
+        Ok(patterns) if !patterns.is_empty() => patterns,
+        Ok(_) => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let result = OpenOptions::new()
+        .read(true)
+        .write(!dry_run)
+        .open(disk_image_path)
+        .and_then(|image| {
+            lock_image(&image, !dry_run)?;
+            let fs = tree::open_volume(&image)?;
+            let found = tree::expand(&fs, &patterns, allow_empty)?;
+            // Check everything first, so nothing is deleted if anything would be refused.
+            for entry in &found {
+                if entry.path == Path::new("/") {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "The root directory can't be deleted.",
+                    ));
+                }
+                if entry.is_dir && !recursive {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "{} is a directory, --recursive deletes it with everything in it",
+                            entry.path.display()
+                        ),
+                    ));
+                }
+            }
+            let mut removed: Vec<PathBuf> = Vec::new();
+            for entry in &found {
+                // Entries in a directory that was deleted before are gone already.
+                if removed.iter().any(|dir| entry.path.starts_with(dir)) {
+                    continue;
+                }
+                let mut paths = Vec::new();
+                if entry.is_dir {
+                    paths.extend(
+                        tree::walk(&fs, entry)?
+                            .into_iter()
+                            .rev()
+                            .map(|below| below.path),
+                    );
+                }
+                paths.push(entry.path.clone());
+                for path in paths {
+                    if dry_run {
+                        print_action("delete", &[Arg::Text(&format!("img:{}", path.display()))]);
+                    } else {
+                        remove(&fs, &path)?;
+                    }
+                }
+                removed.push(entry.path.clone());
+            }
+            if dry_run {
+                return Ok(());
+            }
+            drop(fs);
+            image.sync_all()
+        });
+    match result {
+        Ok(()) => 0,
+        Err(e) if dry_run => {
+            eprintln!("{:?}: would fail: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    #[test]
+    fn matching_entries_are_deleted() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "entries": [
+                {"name": "logs", "entries": [
+                    {"name": "old", "entries": [{"name": "a.log"}, {"name": "b.log"}]},
+                    {"name": "c.log"}
+                ]},
+                {"name": "keep.txt"},
+                {"name": "drop.TXT"}
+            ]}"#,
+        );
+        let rm = |args: &[&str]| {
+            let mut all = vec![image.options().disk_image_path.display().to_string()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            run(&all)
+        };
+        let names = || {
+            let fs = image.volume();
+            let names: Vec<String> = fs
+                .root_dir()
+                .iter()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names
+        };
+        assert_eq!(rm(&["/logs/**/*.log", "/logs/old"]), 1);
+        let checksum = image.checksum();
+        assert_eq!(rm(&["/logs", "--recursive", "--dry-run"]), 0);
+        assert_eq!(rm(&["/logs", "--dry-run"]), 1);
+        assert_eq!(image.checksum(), checksum);
+        assert_eq!(rm(&["/*.log", "--allow-empty"]), 0);
+        assert_eq!(rm(&["/drop.txt"]), 0);
+        assert_eq!(names(), ["logs", "keep.txt"]);
+        assert_eq!(rm(&["/logs/**/*.log", "/logs/old", "--recursive"]), 0);
+        assert_eq!(
+            image
+                .volume()
+                .root_dir()
+                .open_dir("logs")
+                .unwrap()
+                .iter()
+                .count(),
+            2
+        );
+        assert_eq!(rm(&["/", "--recursive"]), 1);
+        assert_eq!(rm(&["/l*", "--recursive"]), 0);
+        assert_eq!(names(), ["keep.txt"]);
+    }
+}
@@ -0,0 +1,302 @@
+//! This module implements the `serial` subcommand, which reads and sets the serial number of the
+//! volume on a disk image that is not mounted. With `--dry-run`, the disk image is only opened for
+//! reading, and the change is printed by the `dry_run` module instead.
+use crate::dry_run::{print_action, Arg};
+use crate::filesystem::format_volume_id;
+use crate::floppy;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// Run the `serial` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> [XXXX-XXXX|HEX|--random]
+///   [--dry-run]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 otherwise.
+pub fn run(args: &[String]) -> i32 {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--dry-run")
+        .cloned()
+        .collect();
+    // A random serial number is only made up once it is known how many bits it may have.
+    let (disk_image_path, new_serial) = match &args[..] {
+        [disk_image_path] => (PathBuf::from(disk_image_path), None),
+        [disk_image_path, serial] if serial == "--random" => {
+            (PathBuf::from(disk_image_path), Some(None))
+        }
+        [disk_image_path, serial] if !serial.starts_with("--") => match parse_serial(serial) {
+            Some(serial) => (PathBuf::from(disk_image_path), Some(Some(serial))),
+            None => {
+                eprintln!(
+                    "Invalid serial number '{}', expected XXXX-XXXX or 8 hex digits.",
+                    serial
+                );
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("Usage: nuFAT serial <disk.img> [XXXX-XXXX|HEX|--random] [--dry-run]");
+            return 1;
+        }
+    };
+
+    let image = OpenOptions::new()
+        .read(true)
+        .write(new_serial.is_some() && !dry_run)
+        .open(&disk_image_path);
+    let result = image.and_then(|image| match new_serial {
+        Some(Some(serial)) => write_serial(&image, serial, dry_run),
+        Some(None) => {
+            let serial_len = read_boot_sector(&image)?.serial_len;
+            let serial = random_serial().map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to generate a random serial number: {}", e),
+                )
+            })?;
+            write_serial(&image, serial >> (32 - 8 * serial_len as u32), dry_run)
+        }
+        None => read_serial(&image).map(|serial| println!("{}", format_volume_id(serial))),
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Parse a serial number given as `XXXX-XXXX`, or as 8 hex digits with an optional `0x` prefix.
+///
+/// # Parameters
+///
+/// * `serial: &str` - The serial number to parse.
+///
+/// # Returns
+///
+/// * `Option<u32>` - The serial number, or `None` if it is malformed.
+pub fn parse_serial(serial: &str) -> Option<u32> {
+    let digits = match serial.split_once('-') {
+        Some((high, low)) if high.len() == 4 && low.len() == 4 => format!("{}{}", high, low),
+        Some(_) => return None,
+        None => serial.strip_prefix("0x").unwrap_or(serial).to_string(),
+    };
+    if digits.len() != 8 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+/// Generate a random serial number.
+///
+/// # Returns
+///
+/// * `io::Result<u32>` - The serial number.
+fn random_serial() -> io::Result<u32> {
+    let mut buf = [0; 4];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Represent the fields of the boot sector needed to find the serial number and the volume label.
+///
+/// # Members
+///
+/// * `serial_offset: Option<u64>` - The offset of the serial number in the boot sector, or `None`
+///   if the volume has none, like floppies formatted by DOS 3.x and older.
+/// * `serial_len: usize` - The size of the serial number in bytes, which is 3 on the Atari ST.
+/// * `atari_executable: bool` - Whether the Atari ST runs the boot sector, which requires its
+///   checksum to stay the same.
+/// * `label_offset: Option<u64>` - The offset of the volume label in the boot sector, or `None` if
+///   there is no room for it.
+/// * `backup_boot_sector: Option<u64>` - The offset of the backup boot sector of FAT32 volumes.
+pub struct BootSector {
+    pub serial_offset: Option<u64>,
+    pub serial_len: usize,
+    pub atari_executable: bool,
+    pub label_offset: Option<u64>,
+    pub backup_boot_sector: Option<u64>,
+}
+
+/// Read and check the boot sector of the volume.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<BootSector>` - The boot sector, or an error if the image holds no FAT volume.
+pub fn read_boot_sector(image: &File) -> io::Result<BootSector> {
+    // The Atari ST keeps a 24 bit serial number in the middle of the OEM name.
+    if floppy::is_atari_image(image)? && floppy::patched_boot_sector(image)?.is_some() {
+        let mut on_disk = [0; floppy::BOOT_SECTOR_SIZE];
+        image.read_exact_at(&mut on_disk, 0)?;
+        return Ok(BootSector {
+            serial_offset: Some(0x08),
+            serial_len: 3,
+            atari_executable: floppy::atari_checksum(&on_disk) == floppy::ATARI_EXECUTABLE,
+            label_offset: None,
+            backup_boot_sector: None,
+        });
+    }
+
+    let sector = floppy::boot_sector(image)?;
+    if sector[510..512] != [0x55, 0xAA] {
+        return Err(invalid_data(
+            "Not a FAT volume, the boot sector signature is missing.",
+        ));
+    }
+
+    // FAT32 volumes have neither a fixed size root directory nor a 16 bit FAT size.
+    let root_entries = u16::from_le_bytes([sector[0x11], sector[0x12]]);
+    let fat_size_16 = u16::from_le_bytes([sector[0x16], sector[0x17]]);
+    let fat32 = root_entries == 0 && fat_size_16 == 0;
+
+    // The serial number is only present with the extended boot signature, and the label only
+    // with its later version.
+    let signature_offset = if fat32 { 0x42 } else { 0x26 };
+    let serial_offset = signature_offset as u64 + 1;
+
+    let bytes_per_sector = u16::from_le_bytes([sector[0x0B], sector[0x0C]]) as u64;
+    let backup_sector = u16::from_le_bytes([sector[0x32], sector[0x33]]) as u64;
+    Ok(BootSector {
+        serial_offset: matches!(sector[signature_offset], 0x28 | 0x29).then_some(serial_offset),
+        serial_len: 4,
+        atari_executable: false,
+        label_offset: (sector[signature_offset] == 0x29).then_some(serial_offset + 4),
+        backup_boot_sector: if fat32 && backup_sector != 0 && backup_sector != 0xFFFF {
+            Some(backup_sector * bytes_per_sector)
+        } else {
+            None
+        },
+    })
+}
+
+/// Read the serial number of the volume.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image.
+///
+/// # Returns
+///
+/// * `io::Result<u32>` - The serial number.
+pub fn read_serial(mut image: &File) -> io::Result<u32> {
+    let boot_sector = read_boot_sector(image)?;
+    let serial_offset = boot_sector
+        .serial_offset
+        .ok_or_else(|| invalid_data("The volume has no serial number."))?;
+    let mut buf = [0; 4];
+    image.seek(SeekFrom::Start(serial_offset))?;
+    image.read_exact(&mut buf[..boot_sector.serial_len])?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Write a new serial number to the boot sector, and to the backup boot sector of FAT32 volumes so
+/// both stay consistent. On executable Atari ST boot sectors, the last word is adjusted so the
+/// checksum stays the same.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `serial: u32` - The new serial number.
+/// * `dry_run: bool` - Whether to only check the serial number and print the change, as with
+///   `--dry-run`, for a disk image opened for reading.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the volume can't hold the serial number or writing it
+///   failed.
+fn write_serial(mut image: &File, serial: u32, dry_run: bool) -> io::Result<()> {
+    let boot_sector = read_boot_sector(image)?;
+    let old_serial = read_serial(image)?;
+    let serial_offset = boot_sector.serial_offset.unwrap_or_default();
+    let serial_bytes = &serial.to_le_bytes()[..boot_sector.serial_len];
+    if serial
+        .checked_shr(8 * boot_sector.serial_len as u32)
+        .unwrap_or(0)
+        != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Atari ST serial numbers have only 24 bits, e.g. 0012-3456.",
+        ));
+    }
+    if dry_run {
+        print_action(
+            "serial",
+            &[
+                Arg::Text(&format_volume_id(old_serial)),
+                Arg::Text(&format_volume_id(serial)),
+            ],
+        );
+        return Ok(());
+    }
+
+    image.seek(SeekFrom::Start(serial_offset))?;
+    image.write_all(serial_bytes)?;
+    if let Some(backup_boot_sector) = boot_sector.backup_boot_sector {
+        image.seek(SeekFrom::Start(backup_boot_sector + serial_offset))?;
+        image.write_all(serial_bytes)?;
+    }
+    if boot_sector.atari_executable {
+        let mut sector = [0; floppy::BOOT_SECTOR_SIZE];
+        image.read_exact_at(&mut sector, 0)?;
+        let last_word = floppy::BOOT_SECTOR_SIZE as u64 - 2;
+        let old_word = u16::from_be_bytes([sector[510], sector[511]]);
+        let fixed = floppy::ATARI_EXECUTABLE
+            .wrapping_sub(floppy::atari_checksum(&sector))
+            .wrapping_add(old_word);
+        image.write_all_at(&fixed.to_be_bytes(), last_word)?;
+    }
+    image.sync_all()?;
+
+    println!(
+        "Changed serial number from {} to {}.",
+        format_volume_id(old_serial),
+        format_volume_id(serial)
+    );
+    Ok(())
+}
+
+/// Create an error for a disk image that can't be used.
+///
+/// # Parameters
+///
+/// * `message: &str` - The description of the error.
+///
+/// # Returns
+///
+/// * `io::Error` - The error.
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    #[test]
+    fn dry_runs_leave_the_serial_number_alone() {
+        let image = TestImage::from_spec(r#"{"size": "16M", "serial": "1234-5678"}"#);
+        let path = image.options().disk_image_path.display().to_string();
+        let checksum = image.checksum();
+        let args = |serial: &str| vec![path.clone(), serial.to_string(), "--dry-run".to_string()];
+        assert_eq!(run(&args("ABCD-EF01")), 0);
+        assert_eq!(run(&args("--random")), 0);
+        assert_eq!(image.checksum(), checksum);
+        assert_eq!(run(&[path.clone(), "ABCD-EF01".to_string()]), 0);
+        assert_eq!(read_serial(&image.open()).unwrap(), 0xABCD_EF01);
+    }
+}
@@ -0,0 +1,159 @@
+//! This module implements the storage backend `fatfs` uses to access the disk image.
+use crate::floppy::BOOT_SECTOR_SIZE;
+use crate::log::{self, Fields, Level};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::Duration;
+
+/// The delay before the first retry of a failed read or write. Doubled for every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Represent the disk image as seen by `fatfs`.
+///
+/// Reads and writes failing with errors that are known to be transient on removable media are
+/// retried with an exponential backoff before the error is passed on.
+///
+/// # Members
+///
+/// * `file: File` - The opened disk image.
+/// * `retries: u32` - How often a failed read or write is retried.
+/// * `boot_sector: Option<[u8; BOOT_SECTOR_SIZE]>` - A patched boot sector that is read and written
+///   instead of the one on the disk image.
+pub struct Storage {
+    file: File,
+    retries: u32,
+    boot_sector: Option<[u8; BOOT_SECTOR_SIZE]>,
+}
+
+impl Storage {
+    /// Create a new storage backend.
+    ///
+    /// # Parameters
+    ///
+    /// * `file: File` - The opened disk image.
+    /// * `retries: u32` - How often a failed read or write is retried.
+    /// * `boot_sector: Option<[u8; BOOT_SECTOR_SIZE]>` - The patched boot sector to use instead of
+    ///   the one on the disk image, as returned by `floppy::patched_boot_sector`.
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - A new instance of a `Storage`.
+    pub fn new(file: File, retries: u32, boot_sector: Option<[u8; BOOT_SECTOR_SIZE]>) -> Self {
+        Storage {
+            file,
+            retries,
+            boot_sector,
+        }
+    }
+
+    /// Helper function to find the part of the patched boot sector at the current offset.
+    ///
+    /// # Parameters
+    ///
+    /// * `len: usize` - The size of the read or write.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<(usize, usize)>>` - The offset into the boot sector and the number of
+    ///   bytes to use, or `None` if there is no patched boot sector at the current offset.
+    fn boot_sector_range(&mut self, len: usize) -> io::Result<Option<(usize, usize)>> {
+        if self.boot_sector.is_none() {
+            return Ok(None);
+        }
+        let offset = self.file.stream_position()?;
+        if offset >= BOOT_SECTOR_SIZE as u64 {
+            return Ok(None);
+        }
+        let offset = offset as usize;
+        let len = len.min(BOOT_SECTOR_SIZE - offset);
+        self.file.seek(SeekFrom::Current(len as i64))?;
+        Ok(Some((offset, len)))
+    }
+
+    /// Helper function to run an I/O operation with retries.
+    ///
+    /// A failing `read` or `write` syscall does not move the file offset, so repeating the
+    /// operation is safe.
+    ///
+    /// # Parameters
+    ///
+    /// * `op: impl FnMut(&mut File) -> io::Result<T>` - The operation to run on the image.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<T>` - The result of the first successful attempt or the last error.
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut File) -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match op(&mut self.file) {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    log::log_with(
+                        Level::Warning,
+                        module_path!(),
+                        &format!(
+                            "I/O error on disk image: {}. Retrying in {:?}...",
+                            e, backoff
+                        ),
+                        &Fields {
+                            errno: e.raw_os_error(),
+                            ..Fields::default()
+                        },
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Check whether an I/O error may go away when retrying the operation.
+///
+/// # Parameters
+///
+/// * `error: &io::Error` - The error to check.
+///
+/// # Returns
+///
+/// * `bool` - `true` for `EAGAIN`, `EINTR` and `EIO`.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+        || error.raw_os_error() == Some(libc::EIO)
+}
+
+impl Read for Storage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some((offset, len)) = self.boot_sector_range(buf.len())? {
+            let boot_sector = self.boot_sector.as_ref().unwrap();
+            buf[..len].copy_from_slice(&boot_sector[offset..offset + len]);
+            return Ok(len);
+        }
+        self.retry(|file| file.read(buf))
+    }
+}
+
+impl Write for Storage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A patched boot sector only has fields, like the dirty flag, that don't need to persist.
+        if let Some((offset, len)) = self.boot_sector_range(buf.len())? {
+            let boot_sector = self.boot_sector.as_mut().unwrap();
+            boot_sector[offset..offset + len].copy_from_slice(&buf[..len]);
+            return Ok(len);
+        }
+        self.retry(|file| file.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Storage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
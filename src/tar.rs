@@ -0,0 +1,660 @@
+//! This module reads and writes the tar archives of the `export` and `import` subcommands, in
+//! the POSIX format GNU tar and bsdtar read. Whatever a plain header can't hold, like long or
+//! non-ASCII paths and what FAT keeps besides the modification time, goes into a PAX extended
+//! header in front of the entry, one `key=value` record at a time. Readers that don't know a
+//! key, like GNU tar with the `NUFAT.` ones, skip it.
+use std::io::{self, ErrorKind, Read, Write};
+
+/// The size of a header, and the blocks the data of entries is padded to.
+const BLOCK_SIZE: usize = 512;
+
+/// The longest path the name field of a header holds.
+const NAME_SIZE: usize = 100;
+
+/// Represent the kind of an entry in an archive.
+///
+/// # Variants
+///
+/// * `File` - A regular file, followed by its data.
+/// * `Dir` - A directory.
+/// * `Other(u8)` - Anything else, like a link or a device, with the type flag of its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Other(u8),
+}
+
+/// Represent an entry in an archive.
+///
+/// # Members
+///
+/// * `path: String` - The path of the entry, relative to where the archive is extracted, without
+///   the `/` that directories get in headers.
+/// * `kind: Kind` - What the entry is.
+/// * `size: u64` - The size of the data following the header, 0 for directories.
+/// * `mode: u32` - The permission bits.
+/// * `uid: u32` - The ID of the user owning the entry.
+/// * `gid: u32` - The ID of the group owning the entry.
+/// * `mtime: u64` - When the entry was last modified, in seconds since the Unix epoch.
+/// * `records: Vec<(String, String)>` - The records of the extended header besides those standing
+///   in for the fields above, e.g. `NUFAT.crtime`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub records: Vec<(String, String)>,
+}
+
+impl Entry {
+    /// Get the value of a record of the extended header.
+    ///
+    /// # Parameters
+    ///
+    /// * `key: &str` - The key of the record.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&str>` - The value of the last record with the key, or `None` if there is none.
+    pub fn record(&self, key: &str) -> Option<&str> {
+        self.records
+            .iter()
+            .rev()
+            .find(|(record_key, _)| record_key == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Write the header of an entry, after an extended header if it needs one. The data of a file
+/// follows, and then `write_padding`.
+///
+/// # Parameters
+///
+/// * `out: &mut impl Write` - The archive.
+/// * `entry: &Entry` - The entry.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the archive can't be written.
+pub fn write_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let mut name = entry.path.clone();
+    if entry.kind == Kind::Dir {
+        name.push('/');
+    }
+    let mut records = entry.records.clone();
+    if name.len() > NAME_SIZE || !name.is_ascii() {
+        records.insert(0, ("path".to_string(), name.clone()));
+    }
+    if !records.is_empty() {
+        let mut data = Vec::new();
+        for (key, value) in &records {
+            data.extend(format_record(key, value));
+        }
+        // Like GNU tar, the extended header gets a name of its own, in case it is extracted.
+        let base = name.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+        let pax_name: String = format!("PaxHeaders/{}", ascii_only(base))
+            .chars()
+            .take(NAME_SIZE)
+            .collect();
+        out.write_all(&header(
+            &pax_name,
+            b'x',
+            data.len() as u64,
+            0o644,
+            0,
+            0,
+            entry.mtime,
+        ))?;
+        out.write_all(&data)?;
+        write_padding(out, data.len() as u64)?;
+    }
+    let type_flag = match entry.kind {
+        Kind::File => b'0',
+        Kind::Dir => b'5',
+        Kind::Other(type_flag) => type_flag,
+    };
+    // Readers knowing extended headers take the path from there, others get what fits.
+    let short_name = ascii_only(&name);
+    let short_name = &short_name[..short_name.len().min(NAME_SIZE)];
+    let header = header(
+        short_name,
+        type_flag,
+        entry.size,
+        entry.mode,
+        entry.uid,
+        entry.gid,
+        entry.mtime,
+    );
+    out.write_all(&header)
+}
+
+/// Pad the data of an entry to a whole block.
+///
+/// # Parameters
+///
+/// * `out: &mut impl Write` - The archive.
+/// * `size: u64` - The size of the data written.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the archive can't be written.
+pub fn write_padding(out: &mut impl Write, size: u64) -> io::Result<()> {
+    out.write_all(&[0; BLOCK_SIZE][..padding_of(size) as usize])
+}
+
+/// End an archive with the two empty blocks readers expect.
+///
+/// # Parameters
+///
+/// * `out: &mut impl Write` - The archive.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the archive can't be written.
+pub fn finish(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(&[0; 2 * BLOCK_SIZE])?;
+    out.flush()
+}
+
+/// Represent an archive being read, an entry at a time.
+///
+/// # Members
+///
+/// * `input: R` - The archive.
+/// * `offset: u64` - The offset of the next block, to tell where a malformed header is.
+/// * `data_left: u64` - The bytes of the data of the last entry not read yet.
+/// * `padding: u64` - The bytes of padding following them.
+pub struct Reader<R: Read> {
+    input: R,
+    offset: u64,
+    data_left: u64,
+    padding: u64,
+}
+
+impl<R: Read> Reader<R> {
+    /// Start reading an archive.
+    ///
+    /// # Parameters
+    ///
+    /// * `input: R` - The archive.
+    ///
+    /// # Returns
+    ///
+    /// * `Reader<R>` - The reader, before the first entry.
+    pub fn new(input: R) -> Self {
+        Reader {
+            input,
+            offset: 0,
+            data_left: 0,
+            padding: 0,
+        }
+    }
+
+    /// Read the header of the next entry, skipping the data of the last one if it wasn't read.
+    /// Extended headers, and the long names of GNU tar, apply to the entry following them.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<Entry>>` - The entry, `None` at the end of the archive, or an
+    ///   `InvalidData` error if a header is malformed.
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let mut records: Vec<(String, String)> = Vec::new();
+        let mut long_name = None;
+        loop {
+            self.skip_data()?;
+            let header_offset = self.offset;
+            let mut block = [0; BLOCK_SIZE];
+            if !self.read_block(&mut block)? || block.iter().all(|byte| *byte == 0) {
+                return Ok(None);
+            }
+            let malformed = |what: &str| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed header at offset {}: {}", header_offset, what),
+                )
+            };
+            let checksum = parse_number(&block[148..156]).ok_or_else(|| malformed("checksum"))?;
+            if checksum != header_checksum(&block) {
+                return Err(malformed("wrong checksum"));
+            }
+            let size = parse_number(&block[124..136]).ok_or_else(|| malformed("size"))?;
+            let type_flag = block[156];
+            match type_flag {
+                b'x' | b'g' | b'L' => {
+                    let mut data = vec![0; usize::try_from(size).map_err(|_| malformed("size"))?];
+                    self.read_data(&mut data)?;
+                    if type_flag == b'L' {
+                        long_name = Some(field(&data));
+                    } else if type_flag == b'x' {
+                        records.extend(
+                            parse_records(&data).ok_or_else(|| malformed("extended header"))?,
+                        );
+                    }
+                    // Global extended headers hold nothing entries are imported with.
+                    continue;
+                }
+                _ => {}
+            }
+            let mut path = long_name.unwrap_or_else(|| {
+                let name = field(&block[0..100]);
+                let prefix = field(&block[345..500]);
+                if &block[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, name)
+                } else {
+                    name
+                }
+            });
+            let mut mtime = parse_number(&block[136..148]).ok_or_else(|| malformed("mtime"))?;
+            let mut size = size;
+            let mut kept = Vec::new();
+            for (key, value) in records {
+                match key.as_str() {
+                    "path" => path = value,
+                    "size" => size = value.parse().map_err(|_| malformed("size record"))?,
+                    // Fractions of seconds are dropped, FAT keeps less than that anyway.
+                    "mtime" => {
+                        mtime = value
+                            .split('.')
+                            .next()
+                            .and_then(|secs| secs.parse().ok())
+                            .ok_or_else(|| malformed("mtime record"))?
+                    }
+                    _ => kept.push((key, value)),
+                }
+            }
+            // Old archives mark directories as files with a `/` at the end of their names.
+            let kind = match type_flag {
+                b'0' | b'\0' | b'7' if path.ends_with('/') => Kind::Dir,
+                b'0' | b'\0' | b'7' => Kind::File,
+                b'5' => Kind::Dir,
+                _ => Kind::Other(type_flag),
+            };
+            let path = path.trim_end_matches('/').to_string();
+            // Links, devices, FIFOs and directories have no data, whatever their size says.
+            self.data_left = match (kind, type_flag) {
+                (Kind::Dir, _) | (_, b'1'..=b'6') => 0,
+                _ => size,
+            };
+            self.padding = padding_of(self.data_left);
+            return Ok(Some(Entry {
+                path,
+                kind,
+                size: if kind == Kind::File { size } else { 0 },
+                mode: parse_number(&block[100..108]).unwrap_or(0o644) as u32,
+                uid: parse_number(&block[108..116]).unwrap_or(0) as u32,
+                gid: parse_number(&block[116..124]).unwrap_or(0) as u32,
+                mtime,
+                records: kept,
+            }));
+        }
+    }
+
+    /// Copy the data of the last entry read.
+    ///
+    /// # Parameters
+    ///
+    /// * `out: &mut impl Write` - Where to copy it to.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<u64>` - The number of bytes copied, or an error if the archive ends early or
+    ///   `out` can't be written.
+    pub fn copy_data(&mut self, out: &mut impl Write) -> io::Result<u64> {
+        let size = self.data_left;
+        let copied = io::copy(&mut (&mut self.input).take(size), out)?;
+        self.offset += copied;
+        self.data_left -= copied;
+        if copied < size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the archive ends in the data of an entry",
+            ));
+        }
+        self.skip_data()?;
+        Ok(copied)
+    }
+
+    /// Helper function to skip what is left of the data of the last entry, with its padding.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if the archive ends early.
+    fn skip_data(&mut self) -> io::Result<()> {
+        let left = self.data_left + self.padding;
+        let skipped = io::copy(&mut (&mut self.input).take(left), &mut io::sink())?;
+        self.offset += skipped;
+        if skipped < left {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the archive ends in the data of an entry",
+            ));
+        }
+        self.data_left = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// Helper function to read the data of an extended header, with its padding.
+    ///
+    /// # Parameters
+    ///
+    /// * `data: &mut [u8]` - The buffer to read the data into, of its size.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - An error if the archive ends early.
+    fn read_data(&mut self, data: &mut [u8]) -> io::Result<()> {
+        self.input.read_exact(data)?;
+        self.offset += data.len() as u64;
+        self.padding = padding_of(data.len() as u64);
+        self.skip_data()
+    }
+
+    /// Helper function to read a block.
+    ///
+    /// # Parameters
+    ///
+    /// * `block: &mut [u8; BLOCK_SIZE]` - The buffer to read it into.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<bool>` - `false` if the archive ended before the block, which some writers
+    ///   leave out the empty blocks at the end for, or an error if it ended in the block.
+    fn read_block(&mut self, block: &mut [u8; BLOCK_SIZE]) -> io::Result<bool> {
+        let mut read = 0;
+        while read < BLOCK_SIZE {
+            match self.input.read(&mut block[read..])? {
+                0 if read == 0 => return Ok(false),
+                0 => return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated header")),
+                count => read += count,
+            }
+        }
+        self.offset += BLOCK_SIZE as u64;
+        Ok(true)
+    }
+}
+
+/// Helper function to build a header.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name field, ASCII of up to 100 bytes.
+/// * `type_flag: u8` - The type flag.
+/// * `size: u64` - The size of the data.
+/// * `mode: u32` - The permission bits.
+/// * `uid: u32` - The ID of the owning user.
+/// * `gid: u32` - The ID of the owning group.
+/// * `mtime: u64` - The modification time, in seconds since the Unix epoch.
+///
+/// # Returns
+///
+/// * `[u8; BLOCK_SIZE]` - The header.
+fn header(
+    name: &str,
+    type_flag: u8,
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+) -> [u8; BLOCK_SIZE] {
+    let mut block = [0; BLOCK_SIZE];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], mode as u64);
+    write_octal(&mut block[108..116], uid as u64);
+    write_octal(&mut block[116..124], gid as u64);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime);
+    block[156] = type_flag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    let checksum = header_checksum(&block);
+    write_octal(&mut block[148..155], checksum);
+    block[155] = b' ';
+    block
+}
+
+/// Helper function to compute the checksum of a header, with its checksum field taken as spaces.
+///
+/// # Parameters
+///
+/// * `block: &[u8; BLOCK_SIZE]` - The header.
+///
+/// # Returns
+///
+/// * `u64` - The sum of its bytes.
+fn header_checksum(block: &[u8; BLOCK_SIZE]) -> u64 {
+    block
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| {
+            if (148..156).contains(&index) {
+                b' ' as u64
+            } else {
+                *byte as u64
+            }
+        })
+        .sum()
+}
+
+/// Helper function to write a number into a field, as zero-padded octal digits and a NUL.
+///
+/// # Parameters
+///
+/// * `field: &mut [u8]` - The field.
+/// * `number: u64` - The number, which has to fit.
+fn write_octal(field: &mut [u8], number: u64) {
+    let digits = format!("{:0width$o}", number, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Helper function to parse a numeric field, in octal or in the base-256 form GNU tar writes
+/// for numbers too large for it.
+///
+/// # Parameters
+///
+/// * `field: &[u8]` - The field.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The number, or `None` if the field is malformed.
+fn parse_number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|byte| byte & 0x80 != 0) {
+        return field[1..].iter().try_fold(0u64, |number, byte| {
+            number.checked_mul(256)?.checked_add(*byte as u64)
+        });
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Helper function to read a text field, up to its first NUL.
+///
+/// # Parameters
+///
+/// * `field: &[u8]` - The field.
+///
+/// # Returns
+///
+/// * `String` - The text, with bytes that aren't UTF-8 replaced.
+fn field(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Helper function to format a record of an extended header, `LENGTH key=value\n`, where the
+/// length counts the whole record, its own digits too.
+///
+/// # Parameters
+///
+/// * `key: &str` - The key.
+/// * `value: &str` - The value.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The record.
+fn format_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + rest.to_string().len();
+    if length.to_string().len() + rest != length {
+        length += 1;
+    }
+    format!("{} {}={}\n", length, key, value).into_bytes()
+}
+
+/// Helper function to parse the records of an extended header.
+///
+/// # Parameters
+///
+/// * `data: &[u8]` - The data of the extended header.
+///
+/// # Returns
+///
+/// * `Option<Vec<(String, String)>>` - The keys and values, or `None` if a record is malformed.
+fn parse_records(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data.iter().position(|byte| *byte == b' ')?;
+        let length: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        if length <= space + 1 || length > data.len() || data[length - 1] != b'\n' {
+            return None;
+        }
+        let record = std::str::from_utf8(&data[space + 1..length - 1]).ok()?;
+        let (key, value) = record.split_once('=')?;
+        records.push((key.to_string(), value.to_string()));
+        data = &data[length..];
+    }
+    Some(records)
+}
+
+/// Helper function to replace the characters of a name that aren't ASCII, for the fields only
+/// readers without extended headers use.
+///
+/// # Parameters
+///
+/// * `name: &str` - The name.
+///
+/// # Returns
+///
+/// * `String` - The name, with `_` for characters that aren't ASCII.
+fn ascii_only(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect()
+}
+
+/// Helper function to compute the padding following data of a size.
+///
+/// # Parameters
+///
+/// * `size: u64` - The size of the data.
+///
+/// # Returns
+///
+/// * `u64` - The bytes up to the next whole block.
+fn padding_of(size: u64) -> u64 {
+    (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let long_path = format!("{}/ünïcode.txt", "deep/".repeat(30));
+        let entries = [
+            Entry {
+                path: "EFI".to_string(),
+                kind: Kind::Dir,
+                size: 0,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                mtime: 1_700_000_000,
+                records: vec![("NUFAT.attributes".to_string(), "-h--".to_string())],
+            },
+            Entry {
+                path: long_path,
+                kind: Kind::File,
+                size: 5,
+                mode: 0o644,
+                uid: 1000,
+                gid: 100,
+                mtime: 1_700_000_002,
+                records: vec![("NUFAT.crtime".to_string(), "1700000000.25".to_string())],
+            },
+        ];
+        let mut archive = Vec::new();
+        for entry in &entries {
+            write_entry(&mut archive, entry).unwrap();
+            archive.extend(&b"hello"[..entry.size as usize]);
+            write_padding(&mut archive, entry.size).unwrap();
+        }
+        finish(&mut archive).unwrap();
+        assert_eq!(archive.len() % BLOCK_SIZE, 0);
+
+        let mut reader = Reader::new(&archive[..]);
+        let first = reader.next_entry().unwrap().unwrap();
+        assert_eq!(first, entries[0]);
+        assert_eq!(first.record("NUFAT.attributes"), Some("-h--"));
+        let second = reader.next_entry().unwrap().unwrap();
+        assert_eq!(second, entries[1]);
+        let mut data = Vec::new();
+        assert_eq!(reader.copy_data(&mut data).unwrap(), 5);
+        assert_eq!(data, b"hello");
+        assert_eq!(reader.next_entry().unwrap(), None);
+    }
+
+    #[test]
+    fn records_count_their_own_length() {
+        assert_eq!(format_record("a", "b"), b"6 a=b\n");
+        assert_eq!(format_record("path", "x".repeat(91).as_str()).len(), 101);
+        assert_eq!(
+            &format_record("path", "x".repeat(91).as_str())[..4],
+            b"101 "
+        );
+        let data = [format_record("k", "v=w"), format_record("path", "é")].concat();
+        assert_eq!(
+            parse_records(&data).unwrap(),
+            [
+                ("k".to_string(), "v=w".to_string()),
+                ("path".to_string(), "é".to_string())
+            ]
+        );
+        assert_eq!(parse_records(b"9 a=b\n"), None);
+    }
+
+    #[test]
+    fn damaged_headers_are_refused() {
+        let mut archive = Vec::new();
+        let entry = Entry {
+            path: "a".to_string(),
+            kind: Kind::File,
+            size: 0,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            records: Vec::new(),
+        };
+        write_entry(&mut archive, &entry).unwrap();
+        archive[0] = b'b';
+        let e = Reader::new(&archive[..]).next_entry().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(parse_number(b"0000644\0"), Some(0o644));
+        assert_eq!(parse_number(&[0x80, 0, 0, 1, 0]), Some(256));
+    }
+}
@@ -0,0 +1,163 @@
+//! This module makes up disk images for the tests. They are formatted by `fatfs` as files in the
+//! temporary directory and deleted again once the test is done with them. Those with more to them
+//! than a few files are built from a spec, like the `mkimage` subcommand does.
+use crate::hash;
+use crate::mkimage;
+use crate::options::Options;
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The number of disk images made up so far, to give each one its own name.
+static IMAGES: AtomicU32 = AtomicU32::new(0);
+
+/// Represent a disk image made up for a test.
+///
+/// # Members
+///
+/// * `path: PathBuf` - The path of the disk image.
+pub struct TestImage {
+    path: PathBuf,
+}
+
+impl TestImage {
+    /// Format a new disk image.
+    ///
+    /// # Parameters
+    ///
+    /// * `fat_type: FatType` - The FAT variant to format with. FAT12 gets the size of a 1.44 MB
+    ///   floppy, FAT16 16 MiB and FAT32 the 64 MiB it needs at least.
+    ///
+    /// # Returns
+    ///
+    /// * `TestImage` - The formatted, empty disk image.
+    pub fn new(fat_type: FatType) -> Self {
+        let size = match fat_type {
+            FatType::Fat12 => 1_474_560,
+            FatType::Fat16 => 16 << 20,
+            FatType::Fat32 => 64 << 20,
+        };
+        let image = Self::blank(size);
+        fatfs::format_volume(
+            image.open(),
+            FormatVolumeOptions::new()
+                .fat_type(fat_type)
+                .volume_id(0x1234_5678),
+        )
+        .unwrap();
+        image
+    }
+
+    /// Make up a new disk image that is not formatted, all zeros.
+    ///
+    /// # Parameters
+    ///
+    /// * `size: u64` - The size of the disk image in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `TestImage` - The empty disk image.
+    pub fn blank(size: u64) -> Self {
+        let path = env::temp_dir().join(format!(
+            "nufat-test-{}-{}.img",
+            process::id(),
+            IMAGES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let image = TestImage { path };
+        image.open().set_len(size).unwrap();
+        image
+    }
+
+    /// Format a new disk image holding some files.
+    ///
+    /// # Parameters
+    ///
+    /// * `fat_type: FatType` - The FAT variant to format with.
+    /// * `files: &[(&str, &[u8])]` - The paths and contents of the files, in directories that
+    ///   already exist.
+    ///
+    /// # Returns
+    ///
+    /// * `TestImage` - The formatted disk image.
+    pub fn with_files(fat_type: FatType, files: &[(&str, &[u8])]) -> Self {
+        let image = Self::new(fat_type);
+        {
+            let fs = image.volume();
+            for (path, data) in files {
+                let mut file = fs.root_dir().create_file(path).unwrap();
+                file.write_all(data).unwrap();
+            }
+        }
+        image
+    }
+
+    /// Build a new disk image from a spec, see the `mkimage` module.
+    ///
+    /// # Parameters
+    ///
+    /// * `spec: &str` - The spec in JSON.
+    ///
+    /// # Returns
+    ///
+    /// * `TestImage` - The disk image.
+    pub fn from_spec(spec: &str) -> Self {
+        let image = Self::blank(0);
+        mkimage::build(&mkimage::parse_spec(spec).unwrap(), &image.open()).unwrap();
+        image
+    }
+
+    /// Open the disk image for reading and writing.
+    ///
+    /// # Returns
+    ///
+    /// * `File` - The opened disk image.
+    pub fn open(&self) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .unwrap()
+    }
+
+    /// Open the volume on the disk image with `fatfs`, to set it up or look at it.
+    ///
+    /// # Returns
+    ///
+    /// * `FileSystem<File>` - The opened volume.
+    pub fn volume(&self) -> FileSystem<File> {
+        FileSystem::new(self.open(), FsOptions::new()).unwrap()
+    }
+
+    /// Compute the SHA-256 digest of the disk image, to check that nothing on it changed.
+    ///
+    /// # Returns
+    ///
+    /// * `[u8; 32]` - The digest.
+    pub fn checksum(&self) -> [u8; 32] {
+        hash::sha256(&mut File::open(&self.path).unwrap()).unwrap()
+    }
+
+    /// Get the options to mount the disk image with.
+    ///
+    /// # Returns
+    ///
+    /// * `Options` - The default options, with the path of the disk image.
+    pub fn options(&self) -> Options {
+        Options {
+            disk_image_path: self.path.clone(),
+            ..Options::default()
+        }
+    }
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,377 @@
+//! This module finds the files and directories on a disk image that is not mounted for the
+//! subcommands working on them, like `ls` and `extract`, and expands the glob patterns they take
+//! against the tree of the volume.
+use crate::filesystem::{check_supported, find_entry, open_dir};
+use crate::floppy;
+use crate::glob::Pattern;
+use crate::storage::Storage;
+use fatfs::{Date, DateTime, FileAttributes, FileSystem as FatfsFileSystem, FsOptions, Time};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, ErrorKind, Seek};
+use std::path::{Path, PathBuf};
+
+/// Represent a file or directory found on the volume.
+///
+/// # Members
+///
+/// * `path: PathBuf` - The absolute path of the entry, with its long names.
+/// * `base: PathBuf` - The directory the pattern that found the entry starts in, see
+///   `Pattern::base`, with its names as stored. Subcommands copying entries recreate their paths
+///   below it.
+/// * `is_dir: bool` - Whether the entry is a directory.
+/// * `size: u64` - The size of a file, 0 for directories.
+/// * `attributes: FileAttributes` - The attributes of the entry.
+/// * `modified: DateTime` - When the entry was last modified.
+#[derive(Clone, Debug)]
+pub struct Found {
+    pub path: PathBuf,
+    pub base: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    pub attributes: FileAttributes,
+    pub modified: DateTime,
+}
+
+/// Open the volume on a disk image.
+///
+/// # Parameters
+///
+/// * `image: &File` - The opened disk image, wherever its offset is.
+///
+/// # Returns
+///
+/// * `io::Result<FatfsFileSystem<Storage>>` - The opened volume, which can be changed if the disk
+///   image was opened for writing.
+pub fn open_volume(image: &File) -> io::Result<FatfsFileSystem<Storage>> {
+    check_supported(image)?;
+    // The clone shares the offset of the disk image, which a volume unmounted before leaves
+    // anywhere, and `fatfs` expects to start at the boot sector.
+    let boot_sector = floppy::patched_boot_sector(image)?;
+    let mut clone = image.try_clone()?;
+    clone.rewind()?;
+    let storage = Storage::new(clone, 0, boot_sector);
+    FatfsFileSystem::new(storage, FsOptions::new())
+}
+
+/// Find a file or directory and describe it.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `path: &Path` - The absolute path, matched ignoring case, on long or short names.
+/// * `base: &Path` - The directory the entry is copied below, see `Found::base`.
+///
+/// # Returns
+///
+/// * `io::Result<Found>` - The entry with the names as stored, or a `NotFound` error if there is
+///   none. The root directory has no entry, its times are the earliest FAT knows.
+pub fn find(fs: &FatfsFileSystem<Storage>, path: &Path, base: &Path) -> io::Result<Found> {
+    if path.parent().is_none() {
+        let earliest = DateTime {
+            date: Date {
+                year: 1980,
+                month: 1,
+                day: 1,
+            },
+            time: Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        };
+        return Ok(Found {
+            path: PathBuf::from("/"),
+            base: PathBuf::from("/"),
+            is_dir: true,
+            size: 0,
+            attributes: FileAttributes::DIRECTORY,
+            modified: earliest,
+        });
+    }
+    // The names as stored, in case they were given in another case or by their short names.
+    let mut stored = PathBuf::from("/");
+    let mut entry = None;
+    for component in path.components().skip(1) {
+        let found = find_entry(fs, &stored.join(component))?;
+        stored.push(found.file_name());
+        entry = Some(found);
+    }
+    let entry = entry.unwrap();
+    let base = stored
+        .ancestors()
+        .find(|ancestor| ancestor.components().count() == base.components().count())
+        .unwrap_or(Path::new("/"))
+        .to_path_buf();
+    Ok(Found {
+        is_dir: entry.is_dir(),
+        size: if entry.is_dir() { 0 } else { entry.len() },
+        attributes: entry.attributes(),
+        modified: entry.modified(),
+        path: stored,
+        base,
+    })
+}
+
+/// Find everything below a directory.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `dir: &Found` - The directory.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Found>>` - The entries, every directory before the entries in it, with the
+///   same base as `dir`.
+pub fn walk(fs: &FatfsFileSystem<Storage>, dir: &Found) -> io::Result<Vec<Found>> {
+    let mut found = Vec::new();
+    walk_into(fs, &dir.path, &dir.base, true, &mut found)?;
+    Ok(found)
+}
+
+/// Find the entries right in a directory.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `dir: &Found` - The directory.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Found>>` - The entries, but not those below them, with the same base as
+///   `dir`.
+pub fn list(fs: &FatfsFileSystem<Storage>, dir: &Found) -> io::Result<Vec<Found>> {
+    let mut found = Vec::new();
+    walk_into(fs, &dir.path, &dir.base, false, &mut found)?;
+    Ok(found)
+}
+
+/// Helper function to find the entries in a directory, and everything below them depth first.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `dir: &Path` - The path of the directory.
+/// * `base: &Path` - The base to give the entries.
+/// * `recursive: bool` - Whether to find what is below the entries too.
+/// * `found: &mut Vec<Found>` - The entries found so far, which those below `dir` are added to.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if a directory can't be read.
+fn walk_into(
+    fs: &FatfsFileSystem<Storage>,
+    dir: &Path,
+    base: &Path,
+    recursive: bool,
+    found: &mut Vec<Found>,
+) -> io::Result<()> {
+    for entry in open_dir(fs, dir)?.iter() {
+        let entry = entry?;
+        if entry.attributes().contains(FileAttributes::VOLUME_ID)
+            || matches!(entry.file_name().as_str(), "." | "..")
+        {
+            continue;
+        }
+        let path = dir.join(entry.file_name());
+        found.push(Found {
+            path: path.clone(),
+            base: base.to_path_buf(),
+            is_dir: entry.is_dir(),
+            size: if entry.is_dir() { 0 } else { entry.len() },
+            attributes: entry.attributes(),
+            modified: entry.modified(),
+        });
+        if recursive && entry.is_dir() {
+            walk_into(fs, &path, base, true, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Expand patterns against the tree of the volume.
+///
+/// # Parameters
+///
+/// * `fs: &FatfsFileSystem<Storage>` - The opened volume.
+/// * `patterns: &[Pattern]` - The patterns.
+/// * `allow_empty: bool` - Whether patterns matching nothing are fine, as with `--allow-empty`.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Found>>` - The entries matching any pattern, each once, in the order of the
+///   patterns and then of the tree, or a `NotFound` error naming the first pattern that matches
+///   nothing.
+pub fn expand(
+    fs: &FatfsFileSystem<Storage>,
+    patterns: &[Pattern],
+    allow_empty: bool,
+) -> io::Result<Vec<Found>> {
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    for pattern in patterns {
+        let matches = match pattern.literal_path() {
+            Some(path) => match find(fs, &path, &pattern.base()) {
+                Ok(entry) => vec![entry],
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            },
+            None => match find(fs, &pattern.base(), &pattern.base()) {
+                Ok(base) if base.is_dir => walk(fs, &base)?
+                    .into_iter()
+                    .filter(|entry| pattern.matches(&entry.path))
+                    .collect(),
+                Ok(_) => Vec::new(),
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            },
+        };
+        if matches.is_empty() && !allow_empty {
+            return Err(io::Error::new(ErrorKind::NotFound, no_match(pattern)));
+        }
+        for entry in matches {
+            if seen.insert(entry.path.clone()) {
+                found.push(entry);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Say that a pattern matches nothing. The shell expands patterns that aren't quoted against the
+/// host, so a pattern without wildcards that names a host file likely was one.
+///
+/// # Parameters
+///
+/// * `pattern: &Pattern` - The pattern.
+///
+/// # Returns
+///
+/// * `String` - The message.
+fn no_match(pattern: &Pattern) -> String {
+    if pattern.has_wildcards() {
+        return format!("Nothing on the volume matches '{}'.", pattern.as_str());
+    }
+    let mut message = format!("'{}' does not exist on the volume.", pattern.as_str());
+    if Path::new(pattern.as_str()).exists() {
+        message.push_str(
+            " It exists on the host. If it came from a pattern, quote the pattern so the shell \
+             doesn't expand it.",
+        );
+    }
+    message
+}
+
+/// Format a time of an entry, e.g. `2024-05-01 12:34:56`.
+///
+/// # Parameters
+///
+/// * `time: DateTime` - The time, as stored in the directory entry.
+///
+/// # Returns
+///
+/// * `String` - The formatted time.
+pub fn format_time(time: DateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        time.date.year,
+        time.date.month,
+        time.date.day,
+        time.time.hour,
+        time.time.min,
+        time.time.sec
+    )
+}
+
+/// Parse the patterns given to a subcommand.
+///
+/// # Parameters
+///
+/// * `patterns: &[&str]` - The patterns.
+///
+/// # Returns
+///
+/// * `Result<Vec<Pattern>, String>` - The parsed patterns, or why one is malformed.
+pub fn parse_patterns(patterns: &[&str]) -> Result<Vec<Pattern>, String> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+
+    /// Expand patterns on a disk image and get the paths found.
+    fn expand_paths(
+        image: &TestImage,
+        patterns: &[&str],
+        allow_empty: bool,
+    ) -> io::Result<Vec<String>> {
+        let file = image.open();
+        let fs = open_volume(&file)?;
+        let found = expand(&fs, &parse_patterns(patterns).unwrap(), allow_empty)?;
+        Ok(found
+            .iter()
+            .map(|entry| entry.path.display().to_string())
+            .collect())
+    }
+
+    #[test]
+    fn patterns_expand_against_the_tree() {
+        let image = TestImage::from_spec(
+            r#"{"size": "16M", "fat_type": "FAT16", "entries": [
+                {"name": "EFI", "entries": [
+                    {"name": "BOOT", "entries": [{"name": "bootx64.efi"}, {"name": "grub.cfg"}]},
+                    {"name": "shell.EFI"}
+                ]},
+                {"name": "syslinux.cfg"},
+                {"name": "Read Me.txt"}
+            ]}"#,
+        );
+        assert_eq!(
+            expand_paths(&image, &["/efi/**/*.efi"], false).unwrap(),
+            ["/EFI/BOOT/bootx64.efi", "/EFI/shell.EFI"]
+        );
+        assert_eq!(
+            expand_paths(&image, &["*.CFG", "/**/*.cfg"], false).unwrap(),
+            ["/syslinux.cfg", "/EFI/BOOT/grub.cfg"]
+        );
+        assert_eq!(
+            expand_paths(&image, &["/read me.TXT"], false).unwrap(),
+            ["/Read Me.txt"]
+        );
+
+        let file = image.open();
+        let fs = open_volume(&file).unwrap();
+        let found = expand(&fs, &parse_patterns(&["/EFI/**/*.efi"]).unwrap(), false).unwrap();
+        assert_eq!(found[0].base, Path::new("/EFI"));
+        let found = expand(&fs, &parse_patterns(&["/efi/boot"]).unwrap(), false).unwrap();
+        assert_eq!(found[0].base, Path::new("/EFI"));
+        assert!(found[0].is_dir);
+    }
+
+    #[test]
+    fn patterns_matching_nothing_fail_unless_allowed() {
+        let image = TestImage::from_spec(r#"{"size": "16M", "entries": [{"name": "a.txt"}]}"#);
+        let e = expand_paths(&image, &["*.cfg"], false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "Nothing on the volume matches '*.cfg'.");
+        assert!(expand_paths(&image, &["/missing.txt"], false).is_err());
+        assert_eq!(
+            expand_paths(&image, &["*.cfg", "/missing.txt", "*.txt"], true).unwrap(),
+            ["/a.txt"]
+        );
+
+        // A pattern the shell expanded to a host file gets a hint.
+        let host_file = std::env::temp_dir().join("nufat-tree-hint.cfg");
+        std::fs::write(&host_file, b"").unwrap();
+        let e = expand_paths(&image, &[host_file.to_str().unwrap()], false).unwrap_err();
+        std::fs::remove_file(&host_file).unwrap();
+        assert!(e.to_string().contains("quote the pattern"), "{}", e);
+    }
+}
@@ -0,0 +1,194 @@
+//! This module implements the `umount` subcommand, which unmounts a mounted nuFAT filesystem.
+//!
+//! Unmounting the filesystem through the kernel ends the FUSE session, after which nuFAT flushes
+//! and closes the disk image on its own. This also works when nuFAT is not running anymore and
+//! only the stale kernel mount is left.
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Run the `umount` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<mount_point> [--timeout SECS] [--force]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 if the filesystem was unmounted, 1 otherwise.
+pub fn run(args: &[String]) -> i32 {
+    let mut mount_point = None;
+    let mut timeout = Duration::from_secs(10);
+    let mut force = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--timeout" {
+            timeout = match args.next().map(|secs| secs.parse()) {
+                Some(Ok(secs)) => Duration::from_secs(secs),
+                _ => {
+                    eprintln!("Option '--timeout' requires a number of seconds.");
+                    return 1;
+                }
+            };
+        } else if arg == "--force" {
+            force = true;
+        } else if arg.starts_with('-') || mount_point.is_some() {
+            eprintln!("Usage: nuFAT umount <mount_point> [--timeout SECS] [--force]");
+            return 1;
+        } else {
+            mount_point = Some(PathBuf::from(arg));
+        }
+    }
+
+    let mount_point = match mount_point.map(fs::canonicalize) {
+        Some(Ok(mount_point)) => mount_point,
+        Some(Err(e)) => {
+            eprintln!("Invalid mount point: {}", e);
+            return 1;
+        }
+        None => {
+            eprintln!("Usage: nuFAT umount <mount_point> [--timeout SECS] [--force]");
+            return 1;
+        }
+    };
+
+    if !is_fuse_mount(&mount_point) {
+        eprintln!("{:?} is not a mounted FUSE filesystem.", mount_point);
+        return 1;
+    }
+
+    let state = match unmount(&mount_point, false) {
+        Ok(()) => "clean",
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) && force => {
+            match unmount(&mount_point, true) {
+                Ok(()) => "forced",
+                Err(e) => {
+                    eprintln!("Failed to unmount {:?}: {}", mount_point, e);
+                    return 1;
+                }
+            }
+        }
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+            eprintln!(
+                "{:?} is busy, files on it are still in use. Close them or pass --force.",
+                mount_point
+            );
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Failed to unmount {:?}: {}", mount_point, e);
+            return 1;
+        }
+    };
+
+    // Wait for the kernel to tear the mount down.
+    let start = Instant::now();
+    while is_fuse_mount(&mount_point) {
+        if start.elapsed() > timeout {
+            eprintln!("Timed out waiting for {:?} to be unmounted.", mount_point);
+            return 1;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    println!("Unmounted {:?} ({}).", mount_point, state);
+    0
+}
+
+/// Check whether a FUSE filesystem is mounted on the given path.
+///
+/// # Parameters
+///
+/// * `mount_point: &Path` - The canonical path of the mount point.
+///
+/// # Returns
+///
+/// * `bool` - `true` if `/proc/self/mountinfo` lists a FUSE filesystem mounted there.
+fn is_fuse_mount(mount_point: &Path) -> bool {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    mountinfo.lines().any(|line| {
+        // Format: ID PARENT MAJOR:MINOR ROOT MOUNT_POINT OPTIONS [FIELDS...] - FSTYPE SOURCE ...
+        let mut fields = line.split(' ');
+        let path = fields.nth(4).map(unescape_mountinfo);
+        let fstype = fields.skip_while(|field| *field != "-").nth(1);
+        path.as_deref() == Some(mount_point)
+            && fstype.is_some_and(|fstype| fstype == "fuse" || fstype.starts_with("fuse."))
+    })
+}
+
+/// Decode the octal escapes (e.g. `\040` for a space) used for paths in `/proc/self/mountinfo`.
+///
+/// # Parameters
+///
+/// * `path: &str` - The escaped path.
+///
+/// # Returns
+///
+/// * `PathBuf` - The decoded path.
+fn unescape_mountinfo(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escape
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok())
+        {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsStr::from_bytes(&decoded))
+}
+
+/// Unmount a filesystem, falling back to `fusermount` when not permitted to unmount directly.
+///
+/// # Parameters
+///
+/// * `mount_point: &Path` - The mount point of the filesystem.
+/// * `lazy: bool` - Whether to detach the filesystem even if it is busy.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An error if the filesystem could not be unmounted.
+fn unmount(mount_point: &Path, lazy: bool) -> io::Result<()> {
+    let path = CString::new(mount_point.as_os_str().as_bytes())?;
+    let flags = if lazy { libc::MNT_DETACH } else { 0 };
+    if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 {
+        return Ok(());
+    }
+
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EPERM) {
+        return Err(error);
+    }
+
+    // Unprivileged users have to go through the setuid helper of libfuse.
+    let unmount_flag = if lazy { "-uz" } else { "-u" };
+    for fusermount in ["fusermount3", "fusermount"] {
+        if let Ok(status) = Command::new(fusermount)
+            .arg(unmount_flag)
+            .arg(mount_point)
+            .status()
+        {
+            return if status.success() {
+                Ok(())
+            } else {
+                Err(io::Error::other(format!("{} failed", fusermount)))
+            };
+        }
+    }
+    Err(error)
+}
@@ -0,0 +1,95 @@
+//! This module implements the `unpack` subcommand, which copies everything on a disk image that
+//! is not mounted to a directory on the host, the reverse of `pack`. The copying is done by the
+//! `copy` module. With `--json`, what was copied is printed as a JSON report instead.
+use crate::copy::{self, Conflicts, CopyOptions, Owner};
+use crate::exclude::{read_excludes, Exclude};
+use crate::filesystem::lock_image;
+use crate::tree;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT unpack <disk.img> <DIR> [--force|--update] [--exclude GLOB]... [--exclude-from FILE]... [--dotify-hidden] [--owner UID[:GID]] [--json]";
+
+/// Run the `unpack` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand: `<disk.img> <DIR> [--force|--update]
+///   [--exclude GLOB]... [--exclude-from FILE]... [--dotify-hidden] [--owner UID[:GID]]
+///   [--json]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or an entry can't be
+///   copied, 2 if the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut options = CopyOptions::default();
+    let mut json = false;
+    let mut paths = Vec::new();
+    let mut valid = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => options.conflicts = Conflicts::Overwrite,
+            "--update" => options.conflicts = Conflicts::Update,
+            "--dotify-hidden" => options.dotify_hidden = true,
+            "--json" => json = true,
+            "--owner" => match args.next().map(|arg| Owner::parse(arg)) {
+                Some(Ok(owner)) => options.owner = Some(owner),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            "--exclude" => match args.next().map(|glob| Exclude::new(glob)) {
+                Some(Ok(exclude)) => options.excludes.push(exclude),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            "--exclude-from" => match args.next().map(|path| read_excludes(Path::new(path))) {
+                Some(Ok(excludes)) => options.excludes.extend(excludes),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => valid = false,
+            },
+            _ if arg.starts_with("--") => valid = false,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let ([disk_image_path, output], true) = (&paths[..], valid) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let result = File::open(disk_image_path).and_then(|image| {
+        lock_image(&image, false)?;
+        let fs = tree::open_volume(&image)?;
+        let root = tree::find(&fs, Path::new("/"), Path::new("/"))?;
+        let mut entries = tree::walk(&fs, &root)?;
+        // The root directory itself is copied first, so even an empty volume gives a directory.
+        entries.insert(0, root);
+        let entries = copy::exclude(entries, Path::new("/"), &options.excludes);
+        copy::copy_out(&fs, &entries, Path::new(output), &options)
+    });
+    match result {
+        Ok(summary) if json => {
+            println!("{}", summary.format_json(Path::new(output), options.owner));
+            0
+        }
+        Ok(summary) => {
+            println!("{}", summary.describe(false));
+            0
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", PathBuf::from(disk_image_path), e);
+            1
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! This module implements the `wipe-free` subcommand, which overwrites the free clusters of a disk
+//! image that is not mounted, so no data of deleted files is left in them, e.g. before publishing
+//! the image. Optionally, what is left of deleted directory entries is scrubbed as well, so their
+//! names are gone too. Free clusters are found in the FAT, through the `direntry` module.
+use crate::direntry::{self, ClusterState};
+use crate::filesystem::{check_supported, lock_image};
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// The usage of the subcommand.
+const USAGE: &str = "Usage: nuFAT wipe-free <disk.img> [--pattern BYTE] [--scrub-dirents]";
+
+/// The most bytes read and written in one go.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Run the `wipe-free` subcommand.
+///
+/// # Parameters
+///
+/// * `args: &[String]` - The arguments of the subcommand:
+///   `<disk.img> [--pattern BYTE] [--scrub-dirents]`.
+///
+/// # Returns
+///
+/// * `i32` - The exit code: 0 on success, 1 if the disk image can't be read or written, and 2 if
+///   the arguments are invalid.
+pub fn run(args: &[String]) -> i32 {
+    let mut disk_image_path = None;
+    let mut pattern = Some(0);
+    let mut scrub_dirents = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scrub-dirents" => scrub_dirents = true,
+            "--pattern" => pattern = args.next().and_then(|byte| parse_pattern(byte)),
+            _ if disk_image_path.is_none() && !arg.starts_with("--") => {
+                disk_image_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                disk_image_path = None;
+                break;
+            }
+        }
+    }
+    let (Some(disk_image_path), Some(pattern)) = (disk_image_path, pattern) else {
+        eprintln!("{}", USAGE);
+        eprintln!("BYTE is a number from 0 to 255, e.g. 0 or 0xFF.");
+        return 2;
+    };
+
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&disk_image_path)
+        .and_then(|image| {
+            lock_image(&image, true)?;
+            check_supported(&image)?;
+            let (clusters, wiped) = wipe_free_clusters(&image, pattern)?;
+            println!("Wiped {} bytes in {} free clusters.", wiped, clusters);
+            if scrub_dirents {
+                let scrubbed = direntry::scrub_dir_slots(&image)?;
+                println!("Scrubbed {} bytes of deleted directory entries.", scrubbed);
+            }
+            image.sync_all()
+        });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:?}: {}", disk_image_path, e);
+            1
+        }
+    }
+}
+
+/// Parse the byte to fill free clusters with.
+///
+/// # Parameters
+///
+/// * `byte: &str` - The byte, in decimal or as hex digits starting with `0x`.
+///
+/// # Returns
+///
+/// * `Option<u8>` - The byte, or `None` if it is invalid.
+fn parse_pattern(byte: &str) -> Option<u8> {
+    match byte.strip_prefix("0x").or_else(|| byte.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => byte.parse().ok(),
+    }
+}
+
+/// Fill every free cluster of the volume with a byte. Clusters already holding nothing else are
+/// not written again, so running it again after it was interrupted only writes what is left.
+///
+/// # Parameters
+///
+/// * `image: &File` - The disk image, opened for writing.
+/// * `pattern: u8` - The byte to fill the clusters with.
+///
+/// # Returns
+///
+/// * `io::Result<(u64, u64)>` - How many clusters are free, and how many bytes were written.
+fn wipe_free_clusters(image: &File, pattern: u8) -> io::Result<(u64, u64)> {
+    let map = direntry::cluster_map(image)?;
+    let mut free_clusters = 0;
+    let mut wiped = 0;
+    let mut buf = Vec::new();
+    let mut index = 0;
+    while index < map.states.len() {
+        if map.states[index] != ClusterState::Free {
+            index += 1;
+            continue;
+        }
+        // Adjacent free clusters are wiped together.
+        let run = map.states[index..]
+            .iter()
+            .take_while(|state| **state == ClusterState::Free)
+            .count();
+        let start = map.data_start + index as u64 * map.cluster_size;
+        let end = start + run as u64 * map.cluster_size;
+        let mut offset = start;
+        while offset < end {
+            let size = cmp::min(end - offset, CHUNK_SIZE) as usize;
+            buf.resize(size, 0);
+            image.read_exact_at(&mut buf, offset)?;
+            if buf.iter().any(|b| *b != pattern) {
+                buf.fill(pattern);
+                image.write_all_at(&buf, offset)?;
+                wiped += size as u64;
+            }
+            offset += size as u64;
+        }
+        free_clusters += run as u64;
+        index += run;
+    }
+    Ok((free_clusters, wiped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::TestImage;
+    use fatfs::FatType;
+    use std::io::Read;
+
+    /// Read the whole disk image.
+    fn contents(image: &TestImage) -> Vec<u8> {
+        let mut contents = Vec::new();
+        image.open().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    /// Check whether the disk image holds a sequence of bytes anywhere.
+    fn holds(contents: &[u8], needle: &[u8]) -> bool {
+        contents
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn wipe_free_clusters_removes_deleted_data() {
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let secret = b"secret data of a deleted file".repeat(100);
+            let kept = b"data of a file that is kept".repeat(100);
+            let image =
+                TestImage::with_files(fat_type, &[("secret.txt", &secret), ("kept.txt", &kept)]);
+            image.volume().root_dir().remove("secret.txt").unwrap();
+            assert!(holds(&contents(&image), &secret));
+
+            let file = image.open();
+            let (free_clusters, wiped) = wipe_free_clusters(&file, 0).unwrap();
+            assert_eq!(
+                free_clusters,
+                image.volume().stats().unwrap().free_clusters() as u64
+            );
+            assert!(wiped >= secret.len() as u64);
+            let after = contents(&image);
+            assert!(!holds(&after, &secret[..64]));
+            assert!(holds(&after, &kept));
+
+            // Nothing is left to write the second time.
+            assert_eq!(wipe_free_clusters(&file, 0).unwrap().1, 0);
+            let cluster_size = direntry::cluster_map(&file).unwrap().cluster_size;
+            assert_eq!(
+                wipe_free_clusters(&file, 0xFF).unwrap().1,
+                free_clusters * cluster_size
+            );
+        }
+    }
+
+    #[test]
+    fn scrub_dir_slots_removes_deleted_names() {
+        for fat_type in [FatType::Fat16, FatType::Fat32] {
+            let image = TestImage::with_files(
+                fat_type,
+                &[("Secret long name.txt", b"x"), ("kept.txt", b"y")],
+            );
+            {
+                let fs = image.volume();
+                fs.root_dir().create_dir("Sub").unwrap();
+                fs.root_dir().create_file("Sub/Another secret.txt").unwrap();
+                fs.root_dir().remove("Secret long name.txt").unwrap();
+                fs.root_dir().remove("Sub/Another secret.txt").unwrap();
+            }
+            let utf16 = |name: &str| {
+                name.encode_utf16()
+                    .flat_map(u16::to_le_bytes)
+                    .collect::<Vec<u8>>()
+            };
+            assert!(holds(&contents(&image), b"ECRET~1TXT"));
+
+            let file = image.open();
+            assert!(direntry::scrub_dir_slots(&file).unwrap() > 0);
+            let after = contents(&image);
+            assert!(!holds(&after, b"ECRET~1TXT"));
+            assert!(!holds(&after, b"NOTHER~1TXT"));
+            assert!(!holds(&after, &utf16("Secre")));
+            assert!(!holds(&after, &utf16("Anoth")));
+            assert_eq!(direntry::scrub_dir_slots(&file).unwrap(), 0);
+
+            // The entries after the deleted ones are still found.
+            let fs = image.volume();
+            let names: Vec<String> = fs
+                .root_dir()
+                .iter()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, ["kept.txt", "Sub"]);
+        }
+    }
+
+    #[test]
+    fn parse_pattern_accepts_decimal_and_hex() {
+        assert_eq!(parse_pattern("0"), Some(0));
+        assert_eq!(parse_pattern("255"), Some(255));
+        assert_eq!(parse_pattern("0xA5"), Some(0xA5));
+        assert_eq!(parse_pattern("256"), None);
+        assert_eq!(parse_pattern("0x"), None);
+        assert_eq!(parse_pattern("zero"), None);
+    }
+
+    #[test]
+    fn wipe_free_refuses_mounted_images() {
+        let image = TestImage::new(FatType::Fat12);
+        let mounted = image.open();
+        lock_image(&mounted, false).unwrap();
+        assert_eq!(
+            lock_image(&image.open(), true).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        drop(mounted);
+        lock_image(&image.open(), true).unwrap();
+    }
+}